@@ -0,0 +1,2 @@
+ALTER TABLE boards
+ADD COLUMN IF NOT EXISTS upstream TEXT NOT NULL DEFAULT '4chan';
@@ -0,0 +1,20 @@
+ALTER TABLE boards
+ADD COLUMN IF NOT EXISTS priority INTEGER NOT NULL DEFAULT 0;
+
+-- Unix time of the last time the job was (re)inserted from a board scan
+ALTER TABLE thread_backlog
+ADD COLUMN IF NOT EXISTS last_seen BIGINT NOT NULL DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT;
+
+ALTER TABLE image_backlog
+ADD COLUMN IF NOT EXISTS last_seen BIGINT NOT NULL DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT;
+
+-- Higher score gets fetched first.
+-- Each page adds 10 points, so threads on the last page (about to be pruned) come first.
+-- Jobs gain 1 point per minute since they were last seen, capped at 60, so low pages don't starve.
+-- Each level of board priority is worth one page.
+CREATE OR REPLACE FUNCTION backlog_priority(page INTEGER, last_seen BIGINT, board_priority INTEGER)
+RETURNS BIGINT AS $$
+    SELECT page * 10
+        + LEAST(GREATEST(EXTRACT(EPOCH FROM NOW())::BIGINT - last_seen, 0) / 60, 60)
+        + board_priority * 10;
+$$ LANGUAGE sql STABLE;
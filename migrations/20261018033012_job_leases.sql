@@ -0,0 +1,12 @@
+-- Jobs are leased by one archiver worker at a time, until lease_expires (unix time).
+-- Expired leases can be claimed by any worker, so jobs held by a crashed worker are picked up again.
+ALTER TABLE thread_backlog
+ADD COLUMN IF NOT EXISTS leased_by TEXT,
+ADD COLUMN IF NOT EXISTS lease_expires BIGINT NOT NULL DEFAULT 0;
+
+ALTER TABLE image_backlog
+ADD COLUMN IF NOT EXISTS leased_by TEXT,
+ADD COLUMN IF NOT EXISTS lease_expires BIGINT NOT NULL DEFAULT 0;
+
+CREATE INDEX IF NOT EXISTS thread_backlog_leased_by_index ON thread_backlog (leased_by);
+CREATE INDEX IF NOT EXISTS image_backlog_leased_by_index ON image_backlog (leased_by);
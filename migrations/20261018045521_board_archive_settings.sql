@@ -0,0 +1,11 @@
+-- Seconds between polls of the board's thread list
+ALTER TABLE boards
+ADD COLUMN IF NOT EXISTS poll_interval INTEGER NOT NULL DEFAULT 10;
+
+-- Max thread jobs for the board running at once, across all workers
+ALTER TABLE boards
+ADD COLUMN IF NOT EXISTS max_thread_jobs INTEGER NOT NULL DEFAULT 20;
+
+-- Percentage of the global request rate limit the board is allowed to use
+ALTER TABLE boards
+ADD COLUMN IF NOT EXISTS rate_limit_share INTEGER NOT NULL DEFAULT 100;
@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS thread_filters (
+    id BIGSERIAL PRIMARY KEY,
+    board VARCHAR(16) NOT NULL,
+    exclude BOOLEAN NOT NULL DEFAULT false,
+    pattern TEXT,
+    min_replies BIGINT NOT NULL DEFAULT 0,
+    sticky_only BOOLEAN NOT NULL DEFAULT false
+);
+
+CREATE INDEX IF NOT EXISTS thread_filters_board_index ON thread_filters (board);
@@ -0,0 +1,9 @@
+-- Progress of `migrate-storage` runs, so an interrupted run can resume where it stopped
+CREATE TABLE IF NOT EXISTS storage_migrations (
+    source TEXT NOT NULL,
+    target TEXT NOT NULL,
+    -- Every file up to this id was handled
+    last_file_id BIGINT NOT NULL DEFAULT 0,
+    updated_at BIGINT NOT NULL DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+    PRIMARY KEY (source, target)
+);
@@ -0,0 +1,3 @@
+-- When the orphaned file collector first saw the file unused by any post, NULL while it's in use
+ALTER TABLE files ADD COLUMN IF NOT EXISTS orphaned_since BIGINT;
+CREATE INDEX IF NOT EXISTS orphaned_since_index ON files(orphaned_since) WHERE orphaned_since IS NOT NULL;
@@ -0,0 +1,16 @@
+BEGIN;
+
+-- Left over from the old (post_id, thumbnail_id, file_id) primary key. A post can have a thumbnail without its full file.
+ALTER TABLE posts_files
+ALTER COLUMN file_id DROP NOT NULL,
+ALTER COLUMN thumbnail_id DROP NOT NULL;
+
+-- Attachments after the first one, as served upstream (vichan's extra_files). posts_files.idx is their position plus one.
+ALTER TABLE posts ADD COLUMN IF NOT EXISTS extra_files JSONB NOT NULL DEFAULT '[]';
+
+-- One image job per attachment
+ALTER TABLE image_backlog ADD COLUMN IF NOT EXISTS idx INT NOT NULL DEFAULT 0;
+ALTER TABLE image_backlog DROP CONSTRAINT IF EXISTS image_backlog_board_no_key;
+ALTER TABLE image_backlog ADD CONSTRAINT image_backlog_board_no_idx_key UNIQUE (board, no, idx);
+
+COMMIT;
@@ -0,0 +1,3 @@
+-- Perceptual hash (dHash) of thumbnails, used to find similar images
+ALTER TABLE files ADD COLUMN IF NOT EXISTS phash BIGINT;
+CREATE INDEX IF NOT EXISTS phash_index ON files(phash) WHERE phash IS NOT NULL;
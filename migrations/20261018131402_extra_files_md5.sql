@@ -0,0 +1,2 @@
+-- Finding posts by the md5 of one of their extra files. Partial, most posts have none.
+CREATE INDEX IF NOT EXISTS extra_files_index ON posts USING GIN (extra_files jsonb_path_ops) WHERE extra_files != '[]';
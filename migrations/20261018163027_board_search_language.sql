@@ -0,0 +1,27 @@
+-- Text search configuration used for a board, eg. 'french' or 'simple'. See `SELECT cfgname FROM pg_ts_config`.
+ALTER TABLE boards ADD COLUMN IF NOT EXISTS search_language TEXT NOT NULL DEFAULT 'english';
+
+CREATE OR REPLACE FUNCTION update_search_index() RETURNS trigger AS $$
+DECLARE
+    config TEXT;
+BEGIN
+    -- Fails on configurations that don't exist
+    config := quote_literal(NEW.search_language::regconfig::text);
+    -- Indexes built with another configuration can't be used anymore
+    IF OLD.name IS NOT NULL AND (NOT NEW.enable_search OR OLD.search_language IS DISTINCT FROM NEW.search_language) THEN
+        EXECUTE 'DROP INDEX IF EXISTS com_ft_idx_' || OLD.name;
+        EXECUTE 'DROP INDEX IF EXISTS name_ft_idx_' || OLD.name;
+        EXECUTE 'DROP INDEX IF EXISTS sub_ft_idx_' || OLD.name;
+        EXECUTE 'DROP INDEX IF EXISTS filename_ft_idx_' || OLD.name;
+        EXECUTE 'DROP INDEX IF EXISTS trip_ft_idx_' || OLD.name;
+    END IF;
+    IF NEW.enable_search AND COALESCE(NULLIF(current_setting('mitsuba.search_backend', true), ''), 'postgres') = 'postgres' THEN
+        EXECUTE 'CREATE INDEX IF NOT EXISTS com_ft_idx_' || NEW.name || ' ON posts USING gin(to_tsvector(' || config || '::regconfig, com)) WHERE board = ''' || NEW.name || '''';
+        EXECUTE 'CREATE INDEX IF NOT EXISTS name_ft_idx_' || NEW.name || ' ON posts USING gin(to_tsvector(' || config || '::regconfig, name)) WHERE board = ''' || NEW.name || '''';
+        EXECUTE 'CREATE INDEX IF NOT EXISTS sub_ft_idx_' || NEW.name || ' ON posts USING gin(to_tsvector(' || config || '::regconfig, sub)) WHERE board = ''' || NEW.name || '''';
+        EXECUTE 'CREATE INDEX IF NOT EXISTS filename_ft_idx_' || NEW.name || ' ON posts USING gin(to_tsvector(' || config || '::regconfig, filename)) WHERE board = ''' || NEW.name || '''';
+        EXECUTE 'CREATE INDEX IF NOT EXISTS trip_ft_idx_' || NEW.name || ' ON posts USING gin(to_tsvector(' || config || '::regconfig, trip)) WHERE board = ''' || NEW.name || '''';
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
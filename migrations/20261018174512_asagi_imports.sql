@@ -0,0 +1,10 @@
+-- Progress of `import-asagi` runs, so an interrupted import can resume where it stopped
+CREATE TABLE IF NOT EXISTS asagi_imports (
+    board TEXT NOT NULL,
+    -- Dump file or database the posts come from, and the Asagi table name
+    source TEXT NOT NULL,
+    -- Every row up to this doc_id was imported
+    last_doc_id BIGINT NOT NULL DEFAULT 0,
+    updated_at BIGINT NOT NULL DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+    PRIMARY KEY (board, source)
+);
@@ -0,0 +1,123 @@
+-- Position of every change in the replication feed.
+-- Changes are ordered by the transaction that made them, then by this sequence within it.
+-- The feed only serves changes from transactions older than any still running,
+-- so a change committed late can't end up behind a cursor that was already handed out.
+CREATE SEQUENCE IF NOT EXISTS replication_seq;
+
+-- Last change to each post, or to the files linked to it
+CREATE TABLE IF NOT EXISTS post_changes (
+    post_id BIGINT PRIMARY KEY REFERENCES posts(post_id) ON DELETE CASCADE,
+    board TEXT NOT NULL,
+    change_xid XID8 NOT NULL DEFAULT pg_current_xact_id(),
+    change_seq BIGINT NOT NULL DEFAULT nextval('replication_seq')
+);
+
+CREATE INDEX IF NOT EXISTS post_changes_board_position_idx ON post_changes (board, change_xid, change_seq);
+
+-- Posts archived before this migration are added to the feed in batches once the archiver is running,
+-- rather than here, which would hold up startup on large archives. The triggers below cover every post after up_to.
+CREATE TABLE IF NOT EXISTS post_changes_backfill (
+    up_to BIGINT NOT NULL,
+    done_through BIGINT NOT NULL DEFAULT 0
+);
+
+INSERT INTO post_changes_backfill (up_to)
+SELECT COALESCE(MAX(post_id), 0) FROM posts
+WHERE NOT EXISTS (SELECT 1 FROM post_changes_backfill);
+
+-- Posts deleted from the database, so that replicas delete them as well.
+-- Their post_changes rows go with them, a post that is archived again loses its tombstone.
+CREATE TABLE IF NOT EXISTS post_tombstones (
+    board TEXT NOT NULL,
+    no BIGINT NOT NULL,
+    change_xid XID8 NOT NULL DEFAULT pg_current_xact_id(),
+    change_seq BIGINT NOT NULL DEFAULT nextval('replication_seq'),
+    PRIMARY KEY (board, no)
+);
+
+CREATE INDEX IF NOT EXISTS post_tombstones_board_position_idx ON post_tombstones (board, change_xid, change_seq);
+
+CREATE OR REPLACE FUNCTION record_post_change(changed_post_id BIGINT) RETURNS void AS $$
+BEGIN
+    INSERT INTO post_changes (post_id, board)
+    SELECT post_id, board FROM posts WHERE post_id = changed_post_id
+    ON CONFLICT (post_id) DO UPDATE SET
+    change_xid = pg_current_xact_id(),
+    change_seq = nextval('replication_seq');
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION record_post_change_trigger() RETURNS trigger AS $$
+BEGIN
+    PERFORM record_post_change(NEW.post_id);
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS trigger_post_inserted ON posts;
+CREATE TRIGGER trigger_post_inserted
+AFTER INSERT ON posts
+FOR EACH ROW EXECUTE FUNCTION record_post_change_trigger();
+
+-- Posts are written again whenever they are fetched, only actual changes count
+DROP TRIGGER IF EXISTS trigger_post_updated ON posts;
+CREATE TRIGGER trigger_post_updated
+AFTER UPDATE ON posts
+FOR EACH ROW WHEN (OLD.* IS DISTINCT FROM NEW.*)
+EXECUTE FUNCTION record_post_change_trigger();
+
+-- A statement trigger, so purging a board doesn't run it once per post
+CREATE OR REPLACE FUNCTION record_post_tombstones_trigger() RETURNS trigger AS $$
+BEGIN
+    INSERT INTO post_tombstones (board, no)
+    SELECT board, no FROM deleted_posts
+    ON CONFLICT (board, no) DO UPDATE SET
+    change_xid = pg_current_xact_id(),
+    change_seq = nextval('replication_seq');
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS trigger_posts_deleted ON posts;
+CREATE TRIGGER trigger_posts_deleted
+AFTER DELETE ON posts
+REFERENCING OLD TABLE AS deleted_posts
+FOR EACH STATEMENT EXECUTE FUNCTION record_post_tombstones_trigger();
+
+CREATE OR REPLACE FUNCTION clear_post_tombstone_trigger() RETURNS trigger AS $$
+BEGIN
+    DELETE FROM post_tombstones WHERE board = NEW.board AND no = NEW.no;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS trigger_post_tombstone_cleared ON posts;
+CREATE TRIGGER trigger_post_tombstone_cleared
+AFTER INSERT ON posts
+FOR EACH ROW EXECUTE FUNCTION clear_post_tombstone_trigger();
+
+DROP TRIGGER IF EXISTS trigger_post_files_inserted ON posts_files;
+CREATE TRIGGER trigger_post_files_inserted
+AFTER INSERT ON posts_files
+FOR EACH ROW EXECUTE FUNCTION record_post_change_trigger();
+
+DROP TRIGGER IF EXISTS trigger_post_files_updated ON posts_files;
+CREATE TRIGGER trigger_post_files_updated
+AFTER UPDATE ON posts_files
+FOR EACH ROW WHEN (OLD.* IS DISTINCT FROM NEW.*)
+EXECUTE FUNCTION record_post_change_trigger();
+
+ALTER TABLE moderation_actions ADD COLUMN IF NOT EXISTS change_xid XID8 NOT NULL DEFAULT '0';
+ALTER TABLE moderation_actions ALTER COLUMN change_xid SET DEFAULT pg_current_xact_id();
+ALTER TABLE moderation_actions ADD COLUMN IF NOT EXISTS change_seq BIGINT NOT NULL DEFAULT nextval('replication_seq');
+
+CREATE INDEX IF NOT EXISTS moderation_actions_position_idx ON moderation_actions (change_xid, change_seq);
+
+-- Where each replicated board is in the feed of the instance it's replicated from
+CREATE TABLE IF NOT EXISTS replication_cursors (
+    board TEXT NOT NULL,
+    upstream TEXT NOT NULL,
+    cursor TEXT NOT NULL,
+    updated_at BIGINT NOT NULL DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+    PRIMARY KEY (board, upstream)
+);
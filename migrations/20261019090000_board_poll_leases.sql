@@ -0,0 +1,10 @@
+-- Polls of a board are leased to one archiver worker at a time, like backlog jobs (see job_leases),
+-- and a board is only claimed again once its poll_interval has passed since last_polled (unix time).
+CREATE TABLE IF NOT EXISTS board_polls (
+    board TEXT PRIMARY KEY REFERENCES boards(name) ON DELETE CASCADE,
+    last_polled BIGINT NOT NULL DEFAULT 0,
+    leased_by TEXT,
+    lease_expires BIGINT NOT NULL DEFAULT 0
+);
+
+CREATE INDEX IF NOT EXISTS board_polls_leased_by_index ON board_polls (leased_by);
@@ -0,0 +1,16 @@
+-- When Mitsuba last wrote each post (unix time), for incremental exports.
+-- Upstream timestamps can't tell, a thread archived today can have posts from years ago.
+ALTER TABLE post_changes ADD COLUMN IF NOT EXISTS changed_at BIGINT NOT NULL DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT;
+
+CREATE INDEX IF NOT EXISTS post_changes_board_changed_at_idx ON post_changes (board, changed_at);
+
+CREATE OR REPLACE FUNCTION record_post_change(changed_post_id BIGINT) RETURNS void AS $$
+BEGIN
+    INSERT INTO post_changes (post_id, board)
+    SELECT post_id, board FROM posts WHERE post_id = changed_post_id
+    ON CONFLICT (post_id) DO UPDATE SET
+    change_xid = pg_current_xact_id(),
+    change_seq = nextval('replication_seq'),
+    changed_at = EXTRACT(EPOCH FROM NOW())::BIGINT;
+END;
+$$ LANGUAGE plpgsql;
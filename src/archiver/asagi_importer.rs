@@ -0,0 +1,834 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+
+use futures::stream::{self, StreamExt};
+use sqlx::Row;
+use sqlx::postgres::{PgPool, PgPoolOptions};
+
+#[allow(unused_imports)]
+use log::{info, warn, error, debug};
+
+use crate::archiver::Archiver;
+use crate::models::{AsagiImportReport, Board, Post};
+use crate::util::{civil_from_days, days_from_civil};
+
+// Columns of an Asagi board table, in the order Asagi creates them
+const ASAGI_COLUMNS: [&str; 32] = [
+    "doc_id", "media_id", "poster_ip", "num", "subnum", "thread_num", "op", "timestamp", "timestamp_expired",
+    "preview_orig", "preview_w", "preview_h", "media_filename", "media_w", "media_h", "media_size", "media_hash",
+    "media_orig", "spoiler", "deleted", "capcode", "email", "name", "trip", "title", "comment", "delpass",
+    "sticky", "locked", "poster_hash", "poster_country", "exif"
+];
+// The ones we read when importing from a database
+const IMPORTED_COLUMNS: [&str; 27] = [
+    "doc_id", "num", "subnum", "thread_num", "op", "timestamp", "timestamp_expired",
+    "preview_orig", "preview_w", "preview_h", "media_filename", "media_w", "media_h", "media_size", "media_hash",
+    "media_orig", "spoiler", "deleted", "capcode", "name", "trip", "title", "comment",
+    "sticky", "locked", "poster_hash", "poster_country"
+];
+// Columns of the table Asagi keeps each file in once, named [board]_images
+const ASAGI_IMAGE_COLUMNS: [&str; 7] = ["media_id", "media_hash", "media", "preview_op", "preview_reply", "total", "banned"];
+const BATCH_SIZE: usize = 1000;
+
+// Values of a row, in column order
+type Values = Vec<Option<String>>;
+
+/**
+ * Where Asagi posts are imported from: a MySQL dump made with mysqldump,
+ * or a Postgres database (for Asagi installs that used Postgres).
+ */
+#[derive(Debug, Clone)]
+pub enum AsagiSource {
+    Dump(PathBuf),
+    Database(String),
+}
+
+impl AsagiSource {
+    // Identifies the source in the import progress table, without credentials
+    pub fn key(&self, table: &str) -> String {
+        let location = match self {
+            AsagiSource::Dump(path) => std::fs::canonicalize(path).unwrap_or(path.clone()).display().to_string(),
+            AsagiSource::Database(url) => match reqwest::Url::parse(url) {
+                Ok(mut url) => {
+                    url.set_password(None).ok();
+                    url.to_string()
+                },
+                Err(_) => url.clone()
+            }
+        };
+        format!("{}#{}", location, table)
+    }
+
+    async fn open(&self, table: &str) -> anyhow::Result<AsagiReader> {
+        match self {
+            AsagiSource::Dump(path) => {
+                let file = std::fs::File::open(path)?;
+                Ok(AsagiReader::Dump(Some(DumpReader::new(std::io::BufReader::new(file), table, &ASAGI_COLUMNS))))
+            },
+            AsagiSource::Database(url) => {
+                if !url.starts_with("postgres") {
+                    return Err(anyhow::anyhow!("Only Postgres databases can be read directly, import MySQL databases from a mysqldump file"))
+                }
+                let pool = PgPoolOptions::new().max_connections(1).connect(url).await?;
+                Ok(AsagiReader::Postgres(pool, table.to_string()))
+            }
+        }
+    }
+
+    // The names Asagi stored files under. A dump is read once more to find its images table.
+    async fn open_media(&self, table: &str) -> anyhow::Result<AsagiMediaIndex> {
+        let images_table = format!("{}_images", table);
+        match self {
+            AsagiSource::Dump(path) => {
+                let file = std::fs::File::open(path)?;
+                let rows = tokio::task::spawn_blocking(move || {
+                    DumpReader::<_, AsagiMedia>::new(std::io::BufReader::new(file), &images_table, &ASAGI_IMAGE_COLUMNS).read_batch(0, usize::MAX)
+                }).await??;
+                let mut index = HashMap::new();
+                for row in rows {
+                    match row {
+                        Ok(media) => {
+                            index.insert(media.media_hash.clone(), media);
+                        },
+                        Err(e) => warn!("Skipped invalid row from {}_images: {}", table, e)
+                    }
+                }
+                if index.is_empty() {
+                    warn!("No {}_images rows found in the dump, looking for files by the names in the posts", table);
+                }
+                Ok(AsagiMediaIndex::Dump(index))
+            },
+            AsagiSource::Database(_) => {
+                let AsagiReader::Postgres(pool, _) = self.open(table).await? else {
+                    return Err(anyhow::anyhow!("Expected a database reader"))
+                };
+                // Fail early rather than on every post if the table is missing
+                sqlx::query(&format!("SELECT 1 FROM \"{}\" LIMIT 1", images_table)).fetch_optional(&pool).await?;
+                Ok(AsagiMediaIndex::Postgres(pool, images_table))
+            }
+        }
+    }
+}
+
+/**
+ * Rows that can be read from an Asagi table, `id` is the auto increment primary key.
+ */
+trait AsagiRow: Sized {
+    fn from_row(columns: &[String], values: Values) -> Result<Self, String>;
+    fn id(&self) -> i64;
+}
+
+/// A row of an Asagi board table
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct AsagiPost {
+    pub doc_id: i64,
+    pub num: i64,
+    // Posts made on the archive itself ("ghost posts") have a subnum
+    pub subnum: i64,
+    pub thread_num: i64,
+    pub op: bool,
+    // Asagi times are in America/New_York, not UTC
+    pub timestamp: i64,
+    pub timestamp_expired: i64,
+    pub preview_orig: String,
+    pub preview_w: i64,
+    pub preview_h: i64,
+    pub media_filename: String,
+    pub media_w: i64,
+    pub media_h: i64,
+    pub media_size: i64,
+    pub media_hash: String,
+    pub media_orig: String,
+    pub spoiler: bool,
+    pub deleted: bool,
+    pub capcode: String,
+    pub name: String,
+    pub trip: String,
+    pub title: String,
+    pub comment: String,
+    pub sticky: bool,
+    pub locked: bool,
+    pub poster_hash: String,
+    pub poster_country: String,
+}
+
+impl AsagiRow for AsagiPost {
+    fn from_row(columns: &[String], values: Values) -> Result<Self, String> {
+        if columns.len() != values.len() {
+            return Err(format!("Expected {} values, found {}", columns.len(), values.len()))
+        }
+        let mut post = AsagiPost::default();
+        for (column, value) in columns.iter().zip(values) {
+            let value = value.unwrap_or_default();
+            let int = || if value.is_empty() { Ok(0) } else {
+                value.parse::<i64>().map_err(|_| format!("Invalid {}: {}", column, value))
+            };
+            let boolean = || Ok::<bool, String>(matches!(value.as_str(), "1" | "t" | "true"));
+            match column.as_str() {
+                "doc_id" => post.doc_id = int()?,
+                "num" => post.num = int()?,
+                "subnum" => post.subnum = int()?,
+                "thread_num" => post.thread_num = int()?,
+                "op" => post.op = boolean()?,
+                "timestamp" => post.timestamp = int()?,
+                "timestamp_expired" => post.timestamp_expired = int()?,
+                "preview_orig" => post.preview_orig = value,
+                "preview_w" => post.preview_w = int()?,
+                "preview_h" => post.preview_h = int()?,
+                "media_filename" => post.media_filename = value,
+                "media_w" => post.media_w = int()?,
+                "media_h" => post.media_h = int()?,
+                "media_size" => post.media_size = int()?,
+                "media_hash" => post.media_hash = value,
+                "media_orig" => post.media_orig = value,
+                "spoiler" => post.spoiler = boolean()?,
+                "deleted" => post.deleted = boolean()?,
+                "capcode" => post.capcode = value,
+                "name" => post.name = value,
+                "trip" => post.trip = value,
+                "title" => post.title = value,
+                "comment" => post.comment = value,
+                "sticky" => post.sticky = boolean()?,
+                "locked" => post.locked = boolean()?,
+                "poster_hash" => post.poster_hash = value,
+                "poster_country" => post.poster_country = value,
+                _ => {}
+            }
+        }
+        if post.doc_id == 0 || post.num == 0 {
+            return Err("Missing doc_id or num".to_string())
+        }
+        Ok(post)
+    }
+
+    fn id(&self) -> i64 {
+        self.doc_id
+    }
+}
+
+impl AsagiPost {
+    // Image name, like 1546293948883.jpg, and thumbnail name, like 1546293948883s.jpg
+    fn media(&self) -> Option<(i64, &str, &str)> {
+        let (tim, ext) = self.media_orig.rsplit_once('.')?;
+        Some((tim.parse().ok()?, ext, self.preview_orig.rsplit_once('.').map(|(_, ext)| ext).unwrap_or("jpg")))
+    }
+
+    fn to_post(&self, board: &str) -> Post {
+        let time = asagi_time_to_unix(self.timestamp);
+        let expired = if self.timestamp_expired > 0 { asagi_time_to_unix(self.timestamp_expired) } else { 0 };
+        let mut post = Post {
+            board: board.to_string(),
+            no: self.num,
+            resto: if self.op { 0 } else { self.thread_num },
+            sticky: self.sticky as i64,
+            closed: self.locked as i64,
+            now: fourchan_date(self.timestamp),
+            time,
+            name: escape_html(&self.name),
+            trip: self.trip.clone(),
+            id: self.poster_hash.clone(),
+            capcode: capcode_name(&self.capcode).to_string(),
+            country: self.poster_country.clone(),
+            sub: escape_html(&self.title),
+            com: comment_to_html(&self.comment),
+            // Asagi sets timestamp_expired when a thread falls off the board, or when a post is deleted
+            archived: (self.op && !self.deleted && expired > 0) as i64,
+            archived_on: if self.op && !self.deleted { expired } else { 0 },
+            deleted_on: if self.deleted { expired.max(time) } else { 0 },
+            ..Default::default()
+        };
+        if let Some((tim, ext, _)) = self.media() {
+            post.tim = tim;
+            post.ext = format!(".{}", ext);
+            post.filename = escape_html(self.media_filename.rsplit_once('.').map(|(name, _)| name).unwrap_or(&self.media_filename));
+            post.fsize = self.media_size;
+            post.md5 = self.media_hash.clone();
+            post.w = self.media_w;
+            post.h = self.media_h;
+            post.tn_w = self.preview_w;
+            post.tn_h = self.preview_h;
+            post.spoiler = self.spoiler as i64;
+        }
+        post
+    }
+}
+
+/**
+ * A row of an Asagi images table. Asagi stores each file once, under the names it had the first time it was posted,
+ * so reposts of a file have to be found by their media_hash.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct AsagiMedia {
+    pub media_id: i64,
+    pub media_hash: String,
+    pub media: String,
+    // Threads and replies have separate thumbnails, either may be missing
+    pub preview_op: String,
+    pub preview_reply: String,
+}
+
+impl AsagiRow for AsagiMedia {
+    fn from_row(columns: &[String], values: Values) -> Result<Self, String> {
+        if columns.len() != values.len() {
+            return Err(format!("Expected {} values, found {}", columns.len(), values.len()))
+        }
+        let mut media = AsagiMedia::default();
+        for (column, value) in columns.iter().zip(values) {
+            let value = value.unwrap_or_default();
+            match column.as_str() {
+                "media_id" => media.media_id = value.parse().map_err(|_| format!("Invalid media_id: {}", value))?,
+                "media_hash" => media.media_hash = value,
+                "media" => media.media = value,
+                "preview_op" => media.preview_op = value,
+                "preview_reply" => media.preview_reply = value,
+                _ => {}
+            }
+        }
+        if media.media_hash.is_empty() {
+            return Err("Missing media_hash".to_string())
+        }
+        Ok(media)
+    }
+
+    fn id(&self) -> i64 {
+        self.media_id
+    }
+}
+
+impl AsagiMedia {
+    // Thumbnail name for a thread or a reply, falling back to the other one
+    fn preview(&self, op: bool) -> &str {
+        let (preferred, other) = if op { (&self.preview_op, &self.preview_reply) } else { (&self.preview_reply, &self.preview_op) };
+        if preferred.is_empty() { other } else { preferred }
+    }
+}
+
+enum AsagiMediaIndex {
+    // Read from the dump up front, by media_hash
+    Dump(HashMap<String, AsagiMedia>),
+    Postgres(PgPool, String),
+}
+
+impl AsagiMediaIndex {
+    async fn get(&self, media_hash: &str) -> anyhow::Result<Option<AsagiMedia>> {
+        match self {
+            AsagiMediaIndex::Dump(index) => Ok(index.get(media_hash).cloned()),
+            AsagiMediaIndex::Postgres(pool, table) => {
+                let columns: Vec<String> = ["media_id", "media_hash", "media", "preview_op", "preview_reply"].iter().map(|c| c.to_string()).collect();
+                let select = columns.iter().map(|c| format!("\"{}\"::text", c)).collect::<Vec<_>>().join(", ");
+                let Some(row) = sqlx::query(&format!("SELECT {} FROM \"{}\" WHERE media_hash = $1 LIMIT 1", select, table))
+                    .bind(media_hash)
+                    .fetch_optional(pool).await? else {
+                    return Ok(None)
+                };
+                let values = (0..columns.len()).map(|i| row.try_get::<Option<String>, _>(i)).collect::<Result<Vec<_>, _>>()?;
+                AsagiMedia::from_row(&columns, values).map(Some).map_err(|e| anyhow::anyhow!(e))
+            }
+        }
+    }
+}
+
+enum AsagiReader {
+    // Taken out while a batch is read on a blocking thread
+    Dump(Option<DumpReader<std::io::BufReader<std::fs::File>, AsagiPost>>),
+    Postgres(PgPool, String),
+}
+
+impl AsagiReader {
+    // The next rows with a doc_id greater than `after`, in doc_id order
+    async fn next_batch(&mut self, after: i64) -> anyhow::Result<Vec<Result<AsagiPost, String>>> {
+        match self {
+            AsagiReader::Dump(reader) => {
+                let Some(mut dump) = reader.take() else {
+                    return Err(anyhow::anyhow!("Dump reader was lost"))
+                };
+                let (dump, rows) = tokio::task::spawn_blocking(move || {
+                    let rows = dump.read_batch(after, BATCH_SIZE);
+                    (dump, rows)
+                }).await?;
+                *reader = Some(dump);
+                Ok(rows?)
+            },
+            AsagiReader::Postgres(pool, table) => {
+                let columns: Vec<String> = IMPORTED_COLUMNS.iter().map(|c| c.to_string()).collect();
+                let select = columns.iter().map(|c| format!("\"{}\"::text", c)).collect::<Vec<_>>().join(", ");
+                let rows = sqlx::query(&format!("SELECT {} FROM \"{}\" WHERE doc_id > $1 ORDER BY doc_id LIMIT $2", select, table))
+                    .bind(after)
+                    .bind(BATCH_SIZE as i64)
+                    .fetch_all(&*pool).await?;
+                rows.into_iter().map(|row| {
+                    let values = (0..columns.len()).map(|i| row.try_get::<Option<String>, _>(i)).collect::<Result<Vec<_>, _>>()?;
+                    Ok(AsagiPost::from_row(&columns, values))
+                }).collect()
+            }
+        }
+    }
+}
+
+/**
+ * Reads the rows of one table from a mysqldump file.
+ * mysqldump writes each INSERT statement on a single line, escaping newlines in values,
+ * so the file is read line by line. Column names come from the CREATE TABLE statement if there is one.
+ */
+struct DumpReader<R: BufRead, T: AsagiRow> {
+    reader: R,
+    table: String,
+    columns: Vec<String>,
+    in_create_table: bool,
+    pending: VecDeque<Result<T, String>>,
+}
+
+impl<R: BufRead, T: AsagiRow> DumpReader<R, T> {
+    // `columns` are used if the dump has no CREATE TABLE statement and the INSERTs don't name them
+    fn new(reader: R, table: &str, columns: &[&str]) -> Self {
+        Self {
+            reader,
+            table: table.to_string(),
+            columns: columns.iter().map(|c| c.to_string()).collect(),
+            in_create_table: false,
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn read_batch(&mut self, after: i64, limit: usize) -> std::io::Result<Vec<Result<T, String>>> {
+        let mut line = Vec::new();
+        while self.pending.len() < limit {
+            line.clear();
+            if self.reader.read_until(b'\n', &mut line)? == 0 {
+                break
+            }
+            let line = String::from_utf8_lossy(&line);
+            self.read_line(line.trim_end(), after);
+        }
+        let count = limit.min(self.pending.len());
+        Ok(self.pending.drain(..count).collect())
+    }
+
+    fn read_line(&mut self, line: &str, after: i64) {
+        if let Some(rest) = line.strip_prefix("CREATE TABLE ") {
+            self.in_create_table = table_name(rest).is_some_and(|(name, _)| name == self.table);
+            if self.in_create_table {
+                self.columns.clear();
+            }
+            return
+        }
+        if self.in_create_table {
+            match line.trim_start().strip_prefix('`').and_then(|l| l.split_once('`')) {
+                Some((column, _)) => self.columns.push(column.to_string()),
+                None => self.in_create_table = !line.starts_with(')'),
+            }
+            return
+        }
+        let Some(rest) = ["INSERT INTO ", "INSERT IGNORE INTO ", "REPLACE INTO "].iter().find_map(|p| line.strip_prefix(p)) else {
+            return
+        };
+        let Some((name, rest)) = table_name(rest) else {
+            return
+        };
+        if name != self.table {
+            return
+        }
+        match parse_insert(rest) {
+            Ok((columns, rows)) => {
+                let columns = columns.as_ref().unwrap_or(&self.columns);
+                for values in rows {
+                    let row = T::from_row(columns, values);
+                    if row.as_ref().is_ok_and(|r| r.id() <= after) {
+                        continue
+                    }
+                    self.pending.push_back(row);
+                }
+            },
+            Err(e) => self.pending.push_back(Err(format!("Could not read INSERT statement: {}", e)))
+        }
+    }
+}
+
+// Table name at the start of `s`, and the rest of it
+fn table_name(s: &str) -> Option<(&str, &str)> {
+    match s.strip_prefix('`') {
+        Some(quoted) => quoted.split_once('`'),
+        None => s.split_once(|c: char| c.is_whitespace() || c == '(')
+            .map(|(name, _)| (name, &s[name.len()..]))
+    }
+}
+
+// The optional column list and the rows of the rest of an INSERT statement, after the table name
+fn parse_insert(s: &str) -> Result<(Option<Vec<String>>, Vec<Values>), String> {
+    let s = s.trim_start();
+    let (columns, s) = match s.strip_prefix('(') {
+        Some(rest) => {
+            let (list, rest) = rest.split_once(')').ok_or("Unterminated column list")?;
+            let columns = list.split(',').map(|c| c.trim().trim_matches('`').to_string()).collect();
+            (Some(columns), rest.trim_start())
+        },
+        None => (None, s)
+    };
+    let mut chars = s.strip_prefix("VALUES").ok_or("Expected VALUES")?.chars().peekable();
+    let mut rows = Vec::new();
+    loop {
+        skip_whitespace(&mut chars);
+        match chars.next() {
+            Some('(') => {},
+            Some(';') | None => break,
+            Some(c) => return Err(format!("Unexpected '{}'", c))
+        }
+        let mut row = Vec::new();
+        loop {
+            skip_whitespace(&mut chars);
+            row.push(parse_value(&mut chars)?);
+            skip_whitespace(&mut chars);
+            match chars.next() {
+                Some(',') => continue,
+                Some(')') => break,
+                _ => return Err("Unterminated row".to_string())
+            }
+        }
+        rows.push(row);
+        skip_whitespace(&mut chars);
+        if chars.peek() == Some(&',') {
+            chars.next();
+        }
+    }
+    Ok((columns, rows))
+}
+
+fn skip_whitespace(chars: &mut std::iter::Peekable<std::str::Chars>) {
+    while chars.peek().is_some_and(|c| c.is_whitespace()) {
+        chars.next();
+    }
+}
+
+// A NULL, a number, or a quoted string with MySQL escapes, possibly with a charset introducer like _binary
+fn parse_value(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<Option<String>, String> {
+    let mut token = String::new();
+    while let Some(&c) = chars.peek() {
+        if c == ',' || c == ')' || c == '\'' {
+            break
+        }
+        token.push(c);
+        chars.next();
+    }
+    let token = token.trim();
+    if chars.peek() != Some(&'\'') {
+        return Ok(if token.eq_ignore_ascii_case("NULL") { None } else { Some(token.to_string()) })
+    }
+    if !token.is_empty() && !token.starts_with('_') {
+        return Err(format!("Unexpected '{}'", token))
+    }
+    chars.next();
+    let mut value = String::new();
+    loop {
+        match chars.next().ok_or("Unterminated string")? {
+            '\\' => match chars.next().ok_or("Unterminated string")? {
+                '0' => value.push('\0'),
+                'n' => value.push('\n'),
+                'r' => value.push('\r'),
+                't' => value.push('\t'),
+                'b' => value.push('\u{8}'),
+                'Z' => value.push('\u{1a}'),
+                c => value.push(c)
+            },
+            '\'' if chars.peek() == Some(&'\'') => {
+                chars.next();
+                value.push('\'');
+            },
+            '\'' => break,
+            c => value.push(c)
+        }
+    }
+    Ok(Some(value))
+}
+
+// Offset of America/New_York from UTC at a local time, with the US daylight saving rules since 1987
+fn new_york_offset(local: i64) -> i64 {
+    let (year, _, _) = civil_from_days(local.div_euclid(86400));
+    // Days of the nth Sunday of a month, or of the last one when n is 0
+    let sunday = |month: i64, n: i64| {
+        let first = if n == 0 { days_from_civil(year, month + 1, 1) - 7 } else { days_from_civil(year, month, 1) };
+        let first_sunday = first + (7 - (first + 4).rem_euclid(7)) % 7;
+        first_sunday + 7 * (n - 1).max(0)
+    };
+    let (start, end) = if year >= 2007 {
+        (sunday(3, 2), sunday(11, 1))
+    } else {
+        (sunday(4, 1), sunday(10, 0))
+    };
+    if (start * 86400 + 7200..end * 86400 + 7200).contains(&local) {
+        4 * 3600
+    } else {
+        5 * 3600
+    }
+}
+
+// Asagi stores times as the wall clock time in New York
+fn asagi_time_to_unix(timestamp: i64) -> i64 {
+    timestamp + new_york_offset(timestamp)
+}
+
+// 4chan's "now" field, like 01/31/19(Thu)12:34:56, in New York time like Asagi's timestamps
+fn fourchan_date(timestamp: i64) -> String {
+    let days = timestamp.div_euclid(86400);
+    let seconds = timestamp.rem_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+    let weekday = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"][(days + 4).rem_euclid(7) as usize];
+    format!("{:02}/{:02}/{:02}({}){:02}:{:02}:{:02}", month, day, year % 100, weekday,
+        seconds / 3600, seconds % 3600 / 60, seconds % 60)
+}
+
+fn capcode_name(capcode: &str) -> &'static str {
+    match capcode {
+        "M" => "mod",
+        "A" => "admin",
+        "D" => "developer",
+        "F" => "founder",
+        "V" => "verified",
+        "G" => "manager",
+        _ => ""
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&#039;")
+}
+
+/**
+ * Asagi keeps comments as plain text, with BBCode for spoilers and code.
+ * Turns them back into the HTML 4chan's API serves, with quote links and greentext.
+ */
+fn comment_to_html(comment: &str) -> String {
+    let escaped = escape_html(comment)
+        .replace("[spoiler]", "<s>").replace("[/spoiler]", "</s>")
+        .replace("[code]", "<pre class=\"prettyprint\">").replace("[/code]", "</pre>");
+    escaped.lines().map(|line| {
+        let linked = line.split(' ').map(|word| quote_link(word).unwrap_or(word.to_string())).collect::<Vec<_>>().join(" ");
+        if line.starts_with("&gt;") && !linked.starts_with("<a") {
+            format!("<span class=\"quote\">{}</span>", linked)
+        } else {
+            linked
+        }
+    }).collect::<Vec<_>>().join("<br>")
+}
+
+// >>123 and >>>/board/123, already escaped
+fn quote_link(word: &str) -> Option<String> {
+    if let Some(rest) = word.strip_prefix("&gt;&gt;&gt;/") {
+        let (other_board, no) = rest.split_once('/')?;
+        let no: i64 = no.parse().ok()?;
+        return Some(format!("<a href=\"/{}/thread/{}#p{}\" class=\"quotelink\">{}</a>", other_board, no, no, word))
+    }
+    // Asagi doesn't say which thread the post is in, most quotes are in the same one
+    let no: i64 = word.strip_prefix("&gt;&gt;")?.parse().ok()?;
+    Some(format!("<a href=\"#p{}\" class=\"quotelink\">{}</a>", no, word))
+}
+
+impl Archiver {
+    /**
+     * Imports the posts of an Asagi board table into a board, creating the board with archiving disabled if needed.
+     * Ghost posts, which were made on the archive itself, are skipped.
+     * With `media_dir`, the root of Asagi's image folders, thumbnails are imported too, and full images if the board has full_images.
+     * Progress is saved after every batch, so an interrupted import resumes where it stopped.
+     */
+    pub async fn import_asagi(&self, source: &AsagiSource, table: &str, board_name: &str, media_dir: Option<&Path>) -> anyhow::Result<AsagiImportReport> {
+        if table.is_empty() || !table.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(anyhow::anyhow!("Invalid table name: {}", table))
+        }
+        let board = self.get_or_create_board(board_name).await?;
+        let key = source.key(table);
+        let mut report = AsagiImportReport::default();
+        let mut last_doc_id = self.db_client.get_asagi_import_progress(&board.name, &key).await?;
+        if last_doc_id > 0 {
+            info!("Resuming import of {} into /{}/ after doc_id {}", key, board.name, last_doc_id);
+        }
+        let mut reader = source.open(table).await?;
+        let media_index = match media_dir {
+            Some(_) => Some(source.open_media(table).await?),
+            None => None
+        };
+        loop {
+            let rows = reader.next_batch(last_doc_id).await?;
+            if rows.is_empty() {
+                break
+            }
+            let mut rows_to_import = Vec::new();
+            for row in rows {
+                match row {
+                    Ok(row) => {
+                        last_doc_id = last_doc_id.max(row.doc_id);
+                        if row.subnum > 0 {
+                            report.skipped_ghosts += 1;
+                            continue
+                        }
+                        rows_to_import.push(row);
+                    },
+                    Err(e) => {
+                        warn!("Skipped invalid row from {}: {}", key, e);
+                        report.skipped_invalid += 1;
+                    }
+                }
+            }
+            let posts: Vec<Post> = rows_to_import.iter().map(|row| row.to_post(&board.name)).collect();
+            self.db_client.insert_posts(&posts).await?;
+            report.imported += posts.len() as u64;
+
+            if let (Some(media_dir), Some(media_index)) = (media_dir, &media_index) {
+                let board = &board;
+                let results: Vec<(i64, anyhow::Result<(u64, u64)>)> = stream::iter(rows_to_import.iter())
+                    .map(|row| async move {
+                        (row.num, self.import_asagi_media(board, media_dir, table, media_index, row).await)
+                    })
+                    .buffer_unordered(8)
+                    .collect().await;
+                for (no, result) in results {
+                    match result {
+                        Ok((files, missing)) => {
+                            report.files += files;
+                            report.missing_files += missing;
+                        },
+                        Err(e) => {
+                            error!("Could not import files of /{}/{}: {}", board.name, no, e);
+                            report.failed_files += 1;
+                        }
+                    }
+                }
+            }
+            let threads: HashSet<i64> = posts.iter().map(|p| if p.resto == 0 { p.no } else { p.resto }).collect();
+            self.db_client.update_thread_counts(&board.name, &threads.into_iter().collect::<Vec<_>>()).await?;
+
+            self.db_client.set_asagi_import_progress(&board.name, &key, last_doc_id).await?;
+            info!("Imported up to doc_id {} into /{}/: {} posts imported, {} skipped",
+                last_doc_id, board.name, report.imported, report.skipped_ghosts + report.skipped_invalid);
+        }
+        Ok(report)
+    }
+
+    /**
+     * Imports the thumbnail and full image of a post from Asagi's folders, returns how many files were imported and missing.
+     * Files are looked up under the names in the images table, the post's own names are only used if it has no row there.
+     */
+    async fn import_asagi_media(&self, board: &Board, media_dir: &Path, table: &str, media_index: &AsagiMediaIndex, row: &AsagiPost) -> anyhow::Result<(u64, u64)> {
+        let Some((_, ext, _)) = row.media() else {
+            return Ok((0, 0))
+        };
+        let media = media_index.get(&row.media_hash).await?;
+        let (preview, image) = match &media {
+            Some(media) if !media.media.is_empty() => (media.preview(row.op), media.media.as_str()),
+            _ => (row.preview_orig.as_str(), row.media_orig.as_str())
+        };
+        let thumb_ext = preview.rsplit_once('.').map(|(_, ext)| ext).unwrap_or("jpg");
+        let mut files = vec![(preview, format!(".{}", thumb_ext), true)];
+        if board.full_images {
+            files.push((image, format!(".{}", ext), false));
+        }
+        let (mut imported, mut missing) = (0, 0);
+        for (name, ext, is_thumb) in files {
+            // Asagi splits files in folders by the first digits of their name
+            let (Some(first), Some(second)) = (name.get(0..4), name.get(4..6)) else {
+                missing += 1;
+                continue
+            };
+            let path = media_dir.join(table).join(if is_thumb { "thumb" } else { "image" }).join(first).join(second).join(name);
+            let bytes = match tokio::fs::read(&path).await {
+                Ok(bytes) => bytes::Bytes::from(bytes),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    debug!("Missing file {} for /{}/{}", path.display(), board.name, row.num);
+                    missing += 1;
+                    continue
+                },
+                Err(e) => return Err(e.into())
+            };
+            let sha256 = self.store_file(bytes.clone(), &ext, is_thumb).await?;
+            self.db_client.add_post_file(&board.name, row.num, 0, &sha256, &ext, is_thumb).await?;
+            if is_thumb {
+                self.save_perceptual_hash(&sha256, bytes).await?;
+            }
+            self.handle_blacklist(&board.name, row.num, &sha256, &ext, is_thumb).await?;
+            imported += 1;
+        }
+        Ok((imported, missing))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_asagi_dump() {
+        let dump = "\
+-- MySQL dump
+CREATE TABLE `a` (
+  `doc_id` int unsigned NOT NULL AUTO_INCREMENT,
+  `num` int unsigned NOT NULL,
+  `subnum` int unsigned NOT NULL,
+  `thread_num` int unsigned NOT NULL DEFAULT '0',
+  `op` tinyint(1) NOT NULL DEFAULT '0',
+  `timestamp` int unsigned NOT NULL,
+  `comment` text,
+  PRIMARY KEY (`doc_id`)
+) ENGINE=InnoDB;
+INSERT INTO `a` VALUES (1,100,0,100,1,1546300800,'hello\\nworld'),(2,101,0,100,0,1546300900,NULL);
+INSERT INTO `a` (`doc_id`, `num`, `subnum`, `thread_num`) VALUES (3,102,1,100),(4,'x',0,100);
+INSERT INTO `b` (`doc_id`, `num`, `comment`) VALUES (5,103,'it''s \\'quoted\\', (really)');
+";
+        let mut reader = DumpReader::new(dump.as_bytes(), "a", &ASAGI_COLUMNS);
+        let rows: Vec<Result<AsagiPost, String>> = reader.read_batch(1, 10).unwrap();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0], Ok(AsagiPost {doc_id: 2, num: 101, thread_num: 100, timestamp: 1546300900, ..Default::default()}));
+        assert_eq!(rows[1].as_ref().map(|r| r.subnum), Ok(1));
+        assert!(rows[2].is_err());
+
+        let mut reader = DumpReader::new(dump.as_bytes(), "b", &ASAGI_COLUMNS);
+        let rows: Vec<Result<AsagiPost, String>> = reader.read_batch(0, 10).unwrap();
+        assert_eq!(rows[0].as_ref().unwrap().comment, "it's 'quoted', (really)");
+    }
+
+    #[test]
+    fn test_read_asagi_images() {
+        let dump = "\
+INSERT INTO `a` VALUES (1,1,NULL,100,0,100,1,1546300800,0,'1546300800123s.jpg');
+INSERT INTO `a_images` VALUES (1,'aGFzaA==','1546300800123.png','1546300800123s.jpg',NULL,2,0),(2,'b3RoZXI=','1546300900456.jpg','','1546300900456s.jpg',1,0);
+";
+        let mut reader = DumpReader::new(dump.as_bytes(), "a_images", &ASAGI_IMAGE_COLUMNS);
+        let rows: Vec<Result<AsagiMedia, String>> = reader.read_batch(0, usize::MAX).unwrap();
+        assert_eq!(rows.len(), 2);
+        let first = rows[0].as_ref().unwrap();
+        assert_eq!(first.media_hash, "aGFzaA==");
+        assert_eq!(first.media, "1546300800123.png");
+        // A reply to a file first posted in an OP uses the OP thumbnail
+        assert_eq!(first.preview(false), "1546300800123s.jpg");
+        let second = rows[1].as_ref().unwrap();
+        assert_eq!(second.preview(true), "1546300900456s.jpg");
+        assert_eq!(second.preview(false), "1546300900456s.jpg");
+    }
+
+    #[test]
+    fn test_asagi_post() {
+        let post = AsagiPost {
+            doc_id: 1,
+            num: 101,
+            thread_num: 100,
+            // 2019-07-01 12:00:00 in New York (EDT)
+            timestamp: 1561982400,
+            media_orig: "1561982400123.png".to_string(),
+            preview_orig: "1561982400123s.jpg".to_string(),
+            media_filename: "cat <3.png".to_string(),
+            capcode: "M".to_string(),
+            comment: ">>100\n>implying\n[spoiler]secret[/spoiler] & >>>/g/5".to_string(),
+            ..Default::default()
+        }.to_post("a");
+        assert_eq!(post.time, 1561982400 + 4 * 3600);
+        assert_eq!(post.now, "07/01/19(Mon)12:00:00");
+        assert_eq!(post.resto, 100);
+        assert_eq!(post.tim, 1561982400123);
+        assert_eq!(post.ext, ".png");
+        assert_eq!(post.filename, "cat &lt;3");
+        assert_eq!(post.capcode, "mod");
+        assert_eq!(post.com, "<a href=\"#p100\" class=\"quotelink\">&gt;&gt;100</a><br>\
+<span class=\"quote\">&gt;implying</span><br>\
+<s>secret</s> &amp; <a href=\"/g/thread/5#p5\" class=\"quotelink\">&gt;&gt;&gt;/g/5</a>");
+        // 2019-01-01 00:00:00 in New York (EST)
+        assert_eq!(asagi_time_to_unix(1546300800), 1546300800 + 5 * 3600);
+    }
+}
@@ -1,5 +1,6 @@
 use std::time::{Duration, Instant};
 use std::hash::{Hash, Hasher};
+use std::collections::HashSet;
 use std::collections::hash_map::DefaultHasher;
 
 use std::panic::AssertUnwindSafe;
@@ -10,24 +11,56 @@ use log::{info, warn, error, debug};
 #[allow(unused_imports)]
 use metrics::{gauge, counter, histogram};
 
-use crate::models::{ThreadsPage, ThreadInfo};
-use crate::util::{get_board_page_api_url, get_board_archive_api_url};
+use crate::models::{Board, CatalogPage, CatalogThread, ThreadInfo};
+use crate::upstream::UpstreamSource;
+use crate::http::FetchError;
 use crate::archiver::Archiver;
+use crate::archiver::thread_filter::ThreadFilterSet;
 
 impl Archiver {
-    pub async fn get_board_pages(&self, board: &String) -> Result<Vec<ThreadsPage>, bool> {
-        self.http_client.fetch_json::<Vec<ThreadsPage>>(&get_board_page_api_url(board)).await
+    // Returns Ok(None) if the catalog didn't change since the last poll
+    pub async fn get_board_catalog(&self, source: &dyn UpstreamSource, board: &Board) -> Result<Option<Vec<CatalogPage>>, FetchError> {
+        self.http_client.fetch_json_if_modified::<Vec<CatalogPage>>(&source.catalog_url(&board.name), &board.name, board.rate_limit_share).await
     }
-    pub async fn push_new_threads(&self, board: &String) -> anyhow::Result<u64, bool> {
-        let mut pages = self.get_board_pages(board).await?;
+    pub async fn get_board_filters(&self, board: &str) -> Result<ThreadFilterSet, FetchError> {
+        let filters = self.db_client.get_thread_filters(Some(board)).await
+        .map_err(|e| {error!("Error getting thread filters from database: {}", e); FetchError::from(e)})?;
+        Ok(ThreadFilterSet::new(filters))
+    }
+    pub async fn push_new_threads(&self, source: &dyn UpstreamSource, board: &Board) -> Result<u64, FetchError> {
+        let filters = self.get_board_filters(&board.name).await?;
+        let pages = match self.get_board_catalog(source, board).await? {
+            Some(pages) => pages,
+            None => {
+                debug!("Catalog of /{}/ unchanged", board.name);
+                return Ok(0)
+            }
+        };
+        let res = self.push_catalog_threads(source, &board.name, &filters, pages).await;
+        if res.is_err() {
+            // Some threads might not have been handled, make sure the next poll gets the whole catalog again
+            self.http_client.forget_validators(&source.catalog_url(&board.name));
+        }
+        res
+    }
+    async fn push_catalog_threads(&self, source: &dyn UpstreamSource, board: &str, filters: &ThreadFilterSet, mut pages: Vec<CatalogPage>) -> Result<u64, FetchError> {
         let mut added_jobs: u64 = 0;
         while let Some(mut page) = pages.pop() { // pop lets us iterate in reverse, we want threads about to die to get fetched first
-            // eventually we should implement a formal priority queue system especially for images
-            while let Some(mut thread_info) = page.threads.pop() {
-                thread_info.board = board.clone();
-                thread_info.page = page.page as i32;
+            while let Some(thread) = page.threads.pop() {
+                let thread_info = thread.thread_info(board, page.page as i32);
+                if !filters.allows(&thread_info) {
+                    counter!("thread_filtered", 1);
+                    continue;
+                }
+                if self.db_client.is_thread_info_seen(&thread_info) {
+                    continue;
+                }
+                if self.update_thread_from_catalog(source, board, thread_info.page, &thread).await? {
+                    self.db_client.mark_thread_info_seen(&thread_info);
+                    continue;
+                }
                 let job_opt = self.db_client.insert_thread_job(&thread_info).await
-                .map_err(|e| {error!("Error inserting thread job into database: {}", e); false})?;
+                .map_err(|e| {error!("Error inserting thread job into database: {}", e); FetchError::from(e)})?;
                 if job_opt.is_some() {
                     added_jobs +=1;
                 }
@@ -35,6 +68,60 @@ impl Archiver {
         }
         Ok(added_jobs)
     }
+    /**
+     * Saves the latest changes to a thread straight from the catalog, when the catalog shows all of them:
+     * the OP changed (sticky, closed, reply counts...) and/or every new reply is in `last_replies`, with no replies deleted.
+     * Returns false if the thread has to be fetched instead.
+     * Changes to older replies (eg. a deleted file) are not visible in the catalog, they get picked up whenever the thread is next fetched.
+     */
+    pub async fn update_thread_from_catalog(&self, source: &dyn UpstreamSource, board: &str, page: i32, thread: &CatalogThread) -> Result<bool, FetchError> {
+        let stored_op = match self.db_client.get_post(&board.to_string(), thread.op.no, false).await
+        .map_err(|e| {error!("Error getting post from database: {}", e); FetchError::from(e)})? {
+            Some(op) => op,
+            None => return Ok(false) // New thread
+        };
+        if stored_op.last_modified >= thread.op.last_modified {
+            return Ok(true) // Nothing new
+        }
+
+        let known: HashSet<i64> = self.db_client.get_thread_post_nos(board, thread.op.no).await
+        .map_err(|e| {error!("Error getting posts from database: {}", e); FetchError::from(e)})?
+        .into_iter().collect();
+        let new_replies: Vec<&crate::models::Post> = thread.last_replies.iter().filter(|p| !known.contains(&p.no)).collect();
+        let new_count = new_replies.len() as i64;
+
+        // New replies always come after the ones we have
+        let in_order = thread.last_replies.iter()
+            .skip_while(|p| known.contains(&p.no))
+            .all(|p| !known.contains(&p.no));
+        // If a reply we have is shown, every newer reply is shown too. Otherwise, the catalog must show all of the thread's replies
+        let all_shown = new_replies.len() < thread.last_replies.len() || new_count == thread.op.replies;
+        // Otherwise, some replies were deleted
+        let none_deleted = thread.op.replies - stored_op.replies == new_count;
+        if !(in_order && all_shown && none_deleted) {
+            return Ok(false)
+        }
+
+        let mut op = stored_op;
+        op.sticky = thread.op.sticky;
+        op.closed = thread.op.closed;
+        op.replies = thread.op.replies;
+        op.images = thread.op.images;
+        op.bumplimit = thread.op.bumplimit;
+        op.imagelimit = thread.op.imagelimit;
+        op.filedeleted = thread.op.filedeleted;
+        op.last_modified = thread.op.last_modified;
+
+        let mut posts = vec![op];
+        posts.extend(new_replies.into_iter().cloned().map(|mut post| {
+            post.board = board.to_string();
+            post.last_modified = thread.op.last_modified;
+            post
+        }));
+        self.save_thread_posts(source, board, thread.op.no, page, posts).await?;
+        counter!("thread_catalog_updates", 1);
+        Ok(true)
+    }
     pub fn get_archived_hash(&self, board: &String, tid: i64) -> u64 {
         let mut hasher = DefaultHasher::new();
         (board.clone(), tid).hash(& mut hasher);
@@ -49,11 +136,26 @@ impl Archiver {
         self.archived_ids.insert(tid_hash);
         gauge!("thread_archived_hashes", self.archived_ids.len() as f64);
     }
-    pub async fn get_board_archive(&self, board: &String) -> Result<Vec<i64>, bool> {
-        self.http_client.fetch_json::<Vec<i64>>(&get_board_archive_api_url(board)).await
+    pub async fn push_archived_threads(&self, source: &dyn UpstreamSource, board: &Board) -> Result<(), FetchError> {
+        let url = match source.archive_url(&board.name) {
+            Some(url) => url,
+            None => return Ok(()) // Upstream has no archive
+        };
+        let filters = self.get_board_filters(&board.name).await?;
+        let tids = match self.http_client.fetch_json_if_modified::<Vec<i64>>(&url, &board.name, board.rate_limit_share).await? {
+            Some(tids) => tids,
+            None => {
+                debug!("Archive of /{}/ unchanged", board.name);
+                return Ok(())
+            }
+        };
+        let res = self.push_archived_tids(&board.name, &filters, tids).await;
+        if res.is_err() {
+            self.http_client.forget_validators(&url);
+        }
+        res
     }
-    pub async fn push_archived_threads(&self, board: &String) -> anyhow::Result<(), bool> {
-        let tids = self.get_board_archive(board).await?;
+    async fn push_archived_tids(&self, board: &String, filters: &ThreadFilterSet, tids: Vec<i64>) -> Result<(), FetchError> {
         for tid in tids {
             let tid_hash = self.get_archived_hash(board, tid);
             if self.archived_ids.contains(&tid_hash) {
@@ -63,13 +165,17 @@ impl Archiver {
             let mut last_modified = 0;
             let mut replies = 0;
             if let Some(op_post) = self.db_client.get_post(board, tid, false).await // We have this thread somewhere
-            .map_err(|e| {error!("Error getting post from database: {}", e); false})? {
+            .map_err(|e| {error!("Error getting post from database: {}", e); FetchError::from(e)})? {
                 if op_post.archived == 1 { // We already have the archive version of this, skip
                     self.insert_archived_hash(tid_hash);
                     continue;
                 }
                 last_modified = op_post.last_modified;
                 replies = op_post.replies;
+            } else if !filters.is_empty() {
+                // archive.json only has thread ids, so we can only tell it passed the filters if we archived it before
+                self.insert_archived_hash(tid_hash);
+                continue;
             }
             info!("Scheduling archived thread /{}/{}", board, tid);
             let thread_info = ThreadInfo {
@@ -78,9 +184,10 @@ impl Archiver {
                 last_modified,
                 replies,
                 page: 0,
+                ..Default::default()
             };
             if let Some(job) = self.db_client.insert_thread_job(&thread_info).await
-            .map_err(|e| {error!("Error inserting thread job into database: {}", e); false})? {
+            .map_err(|e| {error!("Error inserting thread job into database: {}", e); FetchError::from(e)})? {
                 counter!("thread_archived_jobs_scheduled", 1);
                 debug!("Archived thread /{}/{} [{}] scheduled", job.board, job.no, job.last_modified)
             }
@@ -88,17 +195,37 @@ impl Archiver {
         }
         Ok(())
     }
-    pub async fn board_cycle(&self) -> anyhow::Result<u64, bool> {
-
-        let boards = self.db_client.get_all_boards().await
-        .map_err(|e| {error!("Error getting board settings from database: {}", e); false})?;
+    /**
+     * Polls every archived board whose poll interval has passed since it was last polled, by this or any other worker.
+     * The boards stay leased to this worker until the cycle is done, so two workers never poll the same board.
+     */
+    pub async fn board_cycle(&self) -> Result<u64, FetchError> {
+        let boards = self.db_client.claim_board_polls(&self.worker_id, self.lease_seconds).await
+        .map_err(|e| {error!("Error claiming boards to poll from database: {}", e); FetchError::from(e)})?;
+        let res = self.poll_boards(boards).await;
+        self.db_client.release_board_polls(&self.worker_id).await
+        .map_err(|e| {error!("Failed to release board polls for worker {}: {}", self.worker_id, e); FetchError::from(e)})?;
+        res
+    }
+    async fn poll_boards(&self, boards: Vec<Board>) -> Result<u64, FetchError> {
         let mut added_jobs: u64 = 0;
         for board in boards {
-            if !board.archive {
+            let source = match self.get_upstream(&board) {
+                Ok(source) => source,
+                Err(e) => {
+                    error!("{}, skipping", e);
+                    continue;
+                }
+            };
+            // Boards from other Mitsuba instances are replicated from their feed, which has every change
+            if source.feed_url(&board.name).is_some() {
+                let report = self.replicate_board(source.as_ref(), &board).await?;
+                debug!("Replicated {} posts, {} files and {} moderation actions on /{}/", report.posts, report.files, report.actions, board.name);
                 continue;
             }
-            added_jobs += self.push_new_threads(&board.name).await?;
-            self.push_archived_threads(&board.name).await?;
+            // Both count against the board's share of the rate limit
+            added_jobs += self.push_new_threads(source.as_ref(), &board).await?;
+            self.push_archived_threads(source.as_ref(), &board).await?;
 
         }
         Ok(added_jobs)
@@ -108,19 +235,95 @@ impl Archiver {
         tokio::task::spawn(async move {
             loop {
                 let s = Instant::now();
-                let add_opt = AssertUnwindSafe(c.board_cycle())
-                .catch_unwind().await
-                .ok().and_then(|res| res.ok());
+                let res = AssertUnwindSafe(c.board_cycle())
+                .catch_unwind().await;
 
                 histogram!("boards_scan_duration", s.elapsed().as_millis() as f64);
-                if let Some(added_jobs) = add_opt {
-                    if added_jobs == 0 { // No new changes
-                        tokio::time::sleep(Duration::from_secs(10)).await;
-                    } else {
-                        tokio::time::sleep(Duration::from_secs(1)).await;
+                match res {
+                    Ok(Ok(added_jobs)) => debug!("Board scan added {} thread jobs", added_jobs),
+                    Ok(Err(e)) => {
+                        warn!("Board scan failed: {}", e);
+                        counter!("board_scan_errors", 1, "class" => e.class());
+                    },
+                    Err(_) => {
+                        // The cycle didn't get to release the boards it claimed
+                        c.db_client.release_board_polls(&c.worker_id).await.ok();
                     }
                 }
+                // Each board is only polled once its own interval has passed
+                tokio::time::sleep(Duration::from_secs(1)).await;
             }
         })
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archiver::tests::memory_archiver;
+    use crate::models::Post;
+    use crate::upstream::FourChanSource;
+
+    fn run_async<F: std::future::Future>(f: F) {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(f);
+    }
+    fn catalog_thread(op: Post, last_replies: Vec<i64>) -> CatalogThread {
+        let last_replies = last_replies.into_iter().map(|no| Post { no, resto: op.no, time: 1700000000 + no, ..Default::default() }).collect();
+        CatalogThread { op, last_replies }
+    }
+    #[test]
+    fn test_update_thread_from_catalog(){
+        run_async(update_thread_from_catalog());
+    }
+    async fn update_thread_from_catalog() {
+        let archiver = memory_archiver().await;
+        let source = FourChanSource::default();
+        let board = "testcatalog".to_string();
+        archiver.get_or_create_board(&board).await.unwrap();
+        let op = Post { board: board.clone(), no: 1, time: 1700000001, last_modified: 100, replies: 1, ..Default::default() };
+        let reply = Post { board: board.clone(), no: 2, resto: 1, time: 1700000002, last_modified: 100, ..Default::default() };
+        archiver.db_client.insert_posts(&vec![op.clone(), reply]).await.unwrap();
+        let stored_op = || async { archiver.db_client.get_post(&board, 1, false).await.unwrap().unwrap() };
+        let stored_nos = || async {
+            let mut nos = archiver.db_client.get_thread_post_nos(&board, 1).await.unwrap();
+            nos.sort();
+            nos
+        };
+
+        // Nothing changed since the thread was saved
+        assert!(archiver.update_thread_from_catalog(&source, &board, 1, &catalog_thread(op.clone(), vec![2])).await.unwrap());
+        assert_eq!(vec![1, 2], stored_nos().await);
+
+        // A new reply, and the thread was stickied and closed
+        let changed = Post { last_modified: 200, replies: 2, sticky: 1, closed: 1, ..op.clone() };
+        assert!(archiver.update_thread_from_catalog(&source, &board, 1, &catalog_thread(changed, vec![2, 3])).await.unwrap());
+        assert_eq!(vec![1, 2, 3], stored_nos().await);
+        let updated = stored_op().await;
+        assert_eq!((200, 2, 1, 1), (updated.last_modified, updated.replies, updated.sticky, updated.closed));
+        assert_eq!(200, archiver.db_client.get_post(&board, 3, false).await.unwrap().unwrap().last_modified);
+
+        // Only the OP changed
+        let unstickied = Post { last_modified: 250, replies: 2, ..op.clone() };
+        assert!(archiver.update_thread_from_catalog(&source, &board, 1, &catalog_thread(unstickied, vec![2, 3])).await.unwrap());
+        let updated = stored_op().await;
+        assert_eq!((250, 0, 0), (updated.last_modified, updated.sticky, updated.closed));
+
+        // The catalog can't show these, the thread has to be fetched
+        for (replies, last_replies) in [
+            (1, vec![3]), // A reply was deleted
+            (4, vec![5]), // Reply 4 isn't shown
+            (3, vec![4, 3]), // Out of order
+        ] {
+            let thread = catalog_thread(Post { last_modified: 300, replies, ..op.clone() }, last_replies.clone());
+            assert!(!archiver.update_thread_from_catalog(&source, &board, 1, &thread).await.unwrap(), "{} replies, {:?} shown", replies, last_replies);
+            assert_eq!(250, stored_op().await.last_modified);
+            assert_eq!(vec![1, 2, 3], stored_nos().await);
+        }
+        // New threads are always fetched
+        let new_thread = Post { no: 10, last_modified: 300, ..op.clone() };
+        assert!(!archiver.update_thread_from_catalog(&source, &board, 1, &catalog_thread(new_thread, vec![])).await.unwrap());
+
+        archiver.db_client.purge_board_data(&board).await.unwrap();
+    }
+}
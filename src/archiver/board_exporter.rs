@@ -0,0 +1,328 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::time::SystemTime;
+
+use tokio::sync::mpsc;
+
+#[allow(unused_imports)]
+use log::{info, warn, error, debug};
+
+use crate::archiver::Archiver;
+use crate::models::{BundleImportReport, ExportManifest, ExportReport, Post, PostModeration, Thread};
+use crate::tar::{TarReader, TarWriter};
+use crate::util::{get_file_key, hash_file};
+
+// Bumped when bundles change in a way older versions can't import
+const EXPORT_VERSION: i64 = 1;
+
+// A file or thumbnail used by a post
+pub(crate) struct Attachment {
+    pub idx: i32,
+    pub sha256: String,
+    pub ext: String,
+    pub is_thumb: bool,
+}
+
+impl Attachment {
+    pub fn key(&self) -> String {
+        get_file_key(&self.sha256, &self.ext, self.is_thumb)
+    }
+}
+
+// Blacklisted files are deleted from storage, so they are left out
+pub(crate) fn attachments(post: &Post) -> Vec<Attachment> {
+    let mut attachments = Vec::new();
+    let mut add = |idx: i32, file: &Option<String>, ext: &str, thumbnail: &Option<String>, thumbnail_ext: &Option<String>| {
+        if let Some(sha256) = file.as_ref().filter(|s| !s.is_empty()) {
+            attachments.push(Attachment { idx, sha256: sha256.clone(), ext: ext.to_string(), is_thumb: false });
+        }
+        if let Some(sha256) = thumbnail.as_ref().filter(|s| !s.is_empty()) {
+            let ext = thumbnail_ext.clone().unwrap_or(".jpg".to_string());
+            attachments.push(Attachment { idx, sha256: sha256.clone(), ext, is_thumb: true });
+        }
+    };
+    if !post.mitsuba_file_blacklisted.unwrap_or(false) {
+        add(0, &post.file_sha256, &post.ext, &post.thumbnail_sha256, &post.thumbnail_ext);
+    }
+    for (i, file) in post.extra_files.0.iter().enumerate() {
+        if !file.mitsuba_file_blacklisted.unwrap_or(false) {
+            add(i as i32 + 1, &file.file_sha256, &file.ext, &file.thumbnail_sha256, &file.thumbnail_ext);
+        }
+    }
+    attachments
+}
+
+fn moderation_state(post: &Post) -> PostModeration {
+    PostModeration {
+        no: post.no,
+        hidden: post.mitsuba_post_hidden,
+        com_hidden: post.mitsuba_com_hidden,
+        file_hidden: post.mitsuba_file_hidden.unwrap_or(false)
+            || post.extra_files.0.iter().any(|file| file.mitsuba_file_hidden.unwrap_or(false)),
+        file_blacklisted: post.mitsuba_file_blacklisted.unwrap_or(false)
+            || post.extra_files.0.iter().any(|file| file.mitsuba_file_blacklisted.unwrap_or(false)),
+    }
+}
+
+impl Archiver {
+    /**
+     * Writes a board's threads to a zstd compressed tar bundle, see `ExportManifest` for its layout.
+     * Only threads changed since `since` are exported, and with `media`, the files they use.
+     * Hidden posts, comments and files are left out, like in the public API.
+     * Moderation isn't visible in the threads, so the state of hidden posts, and of posts moderated since `since`, is exported separately.
+     */
+    pub async fn export_board(&self, board_name: &str, since: i64, media: bool, out: &Path) -> anyhow::Result<ExportReport> {
+        let board = self.db_client.get_board(&board_name.to_string()).await?
+            .ok_or_else(|| anyhow::anyhow!("Board /{}/ not found", board_name))?;
+        let exported_at = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+        let threads = self.db_client.get_threads_changed_since(&board.name, since).await?;
+        let mut report = ExportReport { exported_at, ..Default::default() };
+
+        // Files are written on a blocking thread, entries are sent to it as they are read from the database
+        let file = std::fs::File::create(out)?;
+        let (sender, mut receiver) = mpsc::channel::<(String, Vec<u8>)>(16);
+        let writer = tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+            let mut tar = TarWriter::new(zstd::Encoder::new(file, 3)?);
+            while let Some((path, data)) = receiver.blocking_recv() {
+                tar.append(&path, &data, exported_at)?;
+            }
+            tar.finish()?.finish()?;
+            Ok(())
+        });
+        let manifest = ExportManifest {
+            version: EXPORT_VERSION,
+            board: board.name.clone(),
+            since,
+            exported_at,
+            threads: threads.len() as u64,
+            media,
+        };
+        // Sending only fails if the writer stopped, its error is returned at the end
+        sender.send(("manifest.json".to_string(), serde_json::to_vec_pretty(&manifest)?)).await.ok();
+        let mut exported_files = HashSet::new();
+        'threads: for no in threads {
+            let Some(thread) = self.db_client.get_thread(&board.name, no, true).await? else {
+                continue
+            };
+            if sender.send((format!("threads/{}.json", no), serde_json::to_vec(&thread)?)).await.is_err() {
+                break 'threads
+            }
+            report.threads += 1;
+            report.posts += thread.posts.len() as u64;
+            if !media {
+                continue
+            }
+            for attachment in thread.posts.iter().flat_map(attachments) {
+                let key = attachment.key();
+                if !exported_files.insert(key.clone()) {
+                    continue
+                }
+                match self.file_store.get(&key).await? {
+                    Some(bytes) => {
+                        if sender.send((format!("media/{}", key), bytes.to_vec())).await.is_err() {
+                            break 'threads
+                        }
+                        report.files += 1;
+                    },
+                    None => {
+                        warn!("File {} used by /{}/{} is missing from {} storage", key, board.name, no, self.file_store.name());
+                        report.missing_files += 1;
+                    }
+                }
+            }
+            if report.threads.is_multiple_of(1000) {
+                info!("Exported {} threads from /{}/", report.threads, board.name);
+            }
+        }
+        let mut moderation = Vec::new();
+        for no in self.db_client.get_moderated_posts(&board.name, since).await? {
+            if let Some(post) = self.db_client.get_post(&board.name, no, false).await? {
+                moderation.push(moderation_state(&post));
+            }
+        }
+        report.moderated_posts = moderation.len() as u64;
+        sender.send(("moderation.json".to_string(), serde_json::to_vec(&moderation)?)).await.ok();
+        drop(sender);
+        writer.await??;
+        Ok(report)
+    }
+
+    /**
+     * Imports a bundle written by `export_board` into a board, by default the one it was exported from.
+     * Files are checked against their hash, and posts whose files are not in the bundle
+     * are linked to them anyway if they are already in storage.
+     * Moderation from the bundle is applied last, without being logged again.
+     */
+    pub async fn import_board(&self, path: &Path, board_name: Option<&str>) -> anyhow::Result<BundleImportReport> {
+        let decoder = zstd::Decoder::new(std::fs::File::open(path)?)?;
+        let (sender, mut receiver) = mpsc::channel::<std::io::Result<(String, Vec<u8>)>>(16);
+        tokio::task::spawn_blocking(move || {
+            let mut tar = TarReader::new(decoder);
+            // Until the end of the bundle, the first error, or the import stopping
+            while let Some(entry) = tar.next_file().transpose() {
+                let failed = entry.is_err();
+                if sender.blocking_send(entry).is_err() || failed {
+                    break
+                }
+            }
+        });
+        let manifest: ExportManifest = match receiver.recv().await.transpose()? {
+            Some((name, data)) if name == "manifest.json" => serde_json::from_slice(&data)?,
+            _ => return Err(anyhow::anyhow!("{} is not a Mitsuba export, it has no manifest", path.display()))
+        };
+        if manifest.version > EXPORT_VERSION {
+            return Err(anyhow::anyhow!("{} was made by a newer version of Mitsuba (format {})", path.display(), manifest.version))
+        }
+        let board = self.get_or_create_board(board_name.unwrap_or(&manifest.board)).await?;
+        info!("Importing {} threads exported from /{}/ into /{}/", manifest.threads, manifest.board, board.name);
+
+        let mut report = BundleImportReport::default();
+        // Files come after the threads that use them, or not at all
+        let mut pending: HashMap<String, (Attachment, Vec<(i64, i32)>)> = HashMap::new();
+        let mut imported_files = HashSet::new();
+        let mut moderation: Vec<PostModeration> = Vec::new();
+        while let Some((name, data)) = receiver.recv().await.transpose()? {
+            if name == "moderation.json" {
+                moderation = serde_json::from_slice(&data)?;
+                continue
+            }
+            if name.starts_with("threads/") {
+                let mut thread: Thread = serde_json::from_slice(&data)?;
+                for post in thread.posts.iter_mut() {
+                    post.board = board.name.clone();
+                }
+                self.db_client.insert_posts(&thread.posts).await?;
+                report.threads += 1;
+                report.posts += thread.posts.len() as u64;
+                for post in thread.posts.iter() {
+                    for attachment in attachments(post) {
+                        let key = attachment.key();
+                        if imported_files.contains(&key) {
+                            self.db_client.add_post_file(&board.name, post.no, attachment.idx, &attachment.sha256, &attachment.ext, attachment.is_thumb).await?;
+                        } else {
+                            let idx = attachment.idx;
+                            pending.entry(key).or_insert((attachment, Vec::new())).1.push((post.no, idx));
+                        }
+                    }
+                }
+            } else if let Some(key) = name.strip_prefix("media/") {
+                let Some((attachment, posts)) = pending.remove(key) else {
+                    continue
+                };
+                if hash_file(&data) != attachment.sha256 {
+                    warn!("File {} in {} does not match its hash, skipping it", key, path.display());
+                    report.corrupted_files += 1;
+                    continue
+                }
+                let bytes = bytes::Bytes::from(data);
+                self.file_store.put(key, bytes.clone()).await?;
+                if attachment.is_thumb {
+                    self.save_perceptual_hash(&attachment.sha256, bytes).await?;
+                }
+                for (no, idx) in posts {
+                    self.db_client.add_post_file(&board.name, no, idx, &attachment.sha256, &attachment.ext, attachment.is_thumb).await?;
+                    self.handle_blacklist(&board.name, no, &attachment.sha256, &attachment.ext, attachment.is_thumb).await?;
+                }
+                imported_files.insert(key.to_string());
+                report.files += 1;
+            }
+        }
+        for (key, (attachment, posts)) in pending {
+            if !self.file_store.exists(&key).await? {
+                debug!("File {} is not in the bundle or in storage", key);
+                report.missing_files += 1;
+                continue
+            }
+            for (no, idx) in posts {
+                self.db_client.add_post_file(&board.name, no, idx, &attachment.sha256, &attachment.ext, attachment.is_thumb).await?;
+            }
+        }
+        for wanted in moderation {
+            // Posts hidden before they were ever exported are not here
+            let Some(post) = self.db_client.get_post(&board.name, wanted.no, false).await? else {
+                continue
+            };
+            let current = moderation_state(&post);
+            if current == wanted {
+                continue
+            }
+            // Blacklisting hides the files, so it's done before setting their hidden status
+            if wanted.file_blacklisted && !current.file_blacklisted {
+                self.ban_image(&board.name, post.no, None).await?;
+            } else if !wanted.file_blacklisted && current.file_blacklisted {
+                self.unban_image(&board.name, post.no, None).await?;
+            }
+            self.hide_post(&board.name, post.no, Some(wanted.hidden), Some(wanted.com_hidden), Some(wanted.file_hidden), None).await?;
+            report.moderated_posts += 1;
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use super::*;
+    use crate::archiver::tests::memory_archiver;
+    use crate::file_store::MemoryStore;
+    use crate::models::{User, UserRole};
+
+    #[tokio::test]
+    async fn test_export_import() {
+        let source = memory_archiver().await;
+        // A mirror sharing the database, with its own storage
+        let mirror = Archiver { file_store: Arc::new(MemoryStore::new()), ..source.clone() };
+        let (from, to) = ("testexport".to_string(), "testimport".to_string());
+        source.get_or_create_board(&from).await.unwrap();
+        let thumbnail = bytes::Bytes::from_static(b"thumbnail");
+        let sha256 = source.store_file(thumbnail.clone(), ".jpg", true).await.unwrap();
+        let posts: Vec<Post> = (1..4).map(|no| Post {
+            board: from.clone(), no, resto: if no == 1 { 0 } else { 1 }, time: 1700000000 + no, ..Default::default()
+        }).collect();
+        source.db_client.insert_posts(&posts).await.unwrap();
+        source.db_client.add_post_file(&from, 1, 0, &sha256, &".jpg".to_string(), true).await.unwrap();
+
+        let path = std::env::temp_dir().join(format!("mitsuba-test-{:08x}.tar.zst", rand::random::<u32>()));
+        let export = source.export_board(&from, 0, true, &path).await.unwrap();
+        assert_eq!((1, 3, 1, 0), (export.threads, export.posts, export.files, export.moderated_posts));
+        let import = mirror.import_board(&path, Some(&to)).await.unwrap();
+        assert_eq!((1, 3, 1, 0), (import.threads, import.posts, import.files, import.corrupted_files));
+        assert_eq!(Some(thumbnail), mirror.file_store.get(&get_file_key(&sha256, ".jpg", true)).await.unwrap());
+
+        // Hidden posts are left out of the threads, their moderation state is what reaches the mirror
+        source.hide_post(&from, 2, Some(true), None, None, None).await.unwrap();
+        source.hide_post(&from, 3, None, Some(true), None, None).await.unwrap();
+        let export = source.export_board(&from, export.exported_at, true, &path).await.unwrap();
+        assert_eq!(2, export.moderated_posts);
+        let import = mirror.import_board(&path, Some(&to)).await.unwrap();
+        assert_eq!(2, import.moderated_posts);
+        let thread = mirror.db_client.get_thread(&to, 1, true).await.unwrap().unwrap();
+        assert_eq!(vec![1, 3], thread.posts.iter().map(|p| p.no).collect::<Vec<_>>());
+        assert!(thread.posts[1].mitsuba_com_hidden);
+
+        // Unhiding shows up in incremental exports through the moderation log
+        let moderator = "testexporter".to_string();
+        source.db_client.insert_user(&User { name: moderator.clone(), password_hash: String::new(), role: UserRole::Mod }).await.unwrap();
+        let log_id = source.db_client.create_moderation_log_entry(Some(&moderator), None, None).await.unwrap();
+        let since = export.exported_at;
+        source.unhide_post(&from, 2, Some(log_id)).await.unwrap();
+        source.export_board(&from, since, false, &path).await.unwrap();
+        assert_eq!(1, mirror.import_board(&path, Some(&to)).await.unwrap().moderated_posts);
+        assert_eq!(3, mirror.db_client.get_thread(&to, 1, true).await.unwrap().unwrap().posts.len());
+
+        // Threads archived since the last export are in the next one, however old they are upstream
+        let since = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64;
+        let old = Post { board: from.clone(), no: 10, time: 1600000000, last_modified: 1600000000, ..Default::default() };
+        source.db_client.insert_posts(&vec![old]).await.unwrap();
+        source.export_board(&from, since, false, &path).await.unwrap();
+        mirror.import_board(&path, Some(&to)).await.unwrap();
+        assert!(mirror.db_client.get_thread(&to, 10, true).await.unwrap().is_some());
+
+        sqlx::query("DELETE FROM moderation_log WHERE log_id = $1").bind(log_id).execute(&source.db_client.pool).await.unwrap();
+        source.db_client.delete_user(&moderator).await.unwrap();
+        std::fs::remove_file(&path).ok();
+        for board in [&from, &to] {
+            mirror.db_client.purge_board_data(board).await.unwrap();
+        }
+    }
+}
@@ -10,32 +10,39 @@ use metrics::{gauge, increment_gauge, decrement_gauge, counter, histogram};
 
 use crate::models::ImageJob;
 use crate::archiver::Archiver;
+use crate::http::FetchError;
+use crate::util::{hash_file, get_file_key, perceptual_hash};
 
 impl Archiver {
     pub async fn image_cycle(&self) -> Result<(),()> {
         let (tx, mut rx) = tokio::sync::mpsc::channel(100);
         let mut running_jobs = HashMap::new();
         loop {
-            let mut jobs = self.db_client.get_image_jobs(250).await
-                .map_err(|e|{error!("Failed to get new image jobs from database: {}", e);})?;
-            
-            if jobs.len() == 0 { // No more jobs available
+            // Run up to 20 jobs at once. Only claim as many as we can start right away, the rest are left to other workers
+            if running_jobs.len() < 20 {
+                let jobs = self.db_client.claim_image_jobs(&self.worker_id, self.lease_seconds, (20 - running_jobs.len()) as i64).await
+                    .map_err(|e|{error!("Failed to claim new image jobs from database: {}", e);})?;
+                for job in jobs { // jobs are already sorted by priority
+                    running_jobs.insert(job.id, self.dispatch_archive_image(tx.clone(), job));
+                }
+            }
+
+            if running_jobs.is_empty() { // No more jobs available
                 tokio::time::sleep(Duration::from_secs(10)).await;
                 continue;
-            };            
-            while let Some(job) = jobs.pop() {
-                if running_jobs.contains_key(&job.id) { // Don't schedule the same job twice
-                    continue;
-                }
-                running_jobs.insert(job.id, self.dispatch_archive_image(tx.clone(), job));
-                if running_jobs.len() < 20 {continue}; // Dispatch up to 20 jobs at once
+            };
 
-                if let Some(job_id) = rx.recv().await { // wait for a job to complete before dispatching the next
+            // wait for a job to complete before claiming more, but check for new jobs every 10s
+            match tokio::time::timeout(Duration::from_secs(10), rx.recv()).await {
+                Ok(Some(job_id)) => {
                     running_jobs.remove(&job_id);
-                } else { // All jobs are terminated or dead
-                    running_jobs.clear();
-                }
-                debug!("One image job has completed")
+                    while let Ok(job_id) = rx.try_recv() {
+                        running_jobs.remove(&job_id);
+                    }
+                    debug!("Image jobs have completed")
+                },
+                Ok(None) => running_jobs.clear(), // All jobs are terminated or dead
+                Err(_) => {}
             }
         }
     }
@@ -45,28 +52,95 @@ impl Archiver {
             async move {
                 increment_gauge!("file_jobs_running", 1.0);
                 let s = Instant::now();
-                AssertUnwindSafe(c.archive_image(&job.clone())).catch_unwind().await.ok();
+                if let Ok(Err(e)) = AssertUnwindSafe(c.archive_image(&job.clone())).catch_unwind().await {
+                    counter!("file_job_errors", 1, "class" => e.class());
+                }
                 histogram!("file_job_duration", s.elapsed().as_millis() as f64);
                 decrement_gauge!("file_jobs_running", 1.0);
+                // Does nothing if the job was completed, otherwise it can be retried by any worker
+                c.db_client.release_image_job(job.id).await.ok();
                 tx.send(job.id).await.ok();
             }
         )
     }
-    pub async fn archive_image(&self, job: &ImageJob) -> Result<(),()> {
+    pub async fn archive_image(&self, job: &ImageJob) -> Result<(), FetchError> {
+        match self.process_job_images(job).await {
+            Ok(()) => {},
+            // Retrying won't help, the job is done
+            Err(e) if e.is_permanent() => {
+                warn!("File job /{}/{} ({}): {}, deleting from backlog", job.board, job.no, job.id, e);
+                counter!("file_job_errors", 1, "class" => e.class());
+            },
+            Err(e) => return Err(e)
+        }
+        self.db_client.delete_image_job(job.id).await
+        .map_err(|e| {error!("Failed to delete file job {} from backlog: {}", job.id, e); FetchError::from(e)})?;
+        Ok(())
+    }
+
+    async fn process_job_images(&self, job: &ImageJob) -> Result<(), FetchError> {
         if let Some(board) = self.db_client.get_board(&job.board).await
-            .map_err(|e| {error!("Failed to get board info for file job: /{}/{}: {}", job.board, job.no, e);})?
+            .map_err(|e| {error!("Failed to get board info for file job: /{}/{}: {}", job.board, job.no, e); FetchError::from(e)})?
         {
+            // Thumbnails missing upstream are made from the full file instead, when we have it
+            let mut generate_thumbnail = false;
             if job.thumbnail_sha256.is_none() && board.archive {
-                self.process_single_image(&job.board, job.no, &job.thumbnail_url, &".jpg".to_string(), true).await?;
+                if job.thumbnail_url.is_empty() {
+                    generate_thumbnail = true;
+                } else {
+                    self.http_client.until_board_ready(&board.name, board.rate_limit_share).await;
+                    match self.process_single_image(&job.board, job.no, job.idx, &job.thumbnail_url, &".jpg".to_string(), true).await {
+                        Ok(_) => {},
+                        Err(FetchError::NotFound) if board.full_images => {
+                            debug!("Thumbnail for /{}/{} ({}) not found upstream", job.board, job.no, job.idx);
+                            generate_thumbnail = true;
+                        },
+                        Err(e) => return Err(e)
+                    }
+                }
             }
     
             // If full_images is enabled for the board (and the board is still enabled), download the full image
-            if job.file_sha256.is_none() && board.full_images && board.archive {
-                self.process_single_image(&job.board, job.no, &job.url, &job.ext, false).await?;
+            let full_file = if job.file_sha256.is_none() && board.full_images && board.archive {
+                self.http_client.until_board_ready(&board.name, board.rate_limit_share).await;
+                Some(self.process_single_image(&job.board, job.no, job.idx, &job.url, &job.ext, false).await?)
+            } else if let (true, Some(sha256)) = (generate_thumbnail, &job.file_sha256) {
+                self.file_store.get(&get_file_key(sha256, &job.ext, false)).await
+                .map_err(|e| {error!("Failed to read file for /{}/{} from {} storage: {}", job.board, job.no, self.file_store.name(), e); FetchError::Storage(e.to_string())})?
+            } else {
+                None
+            };
+
+            if generate_thumbnail {
+                match full_file {
+                    Some(bytes) => self.generate_thumbnail(job, bytes).await?,
+                    None => return Err(FetchError::NotFound)
+                }
             }
         }
-        self.db_client.delete_image_job(job.id).await
-        .map_err(|e| {error!("Failed to delete file job {} from backlog: {}", job.id, e);})?;
+        Ok(())
+    }
+
+    async fn generate_thumbnail(&self, job: &ImageJob, bytes: bytes::Bytes) -> Result<(), FetchError> {
+        let thumbnail = match self.thumbnailer.generate(bytes, &job.ext).await {
+            Ok(Some(thumbnail)) => thumbnail,
+            Ok(None) => {
+                debug!("Can't make a thumbnail for /{}/{} ({}), {} files are not supported", job.board, job.no, job.idx, job.ext);
+                return Ok(())
+            },
+            // Retrying won't fix a file we can't decode
+            Err(e) => {
+                warn!("Failed to make a thumbnail for /{}/{} ({}): {}", job.board, job.no, job.idx, e);
+                return Ok(())
+            }
+        };
+        let ext = self.thumbnailer.ext().to_string();
+        let sha256 = self.store_file(thumbnail.clone(), &ext, true).await?;
+        self.db_client.add_post_file(&job.board, job.no, job.idx, &sha256, &ext, true).await
+            .map_err(|e| {error!("Failed to update thumbnail for post: /{}/{}: {}", job.board, job.no, e); FetchError::from(e)})?;
+        self.save_perceptual_hash(&sha256, thumbnail).await?;
+        counter!("thumbnails_generated", 1);
+        info!("Generated thumbnail for /{}/{} ({})", job.board, job.no, job.idx);
         Ok(())
     }
 
@@ -74,49 +148,87 @@ impl Archiver {
         &self,
         board: &String,
         no: i64,
+        idx: i32,
         url: &String,
         ext: &String,
         is_thumb: bool
     )
-    -> Result<(),()> {
-        let sha256 = self.http_client
-            .download_file_checksum(
-                url,
-                ext,
-                is_thumb
-        ).await?;
+    -> Result<bytes::Bytes, FetchError> {
+        let bytes = self.http_client.download_file(url, is_thumb).await?;
+        let sha256 = self.store_file(bytes.clone(), ext, is_thumb).await?;
         if is_thumb {
             counter!("thumbnails_fetched", 1);
         } else {
             counter!("files_fetched", 1);
         }
-        info!("Processed file (thumb: {}) for /{}/{}", is_thumb, board, no);
+        info!("Processed file (thumb: {}) for /{}/{} ({})", is_thumb, board, no, idx);
         self.db_client
             .add_post_file(
                 board,
                 no,
-                0,
+                idx,
                 &sha256,
                 ext,
-                true
+                is_thumb
             ).await
-            .map_err(|e| {error!("Failed to update file for post: /{}/{}: {}", board, no, e);})?;
-        self.handle_blacklist(board, no, &sha256, ext, is_thumb)
-            .await.map_err(|e| {error!("Failed to check file blacklist for post: /{}/{}: {}", board, no, e);})?;
+            .map_err(|e| {error!("Failed to update file for post: /{}/{}: {}", board, no, e); FetchError::from(e)})?;
+        if is_thumb {
+            self.save_perceptual_hash(&sha256, bytes.clone()).await?;
+        }
+        if self.handle_blacklist(board, no, &sha256, ext, is_thumb)
+            .await.map_err(|e| {error!("Failed to check file blacklist for post: /{}/{}: {}", board, no, e); FetchError::from(e)})? {
+            return Err(FetchError::Blacklisted)
+        }
+        Ok(bytes)
+    }
+
+    // Hashes are computed from thumbnails, a thumbnail we can't decode just won't show up in similar image searches
+    pub(crate) async fn save_perceptual_hash(&self, sha256: &String, bytes: bytes::Bytes) -> Result<(), FetchError> {
+        let phash = match tokio::task::spawn_blocking(move || perceptual_hash(&bytes)).await
+            .map_err(|e| FetchError::Internal(e.into()))? {
+            Ok(phash) => phash,
+            Err(e) => {
+                debug!("Failed to compute perceptual hash of {}: {}", sha256, e);
+                return Ok(())
+            }
+        };
+        self.db_client.set_file_phash(sha256, phash).await
+            .map_err(|e| {error!("Failed to save perceptual hash of {}: {}", sha256, e); FetchError::from(e)})?;
         Ok(())
     }
 
-    async fn handle_blacklist(&self, board_name: &String, no: i64, sha256: &String, ext: &String, is_thumb: bool) -> anyhow::Result<()> {
+    // Returns true if the file was blacklisted
+    pub(crate) async fn handle_blacklist(&self, board_name: &String, no: i64, sha256: &String, ext: &str, is_thumb: bool) -> anyhow::Result<bool> {
         if !self.db_client.is_file_blacklisted(sha256).await? {
-            return Ok(());
+            return Ok(false);
         }
         // File is blacklisted, hide the image and delete it
         warn!("Blacklisted file on /{}/{} ({}) detected, hiding and deleting", board_name, no, sha256);
         self.db_client.set_post_hidden_status(board_name, no, false, false, true).await?;
-        self.http_client.delete_downloaded_file(sha256, ext, is_thumb).await?;
-        Ok(())
+        self.delete_stored_file(sha256, ext, is_thumb).await?;
+        Ok(true)
     }
 
+    // Saves a downloaded file to the file store, returns its sha256
+    pub async fn store_file(&self, bytes: bytes::Bytes, ext: &str, is_thumb: bool) -> Result<String, FetchError> {
+        let hash = hash_file(&bytes);
+        let key = get_file_key(&hash, ext, is_thumb);
+        self.file_store.put(&key, bytes).await
+        .map_err(|e| {error!("Could not save file {} to {} storage: {}", key, self.file_store.name(), e); FetchError::Storage(e.to_string())})?;
+        Ok(hash)
+    }
+    pub async fn delete_stored_file(&self, hash: &str, ext: &str, is_thumb: bool) -> anyhow::Result<()> {
+        let key = get_file_key(hash, ext, is_thumb);
+        match self.file_store.delete(&key).await {
+            Ok(true) => {},
+            Ok(false) => warn!("File {} not found on {} storage", key, self.file_store.name()),
+            Err(e) => {
+                error!("Failed to delete file {} from {} storage: {}", key, self.file_store.name(), e);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
     pub fn run_image_cycle(&self) -> tokio::task::JoinHandle<()> {
         let c = self.clone();
         tokio::task::spawn(async move {
@@ -125,4 +237,46 @@ impl Archiver {
             }
         })
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::io::Cursor;
+    use crate::archiver::tests::memory_archiver;
+    use crate::http::tests::mock_server;
+    use crate::models::{Board, ImageJob, Post};
+
+    #[tokio::test]
+    async fn test_thumbnail_not_found_upstream() {
+        let archiver = memory_archiver().await;
+        let board = "testthumb404".to_string();
+        archiver.db_client.insert_board(&Board { name: board.clone(), full_images: true, archive: true, ..Default::default() }).await.unwrap();
+        archiver.db_client.insert_posts(&vec![Post { board: board.clone(), no: 1, time: 1700000000, tim: 1700000000000, ext: ".png".to_string(), ..Default::default() }]).await.unwrap();
+
+        let mut png = Cursor::new(Vec::new());
+        image::RgbImage::from_pixel(500, 100, image::Rgb([0, 128, 255])).write_to(&mut png, image::ImageFormat::Png).unwrap();
+        // Only the full file is served, the thumbnail is a 404
+        let routes = Arc::new(Mutex::new([(format!("/{}/1700000000000.png", board), png.into_inner())].into_iter().collect()));
+        let url = mock_server(routes).await;
+        let job = ImageJob {
+            board: board.clone(),
+            no: 1,
+            url: format!("{}/{}/1700000000000.png", url, board),
+            thumbnail_url: format!("{}/{}/1700000000000s.jpg", url, board),
+            ext: ".png".to_string(),
+            ..Default::default()
+        };
+        archiver.archive_image(&job).await.unwrap();
+
+        let post = archiver.db_client.get_post(&board, 1, false).await.unwrap().unwrap();
+        let (file, thumbnail) = (post.file_sha256.unwrap(), post.thumbnail_sha256.unwrap());
+        assert_eq!(Some(archiver.thumbnailer.ext()), post.thumbnail_ext.as_deref());
+        let stored = archiver.file_store.get(&crate::util::get_file_key(&thumbnail, archiver.thumbnailer.ext(), true)).await.unwrap().unwrap();
+        let decoded = image::load_from_memory(&stored).unwrap();
+        assert_eq!((250, 50), (decoded.width(), decoded.height()));
+
+        archiver.db_client.purge_board_data(&board).await.unwrap();
+        archiver.db_client.delete_file(&file).await.unwrap();
+        archiver.db_client.delete_file(&thumbnail).await.unwrap();
+    }
+}
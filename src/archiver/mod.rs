@@ -1,5 +1,6 @@
 use std::collections::HashSet;
 use std::sync::Arc;
+use std::time::Duration;
 use anyhow::Ok;
 #[allow(unused_imports)]
 use log::{info, warn, error, debug};
@@ -15,16 +16,34 @@ mod board_archiver;
 mod image_archiver;
 mod thread_archiver;
 mod archiver_metrics;
+mod storage_verifier;
+mod storage_migrator;
+mod search_indexer;
+pub mod asagi_importer;
+pub(crate) mod board_exporter;
+mod replicator;
+pub mod orphan_collector;
+pub mod thumbnailer;
+pub mod thread_filter;
 
 use crate::{http::HttpClient, models::{ModActionType, User, UserRole}};
-use crate::models::{Board, BoardsList, PurgeReport};
+use crate::models::{Board, BoardsList, Post, PurgeReport, ThreadFilter};
 use crate::db::DBClient;
+use crate::upstream::{UpstreamSource, Upstreams};
+use crate::file_store::{FileStore, file_store_from_env};
+use thumbnailer::Thumbnailer;
 
 #[derive(Clone)]
 pub struct Archiver {
     pub http_client: HttpClient,
     pub db_client: DBClient,
-    pub archived_ids: Arc<DashSet<u64>>
+    pub upstreams: Upstreams,
+    pub file_store: Arc<dyn FileStore>,
+    pub thumbnailer: Arc<Thumbnailer>,
+    pub archived_ids: Arc<DashSet<u64>>,
+    /// Identifies this process in job leases. Set WORKER_ID to keep it stable across restarts.
+    pub worker_id: String,
+    pub lease_seconds: i64
 }
 
 
@@ -33,47 +52,107 @@ impl Archiver {
         Self {
             http_client: client,
             db_client: DBClient::new().await,
-            archived_ids: Arc::new(DashSet::new())
+            upstreams: Upstreams::new(),
+            file_store: file_store_from_env(),
+            thumbnailer: Arc::new(Thumbnailer::from_env()),
+            archived_ids: Arc::new(DashSet::new()),
+            worker_id: std::env::var("WORKER_ID").unwrap_or(format!("{:08x}", rand::random::<u32>())),
+            lease_seconds: std::env::var("JOB_LEASE_SECONDS").ok()
+                .and_then(|s| s.parse().ok()).unwrap_or(300)
         }
     }
     pub fn run_archivers(&self) -> tokio::task::JoinHandle<()> {
         self.run_metrics_cycle();
+        self.run_lease_cycle();
+        self.run_storage_verify_cycle();
+        self.run_orphan_gc_cycle();
+        self.run_feed_backfill();
         self.run_board_cycle();
         self.run_thread_cycle();
         self.run_image_cycle()
     }
-    pub async fn get_all_boards_api(&self) -> Result<BoardsList, bool> {
-        self.http_client.fetch_json::<BoardsList>("https://a.4cdn.org/boards.json").await
+    // Keeps the leases on our running jobs alive. If this process dies, its jobs become available to other workers.
+    pub fn run_lease_cycle(&self) -> tokio::task::JoinHandle<()> {
+        let c = self.clone();
+        tokio::task::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs((c.lease_seconds / 3).max(1) as u64)).await;
+                c.db_client.renew_job_leases(&c.worker_id, c.lease_seconds).await
+                    .map_err(|e| error!("Failed to renew job leases for worker {}: {}", c.worker_id, e)).ok();
+            }
+        })
+    }
+    // Release jobs a previous run of this worker left behind, without waiting for their leases to expire
+    pub async fn release_job_leases(&self) -> anyhow::Result<u64> {
+        let released = self.db_client.release_job_leases(&self.worker_id).await?;
+        if released > 0 {
+            info!("Released {} jobs left over by a previous run of worker {}", released, self.worker_id);
+        }
+        Ok(released)
+    }
+    pub fn get_upstream(&self, board: &Board) -> anyhow::Result<Arc<dyn UpstreamSource>> {
+        self.upstreams.get(&board.upstream)
+            .ok_or(anyhow::anyhow!("Upstream '{}' for board /{}/ is not configured", board.upstream, board.name))
     }
-    pub async fn get_boards_set(&self) -> anyhow::Result<HashSet<String>> {
-        let boardslist = self.get_all_boards_api().await.map_err(|_| anyhow::anyhow!(""))?;
+    // Returns None if the upstream doesn't publish a list of boards
+    pub async fn get_all_boards_api(&self, source: &dyn UpstreamSource) -> anyhow::Result<Option<BoardsList>> {
+        let url = match source.boards_url() {
+            Some(url) => url,
+            None => return Ok(None)
+        };
+        let boardslist = self.http_client.fetch_json::<BoardsList>(&url).await
+            .map_err(|_| anyhow::anyhow!("Failed to fetch list of boards from {}", source.name()))?;
+        Ok(Some(boardslist))
+    }
+    pub async fn get_boards_set(&self, source: &dyn UpstreamSource) -> anyhow::Result<Option<HashSet<String>>> {
+        let boardslist = match self.get_all_boards_api(source).await? {
+            Some(b) => b,
+            None => return Ok(None)
+        };
         let mut name_set = HashSet::new();
         for board in boardslist.boards {
             name_set.insert(board.board);
         }
-        Ok(name_set)
+        Ok(Some(name_set))
     }
-    pub async fn set_board(&self, board: Board) -> anyhow::Result<Option<Board>> {
-        if !self.get_boards_set().await?.contains(&board.name) {
-            error!("Board /{}/ does not exist, skipping", board.name);
-            return Ok(None)
-        }
-        // If full_images is being set to true, check if the board is already in the database
-        if board.full_images {
-            if let Some(b) = self.db_client.get_board(&board.name).await? {
-                if !b.full_images {
-                    // The board is being enabled for full images, but it's already in the database with full_images = false
-                    let result = self.db_client.insert_board(&board).await?;
-                    // We need to make sure existing posts have their full images downloaded
-                    let jobs_scheduled = self.db_client.schedule_missing_full_files(&board.name).await?;
-                    if jobs_scheduled > 0 {
-                        info!("Scheduled {} missing full images for board /{}/", jobs_scheduled, board.name);
-                    }
-                    return Ok(Some(result))
-                }
+    /**
+     * Saves a board's settings. Returns None if the board doesn't exist upstream.
+     * Rebuilding the search index of a large board takes a while, so it's done by the returned task rather than holding up the caller.
+     */
+    pub async fn set_board(&self, board: Board) -> anyhow::Result<Option<(Board, tokio::task::JoinHandle<()>)>> {
+        let source = self.get_upstream(&board)?;
+        if let Some(boards_set) = self.get_boards_set(source.as_ref()).await? {
+            if !boards_set.contains(&board.name) {
+                error!("Board /{}/ does not exist on {}, skipping", board.name, source.name());
+                return Ok(None)
+            }
+        }
+        let previous = self.db_client.get_board(&board.name).await?;
+        let result = self.db_client.insert_board(&board).await?;
+        // The board is being enabled for full images, but it's already in the database with full_images = false
+        if board.full_images && previous.as_ref().is_some_and(|b| !b.full_images) {
+            // We need to make sure existing posts have their full images downloaded
+            let jobs_scheduled = self.db_client.schedule_missing_full_files(source.as_ref(), &board.name).await?;
+            if jobs_scheduled > 0 {
+                info!("Scheduled {} missing full images for board /{}/", jobs_scheduled, board.name);
             }
         }
-        Ok(Some(self.db_client.insert_board(&board).await?))
+        let (archiver, updated) = (self.clone(), result.clone());
+        let search_update = tokio::task::spawn(async move {
+            archiver.update_board_search(previous.as_ref(), &updated).await
+        });
+        Ok(Some((result, search_update)))
+    }
+    // Boards that posts are imported into are created with archiving disabled
+    pub(crate) async fn get_or_create_board(&self, board_name: &str) -> anyhow::Result<Board> {
+        if let Some(board) = self.db_client.get_board(&board_name.to_string()).await? {
+            return Ok(board)
+        }
+        info!("Creating board /{}/ with archiving disabled", board_name);
+        self.db_client.insert_board(&Board {
+            name: board_name.to_string(),
+            ..Default::default()
+        }).await
     }
     pub async fn stop_board(&self, board_name: &String) -> anyhow::Result<Option<Board>> {
         if let Some(mut board) = self.db_client.get_board(board_name).await? {
@@ -128,7 +207,7 @@ impl Archiver {
                 info!("Skipping file {}{} which is not orphaned", file.sha256, file.file_ext);
                 continue;
             }
-            if self.http_client.delete_downloaded_file(&file.sha256, &file.file_ext, file.is_thumbnail).await.is_ok() {
+            if self.delete_stored_file(&file.sha256, &file.file_ext, file.is_thumbnail).await.is_ok() {
                 self.db_client.delete_file(&file.sha256).await?;
                 if file.is_thumbnail {
                     report.thumbnails_deleted += 1;
@@ -154,7 +233,7 @@ impl Archiver {
         hide_post: Option<bool>,
         hide_comment: Option<bool>,
         hide_image: Option<bool>,
-        log_id: i64,
+        log_id: Option<i64>,
     ) -> anyhow::Result<()> {
         let post_opt = self.db_client
             .get_post(board_name, no, false).await?;
@@ -197,8 +276,11 @@ impl Archiver {
                 set_hide_comment,
                 set_hide_image
             ).await?;
-        for (action, is_file) in actions {
-            self.db_client.register_mod_action(log_id, no, board_name, is_file, action).await?;
+        // Actions replicated from another instance are logged there
+        if let Some(log_id) = log_id {
+            for (action, is_file) in actions {
+                self.db_client.register_mod_action(log_id, no, board_name, is_file, action).await?;
+            }
         }
         Ok(())
     }
@@ -207,7 +289,7 @@ impl Archiver {
         &self,
         board_name: &String,
         no: i64,
-        log_id: i64
+        log_id: Option<i64>
     ) -> anyhow::Result<()> {
         self.hide_post(
             board_name,
@@ -224,18 +306,14 @@ impl Archiver {
         &self,
         board_name: &String,
         no: i64,
-        log_id: i64
+        log_id: Option<i64>
     ) -> anyhow::Result<Vec<String>> {
         let mut purged_files = Vec::new();
         let post = self.db_client.get_post(board_name, no, false).await?;
         if let Some(post) = post {
-            if let Some(thumbnail_sha256) = &post.thumbnail_sha256 {
-                self.http_client.delete_downloaded_file(thumbnail_sha256, &".jpg".to_string(), true).await?;
-                purged_files.push(thumbnail_sha256.clone());
-            }
-            if let Some(file_sha256) = &post.file_sha256 {
-                self.http_client.delete_downloaded_file(file_sha256, &post.ext, false).await?;
-                purged_files.push(file_sha256.clone());
+            for (sha256, ext, is_thumb) in post_files(&post) {
+                self.delete_stored_file(&sha256, &ext, is_thumb).await?;
+                purged_files.push(sha256);
             }
         } else {
             warn!("Post /{}/{} not found.", board_name, no);
@@ -243,14 +321,17 @@ impl Archiver {
         if purged_files.is_empty() {
             return Ok(purged_files);
         }
-        let action_id = self
-            .db_client.register_mod_action(
-                log_id, 
-                no, 
-                board_name, 
-                true, 
-                ModActionType::BlacklistImage
-            ).await?;
+        let action_id = match log_id {
+            Some(log_id) => Some(self
+                .db_client.register_mod_action(
+                    log_id, 
+                    no, 
+                    board_name, 
+                    true, 
+                    ModActionType::BlacklistImage
+                ).await?),
+            None => None
+        };
         for sha256 in &purged_files {
             self.db_client
                 .blacklist_file(&sha256, action_id).await?;
@@ -262,18 +343,14 @@ impl Archiver {
         &self,
         board_name: &String,
         no: i64,
-        log_id: i64
+        log_id: Option<i64>
     ) -> anyhow::Result<Vec<String>> {
         let mut purged_files = Vec::new();
         let post = self.db_client.get_post(board_name, no, false).await?;
         if let Some(post) = post {
-            if let Some(thumbnail_sha256) = &post.thumbnail_sha256 {
-                self.db_client.remove_file_blacklist(thumbnail_sha256).await?;
-                purged_files.push(thumbnail_sha256.clone());
-            }
-            if let Some(file_sha256) = &post.file_sha256 {
-                self.db_client.remove_file_blacklist(file_sha256).await?;
-                purged_files.push(file_sha256.clone());
+            for (sha256, _, _) in post_files(&post) {
+                self.db_client.remove_file_blacklist(&sha256).await?;
+                purged_files.push(sha256);
             }
         } else {
             warn!("Post /{}/{} not found.", board_name, no);
@@ -281,14 +358,16 @@ impl Archiver {
         if purged_files.is_empty() {
             return Ok(purged_files);
         }
-        let _action_id = self
-        .db_client.register_mod_action(
-            log_id, 
-            no, 
-            board_name, 
-            true, 
-            ModActionType::UndoBlacklistImage
-        ).await?;
+        if let Some(log_id) = log_id {
+            self
+            .db_client.register_mod_action(
+                log_id, 
+                no, 
+                board_name, 
+                true, 
+                ModActionType::UndoBlacklistImage
+            ).await?;
+        }
         Ok(purged_files)
     }
 
@@ -310,6 +389,11 @@ impl Archiver {
         Ok(())
     }
 
+    pub async fn add_thread_filter(&self, filter: &ThreadFilter) -> anyhow::Result<ThreadFilter> {
+        thread_filter::validate_filter(filter)?;
+        self.db_client.insert_thread_filter(filter).await
+    }
+
     pub async fn delete_user(&self, username: &String) -> anyhow::Result<()> {
         self.db_client.delete_user(username).await?;
         Ok(())
@@ -361,5 +445,115 @@ impl Archiver {
     }
 }
 
+// Every file and thumbnail of a post, including its extra attachments, as (sha256, ext, is_thumb)
+fn post_files(post: &Post) -> Vec<(String, String, bool)> {
+    let mut files = Vec::new();
+    let mut add = |file: &Option<String>, ext: &str, thumbnail: &Option<String>, thumbnail_ext: &Option<String>| {
+        if let Some(sha256) = thumbnail.as_ref().filter(|s| !s.is_empty()) {
+            files.push((sha256.clone(), thumbnail_ext.clone().unwrap_or(".jpg".to_string()), true));
+        }
+        if let Some(sha256) = file.as_ref().filter(|s| !s.is_empty()) {
+            files.push((sha256.clone(), ext.to_string(), false));
+        }
+    };
+    add(&post.file_sha256, &post.ext, &post.thumbnail_sha256, &post.thumbnail_ext);
+    for file in &post.extra_files.0 {
+        add(&file.file_sha256, &file.ext, &file.thumbnail_sha256, &file.thumbnail_ext);
+    }
+    files
+}
+
 impl std::panic::UnwindSafe for Archiver {}
-impl std::panic::RefUnwindSafe for Archiver {}
\ No newline at end of file
+impl std::panic::RefUnwindSafe for Archiver {}
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use nonzero_ext::nonzero;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use crate::file_store::MemoryStore;
+    use crate::http::tests::mock_server;
+    use crate::models::{CatalogPage, CatalogThread, ExtraFiles, PostFile, Thread, ThreadJob};
+    use crate::upstream::FourChanSource;
+    use crate::util::get_file_key;
+
+    // An archiver that keeps its files in memory, for tests that need the database
+    pub(crate) async fn memory_archiver() -> Archiver {
+        let http_client = HttpClient::new(nonzero!(600u32), nonzero!(60u32), 0, 1, 10);
+        Archiver { file_store: Arc::new(MemoryStore::new()), ..Archiver::new(http_client).await }
+    }
+
+    #[tokio::test]
+    async fn test_ban_image_extra_files() {
+        let archiver = memory_archiver().await;
+        let board = "testbanimage".to_string();
+        archiver.get_or_create_board(&board).await.unwrap();
+        let extra_files = ExtraFiles(vec![PostFile { tim: "1700000000000-1".to_string(), ext: ".png".to_string(), ..Default::default() }]);
+        archiver.db_client.insert_posts(&vec![Post { board: board.clone(), no: 1, time: 1700000000, extra_files, ..Default::default() }]).await.unwrap();
+        let mut hashes = Vec::new();
+        for (idx, content, ext) in [(0, &b"first"[..], ".jpg"), (1, &b"second"[..], ".png")] {
+            let sha256 = archiver.store_file(bytes::Bytes::from_static(content), ext, false).await.unwrap();
+            archiver.db_client.add_post_file(&board, 1, idx, &sha256, &ext.to_string(), false).await.unwrap();
+            hashes.push(sha256);
+        }
+
+        let purged = archiver.ban_image(&board, 1, None).await.unwrap();
+        assert_eq!(hashes, purged);
+        for sha256 in &hashes {
+            assert!(archiver.db_client.is_file_blacklisted(sha256).await.unwrap());
+        }
+        assert_eq!(hashes, archiver.unban_image(&board, 1, None).await.unwrap());
+        for sha256 in &hashes {
+            assert!(!archiver.db_client.is_file_blacklisted(sha256).await.unwrap());
+        }
+        archiver.db_client.purge_board_data(&board).await.unwrap();
+    }
+
+    // Runs a thread from the catalog through the thread and file jobs, like the archiver cycles do
+    #[tokio::test]
+    async fn test_archive_board() {
+        let board = "testendtoend".to_string();
+        let thumbnail = b"upstream thumbnail".to_vec();
+        let file = b"full file".to_vec();
+        let op = Post { no: 100, time: 1700000000, last_modified: 1700000010, replies: 1, tim: 1700000000100, ext: ".png".to_string(), ..Default::default() };
+        let reply = Post { no: 101, resto: 100, time: 1700000010, ..Default::default() };
+        let catalog = vec![CatalogPage { page: 1, threads: vec![CatalogThread { op: op.clone(), last_replies: vec![] }] }];
+        let thread = Thread { posts: vec![op.clone(), reply] };
+        let routes = Arc::new(Mutex::new(HashMap::from([
+            (format!("/{}/catalog.json", board), serde_json::to_vec(&catalog).unwrap()),
+            (format!("/{}/thread/100.json", board), serde_json::to_vec(&thread).unwrap()),
+            (format!("/{}/1700000000100s.jpg", board), thumbnail.clone()),
+            (format!("/{}/1700000000100.png", board), file.clone()),
+        ])));
+        let url = mock_server(routes).await;
+        let source = FourChanSource { name: "testendtoend".to_string(), api_url: url.clone(), media_url: url };
+        let base = memory_archiver().await;
+        let archiver = Archiver { upstreams: base.upstreams.clone().with_source(Arc::new(source)), ..base };
+        let board = Board { name: board, upstream: "testendtoend".to_string(), full_images: true, archive: true, ..Default::default() };
+        archiver.db_client.insert_board(&board).await.unwrap();
+
+        // Job tables are shared with other tests, so this board's jobs are looked up directly rather than claimed
+        let source = archiver.get_upstream(&board).unwrap();
+        assert_eq!(1, archiver.push_new_threads(source.as_ref(), &board).await.unwrap());
+        let (id, no, last_modified, replies, page): (i64, i64, i64, i64, i32) = sqlx::query_as("SELECT id, no, last_modified, replies, page FROM thread_backlog WHERE board = $1")
+            .bind(&board.name).fetch_one(&archiver.db_client.pool).await.unwrap();
+        archiver.archive_thread(ThreadJob { id, board: board.name.clone(), no, last_modified, replies, page, ..Default::default() }).await.unwrap();
+
+        let image_jobs: Vec<i64> = sqlx::query_scalar("SELECT id FROM image_backlog WHERE board = $1")
+            .bind(&board.name).fetch_all(&archiver.db_client.pool).await.unwrap();
+        assert_eq!(1, image_jobs.len());
+        let job = archiver.db_client.get_image_job(image_jobs[0]).await.unwrap().unwrap();
+        archiver.archive_image(&job).await.unwrap();
+        assert_eq!(None, archiver.db_client.get_image_job(job.id).await.unwrap());
+
+        let stored = archiver.db_client.get_thread(&board.name, 100, false).await.unwrap().unwrap();
+        assert_eq!(vec![100, 101], stored.posts.iter().map(|p| p.no).collect::<Vec<_>>());
+        let (file_sha256, thumbnail_sha256) = (stored.posts[0].file_sha256.clone().unwrap(), stored.posts[0].thumbnail_sha256.clone().unwrap());
+        assert_eq!(Some(bytes::Bytes::from(file)), archiver.file_store.get(&get_file_key(&file_sha256, ".png", false)).await.unwrap());
+        assert_eq!(Some(bytes::Bytes::from(thumbnail)), archiver.file_store.get(&get_file_key(&thumbnail_sha256, ".jpg", true)).await.unwrap());
+
+        archiver.db_client.purge_board_data(&board.name).await.unwrap();
+        archiver.db_client.delete_file(&file_sha256).await.unwrap();
+        archiver.db_client.delete_file(&thumbnail_sha256).await.unwrap();
+    }
+}
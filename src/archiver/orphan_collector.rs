@@ -0,0 +1,126 @@
+use std::time::{Duration, SystemTime};
+use futures::stream::{self, StreamExt};
+
+#[allow(unused_imports)]
+use log::{info, warn, error, debug};
+#[allow(unused_imports)]
+use metrics::{gauge, counter, histogram};
+
+use crate::models::{OrphanReport, StoredFile};
+use crate::util::get_file_key;
+use crate::archiver::Archiver;
+
+impl Archiver {
+    /**
+     * Deletes files that no post has used for at least `grace_seconds`, from the database and the file store.
+     * Files only start their grace period once a run of this function sees them unused,
+     * so a file that was just downloaded is never deleted. A file that a post starts using again
+     * while it's being deleted is scheduled for download again.
+     * With `dry_run`, nothing is changed and the report lists the files that would be deleted.
+     */
+    pub async fn collect_orphaned_files(&self, grace_seconds: i64, dry_run: bool) -> anyhow::Result<OrphanReport> {
+        let mut report = OrphanReport::default();
+        if !dry_run {
+            let (marked, unmarked) = self.db_client.mark_orphaned_files().await?;
+            debug!("{} files became orphaned, {} are used again", marked, unmarked);
+        }
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+        let cutoff = now - grace_seconds;
+        report.waiting = self.db_client.count_orphaned_files_after(cutoff).await?.max(0) as u64;
+
+        let mut last_id = 0;
+        loop {
+            let files = self.db_client.get_orphaned_files_before(cutoff, last_id, 1000).await?;
+            let Some(last) = files.last() else {
+                break
+            };
+            last_id = last.file_id;
+
+            let results: Vec<(StoredFile, anyhow::Result<Option<u64>>)> = stream::iter(files)
+                .map(|file| async move {
+                    let result = self.delete_orphaned_file(&file, dry_run).await;
+                    (file, result)
+                })
+                .buffer_unordered(16)
+                .collect().await;
+
+            for (file, result) in results {
+                match result {
+                    Ok(Some(size)) => {
+                        report.deleted += 1;
+                        report.bytes_reclaimed += size;
+                        if dry_run {
+                            report.files.push(get_file_key(&file.sha256, &file.file_ext, file.is_thumbnail));
+                        }
+                    },
+                    // A post started using it again
+                    Ok(None) => continue,
+                    Err(e) => {
+                        error!("Failed to delete orphaned file {}{}: {}", file.sha256, file.file_ext, e);
+                        report.failed += 1;
+                    }
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    // Returns the size of the deleted file, None if it wasn't orphaned anymore
+    async fn delete_orphaned_file(&self, file: &StoredFile, dry_run: bool) -> anyhow::Result<Option<u64>> {
+        let key = get_file_key(&file.sha256, &file.file_ext, file.is_thumbnail);
+        let size = self.file_store.stat(&key).await?.map(|stat| stat.size).unwrap_or(0);
+        if dry_run {
+            return Ok(Some(size))
+        }
+        // The database is checked first, the foreign keys on posts_files keep a file from being deleted while in use
+        if !self.db_client.delete_orphaned_file(file.file_id).await? {
+            return Ok(None)
+        }
+        self.file_store.delete(&key).await
+            .map_err(|e| anyhow::anyhow!("{} was removed from the database but is left on {} storage: {}", key, self.file_store.name(), e))?;
+        debug!("Deleted orphaned file (thumbnail: {}) {}", file.is_thumbnail, key);
+        // A post can start using the file again between the two deletions, finding it still in storage.
+        // Then it's gone for that post, so it's downloaded again.
+        if let Some(stored) = self.db_client.get_stored_file(&file.sha256).await? {
+            warn!("Orphaned file {} was used again while it was deleted, requeuing it", key);
+            self.requeue_stored_file(&stored).await?;
+        }
+        Ok(Some(size))
+    }
+
+    /**
+     * Runs `collect_orphaned_files` every ORPHAN_GC_INTERVAL_MINUTES (default 60, 0 disables it),
+     * deleting files that have been orphaned for more than ORPHAN_GC_GRACE_HOURS (default 24).
+     */
+    pub fn run_orphan_gc_cycle(&self) -> Option<tokio::task::JoinHandle<()>> {
+        let interval_minutes: u64 = std::env::var("ORPHAN_GC_INTERVAL_MINUTES").ok()
+            .and_then(|s| s.parse().ok()).unwrap_or(60);
+        if interval_minutes == 0 {
+            return None
+        }
+        let grace_hours = orphan_grace_hours_from_env();
+        let c = self.clone();
+        Some(tokio::task::spawn(async move {
+            loop {
+                match c.collect_orphaned_files(grace_hours * 3600, false).await {
+                    Ok(report) => {
+                        if report.deleted > 0 || report.failed > 0 {
+                            info!("Deleted {} orphaned files ({} bytes), {} failed, {} waiting for their grace period",
+                                report.deleted, report.bytes_reclaimed, report.failed, report.waiting);
+                        }
+                        counter!("orphan_files_deleted", report.deleted);
+                        counter!("orphan_bytes_reclaimed", report.bytes_reclaimed);
+                        gauge!("orphan_files_waiting", report.waiting as f64);
+                    },
+                    Err(e) => error!("Orphaned file collection failed: {}", e)
+                }
+                tokio::time::sleep(Duration::from_secs(interval_minutes * 60)).await;
+            }
+        }))
+    }
+}
+
+pub fn orphan_grace_hours_from_env() -> i64 {
+    std::env::var("ORPHAN_GC_GRACE_HOURS").ok()
+        .and_then(|s| s.parse().ok()).unwrap_or(24)
+}
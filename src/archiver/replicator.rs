@@ -0,0 +1,260 @@
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::time::Duration;
+
+#[allow(unused_imports)]
+use log::{info, warn, error, debug};
+#[allow(unused_imports)]
+use metrics::{gauge, counter, histogram};
+
+use futures::future::join_all;
+
+use crate::archiver::Archiver;
+use crate::archiver::board_exporter::{attachments, Attachment};
+use crate::http::FetchError;
+use crate::models::{Board, ChangeFeed, FeedCursor, ModActionType, Post, ReplicationReport};
+use crate::upstream::UpstreamSource;
+use crate::util::hash_file;
+
+// Pages of the feed read per poll, so a board catching up doesn't hold up the others
+const FEED_PAGES_PER_POLL: usize = 10;
+// Posts added to the feed at a time by the backfill that follows the upgrade
+const FEED_BACKFILL_BATCH: i64 = 10000;
+
+impl Archiver {
+    /**
+     * Adds the posts archived before the replication feed existed to it, in batches, then stops.
+     * They come after the posts archived since, in the order they were first archived.
+     */
+    pub fn run_feed_backfill(&self) -> tokio::task::JoinHandle<()> {
+        let c = self.clone();
+        tokio::task::spawn(async move {
+            let mut batches = 0;
+            loop {
+                match c.db_client.backfill_post_changes(FEED_BACKFILL_BATCH).await {
+                    Ok(true) => batches += 1,
+                    Ok(false) => break,
+                    Err(e) => {
+                        error!("Failed to add archived posts to the replication feed: {}", e);
+                        tokio::time::sleep(Duration::from_secs(60)).await;
+                    }
+                }
+            }
+            if batches > 0 {
+                info!("Added the posts archived before the replication feed to it");
+            }
+        })
+    }
+
+    /**
+     * Replicates a board from another Mitsuba instance through its change feed, starting where the last poll stopped.
+     * Posts are written like posts fetched from 4chan, and their files are downloaded from the other instance by hash.
+     * Its moderation actions are applied here too, without being logged again.
+     */
+    pub async fn replicate_board(&self, source: &dyn UpstreamSource, board: &Board) -> Result<ReplicationReport, FetchError> {
+        let mut report = ReplicationReport::default();
+        let Some(feed_url) = source.feed_url(&board.name) else {
+            return Ok(report)
+        };
+        let mut cursor = self.db_client.get_replication_cursor(&board.name, source.name()).await
+            .map_err(|e| {error!("Error getting replication cursor of /{}/ from database: {}", board.name, e); FetchError::from(e)})?
+            .unwrap_or(FeedCursor::default().to_string());
+        for _ in 0..FEED_PAGES_PER_POLL {
+            self.http_client.until_board_ready(&board.name, board.rate_limit_share).await;
+            let feed = self.http_client.fetch_json::<ChangeFeed>(&format!("{}?cursor={}", feed_url, cursor)).await?;
+            self.apply_feed(source, board, &feed, &mut report).await?;
+            // Only saved once the page is applied, so an interrupted poll reads it again
+            self.db_client.set_replication_cursor(&board.name, source.name(), &feed.cursor).await
+                .map_err(|e| {error!("Error saving replication cursor of /{}/: {}", board.name, e); FetchError::from(e)})?;
+            cursor = feed.cursor;
+            if !feed.more {
+                break
+            }
+        }
+        counter!("posts_replicated", report.posts);
+        counter!("files_replicated", report.files);
+        Ok(report)
+    }
+
+    pub(crate) async fn apply_feed(&self, source: &dyn UpstreamSource, board: &Board, feed: &ChangeFeed, report: &mut ReplicationReport) -> Result<(), FetchError> {
+        let posts: Vec<Post> = feed.posts.iter().cloned()
+            .map(|mut post| {post.board = board.name.clone(); post}).collect();
+        // Posts that are the same as the ones we have are skipped by their hash
+        let inserted = self.db_client.insert_posts(&posts).await
+            .map_err(|e| {error!("Failed to insert replicated posts of /{}/ into database: {}", board.name, e); FetchError::from(e)})?;
+        report.posts += inserted.len() as u64;
+        self.replicate_files(source, board, &posts, report).await?;
+
+        for no in &feed.deleted {
+            report.deleted += self.db_client.delete_post(&board.name, *no).await
+                .map_err(|e| {error!("Failed to delete replicated post /{}/{}: {}", board.name, no, e); FetchError::from(e)})?;
+        }
+
+        for action in &feed.actions {
+            let Ok(action_type) = ModActionType::from_str(&action.action) else {
+                debug!("Unknown moderation action {} on /{}/{}, skipping", action.action, board.name, action.no);
+                continue
+            };
+            let (hide_post, hide_comment, hide_image) = match action_type {
+                ModActionType::HidePost => (Some(true), None, None),
+                ModActionType::HidePostContent => (None, Some(true), None),
+                ModActionType::HidePostFile => (None, None, Some(true)),
+                ModActionType::UnhidePost => (Some(false), None, None),
+                ModActionType::UnhidePostContent => (None, Some(false), None),
+                ModActionType::UnhidePostFile => (None, None, Some(false)),
+                ModActionType::BlacklistImage => {
+                    self.ban_image(&board.name, action.no, None).await?;
+                    report.actions += 1;
+                    continue
+                },
+                ModActionType::UndoBlacklistImage => {
+                    self.unban_image(&board.name, action.no, None).await?;
+                    report.actions += 1;
+                    continue
+                }
+            };
+            // Posts hidden before they were replicated were never sent
+            if self.db_client.get_post(&board.name, action.no, false).await?.is_none() {
+                continue
+            }
+            self.hide_post(&board.name, action.no, hide_post, hide_comment, hide_image, None).await?;
+            report.actions += 1;
+        }
+        Ok(())
+    }
+
+    /**
+     * Links the files of replicated posts, downloading the ones we don't have from the source.
+     * File hashes are not part of the post hash, so new files are looked for on every post in the feed.
+     */
+    async fn replicate_files(&self, source: &dyn UpstreamSource, board: &Board, posts: &[Post], report: &mut ReplicationReport) -> Result<(), FetchError> {
+        let nos: Vec<i64> = posts.iter().map(|post| post.no).collect();
+        let linked: HashSet<(i64, i32, String)> = self.db_client.get_posts_by_nos(&board.name, &nos).await?.iter()
+            .flat_map(|stored| attachments(stored).into_iter().map(|a| (stored.no, a.idx, a.key())))
+            .collect();
+        let wanted: Vec<(i64, Attachment)> = posts.iter()
+            .flat_map(|post| attachments(post).into_iter().map(|attachment| (post.no, attachment)))
+            .filter(|(_, attachment)| attachment.is_thumb || board.full_images)
+            .filter(|(no, attachment)| !linked.contains(&(*no, attachment.idx, attachment.key())))
+            .collect();
+        if wanted.is_empty() {
+            return Ok(())
+        }
+        // Each file is looked for once, however many posts use it, a few at a time
+        let files: HashMap<String, &Attachment> = wanted.iter().map(|(_, attachment)| (attachment.key(), attachment)).collect();
+        let files: Vec<(String, &Attachment)> = files.into_iter().collect();
+        let mut available = HashSet::new();
+        for chunk in files.chunks(8) {
+            let mut lookups = Vec::new();
+            for (_, attachment) in chunk {
+                lookups.push(self.replicate_file(source, board, attachment));
+            }
+            for ((key, _), result) in chunk.iter().zip(join_all(lookups).await) {
+                match result? {
+                    ReplicatedFile::Stored => {},
+                    ReplicatedFile::Downloaded => report.files += 1,
+                    ReplicatedFile::Unavailable => continue
+                }
+                available.insert(key.clone());
+            }
+        }
+        for (no, attachment) in wanted {
+            if !available.contains(&attachment.key()) {
+                continue
+            }
+            self.db_client.add_post_file(&board.name, no, attachment.idx, &attachment.sha256, &attachment.ext, attachment.is_thumb).await?;
+            self.handle_blacklist(&board.name, no, &attachment.sha256, &attachment.ext, attachment.is_thumb).await?;
+        }
+        Ok(())
+    }
+
+    async fn replicate_file(&self, source: &dyn UpstreamSource, board: &Board, attachment: &Attachment) -> Result<ReplicatedFile, FetchError> {
+        let key = attachment.key();
+        if self.file_store.exists(&key).await? {
+            return Ok(ReplicatedFile::Stored)
+        }
+        let Some(url) = source.stored_file_url(&key) else {
+            return Ok(ReplicatedFile::Unavailable)
+        };
+        let bytes = match self.http_client.download_file(&url, attachment.is_thumb).await {
+            Ok(bytes) => bytes,
+            Err(FetchError::NotFound) => {
+                warn!("File {} of /{}/ is missing on {}, skipping", key, board.name, source.name());
+                return Ok(ReplicatedFile::Unavailable)
+            },
+            Err(e) => return Err(e)
+        };
+        if hash_file(&bytes) != attachment.sha256 {
+            warn!("File {} of /{}/ from {} does not match its hash, skipping", key, board.name, source.name());
+            return Ok(ReplicatedFile::Unavailable)
+        }
+        self.file_store.put(&key, bytes.clone()).await
+            .map_err(|e| {error!("Could not save file {} to {} storage: {}", key, self.file_store.name(), e); FetchError::Storage(e.to_string())})?;
+        if attachment.is_thumb {
+            self.save_perceptual_hash(&attachment.sha256, bytes).await?;
+        }
+        Ok(ReplicatedFile::Downloaded)
+    }
+}
+
+enum ReplicatedFile {
+    // Already in our storage
+    Stored,
+    Downloaded,
+    // Missing or corrupted on the source
+    Unavailable,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::archiver::tests::memory_archiver;
+    use crate::http::tests::mock_server;
+    use crate::models::FeedAction;
+    use crate::upstream::MitsubaSource;
+    use crate::util::get_file_key;
+
+    #[tokio::test]
+    async fn test_replicate_board() {
+        let archiver = memory_archiver().await;
+        let board = archiver.get_or_create_board("testreplica").await.unwrap();
+        // A post that was deleted on the source before we got the tombstone
+        archiver.db_client.insert_posts(&vec![Post { board: board.name.clone(), no: 3, resto: 1, time: 1700000003, ..Default::default() }]).await.unwrap();
+
+        let thumbnail = b"replicated thumbnail".to_vec();
+        let sha256 = hash_file(&thumbnail);
+        let key = get_file_key(&sha256, ".jpg", true);
+        let feed = ChangeFeed {
+            posts: vec![
+                Post { board: "source".to_string(), no: 1, time: 1700000001, tim: 1700000001000, ext: ".png".to_string(),
+                    thumbnail_sha256: Some(sha256.clone()), thumbnail_ext: Some(".jpg".to_string()), ..Default::default() },
+                Post { board: "source".to_string(), no: 2, resto: 1, time: 1700000002, ..Default::default() },
+            ],
+            deleted: vec![3],
+            actions: vec![FeedAction { no: 2, action: "hide_post".to_string() }],
+            cursor: "1-1".to_string(),
+            more: false
+        };
+        let routes = Arc::new(Mutex::new(HashMap::from([
+            (format!("/{}/feed.json", board.name), serde_json::to_vec(&feed).unwrap()),
+            (format!("/img/{}", key), thumbnail.clone()),
+        ])));
+        let source = MitsubaSource { name: "testsource".to_string(), base_url: mock_server(routes).await };
+
+        let report = archiver.replicate_board(&source, &board).await.unwrap();
+        assert_eq!(ReplicationReport { posts: 2, deleted: 1, files: 1, actions: 1 }, report);
+        assert_eq!(Some(bytes::Bytes::from(thumbnail)), archiver.file_store.get(&key).await.unwrap());
+        let thread = archiver.db_client.get_thread(&board.name, 1, true).await.unwrap().unwrap();
+        assert_eq!(vec![1], thread.posts.iter().map(|p| p.no).collect::<Vec<_>>());
+        assert_eq!(Some(sha256), thread.posts[0].thumbnail_sha256);
+        assert_eq!(Some("1-1".to_string()), archiver.db_client.get_replication_cursor(&board.name, "testsource").await.unwrap());
+
+        // Reading the same changes again changes nothing
+        let report = archiver.replicate_board(&source, &board).await.unwrap();
+        assert_eq!(ReplicationReport { posts: 0, deleted: 0, files: 0, actions: 1 }, report);
+
+        sqlx::query("DELETE FROM replication_cursors WHERE board = $1").bind(&board.name).execute(&archiver.db_client.pool).await.unwrap();
+        archiver.db_client.purge_board_data(&board.name).await.unwrap();
+    }
+}
@@ -0,0 +1,68 @@
+#[allow(unused_imports)]
+use log::{info, warn, error, debug};
+
+use crate::models::{Board, ReindexReport};
+use crate::archiver::Archiver;
+
+impl Archiver {
+    /**
+     * Rebuilds the search index of `board`, or of every board with search enabled.
+     * External indexes are emptied and fed every post again, postgres rebuilds its full text indexes.
+     */
+    pub async fn reindex_search(&self, board: Option<&str>) -> anyhow::Result<ReindexReport> {
+        let mut report = ReindexReport::default();
+        let search_index = self.db_client.search_index.clone();
+        let boards: Vec<Board> = self.db_client.get_all_boards().await?.into_iter()
+            .filter(|b| b.enable_search && board.is_none_or(|name| name == b.name))
+            .collect();
+        if let Some(name) = board {
+            if boards.is_empty() {
+                anyhow::bail!("Search is not enabled for /{}/", name);
+            }
+        }
+        for board in boards {
+            report.boards += 1;
+            if !search_index.needs_posts() {
+                info!("Rebuilding {} search indexes for /{}/", search_index.name(), board.name);
+                self.db_client.reindex_postgres_search(&board.name).await?;
+                continue
+            }
+            search_index.remove_board(&board.name).await?;
+            let mut last_id = 0;
+            loop {
+                let posts = self.db_client.get_board_posts_after(&board.name, last_id, 5000).await?;
+                let Some(last) = posts.last() else {
+                    break
+                };
+                last_id = last.post_id;
+                search_index.index_posts(&board, &posts).await?;
+                report.posts += posts.len() as u64;
+                info!("Indexed {} posts of /{}/ in {}", report.posts, board.name, search_index.name());
+            }
+        }
+        Ok(report)
+    }
+
+    /**
+     * Updates an external search index after a board's search settings changed.
+     * Postgres indexes are handled by the update_search_index() trigger.
+     */
+    pub(crate) async fn update_board_search(&self, previous: Option<&Board>, board: &Board) {
+        let search_index = &self.db_client.search_index;
+        if !search_index.needs_posts() {
+            return
+        }
+        let was_enabled = previous.is_some_and(|b| b.enable_search);
+        let language_changed = previous.is_some_and(|b| b.search_language != board.search_language);
+        let result = if board.enable_search && (!was_enabled || language_changed) {
+            self.reindex_search(Some(&board.name)).await.map(|_| ())
+        } else if !board.enable_search && was_enabled {
+            search_index.remove_board(&board.name).await
+        } else {
+            return
+        };
+        if let Err(e) = result {
+            error!("Failed to update the {} search index for /{}/, run `reindex`: {}", search_index.name(), board.name, e);
+        }
+    }
+}
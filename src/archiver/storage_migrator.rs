@@ -0,0 +1,232 @@
+use futures::stream::{self, StreamExt};
+use sha2::{Digest, Sha256};
+
+#[allow(unused_imports)]
+use log::{info, warn, error, debug};
+
+use crate::file_store::FileStore;
+use crate::models::{StorageMigrationReport, StoredFile};
+use crate::util::{hash_file, get_file_key};
+use crate::archiver::Archiver;
+
+enum MigrationStatus {
+    Copied,
+    Skipped,
+    Missing,
+    Corrupted,
+}
+
+impl Archiver {
+    /**
+     * Copies every file in the database from one file store to another.
+     * Files are checked against their sha256 before being copied, and read back from the target afterwards.
+     * With `delete_source`, files are deleted from the source once they are safely in the target.
+     * Progress is saved in the database after every batch, so an interrupted migration resumes where it stopped.
+     * A batch with failures is not saved, so running the migration again retries them.
+     */
+    pub async fn migrate_storage(&self, source: &dyn FileStore, target: &dyn FileStore, delete_source: bool) -> anyhow::Result<StorageMigrationReport> {
+        if source.name() == target.name() {
+            return Err(anyhow::anyhow!("Source and target storage are both {}", source.name()))
+        }
+        let mut report = StorageMigrationReport::default();
+        let mut last_id = self.db_client.get_storage_migration_progress(source.name(), target.name()).await?;
+        if last_id > 0 {
+            info!("Resuming migration from {} to {} after file {}", source.name(), target.name(), last_id);
+        }
+        // Once a batch has failures, later batches can't be marked done either
+        let mut save_progress = true;
+        loop {
+            let files = self.db_client.get_files_after(last_id, 1000).await?;
+            let Some(last) = files.last() else {
+                break
+            };
+            last_id = last.file_id;
+
+            let statuses: Vec<(StoredFile, anyhow::Result<MigrationStatus>)> = stream::iter(files)
+                .map(|file| async move {
+                    let status = migrate_stored_file(&file, source, target).await;
+                    (file, status)
+                })
+                .buffer_unordered(8)
+                .collect().await;
+
+            for (file, status) in statuses {
+                match status {
+                    Ok(MigrationStatus::Copied) => report.copied += 1,
+                    Ok(MigrationStatus::Skipped) => report.skipped += 1,
+                    Ok(MigrationStatus::Missing) => {
+                        warn!("File {}{} (thumbnail: {}) is missing from {} storage", file.sha256, file.file_ext, file.is_thumbnail, source.name());
+                        report.missing += 1;
+                        continue
+                    },
+                    Ok(MigrationStatus::Corrupted) => {
+                        warn!("File {}{} (thumbnail: {}) does not match its hash in {} storage, not copying it", file.sha256, file.file_ext, file.is_thumbnail, source.name());
+                        report.corrupted += 1;
+                        continue
+                    },
+                    Err(e) => {
+                        error!("Failed to migrate file {}{}: {}", file.sha256, file.file_ext, e);
+                        report.failed += 1;
+                        save_progress = false;
+                        continue
+                    }
+                }
+                if delete_source {
+                    let key = get_file_key(&file.sha256, &file.file_ext, file.is_thumbnail);
+                    match source.delete(&key).await {
+                        Ok(_) => report.deleted += 1,
+                        Err(e) => error!("Failed to delete {} from {} storage: {}", key, source.name(), e)
+                    }
+                }
+            }
+            if save_progress {
+                self.db_client.set_storage_migration_progress(source.name(), target.name(), last_id).await?;
+            }
+            info!("Migrated up to file {}: {} copied, {} already present, {} failed",
+                last_id, report.copied, report.skipped, report.failed);
+        }
+        Ok(report)
+    }
+}
+
+/**
+ * The source file is held in memory, since `FileStore::put` takes the whole file. Archived files are a few MB at most
+ * and 8 are copied at once, so that stays small. Files in the target are only read as a stream, to check their hash.
+ */
+async fn migrate_stored_file(file: &StoredFile, source: &dyn FileStore, target: &dyn FileStore) -> anyhow::Result<MigrationStatus> {
+    let key = get_file_key(&file.sha256, &file.file_ext, file.is_thumbnail);
+    let bytes = match source.get(&key).await? {
+        Some(bytes) if hash_file(&bytes) == file.sha256 => bytes,
+        Some(_) => return Ok(MigrationStatus::Corrupted),
+        None => return Ok(MigrationStatus::Missing)
+    };
+    // Left over from an interrupted run. The source may be deleted next, so it has to match its hash, not just its size.
+    if target.stat(&key).await?.is_some_and(|stat| stat.size == bytes.len() as u64) {
+        if stream_sha256(target, &key).await?.as_ref() == Some(&file.sha256) {
+            return Ok(MigrationStatus::Skipped)
+        }
+        warn!("{} does not match its hash in {} storage, copying it again", key, target.name());
+    }
+    target.put(&key, bytes).await?;
+    match stream_sha256(target, &key).await? {
+        Some(sha256) if sha256 == file.sha256 => Ok(MigrationStatus::Copied),
+        Some(_) => Err(anyhow::anyhow!("{} does not match its hash after copying it to {} storage", key, target.name())),
+        None => Err(anyhow::anyhow!("{} is missing after copying it to {} storage", key, target.name()))
+    }
+}
+
+// Same as `hash_file`, without holding the file in memory
+async fn stream_sha256(store: &dyn FileStore, key: &str) -> anyhow::Result<Option<String>> {
+    let Some(mut chunks) = store.stream(key).await? else {
+        return Ok(None)
+    };
+    let mut hasher = Sha256::new();
+    while let Some(chunk) = chunks.next().await {
+        hasher.update(chunk?);
+    }
+    Ok(Some(base32::encode(base32::Alphabet::Rfc4648{padding: false}, hasher.finalize().as_slice())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use async_trait::async_trait;
+    use bytes::Bytes;
+    use crate::archiver::tests::memory_archiver;
+    use crate::file_store::{ByteStream, FileStat, MemoryStore};
+    use crate::models::Post;
+
+    // Counts downloads by key, and has a name of its own so two of them can be migrated between
+    struct CountingStore {
+        name: String,
+        files: MemoryStore,
+        gets: Mutex<HashMap<String, usize>>,
+    }
+
+    impl CountingStore {
+        fn new(name: &str) -> Self {
+            Self { name: name.to_string(), files: MemoryStore::new(), gets: Mutex::new(HashMap::new()) }
+        }
+        fn gets(&self, key: &str) -> usize {
+            self.gets.lock().unwrap().get(key).copied().unwrap_or_default()
+        }
+    }
+
+    #[async_trait]
+    impl FileStore for CountingStore {
+        fn name(&self) -> &str {
+            &self.name
+        }
+        async fn put(&self, key: &str, bytes: Bytes) -> anyhow::Result<()> {
+            self.files.put(key, bytes).await
+        }
+        async fn get(&self, key: &str) -> anyhow::Result<Option<Bytes>> {
+            *self.gets.lock().unwrap().entry(key.to_string()).or_default() += 1;
+            self.files.get(key).await
+        }
+        async fn delete(&self, key: &str) -> anyhow::Result<bool> {
+            self.files.delete(key).await
+        }
+        async fn stat(&self, key: &str) -> anyhow::Result<Option<FileStat>> {
+            self.files.stat(key).await
+        }
+        async fn stream(&self, key: &str) -> anyhow::Result<Option<ByteStream>> {
+            *self.gets.lock().unwrap().entry(key.to_string()).or_default() += 1;
+            self.files.stream(key).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_migrate_storage() {
+        let archiver = memory_archiver().await;
+        let board = "testmigrate".to_string();
+        archiver.get_or_create_board(&board).await.unwrap();
+        let (source, target) = (CountingStore::new("testsource"), CountingStore::new("testtarget"));
+
+        // Copied, already copied by an interrupted run, left over but corrupted with the same size, and missing from the source
+        let mut files = Vec::new();
+        for (no, content) in [(1, &b"copied"[..]), (2, &b"left over"[..]), (3, &b"corrupted"[..]), (4, &b"missing"[..])] {
+            archiver.db_client.insert_posts(&vec![Post { board: board.clone(), no, time: 1700000000, tim: no, ext: ".png".to_string(), ..Default::default() }]).await.unwrap();
+            let sha256 = hash_file(content);
+            archiver.db_client.add_post_file(&board, no, 0, &sha256, &".png".to_string(), false).await.unwrap();
+            let key = get_file_key(&sha256, ".png", false);
+            match no {
+                2 => target.put(&key, Bytes::from_static(content)).await.unwrap(),
+                3 => target.put(&key, Bytes::from_static(b"corrupt3d")).await.unwrap(),
+                _ => {}
+            }
+            if no != 4 {
+                source.put(&key, Bytes::from_static(content)).await.unwrap();
+            }
+            files.push((archiver.db_client.get_stored_file(&sha256).await.unwrap().unwrap(), key));
+        }
+        let (copied, left_over, corrupted, missing) = (&files[0].1, &files[1].1, &files[2].1, &files[3].1);
+        // Other tests' files come after ours too, they are only ever missing from the source
+        let first_id = files.iter().map(|(file, _)| file.file_id).min().unwrap();
+        archiver.db_client.set_storage_migration_progress("testsource", "testtarget", first_id - 1).await.unwrap();
+
+        let report = archiver.migrate_storage(&source, &target, true).await.unwrap();
+        assert!(report.copied >= 2 && report.skipped >= 1 && report.missing >= 1, "{:?}", report);
+        assert_eq!(0, report.failed);
+        for (key, content) in [(copied, &b"copied"[..]), (left_over, &b"left over"[..]), (corrupted, &b"corrupted"[..])] {
+            assert_eq!(Some(Bytes::from_static(content)), target.files.get(key).await.unwrap());
+            assert!(!source.files.exists(key).await.unwrap());
+        }
+        assert!(!target.files.exists(missing).await.unwrap());
+        // Files in the target are read once to check them, then once more after copying over a bad one
+        assert_eq!((1, 1, 2), (target.gets(copied), target.gets(left_over), target.gets(corrupted)));
+        assert!(archiver.db_client.get_storage_migration_progress("testsource", "testtarget").await.unwrap() >= files[3].0.file_id);
+
+        // Resuming after the end goes through none of them again
+        archiver.migrate_storage(&source, &target, false).await.unwrap();
+        assert_eq!((1, 1), (source.gets(copied), source.gets(left_over)));
+
+        sqlx::query("DELETE FROM storage_migrations WHERE source = 'testsource'").execute(&archiver.db_client.pool).await.unwrap();
+        archiver.db_client.purge_board_data(&board).await.unwrap();
+        for (file, _) in files {
+            archiver.db_client.delete_file(&file.sha256).await.unwrap();
+        }
+    }
+}
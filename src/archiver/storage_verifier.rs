@@ -0,0 +1,154 @@
+use std::time::{Duration, SystemTime};
+use futures::stream::{self, StreamExt};
+
+#[allow(unused_imports)]
+use log::{info, warn, error, debug};
+#[allow(unused_imports)]
+use metrics::{gauge, counter, histogram};
+
+use crate::models::{StorageReport, StoredFile};
+use crate::util::{hash_file, get_file_key, get_post_image_infos, bool_from_env};
+use crate::archiver::Archiver;
+
+// How long 4chan keeps archived threads, and their files, before pruning them
+const UPSTREAM_ARCHIVE_SECONDS: i64 = 3 * 24 * 3600;
+
+enum FileStatus {
+    Ok,
+    Missing,
+    Corrupted,
+}
+
+impl Archiver {
+    /**
+     * Checks that every file in the database is present in the file store.
+     * With `rehash`, every file is downloaded and its sha256 compared with the database, which is much slower.
+     * With `requeue`, missing and corrupted files are scheduled for download again,
+     * if at least one post using them should still be available upstream: not deleted, and not in a thread archived more than 3 days ago.
+     */
+    pub async fn verify_storage(&self, rehash: bool, requeue: bool) -> anyhow::Result<StorageReport> {
+        let mut report = StorageReport::default();
+        let mut last_id = 0;
+        loop {
+            let files = self.db_client.get_files_after(last_id, 1000).await?;
+            let Some(last) = files.last() else {
+                break
+            };
+            last_id = last.file_id;
+
+            let statuses: Vec<(StoredFile, anyhow::Result<FileStatus>)> = stream::iter(files)
+                .map(|file| async move {
+                    let status = self.check_stored_file(&file, rehash).await;
+                    (file, status)
+                })
+                .buffer_unordered(16)
+                .collect().await;
+
+            for (file, status) in statuses {
+                report.checked += 1;
+                match status {
+                    Ok(FileStatus::Ok) => continue,
+                    Ok(FileStatus::Missing) => {
+                        warn!("File {}{} (thumbnail: {}) is missing from {} storage", file.sha256, file.file_ext, file.is_thumbnail, self.file_store.name());
+                        report.missing += 1;
+                    },
+                    Ok(FileStatus::Corrupted) => {
+                        warn!("File {}{} (thumbnail: {}) does not match its hash", file.sha256, file.file_ext, file.is_thumbnail);
+                        report.corrupted += 1;
+                    },
+                    Err(e) => {
+                        error!("Failed to check file {}{}: {}", file.sha256, file.file_ext, e);
+                        report.failed += 1;
+                        continue;
+                    }
+                }
+                if requeue {
+                    match self.requeue_stored_file(&file).await {
+                        Ok(requeued) => report.requeued += requeued,
+                        Err(e) => {
+                            error!("Failed to schedule downloads of file {}{}: {}", file.sha256, file.file_ext, e);
+                            report.requeue_failed += 1;
+                        }
+                    }
+                }
+            }
+            info!("Checked {} files: {} missing, {} corrupted", report.checked, report.missing, report.corrupted);
+        }
+        Ok(report)
+    }
+
+    async fn check_stored_file(&self, file: &StoredFile, rehash: bool) -> anyhow::Result<FileStatus> {
+        let key = get_file_key(&file.sha256, &file.file_ext, file.is_thumbnail);
+        if !rehash {
+            return Ok(if self.file_store.exists(&key).await? { FileStatus::Ok } else { FileStatus::Missing })
+        }
+        match self.file_store.get(&key).await? {
+            Some(bytes) if hash_file(&bytes) == file.sha256 => Ok(FileStatus::Ok),
+            Some(_) => Ok(FileStatus::Corrupted),
+            None => Ok(FileStatus::Missing)
+        }
+    }
+
+    // Schedules image jobs to download a file again for every live post using it. Returns the number of jobs.
+    pub(crate) async fn requeue_stored_file(&self, file: &StoredFile) -> anyhow::Result<u64> {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+        let mut requeued = 0;
+        for (board_name, no, idx) in self.db_client.get_live_posts_with_file(file.file_id, now - UPSTREAM_ARCHIVE_SECONDS).await? {
+            let (Some(board), Some(post)) = (
+                self.db_client.get_board(&board_name).await?,
+                self.db_client.get_post(&board_name, no, false).await?
+            ) else {
+                continue
+            };
+            let source = match self.get_upstream(&board) {
+                Ok(source) => source,
+                Err(e) => {
+                    error!("{}, not requeuing file for /{}/{}", e, board_name, no);
+                    continue
+                }
+            };
+            let image_info = get_post_image_infos(source.as_ref(), &board_name, 0, &post)
+                .into_iter().find(|image_info| image_info.idx == idx);
+            if let Some(mut image_info) = image_info {
+                // Image jobs only download what the post doesn't have yet
+                if file.is_thumbnail {
+                    image_info.thumbnail_sha256 = None;
+                } else {
+                    image_info.file_sha256 = None;
+                }
+                self.db_client.insert_image_job(&image_info).await?;
+                debug!("Requeued file for /{}/{}", board_name, no);
+                requeued += 1;
+            }
+        }
+        Ok(requeued)
+    }
+
+    /**
+     * Runs `verify_storage` (with requeuing) every VERIFY_STORAGE_INTERVAL_HOURS, if set.
+     * VERIFY_STORAGE_REHASH=true makes it rehash every file as well.
+     */
+    pub fn run_storage_verify_cycle(&self) -> Option<tokio::task::JoinHandle<()>> {
+        let interval_hours: u64 = std::env::var("VERIFY_STORAGE_INTERVAL_HOURS").ok()
+            .and_then(|s| s.parse().ok()).unwrap_or(0);
+        if interval_hours == 0 {
+            return None
+        }
+        let rehash = bool_from_env(&"VERIFY_STORAGE_REHASH".to_string());
+        let c = self.clone();
+        Some(tokio::task::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(interval_hours * 3600)).await;
+                match c.verify_storage(rehash, true).await {
+                    Ok(report) => {
+                        info!("Storage verification done: {} files checked, {} missing, {} corrupted, {} requeued, {} could not be requeued",
+                            report.checked, report.missing, report.corrupted, report.requeued, report.requeue_failed);
+                        gauge!("storage_files_missing", report.missing as f64);
+                        gauge!("storage_files_corrupted", report.corrupted as f64);
+                    },
+                    Err(e) => error!("Storage verification failed: {}", e)
+                }
+            }
+        }))
+    }
+}
@@ -8,48 +8,46 @@ use log::{info, warn, error, debug};
 #[allow(unused_imports)]
 use metrics::{gauge, increment_gauge, decrement_gauge, counter, histogram};
 
-use crate::models::{ThreadJob, Post, Thread};
-use crate::util::{get_post_image_info, get_thread_api_url};
+use crate::models::{Board, ThreadJob, Post, Thread};
+use crate::util::get_post_image_infos;
+use crate::upstream::UpstreamSource;
+use crate::http::FetchError;
 use crate::archiver::Archiver;
 
 impl Archiver {
-    pub async fn get_thread(&self, board: &String, tid: &String) -> Result<Option<Thread>, bool> {
-        match self.http_client.fetch_json::<Thread>(&get_thread_api_url(board, tid)).await {
-            Ok(t) => Ok(Some(t)),
-            Err(is_404) => {
-                if is_404 {
-                    Ok(None)
-                } else {
-                    Err(is_404)
-                }
-            }
-        }
+    // Returns Ok(None) if the thread didn't change since it was last fetched
+    pub async fn get_thread(&self, source: &dyn UpstreamSource, board: &Board, tid: i64) -> Result<Option<Thread>, FetchError> {
+        self.http_client.fetch_json_if_modified::<Thread>(&source.thread_url(&board.name, tid), &board.name, board.rate_limit_share).await
     }
     pub async fn thread_cycle(&self) -> anyhow::Result<()> {
         let (tx, mut rx) = tokio::sync::mpsc::channel(100);
         let mut running_jobs = HashMap::new();
         loop {
-            let mut jobs = self.db_client.get_thread_jobs(250).await
-                .map_err(|e|{error!("Failed to get new thread jobs from database: {}", e); e})?;
-            
-            if jobs.len() == 0 { // No more jobs available
+            // Run up to 20 jobs at once. Only claim as many as we can start right away, the rest are left to other workers
+            if running_jobs.len() < 20 {
+                let jobs = self.db_client.claim_thread_jobs(&self.worker_id, self.lease_seconds, (20 - running_jobs.len()) as i64).await
+                    .map_err(|e|{error!("Failed to claim new thread jobs from database: {}", e); e})?;
+                for job in jobs { // jobs are already sorted by priority
+                    running_jobs.insert(job.id, self.dispatch_archive_thread(tx.clone(), job));
+                }
+            }
+
+            if running_jobs.is_empty() { // No more jobs available
                 tokio::time::sleep(Duration::from_secs(10)).await;
                 continue;
             };
-            
-            while let Some(job) = jobs.pop() {
-                if running_jobs.contains_key(&job.id) { // Don't schedule the same job twice
-                    continue;
-                }
-                running_jobs.insert(job.id, self.dispatch_archive_thread(tx.clone(), job));
-                if running_jobs.len() < 20 {continue}; // Dispatch up to 20 jobs at once
 
-                if let Some(job_id) = rx.recv().await { // wait for a job to complete before dispatching the next
+            // wait for a job to complete before claiming more, but check for new jobs every 10s
+            match tokio::time::timeout(Duration::from_secs(10), rx.recv()).await {
+                Ok(Some(job_id)) => {
                     running_jobs.remove(&job_id);
-                } else { // All jobs are terminated or panicked
-                    running_jobs.clear();
-                }
-                debug!("One thread job has completed")
+                    while let Ok(job_id) = rx.try_recv() {
+                        running_jobs.remove(&job_id);
+                    }
+                    debug!("Thread jobs have completed")
+                },
+                Ok(None) => running_jobs.clear(), // All jobs are terminated or dead
+                Err(_) => {}
             }
         }
     }
@@ -59,62 +57,96 @@ impl Archiver {
             increment_gauge!("thread_jobs_running", 1.0);
             let s = Instant::now();
             let job_id = job.id.clone();
-            AssertUnwindSafe(c.archive_thread(job)).catch_unwind().await.ok();
+            if let Ok(Err(e)) = AssertUnwindSafe(c.archive_thread(job)).catch_unwind().await {
+                counter!("thread_job_errors", 1, "class" => e.class());
+            }
             histogram!("thread_job_duration", s.elapsed().as_millis() as f64);
             decrement_gauge!("thread_jobs_running", 1.0);
+            // Does nothing if the job was completed, otherwise it can be retried by any worker
+            c.db_client.release_thread_job(job_id).await.ok();
             tx.send(job_id).await.ok();
         })
     }
-    pub async fn archive_thread(&self, job: ThreadJob) -> Result<(), ()> {
+    pub async fn archive_thread(&self, job: ThreadJob) -> Result<(), FetchError> {
         let timestamp: i64 = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
-        .map_err(|_| {error!("SystemTime before UNIX EPOCH!");})?.as_secs() as i64;
+        .unwrap_or_default().as_secs() as i64;
         let board_opt = self.db_client.get_board(&job.board).await
-        .map_err(|_| {error!("Failed to get board /{}/ from database", job.board)})?;
-
-        let thread_opt = self.get_thread(&job.board, &job.no.to_string()).await
-        .map_err(|_| {error!("Failed to fetch thread /{}/{}", job.board, job.no);})?;
-        counter!("threads_fetched", 1);
+        .map_err(|e| {error!("Failed to get board /{}/ from database", job.board); FetchError::from(e)})?;
 
-        if board_opt.is_none() || !board_opt.unwrap_or_default().archive {
-            error!("Board /{}/ does not exist or is not enabled for archival, skipping", job.board);
-            self.db_client.delete_thread_job(job.id).await
-            .map_err(|e| {error!("Failed to delete thread /{}/{} from backlog: {}", job.board, job.no, e);})?;
-            return Ok(())
-        }
+        let board = match board_opt {
+            Some(board) if board.archive => board,
+            _ => {
+                error!("Board /{}/ does not exist or is not enabled for archival, skipping", job.board);
+                self.db_client.delete_thread_job(job.id).await
+                .map_err(|e| {error!("Failed to delete thread /{}/{} from backlog: {}", job.board, job.no, e); FetchError::from(e)})?;
+                return Ok(())
+            }
+        };
+        let source = self.get_upstream(&board)
+        .map_err(|e| {error!("{}", e); FetchError::from(e)})?;
 
-        if thread_opt.is_none() { // Thread was 404
-            warn!("Thread /{}/{} [{}] 404, deleting from backlog ({}).", job.board, job.no, job.last_modified, job.id);
-            self.db_client.set_post_deleted(&job.board, job.no, timestamp).await
-            .map_err(|e| {error!("Failed to set thread /{}/{} as deleted: {}", job.board, job.no, e);})?;
+        let thread_url = source.thread_url(&job.board, job.no);
+        let thread = match self.get_thread(source.as_ref(), &board, job.no).await {
+            Ok(Some(thread)) => thread,
+            Ok(None) => {
+                debug!("Thread /{}/{} unchanged since it was last fetched", job.board, job.no);
+                self.db_client.delete_thread_job(job.id).await
+                .map_err(|e| {error!("Failed to delete thread /{}/{} from backlog: {}", job.board, job.no, e); FetchError::from(e)})?;
+                return Ok(())
+            },
+            Err(FetchError::NotFound) => {
+                self.http_client.forget_validators(&thread_url);
+                warn!("Thread /{}/{} [{}] 404, deleting from backlog ({}).", job.board, job.no, job.last_modified, job.id);
+                self.db_client.set_post_deleted(&job.board, job.no, timestamp).await
+                .map_err(|e| {error!("Failed to set thread /{}/{} as deleted: {}", job.board, job.no, e); FetchError::from(e)})?;
 
-            self.db_client.delete_thread_job(job.id).await
-            .map_err(|e| {error!("Failed to delete thread /{}/{} from backlog: {}", job.board, job.no, e);})?;
-            counter!("thread_404", 1);
-            return Ok(())
+                self.db_client.delete_thread_job(job.id).await
+                .map_err(|e| {error!("Failed to delete thread /{}/{} from backlog: {}", job.board, job.no, e); FetchError::from(e)})?;
+                counter!("thread_404", 1);
+                return Ok(())
+            },
+            Err(e) => {
+                error!("Failed to fetch thread /{}/{}: {}", job.board, job.no, e);
+                return Err(e)
+            }
+        };
+        counter!("threads_fetched", 1);
+        let thread_archived = thread.posts.first().is_some_and(|op| op.archived == 1);
+        let res = self.save_thread(source.as_ref(), &job, thread, timestamp).await;
+        // Archived threads don't change anymore, and a thread that wasn't saved has to be fetched in full again
+        if res.is_err() || thread_archived {
+            self.http_client.forget_validators(&thread_url);
         }
-        let thread = thread_opt.unwrap_or_default();
-
+        res
+    }
+    async fn save_thread(&self, source: &dyn UpstreamSource, job: &ThreadJob, thread: Thread, timestamp: i64) -> Result<(), FetchError> {
         let posts: Vec<Post> = thread.posts.clone().into_iter()
         .map(|mut post|{post.board = job.board.clone(); post.last_modified = job.last_modified; post}).collect();
 
         // Handle detecting posts that have been deleted
         let post_ids: Vec<i64> = thread.posts.iter().map(|p| p.no).collect();
         let deleted_posts = self.db_client.set_missing_posts_deleted(&job.board, job.no, post_ids, timestamp).await
-        .map_err(|e| {error!("Failed to set deleted posts for /{}/{} in database: {}", job.board, job.no, e);})?;
+        .map_err(|e| {error!("Failed to set deleted posts for /{}/{} in database: {}", job.board, job.no, e); FetchError::from(e)})?;
         counter!("post_deleted", deleted_posts.len() as u64);
         
+        self.save_thread_posts(source, &job.board, job.no, job.page, posts).await?;
+
+        self.db_client.delete_thread_job(job.id).await
+        .map_err(|e| {error!("Failed to delete thread /{}/{} from backlog: {}", job.board, job.no, e); FetchError::from(e)})?;
+        Ok(())
+    }
+    // Writes posts to the database, and schedules image jobs for the ones that are new or changed
+    pub async fn save_thread_posts(&self, source: &dyn UpstreamSource, board: &str, thread_no: i64, page: i32, posts: Vec<Post>) -> Result<(), FetchError> {
         let inserted_posts = self.db_client.insert_posts(&posts).await
-        .map_err(|e| {error!("Failed to insert thread /{}/{} into database: {}", job.board, job.no, e);})?;
+        .map_err(|e| {error!("Failed to insert thread /{}/{} into database: {}", board, thread_no, e); FetchError::from(e)})?;
 
         for post in inserted_posts {
-            if let Some(image_info) = get_post_image_info(&job.board, job.page, &post) {
+            for image_info in get_post_image_infos(source, board, page, &post) {
                 self.db_client.insert_image_job(&image_info).await
                 .map_err(|e| {error!("Failed to insert image job /{}/{} into database: {}", 
-                job.board, image_info.no, e);})?;
+                board, image_info.no, e); FetchError::from(e)})?;
             }
         }
-        self.db_client.delete_thread_job(job.id).await
-        .map_err(|e| {error!("Failed to delete thread /{}/{} from backlog: {}", job.board, job.no, e);})?;
         Ok(())
     }
     pub fn run_thread_cycle(&self) -> tokio::task::JoinHandle<()> {
@@ -0,0 +1,142 @@
+use regex::Regex;
+#[allow(unused_imports)]
+use log::{info, warn, error, debug};
+
+use crate::models::{ThreadFilter, ThreadInfo};
+
+struct CompiledFilter {
+    filter: ThreadFilter,
+    regex: Option<Regex>,
+}
+
+impl CompiledFilter {
+    fn matches(&self, thread: &ThreadInfo) -> bool {
+        if thread.replies < self.filter.min_replies {
+            return false
+        }
+        if self.filter.sticky_only && thread.sticky == 0 {
+            return false
+        }
+        if let Some(regex) = &self.regex {
+            let sub = thread.sub.as_deref().unwrap_or_default();
+            let com = thread.com.as_deref().unwrap_or_default();
+            return regex.is_match(sub) || regex.is_match(com)
+        }
+        true
+    }
+}
+
+/**
+ * The filter rules of a board, ready to be tested against threads.
+ * See `ThreadFilter` for how include and exclude rules combine.
+ */
+pub struct ThreadFilterSet {
+    includes: Vec<CompiledFilter>,
+    excludes: Vec<CompiledFilter>,
+}
+
+impl ThreadFilterSet {
+    pub fn new(filters: Vec<ThreadFilter>) -> Self {
+        let mut includes = Vec::new();
+        let mut excludes = Vec::new();
+        for filter in filters {
+            let regex = match filter.pattern.as_deref().map(Regex::new).transpose() {
+                Ok(regex) => regex,
+                Err(e) => {
+                    // Patterns are checked when added through mitsuba, but the table could have been edited by hand
+                    error!("Invalid pattern in filter {} for /{}/, ignoring it: {}", filter.id, filter.board, e);
+                    continue;
+                }
+            };
+            if filter.exclude {
+                excludes.push(CompiledFilter{filter, regex});
+            } else {
+                includes.push(CompiledFilter{filter, regex});
+            }
+        }
+        Self { includes, excludes }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.includes.is_empty() && self.excludes.is_empty()
+    }
+
+    pub fn allows(&self, thread: &ThreadInfo) -> bool {
+        if self.excludes.iter().any(|f| f.matches(thread)) {
+            return false
+        }
+        self.includes.is_empty() || self.includes.iter().any(|f| f.matches(thread))
+    }
+}
+
+pub fn validate_filter(filter: &ThreadFilter) -> anyhow::Result<()> {
+    if let Some(pattern) = &filter.pattern {
+        Regex::new(pattern).map_err(|e| anyhow::anyhow!("Invalid pattern: {}", e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn thread(sub: &str, com: &str, replies: i64, sticky: i64) -> ThreadInfo {
+        ThreadInfo {
+            sub: Some(sub.to_string()).filter(|s| !s.is_empty()),
+            com: Some(com.to_string()).filter(|s| !s.is_empty()),
+            replies,
+            sticky,
+            ..Default::default()
+        }
+    }
+
+    fn rule(exclude: bool, pattern: Option<&str>, min_replies: i64, sticky_only: bool) -> ThreadFilter {
+        ThreadFilter { exclude, pattern: pattern.map(|p| p.to_string()), min_replies, sticky_only, ..Default::default() }
+    }
+
+    #[test]
+    fn test_filter_precedence() {
+        let empty = ThreadFilterSet::new(vec![]);
+        assert!(empty.is_empty());
+        assert!(empty.allows(&thread("anything", "", 0, 0)));
+
+        // With include rules, only matching threads pass, and an exclude rule beats every include
+        let filters = ThreadFilterSet::new(vec![
+            rule(false, Some("(?i)general"), 0, false),
+            rule(false, None, 0, true),
+            rule(true, Some("spam"), 0, false),
+        ]);
+        assert!(!filters.is_empty());
+        assert!(filters.allows(&thread("/cat/ General", "", 0, 0)));
+        assert!(filters.allows(&thread("rules", "", 0, 1)));
+        assert!(!filters.allows(&thread("a question", "", 0, 0)));
+        assert!(!filters.allows(&thread("General", "spam spam", 0, 0)));
+        assert!(!filters.allows(&thread("rules", "spam", 0, 1)));
+
+        // Only exclude rules let everything else through
+        let filters = ThreadFilterSet::new(vec![rule(true, None, 300, false)]);
+        assert!(filters.allows(&thread("", "", 299, 0)));
+        assert!(!filters.allows(&thread("", "", 300, 0)));
+    }
+
+    #[test]
+    fn test_filter_fields() {
+        // The pattern is tested against the subject or the comment, other fields must all match too
+        let filters = ThreadFilterSet::new(vec![rule(false, Some("^cats?$"), 10, false)]);
+        assert!(filters.allows(&thread("cats", "", 10, 0)));
+        assert!(filters.allows(&thread("", "cat", 50, 0)));
+        assert!(!filters.allows(&thread("cats", "", 9, 0)));
+        assert!(!filters.allows(&thread("catsup", "", 10, 0)));
+        assert!(!filters.allows(&thread("", "", 10, 0)));
+
+        let filters = ThreadFilterSet::new(vec![rule(false, None, 0, true)]);
+        assert!(filters.allows(&thread("", "", 0, 1)));
+        assert!(!filters.allows(&thread("", "", 100, 0)));
+
+        // Invalid patterns are skipped, so they can't filter out everything
+        let filters = ThreadFilterSet::new(vec![rule(false, Some("(unclosed"), 0, false)]);
+        assert!(filters.is_empty());
+        assert!(validate_filter(&rule(false, Some("(unclosed"), 0, false)).is_err());
+        assert!(validate_filter(&rule(false, Some("ok|fine"), 0, false)).is_ok());
+    }
+}
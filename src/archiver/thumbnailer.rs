@@ -0,0 +1,189 @@
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::webp::WebPEncoder;
+use tokio::process::Command;
+#[allow(unused_imports)]
+use log::{info, warn, error, debug};
+
+/**
+ * Extracts a still image from formats the thumbnailer can't decode itself, like videos.
+ * Returns the frame encoded in any format `image` can read, or None if the file's format isn't supported.
+ */
+#[async_trait]
+pub trait FrameDecoder: Send + Sync {
+    async fn first_frame(&self, bytes: &[u8], ext: &str) -> anyhow::Result<Option<Vec<u8>>>;
+}
+
+// A crafted or broken video can keep ffmpeg busy forever
+const FFMPEG_TIMEOUT: Duration = Duration::from_secs(30);
+
+/**
+ * Uses an ffmpeg executable to grab the first frame of webm and mp4 files.
+ */
+pub struct FfmpegDecoder {
+    pub path: String,
+    pub timeout: Duration,
+}
+
+#[async_trait]
+impl FrameDecoder for FfmpegDecoder {
+    async fn first_frame(&self, bytes: &[u8], ext: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        if !matches!(ext, ".webm" | ".mp4") {
+            return Ok(None)
+        }
+        // mp4 files can keep their index at the end, so ffmpeg gets a real file rather than a pipe
+        let input: PathBuf = std::env::temp_dir().join(format!("mitsuba-frame-{:08x}{}", rand::random::<u32>(), ext));
+        tokio::fs::write(&input, bytes).await?;
+        // The process is killed if it's still running when the timeout drops it
+        let output = tokio::time::timeout(self.timeout, Command::new(&self.path)
+            .args(["-hide_banner", "-loglevel", "error", "-i"])
+            .arg(&input)
+            .args(["-frames:v", "1", "-f", "image2pipe", "-vcodec", "png", "pipe:1"])
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .output()
+        ).await;
+        tokio::fs::remove_file(&input).await.ok();
+        let output = output.map_err(|_| anyhow::anyhow!("ffmpeg took longer than {}s", self.timeout.as_secs()))??;
+        if !output.status.success() || output.stdout.is_empty() {
+            return Err(anyhow::anyhow!("ffmpeg failed: {}", String::from_utf8_lossy(&output.stderr).trim()))
+        }
+        Ok(Some(output.stdout))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThumbnailFormat {
+    Jpeg,
+    Webp,
+}
+
+/**
+ * Makes thumbnails out of full files, for posts whose upstream thumbnail is missing.
+ * Configured with THUMBNAIL_FORMAT (`jpeg` or `webp`, default `jpeg`), THUMBNAIL_SIZE (largest side in pixels, default 250)
+ * and FFMPEG_PATH, which enables thumbnails for videos.
+ */
+pub struct Thumbnailer {
+    pub format: ThumbnailFormat,
+    pub max_size: u32,
+    pub frame_decoder: Option<Arc<dyn FrameDecoder>>,
+}
+
+impl Thumbnailer {
+    pub fn from_env() -> Self {
+        let format = match std::env::var("THUMBNAIL_FORMAT").unwrap_or_default().to_lowercase().as_str() {
+            "webp" => ThumbnailFormat::Webp,
+            "jpeg" | "jpg" | "" => ThumbnailFormat::Jpeg,
+            other => {
+                error!("Unknown THUMBNAIL_FORMAT '{}', using jpeg", other);
+                ThumbnailFormat::Jpeg
+            }
+        };
+        let max_size = std::env::var("THUMBNAIL_SIZE").ok()
+            .and_then(|s| s.parse().ok()).unwrap_or(250);
+        let frame_decoder: Option<Arc<dyn FrameDecoder>> = std::env::var("FFMPEG_PATH").ok()
+            .map(|path| Arc::new(FfmpegDecoder { path, timeout: FFMPEG_TIMEOUT }) as Arc<dyn FrameDecoder>);
+        Self { format, max_size, frame_decoder }
+    }
+
+    // Extension of the thumbnails we make, used in their file key
+    pub fn ext(&self) -> &'static str {
+        match self.format {
+            ThumbnailFormat::Jpeg => ".jpg",
+            ThumbnailFormat::Webp => ".webp",
+        }
+    }
+
+    /**
+     * Thumbnail of a full file with extension `ext`. None if neither `image` nor the frame decoder supports the file.
+     * Decoding and resizing run in a blocking task.
+     */
+    pub async fn generate(&self, bytes: Bytes, ext: &str) -> anyhow::Result<Option<Bytes>> {
+        let image = if ImageFormat::from_extension(ext.trim_start_matches('.')).is_some_and(|f| f.reading_enabled()) {
+            bytes.to_vec()
+        } else {
+            let frame = match &self.frame_decoder {
+                Some(decoder) => decoder.first_frame(&bytes, ext).await?,
+                None => None
+            };
+            match frame {
+                Some(frame) => frame,
+                None => return Ok(None)
+            }
+        };
+        let (format, max_size) = (self.format, self.max_size);
+        let thumbnail = tokio::task::spawn_blocking(move || make_thumbnail(&image, format, max_size)).await??;
+        Ok(Some(thumbnail))
+    }
+}
+
+fn make_thumbnail(bytes: &[u8], format: ThumbnailFormat, max_size: u32) -> anyhow::Result<Bytes> {
+    let thumbnail = image::load_from_memory(bytes)?.thumbnail(max_size, max_size);
+    let mut buffer = Cursor::new(Vec::new());
+    match format {
+        ThumbnailFormat::Jpeg => {
+            DynamicImage::ImageRgb8(flatten_on_white(&thumbnail))
+                .write_with_encoder(JpegEncoder::new_with_quality(&mut buffer, 85))?;
+        },
+        ThumbnailFormat::Webp => {
+            DynamicImage::ImageRgba8(thumbnail.to_rgba8())
+                .write_with_encoder(WebPEncoder::new_lossless(&mut buffer))?;
+        }
+    }
+    Ok(Bytes::from(buffer.into_inner()))
+}
+
+// JPEG has no transparency, transparent pixels are drawn over a white background
+fn flatten_on_white(image: &DynamicImage) -> RgbImage {
+    let rgba = image.to_rgba8();
+    RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        let blend = |c: u8| ((c as u32 * a as u32 + 255 * (255 - a as u32)) / 255) as u8;
+        Rgb([blend(r), blend(g), blend(b)])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+
+    #[tokio::test]
+    async fn test_thumbnail() {
+        let mut png = Cursor::new(Vec::new());
+        RgbaImage::from_pixel(600, 300, Rgba([255, 0, 0, 128]))
+            .write_to(&mut png, ImageFormat::Png).unwrap();
+        let png = Bytes::from(png.into_inner());
+        for format in [ThumbnailFormat::Jpeg, ThumbnailFormat::Webp] {
+            let thumbnailer = Thumbnailer { format, max_size: 250, frame_decoder: None };
+            let thumbnail = thumbnailer.generate(png.clone(), ".png").await.unwrap().unwrap();
+            let decoded = image::load_from_memory(&thumbnail).unwrap();
+            assert_eq!((250, 125), (decoded.width(), decoded.height()));
+        }
+        let thumbnailer = Thumbnailer { format: ThumbnailFormat::Jpeg, max_size: 250, frame_decoder: None };
+        assert!(thumbnailer.generate(Bytes::from_static(b"not a video"), ".webm").await.unwrap().is_none());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_ffmpeg_timeout() {
+        // Stands in for an ffmpeg that never finishes
+        let script = std::env::temp_dir().join(format!("mitsuba-slow-ffmpeg-{:08x}", rand::random::<u32>()));
+        std::fs::write(&script, "#!/bin/sh\nexec sleep 30\n").unwrap();
+        std::fs::set_permissions(&script, std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+        let decoder = FfmpegDecoder { path: script.to_string_lossy().to_string(), timeout: Duration::from_millis(500) };
+        let started = std::time::Instant::now();
+        let result = decoder.first_frame(b"video", ".webm").await;
+        assert!(result.unwrap_err().to_string().contains("longer than"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        std::fs::remove_file(&script).ok();
+    }
+}
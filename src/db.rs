@@ -1,29 +1,40 @@
 use std::env;
 use std::sync::Arc;
 use std::hash::{Hash, Hasher};
+use std::collections::HashMap;
 use std::collections::hash_map::DefaultHasher;
 
 use dashmap::DashSet;
-use log::{debug, warn};
+use log::{debug, warn, error};
 #[allow(unused_imports)]
 use metrics::{gauge, increment_gauge, decrement_gauge, counter, histogram};
 
-use crate::models::{ModActionType, ModLog, ModLogInfo, StoredFile, User, UserReport};
+use crate::models::{ModActionType, ModLog, ModLogInfo, StoredFile, User, UserReport, ChangeFeed, FeedAction, FeedCursor};
 
 #[allow(unused_imports)]
 use crate::models::{Post, Image, PostUpdate, Board, Thread, ImageInfo, ImageJob,
-     ThreadInfo, ThreadJob, ThreadNo, UserRole, ModLogEntry, ModLogAction};
+     ThreadInfo, ThreadJob, ThreadNo, ThreadFilter, UserRole, ModLogEntry, ModLogAction, FileLookupResults};
 
-use crate::util::get_post_image_info;
+use crate::util::get_post_image_infos;
+use crate::search::PostSearch;
+use crate::upstream::UpstreamSource;
+use crate::search_index::{SearchIndex, search_index_from_env, search_backend_from_env};
 #[allow(unused_imports)]
 use crate::util::strip_nullchars;
-use crate::util::{process_hidden_post, process_hidden_thread};
+use crate::util::{process_hidden_post, process_hidden_thread, clamp_page};
 
 pub async fn sqlx_connection() -> sqlx::Pool<sqlx::Postgres> {
     use sqlx::postgres::PgPoolOptions;
     dotenv::dotenv().ok();
     let pool = PgPoolOptions::new()
         .max_connections(50)
+        // update_search_index() only creates full text indexes when postgres is the search backend
+        .after_connect(|conn, _meta| Box::pin(async move {
+            sqlx::query("SELECT set_config('mitsuba.search_backend', $1, false)")
+                .bind(search_backend_from_env())
+                .execute(conn).await?;
+            Ok(())
+        }))
         .connect(&env::var("DATABASE_URL")
         .expect("DATABASE_URL must be set")).await
         .expect("Failed to connect to database");
@@ -33,14 +44,17 @@ pub async fn sqlx_connection() -> sqlx::Pool<sqlx::Postgres> {
 #[derive(Clone)]
 pub struct DBClient {
     pub pool: sqlx::Pool<sqlx::Postgres>,
+    pub search_index: Arc<dyn SearchIndex>,
     post_hashes: Arc<DashSet<u64>>,
     tinfo_hashes: Arc<DashSet<u64>>
 }
 
 impl DBClient {
     pub async fn new() -> Self {
+        let pool = sqlx_connection().await;
         Self {
-            pool: sqlx_connection().await,
+            search_index: search_index_from_env(pool.clone()),
+            pool,
             post_hashes: Arc::new(DashSet::new()),
             tinfo_hashes: Arc::new(DashSet::new())
         }
@@ -114,6 +128,7 @@ impl DBClient {
             FROM posts
             LEFT JOIN posts_files 
             ON posts_files.post_id = posts.post_id
+            AND posts_files.idx = 0
             WHERE posts_files.thumbnail_id IS NULL
             AND tim != 0 AND filedeleted = 0 AND deleted_on = 0
             "
@@ -121,14 +136,15 @@ impl DBClient {
         Ok(count.count.unwrap_or(0))
     }
 
-    pub async fn schedule_missing_full_files(&self, board: &String) -> anyhow::Result<usize> {
-        let posts_missing_full_images: Vec<Post> = sqlx::query_as!(Post,
+    pub async fn schedule_missing_full_files(&self, source: &dyn UpstreamSource, board: &String) -> anyhow::Result<usize> {
+        let mut posts_missing_full_images: Vec<Post> = sqlx::query_as!(Post,
             "
             SELECT
             posts.*,
             files.sha256 AS \"file_sha256?\",
             thumbnails.hidden AS \"mitsuba_file_hidden?\",
             thumbnails.sha256 AS \"thumbnail_sha256?\",
+            thumbnails.file_ext AS \"thumbnail_ext?\",
             CASE 
                 WHEN 
                 blacklist_thumbnail.sha256 IS NOT NULL 
@@ -155,18 +171,19 @@ impl DBClient {
             LEFT JOIN file_blacklist as blacklist_file
             ON files.sha256 = blacklist_file.sha256
 
-            WHERE posts_files.file_id IS NULL
+            WHERE (posts_files.file_id IS NULL OR posts.extra_files != '[]')
             AND board = $1
             AND tim != 0 AND filedeleted = 0 AND deleted_on = 0
             ",
             board
         ).fetch_all(&self.pool).await?;
+        self.fill_extra_files(&mut posts_missing_full_images).await?;
 
-        let image_infos: Vec<ImageInfo> = posts_missing_full_images.into_iter()
-        .map(|post| {
-            get_post_image_info(board, 5, &post) // page 5 gives it a middle priority
+        let image_infos: Vec<ImageInfo> = posts_missing_full_images.iter()
+        .flat_map(|post| {
+            get_post_image_infos(source, board, 5, post) // page 5 gives it a middle priority
         })
-        .filter_map(|img| img).collect();
+        .filter(|img| img.file_sha256.is_none()).collect();
 
         let mut job_counter = 0;
         for img in &image_infos {
@@ -180,11 +197,12 @@ impl DBClient {
                     ext, -- 5
                     page, -- 6
                     file_sha256, -- 7
-                    thumbnail_sha256 -- 8
+                    thumbnail_sha256, -- 8
+                    idx -- 9
                 )
                 VALUES
-                ($1, $2, $3, $4, $5, $6, $7, $8)
-                ON CONFLICT(board, no) DO NOTHING
+                ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+                ON CONFLICT(board, no, idx) DO NOTHING
                 RETURNING *;
                 ",
                 img.board, //1
@@ -194,7 +212,8 @@ impl DBClient {
                 img.ext, //5
                 img.page, //6
                 img.file_sha256, //7
-                img.thumbnail_sha256 //8
+                img.thumbnail_sha256, //8
+                img.idx //9
             ).fetch_optional(&self.pool)
             .await?;
             if job.is_some() {
@@ -218,6 +237,7 @@ impl DBClient {
             files.sha256 AS \"file_sha256?\",
             thumbnails.hidden AS \"mitsuba_file_hidden?\",
             thumbnails.sha256 AS \"thumbnail_sha256?\",
+            thumbnails.file_ext AS \"thumbnail_ext?\",
             CASE 
                 WHEN 
                 blacklist_thumbnail.sha256 IS NOT NULL 
@@ -284,39 +304,138 @@ impl DBClient {
         .rows_affected();
         Ok(res)
     }
+    // Whether postgres has a text search configuration with this name
+    pub async fn is_search_language(&self, language: &str) -> anyhow::Result<bool> {
+        let exists = sqlx::query_scalar!(
+            "SELECT EXISTS(SELECT 1 FROM pg_ts_config WHERE cfgname = $1) AS \"exists!\"",
+            language
+        ).fetch_one(&self.pool)
+        .await?;
+        Ok(exists)
+    }
     pub async fn insert_board(&self, board: &Board) -> anyhow::Result<Board> {
         let job = sqlx::query_as!(Board,
             "
-            INSERT INTO boards (name, full_images, archive, enable_search)
+            INSERT INTO boards (name, full_images, archive, enable_search, upstream, priority,
+                poll_interval, max_thread_jobs, rate_limit_share, search_language)
             VALUES
-            ($1, $2, $3, $4)
+            ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
             ON CONFLICT(name) DO
             UPDATE SET
             full_images = $2,
             archive = $3,
-            enable_search = $4
+            enable_search = $4,
+            upstream = $5,
+            priority = $6,
+            poll_interval = $7,
+            max_thread_jobs = $8,
+            rate_limit_share = $9,
+            search_language = $10
             RETURNING *;
             ",
             board.name,
             board.full_images,
             board.archive,
-            board.enable_search
+            board.enable_search,
+            board.upstream,
+            board.priority,
+            board.poll_interval,
+            board.max_thread_jobs,
+            board.rate_limit_share,
+            board.search_language
         ).fetch_one(&self.pool)
         .await?;
         Ok(job)
     }
-    pub async fn get_image_jobs(&self, limit: i64) -> anyhow::Result<Vec<ImageJob>> {
-        let jobs = sqlx::query_as!(ImageJob,
+    // All filters for a board, or for every board if None
+    pub async fn get_thread_filters(&self, board: Option<&str>) -> anyhow::Result<Vec<ThreadFilter>> {
+        let filters = sqlx::query_as!(ThreadFilter,
+            "
+            SELECT * FROM thread_filters
+            WHERE $1::TEXT IS NULL OR board = $1
+            ORDER BY board ASC, id ASC
+            ",
+            board
+        ).fetch_all(&self.pool)
+        .await?;
+        Ok(filters)
+    }
+    pub async fn insert_thread_filter(&self, filter: &ThreadFilter) -> anyhow::Result<ThreadFilter> {
+        let filter = sqlx::query_as!(ThreadFilter,
             "
-            SELECT * FROM image_backlog
-            ORDER BY page DESC, id ASC
+            INSERT INTO thread_filters (board, exclude, pattern, min_replies, sticky_only)
+            VALUES
+            ($1, $2, $3, $4, $5)
+            RETURNING *;
+            ",
+            filter.board,
+            filter.exclude,
+            filter.pattern,
+            filter.min_replies,
+            filter.sticky_only
+        ).fetch_one(&self.pool)
+        .await?;
+        Ok(filter)
+    }
+    pub async fn delete_thread_filter(&self, filter_id: i64) -> anyhow::Result<u64> {
+        let res: u64 = sqlx::query!(
+            "DELETE FROM thread_filters WHERE id = $1",
+            filter_id,
+        ).execute(&self.pool)
+        .await?
+        .rows_affected();
+        Ok(res)
+    }
+    /**
+     * Claims up to `limit` unleased (or expired) jobs for this worker, highest priority first (see backlog_priority() in migrations).
+     * Rows locked by another worker's claim are skipped, so concurrent workers never get the same job.
+     */
+    pub async fn claim_image_jobs(&self, worker_id: &str, lease_seconds: i64, limit: i64) -> anyhow::Result<Vec<ImageJob>> {
+        let mut tx = self.pool.begin().await?;
+        let mut jobs = sqlx::query_as!(ImageJob,
+            "
+            SELECT image_backlog.* FROM image_backlog
+            LEFT JOIN boards ON boards.name = image_backlog.board
+            WHERE image_backlog.lease_expires < EXTRACT(EPOCH FROM NOW())::BIGINT
+            ORDER BY backlog_priority(image_backlog.page, image_backlog.last_seen, COALESCE(boards.priority, 0)) DESC,
+            image_backlog.id ASC
             LIMIT $1
+            FOR UPDATE OF image_backlog SKIP LOCKED
             ",
             limit
-        ).fetch_all(&self.pool)
+        ).fetch_all(&mut *tx)
         .await?;
+
+        let ids: Vec<i64> = jobs.iter().map(|job| job.id).collect();
+        let expires = sqlx::query_scalar!(
+            "
+            UPDATE image_backlog
+            SET leased_by = $1, lease_expires = EXTRACT(EPOCH FROM NOW())::BIGINT + $2
+            WHERE id = ANY($3)
+            RETURNING lease_expires
+            ",
+            worker_id,
+            lease_seconds,
+            &ids
+        ).fetch_all(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        for job in jobs.iter_mut() {
+            job.leased_by = Some(worker_id.to_string());
+            job.lease_expires = expires.first().copied().unwrap_or_default();
+        }
         Ok(jobs)
     }
+    pub async fn release_image_job(&self, job_id: i64) -> anyhow::Result<u64> {
+        let res: u64 = sqlx::query!(
+            "UPDATE image_backlog SET leased_by = NULL, lease_expires = 0 WHERE id = $1",
+            job_id,
+        ).execute(&self.pool)
+        .await?
+        .rows_affected();
+        Ok(res)
+    }
     pub async fn delete_image_job(&self, job_id: i64) -> anyhow::Result<u64> {
         let res: u64 = sqlx::query!(
             "DELETE FROM image_backlog WHERE id = $1",
@@ -347,14 +466,19 @@ impl DBClient {
                 ext, -- 5
                 page, -- 6
                 file_sha256, -- 7
-                thumbnail_sha256 -- 8
+                thumbnail_sha256, -- 8
+                idx -- 9
             )
             VALUES
-            ($1, $2, $3, $4, $5, $6, $7, $8)
-            ON CONFLICT(board, no) DO UPDATE
+            ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            ON CONFLICT(board, no, idx) DO UPDATE
             SET 
-            page = $6
-            WHERE image_backlog.board = $1 AND image_backlog.no = $2
+            page = $6,
+            last_seen = EXTRACT(EPOCH FROM NOW())::BIGINT,
+            -- A file missing from the new job needs to be downloaded, even if the queued job has it
+            file_sha256 = CASE WHEN $7::TEXT IS NULL THEN NULL ELSE image_backlog.file_sha256 END,
+            thumbnail_sha256 = CASE WHEN $8::TEXT IS NULL THEN NULL ELSE image_backlog.thumbnail_sha256 END
+            WHERE image_backlog.board = $1 AND image_backlog.no = $2 AND image_backlog.idx = $9
             RETURNING *;
             ",
             img.board, //1
@@ -364,7 +488,8 @@ impl DBClient {
             img.ext, //5
             img.page, //6
             img.file_sha256, //7
-            img.thumbnail_sha256 //8
+            img.thumbnail_sha256, //8
+            img.idx //9
         ).fetch_one(&self.pool)
         .await?;
         Ok(job)
@@ -378,18 +503,172 @@ impl DBClient {
         .rows_affected();
         Ok(res)
     }
-    pub async fn get_thread_jobs(&self, limit: i64) -> anyhow::Result<Vec<ThreadJob>> {
-        let jobs = sqlx::query_as!(ThreadJob,
+    /**
+     * Claims up to `limit` unleased (or expired) jobs for this worker, highest priority first (see backlog_priority() in migrations).
+     * Rows locked by another worker's claim are skipped, so concurrent workers never get the same job.
+     * Boards never get more than their `max_thread_jobs` leased at once.
+     */
+    pub async fn claim_thread_jobs(&self, worker_id: &str, lease_seconds: i64, limit: i64) -> anyhow::Result<Vec<ThreadJob>> {
+        let mut tx = self.pool.begin().await?;
+        let mut jobs = sqlx::query_as!(ThreadJob,
             "
-            SELECT * FROM thread_backlog
-            ORDER BY page DESC, id ASC
+            WITH running AS (
+                SELECT board, COUNT(*) AS jobs FROM thread_backlog
+                WHERE lease_expires >= EXTRACT(EPOCH FROM NOW())::BIGINT
+                GROUP BY board
+            ),
+            ranked AS (
+                SELECT thread_backlog.id,
+                ROW_NUMBER() OVER (
+                    PARTITION BY thread_backlog.board
+                    ORDER BY backlog_priority(thread_backlog.page, thread_backlog.last_seen, COALESCE(boards.priority, 0)) DESC,
+                    thread_backlog.id ASC
+                ) AS board_rank,
+                COALESCE(boards.max_thread_jobs, 20) - COALESCE(running.jobs, 0) AS free_slots
+                FROM thread_backlog
+                LEFT JOIN boards ON boards.name = thread_backlog.board
+                LEFT JOIN running ON running.board = thread_backlog.board
+                WHERE thread_backlog.lease_expires < EXTRACT(EPOCH FROM NOW())::BIGINT
+            )
+            SELECT thread_backlog.* FROM thread_backlog
+            JOIN ranked ON ranked.id = thread_backlog.id AND ranked.board_rank <= ranked.free_slots
+            LEFT JOIN boards ON boards.name = thread_backlog.board
+            ORDER BY backlog_priority(thread_backlog.page, thread_backlog.last_seen, COALESCE(boards.priority, 0)) DESC,
+            thread_backlog.id ASC
             LIMIT $1
+            FOR UPDATE OF thread_backlog SKIP LOCKED
             ",
             limit
-        ).fetch_all(&self.pool)
+        ).fetch_all(&mut *tx)
         .await?;
+
+        let ids: Vec<i64> = jobs.iter().map(|job| job.id).collect();
+        let expires = sqlx::query_scalar!(
+            "
+            UPDATE thread_backlog
+            SET leased_by = $1, lease_expires = EXTRACT(EPOCH FROM NOW())::BIGINT + $2
+            WHERE id = ANY($3)
+            RETURNING lease_expires
+            ",
+            worker_id,
+            lease_seconds,
+            &ids
+        ).fetch_all(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        for job in jobs.iter_mut() {
+            job.leased_by = Some(worker_id.to_string());
+            job.lease_expires = expires.first().copied().unwrap_or_default();
+        }
         Ok(jobs)
     }
+    pub async fn release_thread_job(&self, job_id: i64) -> anyhow::Result<u64> {
+        let res: u64 = sqlx::query!(
+            "UPDATE thread_backlog SET leased_by = NULL, lease_expires = 0 WHERE id = $1",
+            job_id,
+        ).execute(&self.pool)
+        .await?
+        .rows_affected();
+        Ok(res)
+    }
+    /**
+     * Claims every archived board whose poll interval has passed since it was last polled, and that no other worker is polling.
+     * The boards count as polled from now on, `release_board_polls` lets other workers poll them once the interval passes again.
+     */
+    pub async fn claim_board_polls(&self, worker_id: &str, lease_seconds: i64) -> anyhow::Result<Vec<Board>> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query!(
+            "INSERT INTO board_polls (board) SELECT name FROM boards WHERE archive = true ON CONFLICT DO NOTHING"
+        ).execute(&mut *tx)
+        .await?;
+        let boards = sqlx::query_as!(Board,
+            "
+            SELECT boards.* FROM boards
+            JOIN board_polls ON board_polls.board = boards.name
+            WHERE boards.archive = true
+            AND board_polls.lease_expires < EXTRACT(EPOCH FROM NOW())::BIGINT
+            AND board_polls.last_polled + GREATEST(boards.poll_interval, 1) <= EXTRACT(EPOCH FROM NOW())::BIGINT
+            ORDER BY boards.name ASC
+            FOR UPDATE OF board_polls SKIP LOCKED
+            "
+        ).fetch_all(&mut *tx)
+        .await?;
+
+        let names: Vec<String> = boards.iter().map(|board| board.name.clone()).collect();
+        sqlx::query!(
+            "
+            UPDATE board_polls
+            SET leased_by = $1, lease_expires = EXTRACT(EPOCH FROM NOW())::BIGINT + $2, last_polled = EXTRACT(EPOCH FROM NOW())::BIGINT
+            WHERE board = ANY($3)
+            ",
+            worker_id,
+            lease_seconds,
+            &names
+        ).execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(boards)
+    }
+    pub async fn release_board_polls(&self, worker_id: &str) -> anyhow::Result<u64> {
+        let res: u64 = sqlx::query!(
+            "UPDATE board_polls SET leased_by = NULL, lease_expires = 0 WHERE leased_by = $1",
+            worker_id
+        ).execute(&self.pool)
+        .await?
+        .rows_affected();
+        Ok(res)
+    }
+    // Extends the leases on every job and board poll currently held by this worker
+    pub async fn renew_job_leases(&self, worker_id: &str, lease_seconds: i64) -> anyhow::Result<u64> {
+        let threads: u64 = sqlx::query!(
+            "
+            UPDATE thread_backlog SET lease_expires = EXTRACT(EPOCH FROM NOW())::BIGINT + $2
+            WHERE leased_by = $1
+            ",
+            worker_id,
+            lease_seconds
+        ).execute(&self.pool)
+        .await?
+        .rows_affected();
+        let images: u64 = sqlx::query!(
+            "
+            UPDATE image_backlog SET lease_expires = EXTRACT(EPOCH FROM NOW())::BIGINT + $2
+            WHERE leased_by = $1
+            ",
+            worker_id,
+            lease_seconds
+        ).execute(&self.pool)
+        .await?
+        .rows_affected();
+        sqlx::query!(
+            "
+            UPDATE board_polls SET lease_expires = EXTRACT(EPOCH FROM NOW())::BIGINT + $2
+            WHERE leased_by = $1
+            ",
+            worker_id,
+            lease_seconds
+        ).execute(&self.pool)
+        .await?;
+        Ok(threads + images)
+    }
+    // Gives up every job and board poll held by this worker
+    pub async fn release_job_leases(&self, worker_id: &str) -> anyhow::Result<u64> {
+        self.release_board_polls(worker_id).await?;
+        let threads: u64 = sqlx::query!(
+            "UPDATE thread_backlog SET leased_by = NULL, lease_expires = 0 WHERE leased_by = $1",
+            worker_id
+        ).execute(&self.pool)
+        .await?
+        .rows_affected();
+        let images: u64 = sqlx::query!(
+            "UPDATE image_backlog SET leased_by = NULL, lease_expires = 0 WHERE leased_by = $1",
+            worker_id
+        ).execute(&self.pool)
+        .await?
+        .rows_affected();
+        Ok(threads + images)
+    }
     fn get_threadinfo_hash(&self, tinfo: &ThreadInfo) -> u64 {
         let mut hasher = DefaultHasher::new();
         tinfo.hash(& mut hasher);
@@ -406,6 +685,13 @@ impl DBClient {
         self.tinfo_hashes.insert(tinfo_hash);
         gauge!("thread_jobs_hashes", self.tinfo_hashes.len() as f64);
     }
+    // True if this exact state of the thread was already handled, see insert_thread_job
+    pub fn is_thread_info_seen(&self, tinfo: &ThreadInfo) -> bool {
+        self.tinfo_hashes.contains(&self.get_threadinfo_hash(tinfo))
+    }
+    pub fn mark_thread_info_seen(&self, tinfo: &ThreadInfo) {
+        self.insert_threadinfo_hash(self.get_threadinfo_hash(tinfo));
+    }
     pub async fn insert_thread_job(&self, tinfo: &ThreadInfo) -> anyhow::Result<Option<ThreadJob>> {
         let tinfo_hash = self.get_threadinfo_hash(&tinfo);
         if self.tinfo_hashes.contains(&tinfo_hash) {
@@ -430,7 +716,8 @@ impl DBClient {
             ON CONFLICT(board, no, last_modified) DO
             UPDATE SET
             replies = $4,
-            page = $5
+            page = $5,
+            last_seen = EXTRACT(EPOCH FROM NOW())::BIGINT
             RETURNING *;
             ",
             tinfo.board,
@@ -460,7 +747,14 @@ impl DBClient {
         }
         Ok(Some(job))
     }
-    pub async fn image_tim_to_sha256(&self, board: &String, image_tim: i64, thumb: bool, remove_hidden: bool) -> anyhow::Result<Option<String>> {
+    /**
+     * Hash and extension of the file (or thumbnail) with this tim.
+     * The first attachment of a post has the post's tim, the others have their own in `extra_files` (eg. 1583512345678-1 on vichan).
+     */
+    pub async fn image_tim_to_sha256(&self, board: &String, image_tim: &str, thumb: bool, remove_hidden: bool) -> anyhow::Result<Option<(String, String)>> {
+        let Ok(first_tim) = image_tim.parse::<i64>() else {
+            return self.extra_file_tim_to_sha256(board, image_tim, thumb, remove_hidden).await
+        };
         let post_opt = sqlx::query_as!(Post,
             "
             SELECT
@@ -468,6 +762,7 @@ impl DBClient {
             files.sha256 AS \"file_sha256?\",
             thumbnails.hidden AS \"mitsuba_file_hidden?\",
             thumbnails.sha256 AS \"thumbnail_sha256?\",
+            thumbnails.file_ext AS \"thumbnail_ext?\",
             CASE 
                 WHEN 
                 blacklist_thumbnail.sha256 IS NOT NULL 
@@ -498,7 +793,7 @@ impl DBClient {
             AND tim = $2
             ",
             board,
-            image_tim
+            first_tim
         ).fetch_optional(&self.pool)
         .await?;
         if let Some(post_raw) = post_opt {
@@ -512,13 +807,42 @@ impl DBClient {
                 post_raw
             };
             if thumb {
-                return Ok(post.thumbnail_sha256)
+                let ext = post.thumbnail_ext.unwrap_or(".jpg".to_string());
+                return Ok(post.thumbnail_sha256.map(|sha256| (sha256, ext)))
             }
             if !thumb {
-                return Ok(post.file_sha256)
+                return Ok(post.file_sha256.map(|sha256| (sha256, post.ext)))
             }
         }
-        Ok(None)
+        self.extra_file_tim_to_sha256(board, image_tim, thumb, remove_hidden).await
+    }
+    async fn extra_file_tim_to_sha256(&self, board: &String, image_tim: &str, thumb: bool, remove_hidden: bool) -> anyhow::Result<Option<(String, String)>> {
+        let post_no = sqlx::query_scalar!(
+            "
+            SELECT no FROM posts
+            WHERE board = $1
+            AND extra_files != '[]'
+            AND extra_files @> jsonb_build_array(jsonb_build_object('tim', $2::text))
+            ",
+            board,
+            image_tim
+        ).fetch_optional(&self.pool)
+        .await?;
+        let Some(post_no) = post_no else {
+            return Ok(None)
+        };
+        // get_post fills in the hashes of each attachment, from posts_files rows with its idx
+        let Some(post) = self.get_post(board, post_no, remove_hidden).await? else {
+            return Ok(None)
+        };
+        let Some(file) = post.extra_files.0.into_iter().find(|file| file.tim == image_tim) else {
+            return Ok(None)
+        };
+        if thumb {
+            let ext = file.thumbnail_ext.unwrap_or(".jpg".to_string());
+            return Ok(file.thumbnail_sha256.map(|sha256| (sha256, ext)))
+        }
+        Ok(file.file_sha256.map(|sha256| (sha256, file.ext)))
     }
     pub async fn get_post(&self, board: &String, post_no: i64, remove_hidden: bool) -> anyhow::Result<Option<Post>> {
         let post = sqlx::query_as!(Post,
@@ -528,6 +852,7 @@ impl DBClient {
             files.sha256 AS \"file_sha256?\",
             thumbnails.hidden AS \"mitsuba_file_hidden?\",
             thumbnails.sha256 AS \"thumbnail_sha256?\",
+            thumbnails.file_ext AS \"thumbnail_ext?\",
             CASE 
                 WHEN 
                 blacklist_thumbnail.sha256 IS NOT NULL 
@@ -561,7 +886,8 @@ impl DBClient {
         )
         .fetch_optional(&self.pool)
         .await?;
-        if let Some(post) = post {
+        if let Some(mut post) = post {
+            self.fill_extra_files(std::slice::from_mut(&mut post)).await?;
             if remove_hidden {
                 return Ok(process_hidden_post(&post));
             }
@@ -571,18 +897,23 @@ impl DBClient {
         }
     }
     pub async fn delete_post(&self, board: &String, post_no: i64) -> anyhow::Result<u64> {
-        let res: u64 = sqlx::query!(
+        let post_ids = sqlx::query_scalar!(
             "
             DELETE FROM posts WHERE board = $1 AND no = $2
+            RETURNING post_id
             ",
             board,
             post_no
-        ).execute(&self.pool)
-        .await?
-        .rows_affected();
-        Ok(res)
+        ).fetch_all(&self.pool)
+        .await?;
+        if self.search_index.needs_posts() && !post_ids.is_empty() {
+            if let Err(e) = self.search_index.remove_posts(&post_ids).await {
+                error!("Failed to remove /{}/{} from the {} search index: {}", board, post_no, self.search_index.name(), e);
+            }
+        }
+        Ok(post_ids.len() as u64)
     }
-    pub async fn blacklist_file(&self, sha256: &String, action_id: i64) -> anyhow::Result<(u64, u64)> {
+    pub async fn blacklist_file(&self, sha256: &String, action_id: Option<i64>) -> anyhow::Result<(u64, u64)> {
         let res: u64 = sqlx::query!(
             "
             INSERT INTO file_blacklist (sha256, action_id)
@@ -620,6 +951,232 @@ impl DBClient {
         Ok(!hashes.is_empty())
     }
 
+    pub async fn set_file_phash(&self, sha256: &String, phash: i64) -> anyhow::Result<u64> {
+        let res = sqlx::query!(
+            "
+            UPDATE files SET phash = $2 WHERE sha256 = $1
+            ",
+            sha256,
+            phash
+        ).execute(&self.pool).await?
+        .rows_affected();
+        Ok(res)
+    }
+
+    // Full files don't have a perceptual hash, the one of a thumbnail made from them is used instead
+    pub async fn get_file_phash(&self, sha256: &str) -> anyhow::Result<Option<i64>> {
+        let phash = sqlx::query_scalar!(
+            "
+            SELECT COALESCE(
+                files.phash,
+                (
+                    SELECT thumbnails.phash FROM posts_files
+                    JOIN files AS thumbnails ON thumbnails.file_id = posts_files.thumbnail_id
+                    WHERE posts_files.file_id = files.file_id
+                    AND thumbnails.phash IS NOT NULL
+                    LIMIT 1
+                )
+            )
+            FROM files WHERE sha256 = $1
+            ",
+            sha256
+        ).fetch_optional(&self.pool).await?
+        .flatten();
+        Ok(phash)
+    }
+
+    /**
+     * Posts, on any board, with an image whose perceptual hash differs from `phash` by at most `max_distance` bits.
+     * The most similar come first.
+     * Hamming distance can't use an index, so this compares against the hash of every thumbnail: it's O(files).
+     */
+    pub async fn get_similar_posts(&self, phash: i64, max_distance: i32, limit: i64, remove_hidden: bool) -> anyhow::Result<Vec<Post>> {
+        let mut posts = sqlx::query_as!(Post,
+            "
+            SELECT
+            posts.*,
+            files.sha256 AS \"file_sha256?\",
+            thumbnails.hidden AS \"mitsuba_file_hidden?\",
+            thumbnails.sha256 AS \"thumbnail_sha256?\",
+            thumbnails.file_ext AS \"thumbnail_ext?\",
+            CASE 
+                WHEN 
+                blacklist_thumbnail.sha256 IS NOT NULL 
+                OR 
+                blacklist_file.sha256 IS NOT NULL
+                THEN true
+                ELSE false
+            END AS mitsuba_file_blacklisted
+            FROM (
+                -- Hamming distance between the hashes, bit_count() needs postgres 14
+                SELECT posts_files.post_id, MIN(length(replace((candidates.phash # $1)::bit(64)::text, '0', ''))) AS distance
+                FROM files AS candidates
+                JOIN posts_files ON posts_files.thumbnail_id = candidates.file_id
+                WHERE candidates.phash IS NOT NULL
+                AND ($3 = false OR candidates.hidden = false)
+                AND length(replace((candidates.phash # $1)::bit(64)::text, '0', '')) <= $2
+                GROUP BY posts_files.post_id
+                ORDER BY distance, posts_files.post_id
+                LIMIT $4
+            ) AS matches
+
+            JOIN posts
+            ON posts.post_id = matches.post_id
+
+            LEFT JOIN posts_files
+            ON posts_files.post_id = posts.post_id
+            AND posts_files.idx = 0
+            
+            LEFT JOIN files
+            ON files.file_id = posts_files.file_id
+            
+            LEFT JOIN files as thumbnails
+            ON thumbnails.file_id = posts_files.thumbnail_id
+
+            LEFT JOIN file_blacklist as blacklist_thumbnail
+            ON thumbnails.sha256 = blacklist_thumbnail.sha256
+            
+            LEFT JOIN file_blacklist as blacklist_file
+            ON files.sha256 = blacklist_file.sha256
+
+            ORDER BY matches.distance, posts.time DESC
+            ",
+            phash,
+            max_distance,
+            remove_hidden,
+            limit
+        ).fetch_all(&self.pool).await?;
+        self.fill_extra_files(&mut posts).await?;
+        if remove_hidden {
+            return Ok(posts.iter().filter_map(process_hidden_post).collect());
+        }
+        Ok(posts)
+    }
+
+    // Posts whose first file or one of their extra files has this md5, as given by upstream. Only on `boards` if given.
+    pub async fn get_posts_by_md5(&self, md5: &str, boards: Option<&[String]>, page: i64, page_size: i64, remove_hidden: bool) -> anyhow::Result<FileLookupResults> {
+        let post_ids = sqlx::query_scalar!(
+            "
+            SELECT post_id AS \"post_id!\" FROM posts
+            WHERE md5 = $1
+            AND ($2::text[] IS NULL OR board = ANY($2))
+            UNION
+            SELECT post_id FROM posts
+            WHERE extra_files != '[]'
+            AND extra_files @> jsonb_build_array(jsonb_build_object('md5', $1::text))
+            AND ($2::text[] IS NULL OR board = ANY($2))
+            ",
+            md5,
+            boards
+        ).fetch_all(&self.pool).await?;
+        self.get_file_usage(&post_ids, page, page_size, remove_hidden).await
+    }
+
+    // Posts that have this file, either as a full file or as a thumbnail
+    pub async fn get_posts_by_sha256(&self, sha256: &str, page: i64, page_size: i64, remove_hidden: bool) -> anyhow::Result<FileLookupResults> {
+        let post_ids = sqlx::query_scalar!(
+            "
+            SELECT posts_files.post_id AS \"post_id!\" FROM files
+            JOIN posts_files ON posts_files.file_id = files.file_id
+            WHERE files.sha256 = $1
+            UNION
+            SELECT posts_files.post_id FROM files
+            JOIN posts_files ON posts_files.thumbnail_id = files.file_id
+            WHERE files.sha256 = $1
+            ",
+            sha256
+        ).fetch_all(&self.pool).await?;
+        self.get_file_usage(&post_ids, page, page_size, remove_hidden).await
+    }
+
+    /**
+     * A page of the posts in `post_ids`, oldest first, with how many there are and when the first and last were made.
+     * With `remove_hidden`, hidden posts and posts with a hidden file are left out entirely,
+     * so looking up a hidden file doesn't show where it was posted.
+     */
+    async fn get_file_usage(&self, post_ids: &[i64], page: i64, page_size: i64, remove_hidden: bool) -> anyhow::Result<FileLookupResults> {
+        let page = clamp_page(page, page_size);
+        let stats = sqlx::query!(
+            "
+            SELECT COUNT(*) AS \"total!\", MIN(time) AS first_seen, MAX(time) AS last_seen
+            FROM posts
+            WHERE post_id = ANY($1)
+            AND ($2 = false OR (
+                mitsuba_post_hidden = false
+                AND NOT EXISTS (
+                    SELECT 1 FROM posts_files
+                    JOIN files ON files.file_id IN (posts_files.file_id, posts_files.thumbnail_id)
+                    WHERE posts_files.post_id = posts.post_id AND files.hidden
+                )
+            ))
+            ",
+            post_ids,
+            remove_hidden
+        ).fetch_one(&self.pool).await?;
+
+        let mut posts = sqlx::query_as!(Post,
+            "
+            SELECT
+            posts.*,
+            files.sha256 AS \"file_sha256?\",
+            thumbnails.hidden AS \"mitsuba_file_hidden?\",
+            thumbnails.sha256 AS \"thumbnail_sha256?\",
+            thumbnails.file_ext AS \"thumbnail_ext?\",
+            CASE 
+                WHEN 
+                blacklist_thumbnail.sha256 IS NOT NULL 
+                OR 
+                blacklist_file.sha256 IS NOT NULL
+                THEN true
+                ELSE false
+            END AS mitsuba_file_blacklisted
+            FROM posts
+            
+            LEFT JOIN posts_files
+            ON posts_files.post_id = posts.post_id
+            AND posts_files.idx = 0
+            
+            LEFT JOIN files
+            ON files.file_id = posts_files.file_id
+            
+            LEFT JOIN files as thumbnails
+            ON thumbnails.file_id = posts_files.thumbnail_id
+
+            LEFT JOIN file_blacklist as blacklist_thumbnail
+            ON thumbnails.sha256 = blacklist_thumbnail.sha256
+            
+            LEFT JOIN file_blacklist as blacklist_file
+            ON files.sha256 = blacklist_file.sha256
+
+            WHERE posts.post_id = ANY($1)
+            AND ($2 = false OR (
+                posts.mitsuba_post_hidden = false
+                AND NOT EXISTS (
+                    SELECT 1 FROM posts_files AS attachments
+                    JOIN files AS attachment_files ON attachment_files.file_id IN (attachments.file_id, attachments.thumbnail_id)
+                    WHERE attachments.post_id = posts.post_id AND attachment_files.hidden
+                )
+            ))
+            ORDER BY posts.time ASC, posts.post_id ASC
+            LIMIT $3 OFFSET $4
+            ",
+            post_ids,
+            remove_hidden,
+            page_size,
+            page * page_size
+        ).fetch_all(&self.pool).await?;
+        self.fill_extra_files(&mut posts).await?;
+        if remove_hidden {
+            posts = posts.iter().filter_map(process_hidden_post).collect();
+        }
+        Ok(FileLookupResults {
+            first_seen: stats.first_seen,
+            last_seen: stats.last_seen,
+            total_results: stats.total,
+            posts
+        })
+    }
+
     pub async fn remove_file_blacklist(&self, sha256: &String) -> anyhow::Result<(u64, u64)> {
         let res: u64 = sqlx::query!(
             "
@@ -666,13 +1223,14 @@ impl DBClient {
         Ok(threads)
     }
     pub async fn get_thread(&self, board: &String, no: i64, remove_hidden: bool) -> anyhow::Result<Option<Thread>> {
-        let posts = sqlx::query_as!(Post,
+        let mut posts = sqlx::query_as!(Post,
             "
             SELECT
             posts.*,
             files.sha256 AS \"file_sha256?\",
             thumbnails.hidden AS \"mitsuba_file_hidden?\",
             thumbnails.sha256 AS \"thumbnail_sha256?\",
+            thumbnails.file_ext AS \"thumbnail_ext?\",
             CASE
                 WHEN 
                 blacklist_thumbnail.sha256 IS NOT NULL 
@@ -708,6 +1266,7 @@ impl DBClient {
         )
         .fetch_all(&self.pool)
         .await?;
+        self.fill_extra_files(&mut posts).await?;
         if posts.is_empty() {
             return Ok(None);
         }
@@ -720,8 +1279,73 @@ impl DBClient {
         }
         Ok(Some(thread))
     }
+    /**
+     * Sets the hashes and moderation flags of the posts' extra attachments, from posts_files.
+     * Post queries only join the post's own file (idx 0), the rest is loaded here.
+     */
+    pub async fn fill_extra_files(&self, posts: &mut [Post]) -> anyhow::Result<()> {
+        let post_ids: Vec<i64> = posts.iter()
+            .filter(|post| !post.extra_files.is_empty())
+            .map(|post| post.post_id).collect();
+        if post_ids.is_empty() {
+            return Ok(())
+        }
+        let attachments = sqlx::query!(
+            "
+            SELECT
+            posts_files.post_id AS \"post_id!\",
+            posts_files.idx AS \"idx!\",
+            files.sha256 AS \"file_sha256?\",
+            thumbnails.hidden AS \"mitsuba_file_hidden?\",
+            thumbnails.sha256 AS \"thumbnail_sha256?\",
+            thumbnails.file_ext AS \"thumbnail_ext?\",
+            CASE
+                WHEN
+                blacklist_thumbnail.sha256 IS NOT NULL
+                OR
+                blacklist_file.sha256 IS NOT NULL
+                THEN true
+                ELSE false
+            END AS \"mitsuba_file_blacklisted!\"
+            FROM posts_files
+
+            LEFT JOIN files
+            ON files.file_id = posts_files.file_id
+
+            LEFT JOIN files as thumbnails
+            ON thumbnails.file_id = posts_files.thumbnail_id
+
+            LEFT JOIN file_blacklist as blacklist_thumbnail
+            ON thumbnails.sha256 = blacklist_thumbnail.sha256
+
+            LEFT JOIN file_blacklist as blacklist_file
+            ON files.sha256 = blacklist_file.sha256
+
+            WHERE posts_files.post_id = ANY($1)
+            AND posts_files.idx > 0
+            ",
+            &post_ids
+        ).fetch_all(&self.pool)
+        .await?;
+
+        let post_index: HashMap<i64, usize> = posts.iter().enumerate().map(|(i, post)| (post.post_id, i)).collect();
+        for attachment in attachments {
+            let Some(&i) = post_index.get(&attachment.post_id) else {
+                continue
+            };
+            if let Some(file) = posts[i].extra_files.0.get_mut(attachment.idx as usize - 1) {
+                file.file_sha256 = attachment.file_sha256;
+                file.thumbnail_sha256 = attachment.thumbnail_sha256;
+                file.thumbnail_ext = attachment.thumbnail_ext;
+                file.mitsuba_file_hidden = attachment.mitsuba_file_hidden;
+                file.mitsuba_file_blacklisted = Some(attachment.mitsuba_file_blacklisted);
+            }
+        }
+        Ok(())
+    }
     pub async fn add_post_file(&self, board: &String, no: i64, idx: i32, sha256: &String, ext: &String, is_thumbnail: bool) -> anyhow::Result<u64> {
-        // Insert the files into the files table if they don't exist
+        // Insert the files into the files table if they don't exist.
+        // A file that is used again is no longer orphaned, the update also makes RETURNING give us its id.
         let file_id = if sha256.is_empty() {
             None
         } else {
@@ -729,7 +1353,7 @@ impl DBClient {
                 "
                 INSERT INTO files (sha256, is_thumbnail, hidden, file_ext)
                 VALUES ($1, $2, false, $3)
-                ON CONFLICT(sha256) DO NOTHING
+                ON CONFLICT(sha256) DO UPDATE SET orphaned_since = NULL
                 RETURNING files.file_id;
                 ",
                 sha256,
@@ -786,69 +1410,556 @@ impl DBClient {
         }
         Ok(res)
     }
-    pub async fn set_post_deleted(&self, board: &String, no: i64, deleted_time: i64) -> anyhow::Result<Option<(i64, String)>> {
-        struct PostId {
-            no: i64,
-            board: String
+    pub async fn set_post_deleted(&self, board: &String, no: i64, deleted_time: i64) -> anyhow::Result<Option<(i64, String)>> {
+        struct PostId {
+            no: i64,
+            board: String
+        }
+        let post = sqlx::query_as!(PostId,
+            "
+            UPDATE posts
+            SET 
+            deleted_on = $1
+            WHERE board = $2 AND no = $3
+            RETURNING no, board
+            ",
+            deleted_time,
+            board,
+            no
+        ).fetch_optional(&self.pool)
+        .await?;
+        if post.is_some() {
+            self.reindex_posts(board, &[no]).await?;
+        }
+        Ok(post.map(|p| (p.no, p.board)))
+    }
+    // Numbers of every post we have for a thread, including the OP and deleted posts
+    pub async fn get_thread_post_nos(&self, board: &str, thread_no: i64) -> anyhow::Result<Vec<i64>> {
+        let nos = sqlx::query_scalar!(
+            "SELECT no FROM posts WHERE board = $1 AND (no = $2 OR resto = $2)",
+            board,
+            thread_no
+        ).fetch_all(&self.pool)
+        .await?;
+        Ok(nos)
+    }
+    pub async fn set_missing_posts_deleted(&self, board: &String, thread_no: i64, current_posts: Vec<i64>, deleted_time: i64) -> anyhow::Result<Vec<(i64, String)>> {
+        // Given the current list of post ids in a thread, it sets all posts not in the list as deleted.
+        struct PostId {
+            no: i64,
+            board: String
+        }
+        let posts = sqlx::query_as!(PostId,
+            "
+            UPDATE posts
+            SET 
+            deleted_on = $1
+            WHERE board = $2 AND resto = $3 AND deleted_on = 0 AND no != ALL($4)
+            RETURNING posts.no as no, posts.board as board
+            ",
+            deleted_time,
+            board,
+            thread_no,
+            &current_posts
+        ).fetch_all(&self.pool)
+        .await?;
+        self.reindex_posts(board, &posts.iter().map(|p| p.no).collect::<Vec<_>>()).await?;
+        Ok(posts.into_iter().map(|p| (p.no, p.board)).collect())
+    }
+
+    pub async fn get_orphaned_files(&self) -> anyhow::Result<Vec<StoredFile>> {
+        let files = sqlx::query_as!(StoredFile,
+            "
+            SELECT
+            files.file_id, 
+            files.sha256,
+            files.hidden,
+            files.is_thumbnail,
+            files.file_ext
+            FROM files
+            LEFT JOIN posts_files pf1 ON files.file_id = pf1.file_id
+            LEFT JOIN posts_files pf2 ON files.file_id = pf2.thumbnail_id
+            GROUP BY files.file_id
+            HAVING COUNT(pf1.file_id) = 0 AND COUNT(pf2.thumbnail_id) = 0;
+            "
+        ).fetch_all(&self.pool)
+        .await?;
+        Ok(files)
+    }
+
+    pub async fn get_stored_file(&self, sha256: &String) -> anyhow::Result<Option<StoredFile>> {
+        let file = sqlx::query_as!(StoredFile,
+            "
+            SELECT file_id, sha256, hidden, is_thumbnail, file_ext
+            FROM files
+            WHERE sha256 = $1
+            ",
+            sha256
+        ).fetch_optional(&self.pool)
+        .await?;
+        Ok(file)
+    }
+
+    // Files in the order they were added, `limit` at a time, starting after `after_id`. Skips blacklisted files, which were deleted on purpose.
+    pub async fn get_files_after(&self, after_id: i64, limit: i64) -> anyhow::Result<Vec<StoredFile>> {
+        let files = sqlx::query_as!(StoredFile,
+            "
+            SELECT file_id, sha256, hidden, is_thumbnail, file_ext
+            FROM files
+            WHERE file_id > $1
+            AND NOT EXISTS (SELECT 1 FROM file_blacklist WHERE file_blacklist.sha256 = files.sha256)
+            ORDER BY file_id
+            LIMIT $2
+            ",
+            after_id,
+            limit
+        ).fetch_all(&self.pool)
+        .await?;
+        Ok(files)
+    }
+
+    // Posts of a board by post_id, without the file columns the search index doesn't need
+    pub async fn get_board_posts_after(&self, board: &str, after_post_id: i64, limit: i64) -> anyhow::Result<Vec<Post>> {
+        let posts = sqlx::query_as!(Post,
+            "
+            SELECT
+            posts.*,
+            NULL::text AS \"file_sha256?\",
+            NULL::boolean AS \"mitsuba_file_hidden?\",
+            NULL::text AS \"thumbnail_sha256?\",
+            NULL::text AS \"thumbnail_ext?\",
+            NULL::boolean AS \"mitsuba_file_blacklisted?\"
+            FROM posts
+            WHERE board = $1 AND post_id > $2
+            ORDER BY post_id
+            LIMIT $3
+            ",
+            board,
+            after_post_id,
+            limit
+        ).fetch_all(&self.pool)
+        .await?;
+        Ok(posts)
+    }
+
+    // Rebuilds the full text indexes of a board, creating them if another backend was used before
+    pub async fn reindex_postgres_search(&self, board: &str) -> anyhow::Result<()> {
+        // Fires update_search_index()
+        sqlx::query!(
+            "UPDATE boards SET enable_search = enable_search WHERE name = $1",
+            board
+        ).execute(&self.pool)
+        .await?;
+        for column in ["com", "name", "sub", "filename", "trip"] {
+            sqlx::query(&format!("REINDEX INDEX {}_ft_idx_{}", column, board))
+                .execute(&self.pool).await?;
+        }
+        Ok(())
+    }
+
+    // Last file id handled by `migrate-storage` between these two backends, 0 if it never ran
+    pub async fn get_storage_migration_progress(&self, source: &str, target: &str) -> anyhow::Result<i64> {
+        let progress = sqlx::query!(
+            "SELECT last_file_id FROM storage_migrations WHERE source = $1 AND target = $2",
+            source,
+            target
+        ).fetch_optional(&self.pool)
+        .await?;
+        Ok(progress.map(|p| p.last_file_id).unwrap_or(0))
+    }
+
+    pub async fn set_storage_migration_progress(&self, source: &str, target: &str, last_file_id: i64) -> anyhow::Result<()> {
+        sqlx::query!(
+            "
+            INSERT INTO storage_migrations (source, target, last_file_id, updated_at)
+            VALUES ($1, $2, $3, EXTRACT(EPOCH FROM NOW())::BIGINT)
+            ON CONFLICT (source, target) DO UPDATE SET
+            last_file_id = EXCLUDED.last_file_id,
+            updated_at = EXCLUDED.updated_at
+            ",
+            source,
+            target,
+            last_file_id
+        ).execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    // Threads of a board with posts Mitsuba wrote at or after `since` (see post_changes), whatever their upstream timestamps.
+    // Posts archived before the feed existed, and not backfilled yet, only count for full exports.
+    pub async fn get_threads_changed_since(&self, board: &str, since: i64) -> anyhow::Result<Vec<i64>> {
+        let threads = sqlx::query!(
+            "
+            SELECT DISTINCT CASE WHEN posts.resto = 0 THEN posts.no ELSE posts.resto END AS \"thread!\"
+            FROM posts
+            LEFT JOIN post_changes ON post_changes.post_id = posts.post_id
+            WHERE posts.board = $1
+            AND COALESCE(post_changes.changed_at, 0) >= $2
+            ORDER BY 1
+            ",
+            board,
+            since
+        ).fetch_all(&self.pool)
+        .await?;
+        Ok(threads.into_iter().map(|t| t.thread).collect())
+    }
+
+    // Posts of a board that are hidden in any way, or that moderators acted on since `since`
+    pub async fn get_moderated_posts(&self, board: &str, since: i64) -> anyhow::Result<Vec<i64>> {
+        let posts = sqlx::query!(
+            "
+            SELECT posts.no
+            FROM posts
+            WHERE board = $1
+            AND (
+                mitsuba_post_hidden OR mitsuba_com_hidden
+                OR EXISTS (
+                    SELECT 1 FROM posts_files
+                    JOIN files ON files.file_id = posts_files.file_id OR files.file_id = posts_files.thumbnail_id
+                    WHERE posts_files.post_id = posts.post_id AND files.hidden
+                )
+                OR EXISTS (
+                    SELECT 1 FROM moderation_actions
+                    JOIN moderation_log ON moderation_log.log_id = moderation_actions.log_id
+                    WHERE moderation_actions.post_id = posts.post_id
+                    AND moderation_log.executed_at >= to_timestamp($2)
+                )
+            )
+            ORDER BY posts.no
+            ",
+            board,
+            since as f64
+        ).fetch_all(&self.pool)
+        .await?;
+        Ok(posts.into_iter().map(|p| p.no).collect())
+    }
+
+    /**
+     * A page of the board's replication feed, starting after `after`, with up to `limit` posts, deleted posts and moderation actions.
+     * Only changes from transactions older than every one still running are served,
+     * the others could still commit changes that come before them.
+     */
+    pub async fn get_change_feed(&self, board: &str, after: FeedCursor, limit: i64) -> anyhow::Result<ChangeFeed> {
+        // The three kinds of changes are read from the same snapshot
+        let mut tx = self.pool.begin().await?;
+        sqlx::query!("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ").execute(&mut *tx).await?;
+        let changes = sqlx::query!(
+            "
+            SELECT post_id, change_xid::text::bigint AS \"xid!\", change_seq
+            FROM post_changes
+            WHERE board = $1
+            AND (change_xid, change_seq) > ($2::bigint::text::xid8, $3)
+            AND change_xid < pg_snapshot_xmin(pg_current_snapshot())
+            ORDER BY change_xid, change_seq
+            LIMIT $4
+            ",
+            board,
+            after.xid,
+            after.seq,
+            limit
+        ).fetch_all(&mut *tx)
+        .await?;
+        let tombstones = sqlx::query!(
+            "
+            SELECT no, change_xid::text::bigint AS \"xid!\", change_seq
+            FROM post_tombstones
+            WHERE board = $1
+            AND (change_xid, change_seq) > ($2::bigint::text::xid8, $3)
+            AND change_xid < pg_snapshot_xmin(pg_current_snapshot())
+            ORDER BY change_xid, change_seq
+            LIMIT $4
+            ",
+            board,
+            after.xid,
+            after.seq,
+            limit
+        ).fetch_all(&mut *tx)
+        .await?;
+        let actions = sqlx::query!(
+            "
+            SELECT posts.no, moderation_actions.action, moderation_actions.change_xid::text::bigint AS \"xid!\", moderation_actions.change_seq
+            FROM moderation_actions
+            JOIN posts
+            ON posts.post_id = moderation_actions.post_id
+            WHERE posts.board = $1
+            AND (moderation_actions.change_xid, moderation_actions.change_seq) > ($2::bigint::text::xid8, $3)
+            AND moderation_actions.change_xid < pg_snapshot_xmin(pg_current_snapshot())
+            ORDER BY moderation_actions.change_xid, moderation_actions.change_seq
+            LIMIT $4
+            ",
+            board,
+            after.xid,
+            after.seq,
+            limit
+        ).fetch_all(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        let change_cursors: Vec<FeedCursor> = changes.iter().map(|c| FeedCursor { xid: c.xid, seq: c.change_seq }).collect();
+        let tombstone_cursors: Vec<FeedCursor> = tombstones.iter().map(|t| FeedCursor { xid: t.xid, seq: t.change_seq }).collect();
+        let action_cursors: Vec<FeedCursor> = actions.iter().map(|a| FeedCursor { xid: a.xid, seq: a.change_seq }).collect();
+
+        // When one kind of change fills the page, the others are cut at the same point
+        let all_cursors = [&change_cursors, &tombstone_cursors, &action_cursors];
+        let full_ends: Vec<FeedCursor> = all_cursors.into_iter()
+            .filter(|cursors| cursors.len() as i64 >= limit)
+            .filter_map(|cursors| cursors.last().copied())
+            .collect();
+        let more = !full_ends.is_empty();
+        let end = full_ends.into_iter().min()
+            .or(all_cursors.into_iter().filter_map(|cursors| cursors.last().copied()).max())
+            .unwrap_or(after);
+
+        let post_ids: Vec<i64> = changes.iter().zip(change_cursors.iter())
+            .filter(|(_, cursor)| **cursor <= end)
+            .map(|(change, _)| change.post_id)
+            .collect();
+        // Posts are sent in the order they changed
+        let mut posts = self.get_posts_by_ids(&post_ids).await?;
+        self.fill_extra_files(&mut posts).await?;
+        let deleted = tombstones.into_iter().zip(tombstone_cursors.iter())
+            .filter(|(_, cursor)| **cursor <= end)
+            .map(|(tombstone, _)| tombstone.no)
+            .collect();
+        let actions = actions.into_iter().zip(action_cursors.iter())
+            .filter(|(_, cursor)| **cursor <= end)
+            .map(|(action, _)| FeedAction { no: action.no, action: action.action })
+            .collect();
+        Ok(ChangeFeed {
+            posts: posts.iter().filter_map(process_hidden_post).collect(),
+            deleted,
+            actions,
+            cursor: end.to_string(),
+            more
+        })
+    }
+
+    // Posts of a board by their numbers, with all their files
+    pub async fn get_posts_by_nos(&self, board: &str, nos: &[i64]) -> anyhow::Result<Vec<Post>> {
+        let post_ids = sqlx::query_scalar!(
+            "SELECT post_id FROM posts WHERE board = $1 AND no = ANY($2)",
+            board,
+            nos
+        ).fetch_all(&self.pool)
+        .await?;
+        let mut posts = self.get_posts_by_ids(&post_ids).await?;
+        self.fill_extra_files(&mut posts).await?;
+        Ok(posts)
+    }
+
+    /**
+     * Adds one batch of the posts archived before the replication feed existed to the feed.
+     * Returns false once there are none left.
+     */
+    pub async fn backfill_post_changes(&self, batch_size: i64) -> anyhow::Result<bool> {
+        let mut tx = self.pool.begin().await?;
+        let Some(progress) = sqlx::query!("SELECT up_to, done_through FROM post_changes_backfill FOR UPDATE")
+            .fetch_optional(&mut *tx).await? else {
+            return Ok(false)
+        };
+        if progress.done_through >= progress.up_to {
+            return Ok(false)
         }
-        let post = sqlx::query_as!(PostId,
+        let through = (progress.done_through + batch_size).min(progress.up_to);
+        // Posts that changed since the migration already have their place in the feed.
+        // When the others were written isn't known, so they only count as changed for full exports.
+        sqlx::query!(
             "
-            UPDATE posts
-            SET 
-            deleted_on = $1
-            WHERE board = $2 AND no = $3
-            RETURNING no, board
+            INSERT INTO post_changes (post_id, board, changed_at)
+            SELECT post_id, board, 0 FROM posts
+            WHERE post_id > $1 AND post_id <= $2
+            ORDER BY post_id
+            ON CONFLICT DO NOTHING
             ",
-            deleted_time,
+            progress.done_through,
+            through
+        ).execute(&mut *tx)
+        .await?;
+        sqlx::query!("UPDATE post_changes_backfill SET done_through = $1", through)
+            .execute(&mut *tx).await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    pub async fn get_replication_cursor(&self, board: &str, upstream: &str) -> anyhow::Result<Option<String>> {
+        let cursor = sqlx::query!(
+            "SELECT cursor FROM replication_cursors WHERE board = $1 AND upstream = $2",
             board,
-            no
+            upstream
         ).fetch_optional(&self.pool)
         .await?;
-        Ok(post.map(|p| (p.no, p.board)))
+        Ok(cursor.map(|c| c.cursor))
     }
-    pub async fn set_missing_posts_deleted(&self, board: &String, thread_no: i64, current_posts: Vec<i64>, deleted_time: i64) -> anyhow::Result<Vec<(i64, String)>> {
-        // Given the current list of post ids in a thread, it sets all posts not in the list as deleted.
-        struct PostId {
-            no: i64,
-            board: String
-        }
-        let posts = sqlx::query_as!(PostId,
+
+    pub async fn set_replication_cursor(&self, board: &str, upstream: &str, cursor: &str) -> anyhow::Result<()> {
+        sqlx::query!(
             "
-            UPDATE posts
-            SET 
-            deleted_on = $1
-            WHERE board = $2 AND resto = $3 AND deleted_on = 0 AND no != ALL($4)
-            RETURNING posts.no as no, posts.board as board
+            INSERT INTO replication_cursors (board, upstream, cursor, updated_at)
+            VALUES ($1, $2, $3, EXTRACT(EPOCH FROM NOW())::BIGINT)
+            ON CONFLICT (board, upstream) DO UPDATE SET
+            cursor = EXCLUDED.cursor,
+            updated_at = EXCLUDED.updated_at
             ",
-            deleted_time,
             board,
-            thread_no,
-            &current_posts
+            upstream,
+            cursor
+        ).execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_asagi_import_progress(&self, board: &str, source: &str) -> anyhow::Result<i64> {
+        let progress = sqlx::query!(
+            "SELECT last_doc_id FROM asagi_imports WHERE board = $1 AND source = $2",
+            board,
+            source
+        ).fetch_optional(&self.pool)
+        .await?;
+        Ok(progress.map(|p| p.last_doc_id).unwrap_or(0))
+    }
+
+    pub async fn set_asagi_import_progress(&self, board: &str, source: &str, last_doc_id: i64) -> anyhow::Result<()> {
+        sqlx::query!(
+            "
+            INSERT INTO asagi_imports (board, source, last_doc_id, updated_at)
+            VALUES ($1, $2, $3, EXTRACT(EPOCH FROM NOW())::BIGINT)
+            ON CONFLICT (board, source) DO UPDATE SET
+            last_doc_id = EXCLUDED.last_doc_id,
+            updated_at = EXCLUDED.updated_at
+            ",
+            board,
+            source,
+            last_doc_id
+        ).execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    // Sets the replies and images counts of OPs from the posts we have, for threads that didn't come from upstream
+    pub async fn update_thread_counts(&self, board: &str, threads: &[i64]) -> anyhow::Result<u64> {
+        let res = sqlx::query!(
+            "
+            UPDATE posts AS op SET
+            replies = counts.replies,
+            images = counts.images
+            FROM (
+                SELECT resto, COUNT(*) AS replies, COUNT(*) FILTER (WHERE tim != 0) AS images
+                FROM posts
+                WHERE board = $1 AND resto = ANY($2)
+                GROUP BY resto
+            ) AS counts
+            WHERE op.board = $1 AND op.no = counts.resto AND op.resto = 0
+            AND (op.replies, op.images) IS DISTINCT FROM (counts.replies, counts.images)
+            ",
+            board,
+            threads
+        ).execute(&self.pool)
+        .await?
+        .rows_affected();
+        Ok(res)
+    }
+
+    /**
+     * Posts using a file (as file or thumbnail) that upstream should still have, with the attachment's position.
+     * Posts or files we have seen deleted are left out, and so are posts in threads that were deleted, or archived before `archived_after`.
+     */
+    pub async fn get_live_posts_with_file(&self, file_id: i64, archived_after: i64) -> anyhow::Result<Vec<(String, i64, i32)>> {
+        let posts = sqlx::query!(
+            "
+            SELECT posts.board, posts.no, posts_files.idx
+            FROM posts_files
+            JOIN posts ON posts.post_id = posts_files.post_id
+            LEFT JOIN posts AS op
+            ON op.board = posts.board
+            AND op.no = CASE WHEN posts.resto = 0 THEN posts.no ELSE posts.resto END
+            WHERE (posts_files.file_id = $1 OR posts_files.thumbnail_id = $1)
+            AND posts.deleted_on = 0
+            AND posts.filedeleted = 0
+            AND COALESCE(op.deleted_on, 0) = 0
+            AND (COALESCE(op.archived_on, 0) = 0 OR op.archived_on > $2)
+            ",
+            file_id,
+            archived_after
         ).fetch_all(&self.pool)
         .await?;
-        Ok(posts.into_iter().map(|p| (p.no, p.board)).collect())
+        Ok(posts.into_iter().map(|p| (p.board, p.no, p.idx)).collect())
     }
 
-    pub async fn get_orphaned_files(&self) -> anyhow::Result<Vec<StoredFile>> {
+    /**
+     * Starts the grace period of files no post uses anymore, and ends it for files that are used again.
+     * Returns the number of files that became orphaned and the number that stopped being orphaned.
+     */
+    pub async fn mark_orphaned_files(&self) -> anyhow::Result<(u64, u64)> {
+        let marked = sqlx::query!(
+            "
+            UPDATE files SET orphaned_since = EXTRACT(EPOCH FROM NOW())::BIGINT
+            WHERE orphaned_since IS NULL
+            AND NOT EXISTS (SELECT 1 FROM posts_files WHERE posts_files.file_id = files.file_id OR posts_files.thumbnail_id = files.file_id)
+            "
+        ).execute(&self.pool)
+        .await?
+        .rows_affected();
+        let unmarked = sqlx::query!(
+            "
+            UPDATE files SET orphaned_since = NULL
+            WHERE orphaned_since IS NOT NULL
+            AND EXISTS (SELECT 1 FROM posts_files WHERE posts_files.file_id = files.file_id OR posts_files.thumbnail_id = files.file_id)
+            "
+        ).execute(&self.pool)
+        .await?
+        .rows_affected();
+        Ok((marked, unmarked))
+    }
+
+    // Files orphaned since `before` or earlier and still unused, `limit` at a time, starting after `after_id`
+    pub async fn get_orphaned_files_before(&self, before: i64, after_id: i64, limit: i64) -> anyhow::Result<Vec<StoredFile>> {
         let files = sqlx::query_as!(StoredFile,
             "
-            SELECT
-            files.file_id, 
-            files.sha256,
-            files.hidden,
-            files.is_thumbnail,
-            files.file_ext
+            SELECT file_id, sha256, hidden, is_thumbnail, file_ext
             FROM files
-            LEFT JOIN posts_files pf1 ON files.file_id = pf1.file_id
-            LEFT JOIN posts_files pf2 ON files.file_id = pf2.thumbnail_id
-            GROUP BY files.file_id
-            HAVING COUNT(pf1.file_id) = 0 AND COUNT(pf2.thumbnail_id) = 0;
-            "
+            WHERE orphaned_since <= $1
+            AND file_id > $2
+            AND NOT EXISTS (SELECT 1 FROM posts_files WHERE posts_files.file_id = files.file_id OR posts_files.thumbnail_id = files.file_id)
+            ORDER BY file_id
+            LIMIT $3
+            ",
+            before,
+            after_id,
+            limit
         ).fetch_all(&self.pool)
         .await?;
         Ok(files)
     }
 
+    // Unused files that are still in their grace period, or that haven't been marked as orphaned yet
+    pub async fn count_orphaned_files_after(&self, after: i64) -> anyhow::Result<i64> {
+        let count = sqlx::query!(
+            "
+            SELECT COUNT(*) AS count
+            FROM files
+            WHERE (orphaned_since IS NULL OR orphaned_since > $1)
+            AND NOT EXISTS (SELECT 1 FROM posts_files WHERE posts_files.file_id = files.file_id OR posts_files.thumbnail_id = files.file_id)
+            ",
+            after
+        ).fetch_one(&self.pool)
+        .await?;
+        Ok(count.count.unwrap_or(0))
+    }
+
+    // Deletes a file from the database, unless a post started using it again. Returns false if it wasn't deleted.
+    pub async fn delete_orphaned_file(&self, file_id: i64) -> anyhow::Result<bool> {
+        let res = sqlx::query!(
+            "
+            DELETE FROM files
+            WHERE file_id = $1
+            AND NOT EXISTS (SELECT 1 FROM posts_files WHERE posts_files.file_id = files.file_id OR posts_files.thumbnail_id = files.file_id)
+            ",
+            file_id
+        ).execute(&self.pool)
+        .await?
+        .rows_affected();
+        Ok(res > 0)
+    }
+
     pub async fn is_file_orphaned(&self, file_id: i64) -> anyhow::Result<bool> {
         struct Sha256Field {
             post_id: i64
@@ -925,6 +2036,9 @@ impl DBClient {
         self.delete_board(board_name).await?;
         self.purge_board_backlogs(board_name).await?;
         let posts_deleted = self.purge_board_posts(board_name).await?;
+        if let Err(e) = self.search_index.remove_board(board_name).await {
+            error!("Failed to remove /{}/ from the {} search index: {}", board_name, self.search_index.name(), e);
+        }
         Ok(posts_deleted)
     }
 
@@ -940,6 +2054,7 @@ impl DBClient {
         // ignore image hashes - image hashes are updated with set_post_files()
         hash_post.file_sha256 = None;
         hash_post.thumbnail_sha256 = None;
+        hash_post.thumbnail_ext = None;
         hash_post.hash(& mut hasher);
         hasher.finish()
     }
@@ -998,11 +2113,12 @@ impl DBClient {
                     archived, -- 37
                     archived_on, -- 38
                     last_modified, -- 39
-                    deleted_on -- 40
+                    deleted_on, -- 40
+                    extra_files -- 41
                 )
                 VALUES
                 ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, 
-                $20, $21, $22, $23, $24, $25, $26, $27, $28, $29, $30, $31, $32, $33, $34, $35, $36, $37, $38, $39, $40)
+                $20, $21, $22, $23, $24, $25, $26, $27, $28, $29, $30, $31, $32, $33, $34, $35, $36, $37, $38, $39, $40, $41)
                 ON CONFLICT (board, no) DO 
                 UPDATE 
                 SET
@@ -1020,7 +2136,8 @@ impl DBClient {
                 archived = $37,
                 archived_on = $38,
                 last_modified = $39,
-                deleted_on = $40
+                deleted_on = $40,
+                extra_files = $41
 
                 WHERE posts.board = $1 AND posts.no = $2
                 RETURNING post_id;
@@ -1064,7 +2181,8 @@ impl DBClient {
                 entry.archived, //37
                 entry.archived_on, //38
                 entry.last_modified, //39
-                entry.deleted_on // 40
+                entry.deleted_on, // 40
+                entry.extra_files.to_json() // 41
             )
             .fetch_one(&self.pool)
             .await?;
@@ -1083,9 +2201,50 @@ impl DBClient {
                 posts.push(post);
             }
         }
-        
+        self.index_posts(&posts).await;
         Ok(posts)
     }
+
+    /**
+     * Sends posts of boards with search enabled to the search index.
+     * Failures are only logged, the posts are already saved and `reindex` can add them later.
+     */
+    pub async fn index_posts(&self, posts: &[Post]) {
+        if !self.search_index.needs_posts() || posts.is_empty() {
+            return
+        }
+        let mut by_board: HashMap<&String, Vec<Post>> = HashMap::new();
+        for post in posts {
+            by_board.entry(&post.board).or_default().push(post.clone());
+        }
+        for (board_name, posts) in by_board {
+            let board = match self.get_board(board_name).await {
+                Ok(Some(board)) if board.enable_search => board,
+                Ok(_) => continue,
+                Err(e) => {
+                    error!("Failed to get board /{}/ for search indexing: {}", board_name, e);
+                    continue
+                }
+            };
+            if let Err(e) = self.search_index.index_posts(&board, &posts).await {
+                error!("Failed to add {} posts to the {} search index: {}", posts.len(), self.search_index.name(), e);
+            }
+        }
+    }
+    // Sends changes made to posts outside of insert_posts to search indexes that need them
+    async fn reindex_posts(&self, board: &String, nos: &[i64]) -> anyhow::Result<()> {
+        if !self.search_index.needs_posts() {
+            return Ok(())
+        }
+        let mut posts = Vec::with_capacity(nos.len());
+        for no in nos {
+            if let Some(post) = self.get_post(board, *no, false).await? {
+                posts.push(post);
+            }
+        }
+        self.index_posts(&posts).await;
+        Ok(())
+    }
     pub async fn set_post_hidden_status(&self, board: &String, no: i64, hidden: bool, com_hidden: bool, file_hidden: bool) -> anyhow::Result<u64> {
         let mut res = sqlx::query!(
             "
@@ -1106,7 +2265,7 @@ impl DBClient {
             file_id: Option<i64>,
             thumbnail_id: Option<i64>
         }
-        // Retrieve the image for this post and set it hidden if it exists.
+        // Retrieve the images for this post and set them hidden if they exist.
         let file_ids = sqlx::query_as!(FileIds,
             "
             SELECT
@@ -1119,9 +2278,9 @@ impl DBClient {
             ",
             board,
             no
-        ).fetch_optional(&self.pool).await?;
+        ).fetch_all(&self.pool).await?;
 
-        if let Some(file_ids) = file_ids {
+        for file_ids in file_ids {
             if let Some(file_id) = file_ids.file_id {
                 res += sqlx::query!(
                     "
@@ -1145,37 +2304,37 @@ impl DBClient {
                 ).execute(&self.pool).await?.rows_affected();
             }
         }
-
+        // The index has to know the post is hidden now
+        if let Some(post) = self.get_post(board, no, false).await? {
+            self.index_posts(&[post]).await;
+        }
         Ok(res)
     }
 
-    pub async fn posts_full_text_search(&self, board: &String, text_query: &String, page: i64, page_size: i64, remove_hidden: bool) -> anyhow::Result<(Vec<Post>, i64)> {
-        let offset = page * page_size;
+    /**
+     * Posts on any of `boards` matching `search`, newest first, and how many there are in total.
+     * With `remove_hidden`, hidden posts aren't returned, and hidden comments aren't searched.
+     */
+    pub async fn posts_search(&self, boards: &[String], search: &PostSearch, page: i64, page_size: i64, remove_hidden: bool) -> anyhow::Result<(Vec<Post>, i64)> {
+        let page = clamp_page(page, page_size);
+        let boards: Vec<Board> = self.get_all_boards().await?.into_iter()
+            .filter(|b| boards.contains(&b.name))
+            .collect();
+        let (post_ids, total_count) = self.search_index.search(&boards, search, page, page_size, remove_hidden).await?;
+        let mut posts = self.get_posts_by_ids(&post_ids).await?;
+        self.fill_extra_files(&mut posts).await?;
     
-        // Query to count the total number of matching results
-        let total_count_opt = sqlx::query_scalar!(
-            "
-            SELECT COUNT(*)
-            FROM posts
-            WHERE board = $1
-            AND (
-                $2 = '' OR to_tsvector('english', com) @@ plainto_tsquery('english', $2)
-                OR $3 = '' OR to_tsvector('english', name) @@ plainto_tsquery('english', $3)
-                OR $4 = '' OR to_tsvector('english', sub) @@ plainto_tsquery('english', $4)
-                OR $5 = '' OR to_tsvector('english', filename) @@ plainto_tsquery('english', $5)
-            )
-            ",
-            board,
-            text_query,
-            text_query,
-            text_query,
-            text_query
-        ).fetch_one(&self.pool).await?;
-
-        
-        let total_count = total_count_opt.unwrap_or(0);
+        let posts = if remove_hidden {
+            posts.into_iter().filter_map(|p| process_hidden_post(&p)).collect()
+        } else {
+            posts
+        };
     
-        // Query to fetch the posts
+        Ok((posts, total_count))
+    }
+
+    // Posts in the same order as `post_ids`, missing ones are skipped
+    async fn get_posts_by_ids(&self, post_ids: &[i64]) -> anyhow::Result<Vec<Post>> {
         let posts = sqlx::query_as!(
             Post,
             "
@@ -1184,6 +2343,7 @@ impl DBClient {
             files.sha256 AS \"file_sha256?\",
             thumbnails.hidden AS \"mitsuba_file_hidden?\",
             thumbnails.sha256 AS \"thumbnail_sha256?\",
+            thumbnails.file_ext AS \"thumbnail_ext?\",
             CASE 
                 WHEN 
                 blacklist_thumbnail.sha256 IS NOT NULL 
@@ -1210,32 +2370,12 @@ impl DBClient {
             LEFT JOIN file_blacklist as blacklist_file
             ON files.sha256 = blacklist_file.sha256
 
-            WHERE board = $1
-            AND (
-                $2 = '' OR to_tsvector('english', com) @@ plainto_tsquery('english', $2)
-                OR $3 = '' OR to_tsvector('english', name) @@ plainto_tsquery('english', $3)
-                OR $4 = '' OR to_tsvector('english', sub) @@ plainto_tsquery('english', $4)
-                OR $5 = '' OR to_tsvector('english', filename) @@ plainto_tsquery('english', $5)
-            )
-            ORDER BY time DESC
-            LIMIT $6 OFFSET $7
+            WHERE posts.post_id = ANY($1)
+            ORDER BY array_position($1, posts.post_id)
             ",
-            board,
-            text_query,
-            text_query,
-            text_query,
-            text_query,
-            page_size,
-            offset
+            post_ids
         ).fetch_all(&self.pool).await?;
-    
-        let posts = if remove_hidden {
-            posts.into_iter().filter_map(|p| process_hidden_post(&p)).collect()
-        } else {
-            posts
-        };
-    
-        Ok((posts, total_count))
+        Ok(posts)
     }
 
     pub async fn insert_user(&self, user: &User) -> anyhow::Result<()>{
@@ -1399,24 +2539,24 @@ impl DBClient {
             SELECT thumbnail_id FROM posts_files
             LEFT JOIN posts
             ON posts.post_id = posts_files.post_id
-            WHERE board = $1 AND no = $2
+            WHERE board = $1 AND no = $2 AND idx = 0
             ",
             board,
             post_no
         ).fetch_optional(&self.pool)
-        .await?.map(|f| f.thumbnail_id);
+        .await?.and_then(|f| f.thumbnail_id);
 
         let file_id = sqlx::query!(
             "
             SELECT file_id FROM posts_files
             LEFT JOIN posts
             ON posts.post_id = posts_files.post_id
-            WHERE board = $1 AND no = $2
+            WHERE board = $1 AND no = $2 AND idx = 0
             ",
             board,
             post_no
         ).fetch_optional(&self.pool)
-        .await?.map(|f| f.file_id);
+        .await?.and_then(|f| f.file_id);
 
         Ok((thumbnail_id, file_id))
     }
@@ -1540,6 +2680,8 @@ impl std::panic::RefUnwindSafe for DBClient {}
 #[allow(non_snake_case)]
 mod tests {
     use super::*;
+    use crate::models::{ExtraFiles, PostFile};
+    use crate::search_index::{PostgresSearch, TantivySearch};
 
     fn run_async<F: std::future::Future>(f: F) {
         let rt = tokio::runtime::Runtime::new().unwrap();
@@ -1591,6 +2733,282 @@ mod tests {
         assert_eq!(1, dbc.delete_post(&post3.board, post3.no).await.unwrap());
     }
     #[test]
+    fn test_multiple_files(){
+        run_async(multiple_files());
+    }
+    async fn multiple_files() {
+        let dbc = DBClient::new().await;
+        let post = Post {
+            board: "test".to_string(),
+            no: 20,
+            tim: 1700000000000,
+            ext: ".png".to_string(),
+            extra_files: ExtraFiles(vec![PostFile {
+                tim: "1700000000000-1".to_string(),
+                ext: ".webm".to_string(),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        };
+        dbc.insert_posts(&vec![post.clone()]).await.unwrap();
+        // The second attachment only has its thumbnail, and shares its full file with the first one
+        assert_eq!(1, dbc.add_post_file(&post.board, post.no, 1, &"TESTMULTITHUMB".to_string(), &".jpg".to_string(), true).await.unwrap());
+        assert_eq!(1, dbc.add_post_file(&post.board, post.no, 0, &"TESTMULTIFULL".to_string(), &".png".to_string(), false).await.unwrap());
+        assert_eq!(1, dbc.add_post_file(&post.board, post.no, 1, &"TESTMULTIFULL".to_string(), &".png".to_string(), false).await.unwrap());
+
+        let stored = dbc.get_post(&post.board, post.no, false).await.unwrap().unwrap();
+        assert_eq!(Some("TESTMULTIFULL".to_string()), stored.file_sha256);
+        assert_eq!(None, stored.thumbnail_sha256);
+        assert_eq!(Some("TESTMULTITHUMB".to_string()), stored.extra_files.0[0].thumbnail_sha256);
+        assert_eq!(Some("TESTMULTIFULL".to_string()), stored.extra_files.0[0].file_sha256);
+
+        let infos = get_post_image_infos(&crate::upstream::FourChanSource::default(), &post.board, 0, &stored);
+        assert_eq!(2, infos.len());
+        assert_eq!(1, infos[1].idx);
+        assert!(infos[1].url.ends_with("/1700000000000-1.webm"));
+
+        assert_eq!(1, dbc.delete_post(&post.board, post.no).await.unwrap());
+        dbc.delete_file(&"TESTMULTITHUMB".to_string()).await.unwrap();
+        dbc.delete_file(&"TESTMULTIFULL".to_string()).await.unwrap();
+    }
+    #[test]
+    fn test_change_feed(){
+        run_async(change_feed());
+    }
+    // Reads the feed from `cursor` to its end, returns the post numbers, the deleted ones and the new cursor
+    async fn read_feed(dbc: &DBClient, mut cursor: FeedCursor) -> (Vec<i64>, Vec<i64>, FeedCursor) {
+        let (mut nos, mut deleted) = (Vec::new(), Vec::new());
+        loop {
+            let feed = dbc.get_change_feed("testfeed", cursor, 2).await.unwrap();
+            nos.extend(feed.posts.iter().map(|p| p.no));
+            deleted.extend(feed.deleted);
+            cursor = feed.cursor.parse().unwrap();
+            if !feed.more {
+                return (nos, deleted, cursor)
+            }
+        }
+    }
+    async fn change_feed() {
+        let dbc = DBClient::new().await;
+        let posts: Vec<Post> = (40..43).map(|no| Post { board: "testfeed".to_string(), no, time: 1700000000, ..Default::default() }).collect();
+        dbc.insert_posts(&posts).await.unwrap();
+        // Changes from transactions of other tests that are still running hold the feed back for a moment
+        let (mut nos, mut cursor) = (Vec::new(), FeedCursor::default());
+        for _ in 0..50 {
+            (nos, _, cursor) = read_feed(&dbc, FeedCursor::default()).await;
+            if nos.len() == 3 {
+                break
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        assert_eq!(vec![40, 41, 42], nos);
+        assert_eq!((Vec::<i64>::new(), Vec::<i64>::new(), cursor), read_feed(&dbc, cursor).await);
+
+        let mut changed = posts[1].clone();
+        changed.images = 5;
+        dbc.insert_posts(&vec![changed]).await.unwrap();
+        dbc.set_post_hidden_status(&"testfeed".to_string(), 42, true, false, false).await.unwrap();
+        let mut nos = Vec::new();
+        for _ in 0..50 {
+            nos = read_feed(&dbc, cursor).await.0;
+            if !nos.is_empty() {
+                break
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        // Hidden posts are left out
+        assert_eq!(vec![41], nos);
+        let cursor = read_feed(&dbc, cursor).await.2;
+
+        // Deleted posts leave a tombstone
+        for no in 40..43 {
+            assert_eq!(1, dbc.delete_post(&"testfeed".to_string(), no).await.unwrap());
+        }
+        let mut deleted = Vec::new();
+        for _ in 0..50 {
+            deleted = read_feed(&dbc, cursor).await.1;
+            if deleted.len() == 3 {
+                break
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        deleted.sort();
+        assert_eq!(vec![40, 41, 42], deleted);
+    }
+    #[test]
+    fn test_live_posts_with_file(){
+        run_async(live_posts_with_file());
+    }
+    async fn live_posts_with_file() {
+        let dbc = DBClient::new().await;
+        let board = "testlivefile".to_string();
+        dbc.insert_board(&Board { name: board.clone(), ..Default::default() }).await.unwrap();
+        let post = |no, resto| Post { board: board.clone(), no, resto, time: 1700000000, tim: 1700000000000 + no, ext: ".png".to_string(), ..Default::default() };
+        let posts = vec![
+            // Live thread, with a deleted reply and a reply whose file was deleted
+            post(1, 0), post(2, 1), Post { deleted_on: 1700000100, ..post(3, 1) }, Post { filedeleted: 1, ..post(4, 1) },
+            // Thread archived recently, and one archived long ago
+            Post { archived: 1, archived_on: 1700000000, ..post(10, 0) }, post(11, 10),
+            Post { archived: 1, archived_on: 1500000000, ..post(20, 0) }, post(21, 20),
+            // Deleted thread
+            Post { deleted_on: 1700000100, ..post(30, 0) }, post(31, 30),
+        ];
+        dbc.insert_posts(&posts).await.unwrap();
+        let sha256 = "TESTLIVEFILE".to_string();
+        for post in &posts {
+            dbc.add_post_file(&board, post.no, 0, &sha256, &".png".to_string(), false).await.unwrap();
+        }
+        let file = dbc.get_stored_file(&sha256).await.unwrap().unwrap();
+        let mut live: Vec<i64> = dbc.get_live_posts_with_file(file.file_id, 1600000000).await.unwrap()
+            .into_iter().map(|(_, no, _)| no).collect();
+        live.sort();
+        assert_eq!(vec![1, 2, 10, 11], live);
+
+        dbc.purge_board_data(&board).await.unwrap();
+        dbc.delete_file(&sha256).await.unwrap();
+    }
+    #[test]
+    fn test_similar_posts(){
+        run_async(similar_posts());
+    }
+    async fn similar_posts() {
+        let dbc = DBClient::new().await;
+        let phash: i64 = 0x5a3c_96e1_0f87_d2b4;
+        // The second image differs by 3 bits, the third by 40
+        let images = [(30, "TESTPHASH1", phash), (31, "TESTPHASH2", phash ^ 0b111), (32, "TESTPHASH3", phash ^ 0xff_ffff_ffff)];
+        for (no, sha256, image_phash) in images {
+            let post = Post { board: "test".to_string(), no, tim: 1700000000000, ext: ".png".to_string(), ..Default::default() };
+            dbc.insert_posts(&vec![post]).await.unwrap();
+            dbc.add_post_file(&"test".to_string(), no, 0, &sha256.to_string(), &".jpg".to_string(), true).await.unwrap();
+            assert_eq!(1, dbc.set_file_phash(&sha256.to_string(), image_phash).await.unwrap());
+        }
+        dbc.add_post_file(&"test".to_string(), 30, 0, &"TESTPHASHFULL".to_string(), &".png".to_string(), false).await.unwrap();
+
+        // Full files are looked up through their thumbnail
+        assert_eq!(Some(phash), dbc.get_file_phash("TESTPHASHFULL").await.unwrap());
+        let similar: Vec<i64> = dbc.get_similar_posts(phash, 10, 50, false).await.unwrap()
+            .into_iter().filter(|p| p.board == "test").map(|p| p.no).collect();
+        assert_eq!(vec![30, 31], similar);
+
+        for (no, sha256, _) in images {
+            assert_eq!(1, dbc.delete_post(&"test".to_string(), no).await.unwrap());
+            dbc.delete_file(&sha256.to_string()).await.unwrap();
+        }
+        dbc.delete_file(&"TESTPHASHFULL".to_string()).await.unwrap();
+    }
+    #[test]
+    fn test_search_language(){
+        run_async(search_language());
+    }
+    async fn search_language() {
+        let dbc = DBClient::new().await;
+        let mut boards = Vec::new();
+        for (name, language) in [("test_fts_en", "english"), ("test_fts_simple", "simple")] {
+            let board = Board { name: name.to_string(), enable_search: true, search_language: language.to_string(), ..Default::default() };
+            dbc.insert_board(&board).await.unwrap();
+            dbc.insert_posts(&vec![Post { board: name.to_string(), no: 1, time: 1, com: "running dogs".to_string(), ..Default::default() }]).await.unwrap();
+            boards.push(name.to_string());
+        }
+        // Only the english board stems words
+        let found = |(posts, _): (Vec<Post>, i64)| posts.into_iter().map(|p| p.board).collect::<Vec<String>>();
+        let search = PostSearch { text: "dog".to_string(), ..Default::default() };
+        assert_eq!(vec!["test_fts_en"], found(dbc.posts_search(&boards, &search, 0, 10, false).await.unwrap()));
+        let search = PostSearch { text: "dogs".to_string(), ..Default::default() };
+        assert_eq!(2, found(dbc.posts_search(&boards, &search, 0, 10, false).await.unwrap()).len());
+
+        // Unknown configurations are refused
+        assert!(!dbc.is_search_language("klingon").await.unwrap());
+        let klingon = Board { name: "test_fts_en".to_string(), enable_search: true, search_language: "klingon".to_string(), ..Default::default() };
+        assert!(dbc.insert_board(&klingon).await.is_err());
+
+        for name in boards {
+            // Drops the indexes
+            dbc.insert_board(&Board { name: name.clone(), ..Default::default() }).await.unwrap();
+            dbc.purge_board_data(&name).await.unwrap();
+        }
+    }
+    #[test]
+    fn test_search_backends(){
+        run_async(search_backends());
+    }
+    // The same queries give the same posts with both backends, including after posts are deleted
+    async fn search_backends() {
+        let postgres = DBClient { search_index: Arc::new(PostgresSearch::new(sqlx_connection().await)), ..DBClient::new().await };
+        let path = std::env::temp_dir().join(format!("mitsuba-search-{:08x}", rand::random::<u32>()));
+        let tantivy = DBClient { search_index: Arc::new(TantivySearch::open(&path).unwrap()), ..postgres.clone() };
+        let board = Board { name: "test_fts_or".to_string(), enable_search: true, ..Default::default() };
+        postgres.insert_board(&board).await.unwrap();
+        let posts: Vec<Post> = ["black cats", "white dogs", "red birds"].iter().enumerate()
+            .map(|(i, com)| Post { board: board.name.clone(), no: i as i64 + 1, time: i as i64 + 1, com: com.to_string(), ..Default::default() })
+            .collect();
+        tantivy.insert_posts(&posts).await.unwrap();
+
+        let boards = vec![board.name.clone()];
+        let check = |query: &'static str, expected: Vec<i64>| {
+            let (postgres, tantivy, boards) = (&postgres, &tantivy, &boards);
+            async move {
+                let search = PostSearch::parse(query).unwrap();
+                for dbc in [postgres, tantivy] {
+                    let (found, total) = dbc.posts_search(boards, &search, 0, 10, false).await.unwrap();
+                    assert_eq!(expected, found.iter().map(|p| p.no).collect::<Vec<_>>(), "{} with {}", query, dbc.search_index.name());
+                    assert_eq!(expected.len() as i64, total);
+                    // Pages far past the end are empty, rather than overflowing the offset
+                    let (found, total) = dbc.posts_search(boards, &search, i64::MAX, 10, false).await.unwrap();
+                    assert!(found.is_empty());
+                    assert_eq!(expected.len() as i64, total);
+                }
+            }
+        };
+        check("cats or dogs", vec![2, 1]).await;
+        check("cats OR dogs", vec![2, 1]).await;
+        check("cats -black", vec![]).await;
+        tantivy.set_post_deleted(&board.name, 3, 1000).await.unwrap();
+        tantivy.delete_post(&board.name, 1).await.unwrap();
+        check("deleted:true", vec![3]).await;
+        check("cats or dogs", vec![2]).await;
+
+        postgres.insert_board(&Board { name: board.name.clone(), ..Default::default() }).await.unwrap();
+        postgres.purge_board_data(&board.name).await.unwrap();
+        std::fs::remove_dir_all(path).ok();
+    }
+    #[test]
+    fn test_file_lookup(){
+        run_async(file_lookup());
+    }
+    async fn file_lookup() {
+        let dbc = DBClient::new().await;
+        let first = Post { board: "test".to_string(), no: 40, time: 1000, tim: 1, ext: ".png".to_string(), md5: "TESTLOOKUPMD5AAAAAAAAA==".to_string(), ..Default::default() };
+        let reposted = Post { board: "test2".to_string(), no: 41, time: 2000, tim: 2, ext: ".png".to_string(), extra_files: ExtraFiles(vec![PostFile {
+            tim: "2-1".to_string(),
+            ext: ".png".to_string(),
+            md5: "TESTLOOKUPMD5AAAAAAAAA==".to_string(),
+            ..Default::default()
+        }]), ..Default::default() };
+        dbc.insert_posts(&vec![first.clone(), reposted.clone()]).await.unwrap();
+        dbc.add_post_file(&first.board, first.no, 0, &"TESTLOOKUPFULL".to_string(), &".png".to_string(), false).await.unwrap();
+        dbc.add_post_file(&reposted.board, reposted.no, 1, &"TESTLOOKUPFULL".to_string(), &".png".to_string(), false).await.unwrap();
+
+        for results in [
+            dbc.get_posts_by_md5("TESTLOOKUPMD5AAAAAAAAA==", None, 0, 100, false).await.unwrap(),
+            dbc.get_posts_by_sha256("TESTLOOKUPFULL", 0, 100, false).await.unwrap()
+        ] {
+            assert_eq!(2, results.total_results);
+            assert_eq!((Some(1000), Some(2000)), (results.first_seen, results.last_seen));
+            assert_eq!(vec![40, 41], results.posts.iter().map(|p| p.no).collect::<Vec<i64>>());
+        }
+        let only_second = dbc.get_posts_by_md5("TESTLOOKUPMD5AAAAAAAAA==", Some(std::slice::from_ref(&reposted.board)), 0, 100, false).await.unwrap();
+        assert_eq!(vec![41], only_second.posts.iter().map(|p| p.no).collect::<Vec<i64>>());
+        assert_eq!(0, dbc.get_posts_by_md5("TESTLOOKUPMD5AAAAAAAAA==", Some(&[]), 0, 100, false).await.unwrap().total_results);
+        // Hiding a file hides it everywhere, so it can't be found anymore
+        dbc.set_post_hidden_status(&first.board, first.no, false, false, true).await.unwrap();
+        assert_eq!(0, dbc.get_posts_by_md5("TESTLOOKUPMD5AAAAAAAAA==", None, 0, 100, true).await.unwrap().total_results);
+        assert_eq!(2, dbc.get_posts_by_md5("TESTLOOKUPMD5AAAAAAAAA==", None, 0, 100, false).await.unwrap().total_results);
+
+        assert_eq!(1, dbc.delete_post(&first.board, first.no).await.unwrap());
+        assert_eq!(1, dbc.delete_post(&reposted.board, reposted.no).await.unwrap());
+        dbc.delete_file(&"TESTLOOKUPFULL".to_string()).await.unwrap();
+    }
+    #[test]
     fn test_post_nullchars(){
         run_async(post_insert_nullchars());
     }
@@ -1736,12 +3154,132 @@ mod tests {
         assert_eq!("urlB".to_string(), dbc.get_image_job(img_b_job.id).await.unwrap().unwrap().url);
         assert_eq!(77, dbc.get_image_job(img_b_job.id).await.unwrap().unwrap().page);
 
+        // Requeuing a file clears its hash on the queued job, a hash is never set on it afterwards
+        let img_c = ImageInfo {
+            board: "test_c".to_string(),
+            no: 777,
+            file_sha256: Some("filesha".to_string()),
+            thumbnail_sha256: Some("thumbsha".to_string()),
+            ..Default::default()
+        };
+        let img_c_job = dbc.insert_image_job(&img_c).await.unwrap();
+        let requeued = dbc.insert_image_job(&ImageInfo {thumbnail_sha256: None, ..img_c.clone()}).await.unwrap();
+        assert_eq!(None, requeued.thumbnail_sha256);
+        assert_eq!(Some("filesha".to_string()), requeued.file_sha256);
+        assert_eq!(None, dbc.insert_image_job(&img_c).await.unwrap().thumbnail_sha256);
+        assert_eq!(1u64, dbc.delete_image_job(img_c_job.id).await.unwrap());
+
         assert_eq!(1u64, dbc.delete_image_job(img_b_job.id).await.unwrap());
         assert_eq!(1u64, dbc.delete_image_job(img_job.id).await.unwrap());
         assert_eq!(None, dbc.get_image_job(img_job.id).await.unwrap());
         assert_eq!(None, dbc.get_image_job(img_b_job.id).await.unwrap());
     }
-    
+
+    #[test]
+    fn test_job_priority(){
+        run_async(job_priority());
+    }
+    async fn job_priority() {
+        let dbc = DBClient::new().await;
+        let board = Board {
+            name: "test_prio".to_string(),
+            priority: 1000, // Ahead of anything else in the backlog
+            ..Default::default()
+        };
+        dbc.insert_board(&board).await.unwrap();
+
+        let mut img = ImageInfo {
+            board: board.name.clone(),
+            no: 1,
+            page: 1,
+            ..Default::default()
+        };
+        let low_job = dbc.insert_image_job(&img).await.unwrap();
+        img.no = 2;
+        img.page = 10;
+        let high_job = dbc.insert_image_job(&img).await.unwrap();
+
+        let jobs = dbc.claim_image_jobs("test_worker", 60, 2).await.unwrap();
+        assert_eq!(vec![high_job.id, low_job.id], jobs.iter().map(|j| j.id).collect::<Vec<i64>>());
+        // Leased jobs can't be claimed by another worker until released
+        let other_jobs = dbc.claim_image_jobs("test_worker_b", 60, 2).await.unwrap();
+        assert!(other_jobs.iter().all(|j| j.id != high_job.id && j.id != low_job.id));
+        dbc.release_image_job(high_job.id).await.unwrap();
+        let other_jobs = dbc.claim_image_jobs("test_worker_b", 60, 1).await.unwrap();
+        assert_eq!(Some(high_job.id), other_jobs.first().map(|j| j.id));
+
+        assert_eq!(1u64, dbc.delete_image_job(low_job.id).await.unwrap());
+        assert_eq!(1u64, dbc.delete_image_job(high_job.id).await.unwrap());
+        assert_eq!(1u64, dbc.delete_board(&board.name).await.unwrap());
+    }
+
+    #[test]
+    fn test_thread_job_board_limit(){
+        run_async(thread_job_board_limit());
+    }
+    async fn thread_job_board_limit() {
+        let dbc = DBClient::new().await;
+        let board = Board {
+            name: "test_limit".to_string(),
+            priority: 1000,
+            max_thread_jobs: 1,
+            ..Default::default()
+        };
+        dbc.insert_board(&board).await.unwrap();
+        let mut tinfo = ThreadInfo {
+            board: board.name.clone(),
+            no: 1,
+            last_modified: 1,
+            page: 1,
+            ..Default::default()
+        };
+        let job_a = dbc.insert_thread_job(&tinfo).await.unwrap().unwrap();
+        tinfo.no = 2;
+        let job_b = dbc.insert_thread_job(&tinfo).await.unwrap().unwrap();
+
+        // Only one job for the board can be leased at a time
+        let jobs = dbc.claim_thread_jobs("test_worker", 60, 10).await.unwrap();
+        assert_eq!(1, jobs.iter().filter(|j| j.board == board.name).count());
+        let jobs = dbc.claim_thread_jobs("test_worker", 60, 10).await.unwrap();
+        assert_eq!(0, jobs.iter().filter(|j| j.board == board.name).count());
+
+        assert_eq!(1u64, dbc.delete_thread_job(job_a.id).await.unwrap());
+        assert_eq!(1u64, dbc.delete_thread_job(job_b.id).await.unwrap());
+        assert_eq!(1u64, dbc.delete_board(&board.name).await.unwrap());
+    }
+
+    #[test]
+    fn test_board_poll_leases(){
+        run_async(board_poll_leases());
+    }
+    async fn board_poll_leases() {
+        let dbc = DBClient::new().await;
+        let board = Board {
+            name: "test_polls".to_string(),
+            archive: true,
+            poll_interval: 3600,
+            ..Default::default()
+        };
+        dbc.insert_board(&board).await.unwrap();
+        let claims = |worker: &'static str| {
+            let dbc = dbc.clone();
+            async move {
+                dbc.claim_board_polls(worker, 60).await.unwrap().iter().filter(|b| b.name == "test_polls").count()
+            }
+        };
+
+        assert_eq!(1, claims("test_poller").await);
+        // Another worker can't poll it while the lease is held, or before its interval has passed
+        assert_eq!(0, claims("test_poller_b").await);
+        dbc.release_board_polls("test_poller").await.unwrap();
+        assert_eq!(0, claims("test_poller_b").await);
+        sqlx::query("UPDATE board_polls SET last_polled = 0 WHERE board = 'test_polls'").execute(&dbc.pool).await.unwrap();
+        assert_eq!(1, claims("test_poller_b").await);
+
+        dbc.release_board_polls("test_poller_b").await.unwrap();
+        assert_eq!(1u64, dbc.delete_board(&board.name).await.unwrap());
+    }
+
     #[test]
     fn test_many_post_update(){
         run_async(many_post_update());
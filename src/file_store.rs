@@ -0,0 +1,299 @@
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use dashmap::DashMap;
+use futures::stream::{self, BoxStream, StreamExt};
+use s3::bucket::Bucket;
+use s3::creds::Credentials;
+use s3::error::S3Error;
+use s3::region::Region;
+use tokio::fs::{create_dir_all, File};
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+#[allow(unused_imports)]
+use log::{info, warn, error, debug};
+
+use crate::util::bool_from_env;
+
+pub type ByteStream = BoxStream<'static, std::io::Result<Bytes>>;
+
+#[derive(Debug, Clone)]
+pub struct FileStat {
+    pub size: u64,
+}
+
+/**
+ * Where archived files (full images and thumbnails) are kept.
+ * Files are addressed by key, their path under `/img/` (eg. `full/ab/c/abc[...].png`), see `util::get_file_key`.
+ * Keys are content addressed, so putting a key that already exists just overwrites it with the same bytes.
+ */
+#[async_trait]
+pub trait FileStore: Send + Sync {
+    /// Name of the backend, for logs
+    fn name(&self) -> &str;
+    async fn put(&self, key: &str, bytes: Bytes) -> anyhow::Result<()>;
+    /// None if the file does not exist
+    async fn get(&self, key: &str) -> anyhow::Result<Option<Bytes>>;
+    /// Returns false if the file did not exist. Not every backend can tell, S3 always returns true.
+    async fn delete(&self, key: &str) -> anyhow::Result<bool>;
+    async fn exists(&self, key: &str) -> anyhow::Result<bool> {
+        Ok(self.stat(key).await?.is_some())
+    }
+    /// None if the file does not exist
+    async fn stat(&self, key: &str) -> anyhow::Result<Option<FileStat>>;
+    /// Same as get, without holding the whole file in memory when the backend allows it
+    async fn stream(&self, key: &str) -> anyhow::Result<Option<ByteStream>>;
+    /// Path of the file on disk, for backends that keep files on the local filesystem.
+    /// Lets the web server serve them directly, with support for range requests.
+    fn local_path(&self, _key: &str) -> Option<PathBuf> {
+        None
+    }
+}
+
+// Keys come from URLs when serving files, only allow the characters we generate them with
+pub fn is_valid_key(key: &str) -> bool {
+    !key.is_empty()
+    && !key.starts_with('/')
+    && !key.split('/').any(|part| part.is_empty() || part == "." || part == "..")
+    && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '/' || c == '.' || c == '_' || c == '-')
+}
+
+/**
+ * The backend configured through environment variables.
+ * FILE_STORAGE picks one of `local` (the `images` folder in DATA_ROOT), `s3` or `memory`.
+ * ENABLE_S3_STORAGE=true still selects S3, for older configs.
+ */
+pub fn file_store_from_env() -> Arc<dyn FileStore> {
+    let backend = std::env::var("FILE_STORAGE").unwrap_or_else(|_| {
+        if bool_from_env(&"ENABLE_S3_STORAGE".to_string()) { "s3".to_string() } else { "local".to_string() }
+    });
+    // Falling back to another backend would leave files where nothing expects them
+    file_store_by_name(&backend).unwrap_or_else(|e| panic!("Could not set up {} file storage: {}", backend, e))
+}
+
+// Backends are configured through environment variables, so they can be built from their name alone
+pub fn file_store_by_name(name: &str) -> anyhow::Result<Arc<dyn FileStore>> {
+    match name {
+        "local" => {
+            let data_folder_str = std::env::var("DATA_ROOT").unwrap_or("data".to_string());
+            Ok(Arc::new(LocalFs::new(Path::new(&data_folder_str).join("images"))))
+        },
+        "s3" => Ok(Arc::new(S3Store::new_env()?)),
+        "memory" => {
+            warn!("Using in-memory file storage, archived files will be lost on exit");
+            Ok(Arc::new(MemoryStore::new()))
+        },
+        other => Err(anyhow::anyhow!("Unknown file storage '{}', expected one of local, s3, memory", other))
+    }
+}
+
+pub struct LocalFs {
+    root: PathBuf,
+}
+
+impl LocalFs {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+    // Keys that could point outside of root are refused
+    fn path(&self, key: &str) -> std::io::Result<PathBuf> {
+        if !is_valid_key(key) {
+            return Err(std::io::Error::new(ErrorKind::InvalidInput, format!("Invalid file key {}", key)))
+        }
+        Ok(self.root.join(key))
+    }
+}
+
+#[async_trait]
+impl FileStore for LocalFs {
+    fn name(&self) -> &str {
+        "local"
+    }
+    async fn put(&self, key: &str, bytes: Bytes) -> anyhow::Result<()> {
+        let path = self.path(key)?;
+        if let Some(folder) = path.parent() {
+            create_dir_all(folder).await?;
+        }
+        File::create(&path).await?.write_all(&bytes).await?;
+        Ok(())
+    }
+    async fn get(&self, key: &str) -> anyhow::Result<Option<Bytes>> {
+        match tokio::fs::read(self.path(key)?).await {
+            Ok(data) => Ok(Some(Bytes::from(data))),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into())
+        }
+    }
+    async fn delete(&self, key: &str) -> anyhow::Result<bool> {
+        match tokio::fs::remove_file(self.path(key)?).await {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into())
+        }
+    }
+    async fn stat(&self, key: &str) -> anyhow::Result<Option<FileStat>> {
+        match tokio::fs::metadata(self.path(key)?).await {
+            Ok(metadata) => Ok(Some(FileStat { size: metadata.len() })),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into())
+        }
+    }
+    async fn stream(&self, key: &str) -> anyhow::Result<Option<ByteStream>> {
+        match File::open(self.path(key)?).await {
+            Ok(file) => Ok(Some(ReaderStream::new(file).boxed())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into())
+        }
+    }
+    fn local_path(&self, key: &str) -> Option<PathBuf> {
+        self.path(key).ok()
+    }
+}
+
+pub struct S3Store {
+    bucket: Bucket,
+}
+
+impl S3Store {
+    pub fn new_env() -> anyhow::Result<Self> {
+        let var = |name: &str| std::env::var(name).map_err(|_| anyhow::anyhow!("{} must be set to use S3 storage", name));
+        let credentials = Credentials::new(Some(&var("S3_ACCESS_KEY_ID")?), Some(&var("S3_SECRET_ACCESS_KEY")?), None, None, None)?;
+        let region = Region::Custom {
+            region: var("S3_REGION")?,
+            endpoint: var("S3_ENDPOINT")?,
+        };
+        let mut bucket = Bucket::new(&var("S3_BUCKET")?, region, credentials)?.with_path_style();
+        bucket.add_header("x-amz-acl", "public-read");
+        bucket.add_header("Content-Disposition", "inline");
+        Ok(Self { bucket })
+    }
+    // Objects have always been stored under their URL path
+    fn object_path(key: &str) -> String {
+        format!("/img/{}", key)
+    }
+}
+
+#[async_trait]
+impl FileStore for S3Store {
+    fn name(&self) -> &str {
+        "s3"
+    }
+    async fn put(&self, key: &str, bytes: Bytes) -> anyhow::Result<()> {
+        let path = Self::object_path(key);
+        let response_data = self.bucket.put_object(&path, &bytes).await?;
+        match response_data.status_code() {
+            200 => Ok(()),
+            code => Err(anyhow::anyhow!("Object storage responded with status {} to upload of {}", code, path))
+        }
+    }
+    async fn get(&self, key: &str) -> anyhow::Result<Option<Bytes>> {
+        let path = Self::object_path(key);
+        match self.bucket.get_object(&path).await {
+            Ok(response_data) => match response_data.status_code() {
+                200 => Ok(Some(response_data.bytes().clone())),
+                404 => Ok(None),
+                code => Err(anyhow::anyhow!("Object storage responded with status {} to request for {}", code, path))
+            },
+            Err(S3Error::Http(404, _)) => Ok(None),
+            Err(e) => Err(e.into())
+        }
+    }
+    async fn delete(&self, key: &str) -> anyhow::Result<bool> {
+        match self.bucket.delete_object(Self::object_path(key)).await {
+            Ok(_) => Ok(true),
+            Err(S3Error::Http(404, _)) => Ok(false),
+            Err(e) => Err(e.into())
+        }
+    }
+    async fn stat(&self, key: &str) -> anyhow::Result<Option<FileStat>> {
+        let path = Self::object_path(key);
+        match self.bucket.head_object(&path).await {
+            Ok((head, 200)) => Ok(Some(FileStat { size: head.content_length.unwrap_or_default().max(0) as u64 })),
+            Ok((_, 404)) | Err(S3Error::Http(404, _)) => Ok(None),
+            Ok((_, code)) => Err(anyhow::anyhow!("Object storage responded with status {} to request for {}", code, path)),
+            Err(e) => Err(e.into())
+        }
+    }
+    async fn stream(&self, key: &str) -> anyhow::Result<Option<ByteStream>> {
+        // rust-s3's object streams can't be sent across threads, so the file is fetched whole
+        Ok(self.get(key).await?.map(|bytes| stream::once(async { Ok(bytes) }).boxed()))
+    }
+}
+
+/**
+ * Keeps files in memory. Nothing is persisted, this is meant for testing.
+ */
+#[derive(Default)]
+pub struct MemoryStore {
+    files: DashMap<String, Bytes>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl FileStore for MemoryStore {
+    fn name(&self) -> &str {
+        "memory"
+    }
+    async fn put(&self, key: &str, bytes: Bytes) -> anyhow::Result<()> {
+        self.files.insert(key.to_string(), bytes);
+        Ok(())
+    }
+    async fn get(&self, key: &str) -> anyhow::Result<Option<Bytes>> {
+        Ok(self.files.get(key).map(|b| b.clone()))
+    }
+    async fn delete(&self, key: &str) -> anyhow::Result<bool> {
+        Ok(self.files.remove(key).is_some())
+    }
+    async fn stat(&self, key: &str) -> anyhow::Result<Option<FileStat>> {
+        Ok(self.files.get(key).map(|b| FileStat { size: b.len() as u64 }))
+    }
+    async fn stream(&self, key: &str) -> anyhow::Result<Option<ByteStream>> {
+        Ok(self.get(key).await?.map(|bytes| stream::once(async { Ok(bytes) }).boxed()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::TryStreamExt;
+
+    async fn check_store(store: &dyn FileStore) {
+        let key = "full/ab/c/abc.png";
+        assert!(!store.exists(key).await.unwrap());
+        assert!(store.get(key).await.unwrap().is_none());
+        store.put(key, Bytes::from_static(b"image")).await.unwrap();
+        assert!(store.exists(key).await.unwrap());
+        assert_eq!(store.stat(key).await.unwrap().unwrap().size, 5);
+        assert_eq!(store.get(key).await.unwrap().unwrap(), Bytes::from_static(b"image"));
+        let streamed: Vec<Bytes> = store.stream(key).await.unwrap().unwrap().try_collect().await.unwrap();
+        assert_eq!(streamed.concat(), b"image");
+        assert!(store.delete(key).await.unwrap());
+        assert!(!store.delete(key).await.unwrap());
+        assert!(store.stat(key).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_file_stores() {
+        check_store(&MemoryStore::new()).await;
+        let root = std::env::temp_dir().join(format!("mitsuba-test-{:08x}", rand::random::<u32>()));
+        check_store(&LocalFs::new(root.clone())).await;
+        assert!(LocalFs::new(root.clone()).get("full/../../.env").await.is_err());
+        tokio::fs::remove_dir_all(root).await.ok();
+    }
+
+    #[test]
+    fn test_valid_key() {
+        assert!(is_valid_key("thumb/ab/c/abc.jpg"));
+        assert!(!is_valid_key("../secret"));
+        assert!(!is_valid_key("full/ab/../../x"));
+        assert!(!is_valid_key("/etc/passwd"));
+    }
+}
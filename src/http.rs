@@ -1,39 +1,137 @@
-use std::path::Path;
 use std::num::NonZeroU32;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
-use std::io::ErrorKind;
 
 use reqwest::StatusCode;
+use reqwest::header::{HeaderValue, ETAG, LAST_MODIFIED, IF_MODIFIED_SINCE, IF_NONE_MATCH};
 use serde::de::DeserializeOwned;
-use governor::{Quota, RateLimiter, Jitter, state::keyed::DashMapStateStore, clock::QuantaClock};
+use governor::{Quota, RateLimiter, Jitter, DefaultDirectRateLimiter, state::keyed::DashMapStateStore, clock::QuantaClock};
+use dashmap::DashMap;
 use nonzero_ext::nonzero;
 use backoff::{default, ExponentialBackoff};
-use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
-use s3::error::S3Error;
 
 use log::{info, warn, error, debug};
 #[allow(unused_imports)]
 use metrics::{gauge, increment_gauge, decrement_gauge, counter, histogram};
 
-use tokio::fs::create_dir_all;
 use weighted_rs::Weight;
 
-use crate::util::{hash_file, get_file_folder, get_file_url, get_proxy_config, get_host_string};
-use crate::object_storage::ObjectStorage;
+use crate::util::{get_proxy_config, get_host_string};
 
-async fn write_bytes_to_file(filename: &Path, file_bytes: bytes::Bytes) -> anyhow::Result<()> {
-    Ok(File::create(filename).await?.write_all(&file_bytes).await?)
+// How long validators are kept for a URL that isn't fetched again
+const VALIDATORS_TTL: Duration = Duration::from_secs(6 * 60 * 60);
+
+/**
+ * Why fetching something from an upstream, or saving it, failed.
+ * `NotFound` and `Blacklisted` are final, the rest might succeed if the job is retried later.
+ */
+#[derive(Debug)]
+pub enum FetchError {
+    /// The upstream answered 404, the thread or file is gone
+    NotFound,
+    /// Still rate limited (429) after retrying
+    RateLimited,
+    /// The request timed out
+    Timeout,
+    /// Any other bad status code, after retrying
+    Status(StatusCode),
+    /// Connection and other network errors
+    Network(reqwest::Error),
+    /// The response could not be parsed
+    Decode(String),
+    /// A downloaded file could not be written to the file store
+    Storage(String),
+    /// The downloaded file is blacklisted
+    Blacklisted,
+    /// Database errors, or anything else on our side
+    Internal(anyhow::Error),
+}
+
+impl FetchError {
+    pub fn is_permanent(&self) -> bool {
+        matches!(self, FetchError::NotFound | FetchError::Blacklisted)
+    }
+    // Used as a label in metrics
+    pub fn class(&self) -> &'static str {
+        match self {
+            FetchError::NotFound => "not_found",
+            FetchError::RateLimited => "rate_limited",
+            FetchError::Timeout => "timeout",
+            FetchError::Status(_) => "status",
+            FetchError::Network(_) => "network",
+            FetchError::Decode(_) => "decode",
+            FetchError::Storage(_) => "storage",
+            FetchError::Blacklisted => "blacklisted",
+            FetchError::Internal(_) => "internal",
+        }
+    }
+}
+
+impl std::fmt::Display for FetchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FetchError::NotFound => write!(f, "Not found (404)"),
+            FetchError::RateLimited => write!(f, "Rate limited (429)"),
+            FetchError::Timeout => write!(f, "Request timed out"),
+            FetchError::Status(status) => write!(f, "Bad status code: {}", status),
+            FetchError::Network(e) => write!(f, "Network error: {}", e),
+            FetchError::Decode(e) => write!(f, "Failed to decode response: {}", e),
+            FetchError::Storage(e) => write!(f, "Failed to store file: {}", e),
+            FetchError::Blacklisted => write!(f, "File is blacklisted"),
+            FetchError::Internal(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for FetchError {}
+
+impl From<reqwest::Error> for FetchError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            return FetchError::Timeout
+        }
+        match e.status() {
+            Some(StatusCode::NOT_FOUND) => FetchError::NotFound,
+            Some(StatusCode::TOO_MANY_REQUESTS) => FetchError::RateLimited,
+            Some(status) => FetchError::Status(status),
+            None if e.is_decode() => FetchError::Decode(e.to_string()),
+            None => FetchError::Network(e)
+        }
+    }
+}
+
+impl From<anyhow::Error> for FetchError {
+    fn from(e: anyhow::Error) -> Self {
+        FetchError::Internal(e)
+    }
+}
+
+// Headers from a previous response, sent back so the server only answers with the resource if it changed
+#[derive(Debug, Clone, Default)]
+struct CacheValidators {
+    last_modified: Option<HeaderValue>,
+    etag: Option<HeaderValue>,
+}
+
+enum FetchedBytes {
+    Modified(bytes::Bytes, CacheValidators),
+    NotModified,
 }
 
 #[derive(Clone)]
 pub struct HttpClient {
     limiter: Arc<RateLimiter<String, DashMapStateStore<String>, QuantaClock>>,
+    // Per-board limiters for boards that only get a share of the quota, along with that share
+    board_limiters: Arc<DashMap<String, (i32, Arc<DefaultDirectRateLimiter>)>>,
+    quota: NonZeroU32,
+    burst: NonZeroU32,
     jitter: Arc<Jitter>,
     max_time: u64,
+    // Validators of the last response for each URL fetched with `fetch_json_if_modified`, and when it was last fetched
+    validators: Arc<DashMap<String, (CacheValidators, Instant)>>,
+    // When validators of URLs that weren't fetched in a while were last dropped
+    validators_swept: Arc<Mutex<Instant>>,
     rclient: reqwest::Client,
-    oclient: Arc<ObjectStorage>,
 }
 
 impl Default for HttpClient {
@@ -61,13 +159,42 @@ impl HttpClient {
 
         HttpClient {
             limiter:  Arc::new(RateLimiter::dashmap(Quota::per_minute(quota).allow_burst(burst))),
+            board_limiters: Arc::new(DashMap::new()),
+            quota,
+            burst,
             jitter:  Arc::new(Jitter::new(Duration::from_millis(jitter_min), Duration::from_millis(jitter_interval))),
             max_time,
+            validators: Arc::new(DashMap::new()),
+            validators_swept: Arc::new(Mutex::new(Instant::now())),
             rclient,
-            oclient:  Arc::new(ObjectStorage::new()),
         }
     }
 
+    /**
+     * Waits until the board is within its share (percentage) of the request quota.
+     * Boards with a share of 100 or more are only held back by the global rate limiter.
+     */
+    pub async fn until_board_ready(&self, board: &str, share: i32) {
+        if share >= 100 {
+            return
+        }
+        let existing = self.board_limiters.get(board)
+            .filter(|entry| entry.0 == share)
+            .map(|entry| entry.1.clone());
+        let limiter = match existing {
+            Some(limiter) => limiter,
+            None => { // First request for this board, or its share was changed
+                let percent = share.max(1) as u32;
+                let quota = NonZeroU32::new(self.quota.get() * percent / 100).unwrap_or(nonzero!(1u32));
+                let burst = NonZeroU32::new(self.burst.get() * percent / 100).unwrap_or(nonzero!(1u32));
+                let limiter = Arc::new(RateLimiter::direct(Quota::per_minute(quota).allow_burst(burst)));
+                self.board_limiters.insert(board.to_string(), (share, limiter.clone()));
+                limiter
+            }
+        };
+        limiter.until_ready_with_jitter(*self.jitter).await;
+    }
+
     fn new_backoff(&self) -> ExponentialBackoff {
         ExponentialBackoff{
             current_interval: Duration::from_millis(default::INITIAL_INTERVAL_MILLIS),
@@ -81,17 +208,38 @@ impl HttpClient {
         }
     }
 
-    async fn fetch_url_bytes(&self, url: &str, attempt: u64, rlimit_key: &String) -> Result<bytes::Bytes, backoff::Error<reqwest::Error>> {
+    async fn fetch_url_bytes(&self, url: &str, attempt: u64, rlimit_key: &String, validators: Option<&CacheValidators>) -> Result<FetchedBytes, backoff::Error<reqwest::Error>> {
         self.limiter.until_key_ready_with_jitter(rlimit_key, *self.jitter).await; // wait for rate limiter
+        let mut request = self.rclient.get(url);
+        if let Some(validators) = validators {
+            if let Some(last_modified) = &validators.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified.clone());
+            }
+            if let Some(etag) = &validators.etag {
+                request = request.header(IF_NONE_MATCH, etag.clone());
+            }
+        }
         increment_gauge!("http_requests_running", 1.0);
         let s = Instant::now();
-        let resp = self.rclient.get(url).send().await.map_err(backoff::Error::transient)?;
+        let resp = request.send().await.map_err(backoff::Error::transient)?;
         histogram!("http_request_duration", s.elapsed().as_millis() as f64);
         decrement_gauge!("http_requests_running", 1.0);
         
         info!("Fetching: {} (Attempt {})", url, attempt);
         match resp.status() {
-            StatusCode::OK => Ok(resp.bytes().await.map_err(backoff::Error::transient)?),
+            StatusCode::OK => {
+                let new_validators = CacheValidators {
+                    last_modified: resp.headers().get(LAST_MODIFIED).cloned(),
+                    etag: resp.headers().get(ETAG).cloned(),
+                };
+                let bytes = resp.bytes().await.map_err(backoff::Error::transient)?;
+                Ok(FetchedBytes::Modified(bytes, new_validators))
+            },
+            StatusCode::NOT_MODIFIED if validators.is_some() => {
+                debug!("Not modified: {}", url);
+                counter!("http_not_modified", 1);
+                Ok(FetchedBytes::NotModified)
+            },
             StatusCode::NOT_FOUND => {
                 error!("Error fetching {} (Status: 404)", url);
                 counter!("http_404", 1);
@@ -105,67 +253,88 @@ impl HttpClient {
         }
     }
 
-    async fn fetch_url_backoff(&self, url: &str, rlimit_key: &String) -> Result<bytes::Bytes, reqwest::Error> {
+    async fn fetch_url_backoff(&self, url: &str, rlimit_key: &String, validators: Option<&CacheValidators>) -> Result<FetchedBytes, reqwest::Error> {
         let back = self.new_backoff();
         let mut attempt: u64 = 0;
-        let bytes = backoff::future::retry(back, || {
+        let fetched = backoff::future::retry(back, || {
             attempt += 1;
             debug!("Scheduling: {} (Attempt {})", url, attempt);
-            self.fetch_url_bytes(url, attempt, rlimit_key)
+            self.fetch_url_bytes(url, attempt, rlimit_key, validators)
         }).await?;
-        counter!("bytes_fetched", bytes.len() as u64);
-        Ok(bytes)
+        if let FetchedBytes::Modified(bytes, _) = &fetched {
+            counter!("bytes_fetched", bytes.len() as u64);
+        }
+        Ok(fetched)
     }
 
-    // Returns Err(true) if error was 404 (non recoverable)
-    pub async fn fetch_json<T: DeserializeOwned>(&self, url: &str) -> Result<T, bool> {
-        let bytes = self.fetch_url_backoff(url, &"api".to_string()).await
-        .map_err(|e| e.status().unwrap_or(StatusCode::OK) == StatusCode::NOT_FOUND)?;
-        
-        let obj = serde_json::from_slice(&bytes)
-        .map_err(|e| {error!("Failed to deserialize {} Error: {}", url, e); false})?;
-        Ok(obj)
-    }
-    async fn save_file(&self, bytes: bytes::Bytes, ext: &String, is_thumb: bool) -> Option<String> {
-        let hash = hash_file(&bytes);
-        let folder = get_file_folder(&hash, is_thumb);
-        create_dir_all(&folder).await.ok();
-        let filename = folder.join(hash.clone() + ext);
-        match write_bytes_to_file(&filename, bytes).await {
-            Ok(()) => Some(hash),
-            Err(msg) => {
-                error!("Could not write to file {}: {}", filename.to_str().unwrap_or_default(), msg);
-                None
-            }
+    fn parse_json<T: DeserializeOwned>(&self, url: &str, bytes: &[u8]) -> Result<T, FetchError> {
+        serde_json::from_slice(bytes)
+        .map_err(|e| {error!("Failed to deserialize {} Error: {}", url, e); FetchError::Decode(e.to_string())})
+    }
+
+    pub async fn fetch_json<T: DeserializeOwned>(&self, url: &str) -> Result<T, FetchError> {
+        match self.fetch_url_backoff(url, &"api".to_string(), None).await? {
+            FetchedBytes::Modified(bytes, _) => self.parse_json(url, &bytes),
+            // Only sent back to conditional requests
+            FetchedBytes::NotModified => Err(FetchError::Status(StatusCode::NOT_MODIFIED))
         }
     }
-    async fn upload_file(&self, bytes: bytes::Bytes, ext: &String, is_thumb: bool) -> Option<String> {
-        let hash = hash_file(&bytes);
-        let filename = get_file_url(&hash, &ext, is_thumb);
-        info!("Uploading: {}", filename);
-        if let Some(response_data) = self.oclient.bucket.put_object(filename.clone(), &bytes).await
-        .map_err(|e| {error!("Error uploading file ({}) to object storage: {}", filename, e);})
-        .ok() {
-            let code = response_data.status_code();
-            if code == 200 {
-                return Some(hash);
+
+    /**
+     * Same as `fetch_json`, but remembers the `Last-Modified` and `ETag` headers of the response,
+     * and sends them back the next time the same URL is fetched.
+     * Returns Ok(None) if the server answered that nothing changed since then (304).
+     * The request counts against the board's share of the quota (see `until_board_ready`), whether or not it comes back modified.
+     * If the caller fails to handle the result, it should call `forget_validators`, so the next fetch gets the full response again.
+     */
+    pub async fn fetch_json_if_modified<T: DeserializeOwned>(&self, url: &str, board: &str, share: i32) -> Result<Option<T>, FetchError> {
+        let validators = self.validators.get(url).map(|v| v.0.clone());
+        self.until_board_ready(board, share).await;
+        match self.fetch_url_backoff(url, &"api".to_string(), validators.as_ref()).await? {
+            FetchedBytes::Modified(bytes, new_validators) => {
+                let obj = self.parse_json(url, &bytes)?;
+                if new_validators.last_modified.is_some() || new_validators.etag.is_some() {
+                    self.validators.insert(url.to_string(), (new_validators, Instant::now()));
+                    self.sweep_validators();
+                } else {
+                    self.validators.remove(url);
+                }
+                Ok(Some(obj))
+            },
+            FetchedBytes::NotModified => {
+                if let Some(mut entry) = self.validators.get_mut(url) {
+                    entry.1 = Instant::now();
+                }
+                Ok(None)
             }
-            error!("Error response code from object storage after upload request ({}): {}", filename, code);
         }
-        None
     }
 
-    pub async fn download_file_checksum(&self, url: &String, ext: &String, is_thumb: bool) -> Result<String, ()> {
-        let bytes = match self.fetch_url_backoff(url, &"download".to_string()).await {
-            Ok(b) => b,
+    /**
+     * Drops the validators of URLs that weren't fetched for `VALIDATORS_TTL`, such as threads that were pruned without being archived.
+     * Their next fetch, if there is one, just gets the full response again.
+     */
+    fn sweep_validators(&self) {
+        let mut swept = self.validators_swept.lock().unwrap();
+        if swept.elapsed() < VALIDATORS_TTL {
+            return
+        }
+        *swept = Instant::now();
+        self.validators.retain(|_, (_, fetched)| fetched.elapsed() < VALIDATORS_TTL);
+        gauge!("http_validators", self.validators.len() as f64);
+    }
+
+    pub fn forget_validators(&self, url: &str) {
+        self.validators.remove(url);
+    }
+    pub async fn download_file(&self, url: &String, is_thumb: bool) -> Result<bytes::Bytes, FetchError> {
+        let bytes = match self.fetch_url_backoff(url, &"download".to_string(), None).await {
+            Ok(FetchedBytes::Modified(b, _)) => b,
+            // Only sent back to conditional requests
+            Ok(FetchedBytes::NotModified) => return Err(FetchError::Status(StatusCode::NOT_MODIFIED)),
             Err(err) => {
                 error!("Failed to download {} Error: {}", url, err);
-                if let Some(status) = err.status() {
-                    if status == StatusCode::NOT_FOUND {
-                        return Ok("".to_string());
-                    }
-                }
-                return Err(())
+                return Err(err.into())
             }
         };
         if is_thumb {
@@ -173,43 +342,81 @@ impl HttpClient {
         } else {
             histogram!("http_size_file", bytes.len() as f64);
         }
-        if self.oclient.enabled {
-            self.upload_file(bytes, ext, is_thumb).await.ok_or(())
-        } else {
-            self.save_file(bytes, ext, is_thumb).await.ok_or(())
-        }
+        Ok(bytes)
     }
+}
 
-    pub async fn delete_downloaded_file(&self, hash: &String, ext: &String, is_thumb: bool) -> anyhow::Result<()> {
-        if self.oclient.enabled {
-            let filename = get_file_url(hash, ext, is_thumb);
-            match self.oclient.bucket.delete_object(filename.clone()).await {
-                Ok(_) => {},
-                Err(S3Error::Http(404, _)) => {
-                    warn!("File {} not found on object storage", filename);
-                },
-                Err(e) => {
-                    error!("Failed to delete file {} from object storage: {}", filename, e);
-                    return Err(e.into());
-                }
-            }
-        } else {
-            let folder = get_file_folder(hash, is_thumb);
-            let filename = folder.join(hash.clone() + ext);
-            match tokio::fs::remove_file(&filename).await {
-                Ok(_) => {},
-                Err(e) if e.kind() == ErrorKind::NotFound => {
-                    warn!("File {} not found on disk storage", filename.to_str().unwrap_or_default());
-                },
-                Err(e) => {
-                    error!("Failed to delete file {} from disk storage: {}", filename.to_str().unwrap_or_default(), e);
-                    return Err(e.into());
-                }
+impl std::panic::UnwindSafe for HttpClient {}
+impl std::panic::RefUnwindSafe for HttpClient {}
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // Response bodies by path, which tests can change while the server runs
+    pub(crate) type MockRoutes = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+    /**
+     * Serves `routes` on a local port, ignoring query strings, and 404 for anything else. Returns its base URL.
+     * Responses have an ETag, requests that send it back get a 304 if the body didn't change.
+     */
+    pub(crate) async fn mock_server(routes: MockRoutes) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::task::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let routes = routes.clone();
+                tokio::task::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buf[..n])
+                        }
+                    }
+                    let request = String::from_utf8_lossy(&request);
+                    let path = request.split_whitespace().nth(1).unwrap_or("/");
+                    let path = path.split('?').next().unwrap_or(path);
+                    let if_none_match = request.lines()
+                        .find_map(|line| line.to_ascii_lowercase().strip_prefix("if-none-match:").map(|v| v.trim().to_string()));
+                    let body = routes.lock().unwrap().get(path).cloned();
+                    let (status, etag, body) = match body {
+                        Some(body) => {
+                            let mut hasher = DefaultHasher::new();
+                            body.hash(&mut hasher);
+                            let etag = format!("\"{:x}\"", hasher.finish());
+                            if if_none_match.as_ref() == Some(&etag) {
+                                ("304 Not Modified", etag, Vec::new())
+                            } else {
+                                ("200 OK", etag, body)
+                            }
+                        },
+                        None => ("404 Not Found", String::new(), Vec::new())
+                    };
+                    let head = format!("HTTP/1.1 {}\r\nETag: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, etag, body.len());
+                    stream.write_all(head.as_bytes()).await.ok();
+                    stream.write_all(&body).await.ok();
+                });
             }
-        }
-        Ok(())
+        });
+        url
     }
-}
 
-impl std::panic::UnwindSafe for HttpClient {}
-impl std::panic::RefUnwindSafe for HttpClient {}
\ No newline at end of file
+    #[tokio::test]
+    async fn test_revalidation_quota() {
+        let routes: MockRoutes = Arc::new(Mutex::new(HashMap::from([("/a/thread/1.json".to_string(), b"[1]".to_vec())])));
+        let url = format!("{}/a/thread/1.json", mock_server(routes.clone()).await);
+        // The board gets a single request a minute, which the first fetch uses up
+        let client = HttpClient::new(nonzero!(600u32), nonzero!(60u32), 0, 1, 10);
+        assert_eq!(Some(vec![1]), client.fetch_json_if_modified::<Vec<i64>>(&url, "a", 1).await.unwrap());
+        // Upstream counts answers that nothing changed too, so they have to wait for the board's share as well
+        let revalidation = client.fetch_json_if_modified::<Vec<i64>>(&url, "a", 1);
+        assert!(tokio::time::timeout(Duration::from_secs(2), revalidation).await.is_err());
+        // The validators are kept for the next fetch
+        assert!(client.validators.contains_key(&url));
+    }
+}
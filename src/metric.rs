@@ -42,13 +42,22 @@ fn register_metrics() {
     describe_histogram!("http_size_file", Unit::Bytes, "File sizes");
     describe_histogram!("http_size_thumbnail", Unit::Bytes, "Thumbnail sizes");
     describe_counter!("thread_archived_jobs_scheduled", "Total number of archived threads scheduled for retrieval");
+    describe_counter!("thread_filtered", "Threads skipped because of the board's thread filters");
+    describe_counter!("thread_catalog_updates", "Thread changes saved from the catalog, without fetching the thread");
     describe_counter!("files_fetched", "Total number of files fetched");
     describe_counter!("thumbnails_fetched", "Total number of thumbnails fetched");
+    describe_counter!("thumbnails_generated", "Thumbnails made from full files, because upstream didn't have them");
     describe_counter!("file_jobs_scheduled", "Total number of file jobs that were scheduled");
     describe_counter!("threads_fetched", "Number of times threads were fetched");
     describe_counter!("thread_404", "Total number of http 404 threads");
     describe_counter!("http_404", "Total number of http 404 errors");
+    describe_counter!("http_not_modified", "Total number of conditional requests answered with 304 Not Modified");
     describe_counter!("http_warn", "Total number of http non-404 error codes");
+    describe_counter!("board_scan_errors", "Failed board scans, labeled by error class");
+    describe_counter!("thread_job_errors", "Failed thread jobs, labeled by error class");
+    describe_counter!("file_job_errors", "Failed file jobs, labeled by error class. Not found and blacklisted files are not retried");
+    describe_counter!("orphan_files_deleted", "Files deleted by the orphaned file collector");
+    describe_counter!("orphan_bytes_reclaimed", Unit::Bytes, "Storage space freed by the orphaned file collector");
     describe_counter!("bytes_fetched", Unit::Bytes, "Total number of bytes fetched");
     describe_counter!("post_writes", "Posts inserted or updated to database");
     describe_counter!("thread_job_writes", "Thread jobs inserted or updated to database");
@@ -56,6 +65,9 @@ fn register_metrics() {
     describe_gauge!("files_stored", "Stored files");
     describe_gauge!("thumbnails_stored", "Stored thumbnails");
     describe_gauge!("thumbnails_missing", "Missing thumbnails");
+    describe_gauge!("storage_files_missing", "Files missing from storage, as of the last storage verification");
+    describe_gauge!("storage_files_corrupted", "Files that don't match their hash, as of the last storage verification");
+    describe_gauge!("orphan_files_waiting", "Files no post uses that are still in their grace period");
     describe_gauge!("thread_jobs_running", "Number of thread jobs running at any given time");
     describe_gauge!("file_jobs_running", "Number of file jobs running at any given time");
     describe_gauge!("http_requests_running", "Number of http requests currently being executed");
@@ -1,4 +1,4 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::string::String;
 use std::str::FromStr;
 use sqlx::Type;
@@ -7,6 +7,8 @@ use sqlx::encode::{Encode, IsNull};
 use sqlx::error::BoxDynError;
 use sqlx::postgres::{PgTypeInfo, PgValueRef, Postgres, PgArgumentBuffer};
 
+use crate::upstream::DEFAULT_UPSTREAM;
+
 #[derive(Debug, Clone, Deserialize, Serialize, Default, Eq, PartialEq, Hash)]
 pub struct Post {
     #[serde(skip)]
@@ -37,7 +39,7 @@ pub struct Post {
     pub sub: String,
     #[serde(default, skip_serializing_if = "is_empty_string")]
     pub com: String,
-    #[serde(default, skip_serializing_if = "is_zero")]
+    #[serde(default, skip_serializing_if = "is_zero", deserialize_with = "int_or_string")]
     pub tim: i64,
     #[serde(default, skip_serializing_if = "is_empty_string")]
     pub filename: String,
@@ -89,6 +91,9 @@ pub struct Post {
     pub file_sha256: Option<String>,
     #[serde(default, skip_serializing_if = "is_empty_string_or_none")]
     pub thumbnail_sha256: Option<String>,
+    // Thumbnails from upstream are .jpg, generated ones can be something else
+    #[serde(default, skip_serializing_if = "is_empty_string_or_none")]
+    pub thumbnail_ext: Option<String>,
     #[serde(default, skip_serializing_if = "is_zero")]
     pub deleted_on: i64,
     #[serde(default, skip_serializing_if = "is_false")]
@@ -98,9 +103,87 @@ pub struct Post {
     #[serde(default, skip_serializing_if = "is_false_or_none")]
     pub mitsuba_file_hidden: Option<bool>,
     #[serde(default, skip_serializing_if = "is_false_or_none")]
+    pub mitsuba_file_blacklisted: Option<bool>,
+    #[serde(default, skip_serializing_if = "ExtraFiles::is_empty")]
+    pub extra_files: ExtraFiles
+}
+
+/**
+ * An attachment after the first one, which is described by the fields of the post itself.
+ * Same format as vichan's `extra_files`. The hashes and moderation flags come from our database, not upstream.
+ */
+#[derive(Debug, Clone, Deserialize, Serialize, Default, Eq, PartialEq, Hash)]
+pub struct PostFile {
+    // vichan names extra files after the first one, eg. 1583512345678-1
+    #[serde(deserialize_with = "string_or_int")]
+    pub tim: String,
+    #[serde(default)]
+    pub filename: String,
+    pub ext: String,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub fsize: i64,
+    #[serde(default, skip_serializing_if = "is_empty_string")]
+    pub md5: String,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub w: i64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub h: i64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub tn_w: i64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub tn_h: i64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub spoiler: i64,
+    #[serde(default, skip_serializing_if = "is_empty_string_or_none")]
+    pub file_sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "is_empty_string_or_none")]
+    pub thumbnail_sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "is_empty_string_or_none")]
+    pub thumbnail_ext: Option<String>,
+    #[serde(default, skip_serializing_if = "is_false_or_none")]
+    pub mitsuba_file_hidden: Option<bool>,
+    #[serde(default, skip_serializing_if = "is_false_or_none")]
     pub mitsuba_file_blacklisted: Option<bool>
 }
 
+/**
+ * A post's extra attachments, stored as JSON in `posts.extra_files`.
+ * Only what upstream sent is stored, see `PostFile::without_archive_fields`.
+ */
+#[derive(Debug, Clone, Deserialize, Serialize, Default, Eq, PartialEq, Hash)]
+#[serde(transparent)]
+pub struct ExtraFiles(pub Vec<PostFile>);
+
+impl ExtraFiles {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    pub fn to_json(&self) -> serde_json::Value {
+        let files: Vec<PostFile> = self.0.iter().map(|f| f.without_archive_fields()).collect();
+        serde_json::to_value(files).unwrap_or_default()
+    }
+}
+
+// Lets sqlx read the column straight into Post
+impl From<serde_json::Value> for ExtraFiles {
+    fn from(value: serde_json::Value) -> Self {
+        serde_json::from_value(value).unwrap_or_default()
+    }
+}
+
+impl PostFile {
+    pub fn without_archive_fields(&self) -> Self {
+        Self {
+            file_sha256: None,
+            thumbnail_sha256: None,
+            thumbnail_ext: None,
+            mitsuba_file_hidden: None,
+            mitsuba_file_blacklisted: None,
+            ..self.clone()
+        }
+    }
+}
+
 fn is_empty_string(s: &String) -> bool {
     s.is_empty()
 }
@@ -127,6 +210,33 @@ fn is_false(b: &bool) -> bool {
     !*b
 }
 
+// vichan serves some numeric fields, like tim, as strings
+fn int_or_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<i64, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum IntOrString {
+        Int(i64),
+        Str(String)
+    }
+    match IntOrString::deserialize(deserializer)? {
+        IntOrString::Int(i) => Ok(i),
+        IntOrString::Str(s) => s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+fn string_or_int<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum IntOrString {
+        Int(i64),
+        Str(String)
+    }
+    match IntOrString::deserialize(deserializer)? {
+        IntOrString::Int(i) => Ok(i.to_string()),
+        IntOrString::Str(s) => Ok(s)
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, Default)]
 pub struct PostUpdate {
     pub closed: i64,
@@ -168,10 +278,35 @@ pub struct Thread {
     pub posts: Vec<Post>,
 }
 
+// From /catalog.json endpoint
 #[derive(Debug, Clone, Deserialize, Serialize)]
-pub struct ThreadsPage {
+pub struct CatalogPage {
     pub page: i64,
-    pub threads: Vec<ThreadInfo>
+    pub threads: Vec<CatalogThread>
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CatalogThread {
+    #[serde(flatten)]
+    pub op: Post,
+    /// The latest few replies. Not every upstream includes them.
+    #[serde(default)]
+    pub last_replies: Vec<Post>
+}
+
+impl CatalogThread {
+    pub fn thread_info(&self, board: &str, page: i32) -> ThreadInfo {
+        ThreadInfo {
+            board: board.to_string(),
+            no: self.op.no,
+            last_modified: self.op.last_modified,
+            replies: self.op.replies,
+            page,
+            sub: Some(self.op.sub.clone()),
+            com: Some(self.op.com.clone()),
+            sticky: self.op.sticky
+        }
+    }
 }
 
 #[derive(Debug, Clone, Default, Deserialize, Serialize, Hash)]
@@ -182,7 +317,13 @@ pub struct ThreadInfo {
     pub last_modified: i64,
     pub replies: i64,
     #[serde(default)]
-    pub page: i32
+    pub page: i32,
+    #[serde(default)]
+    pub sub: Option<String>,
+    #[serde(default)]
+    pub com: Option<String>,
+    #[serde(default)]
+    pub sticky: i64
 }
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct ThreadJob {
@@ -191,13 +332,18 @@ pub struct ThreadJob {
     pub no: i64,
     pub last_modified: i64,
     pub replies: i64,
-    pub page: i32
+    pub page: i32,
+    pub last_seen: i64,
+    pub leased_by: Option<String>,
+    pub lease_expires: i64
 }
 
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct ImageInfo {
     pub board: String,
     pub no: i64,
+    // Position of the attachment in the post, 0 for the post's own file
+    pub idx: i32,
     pub url: String,
     pub thumbnail_url: String,
     pub ext: String,
@@ -210,12 +356,16 @@ pub struct ImageJob {
     pub id: i64,
     pub board: String,
     pub no: i64,
+    pub idx: i32,
     pub url: String,
     pub thumbnail_url: String,
     pub ext: String,
     pub page: i32,
     pub file_sha256: Option<String>,
-    pub thumbnail_sha256: Option<String>
+    pub thumbnail_sha256: Option<String>,
+    pub last_seen: i64,
+    pub leased_by: Option<String>,
+    pub lease_expires: i64
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, Eq, PartialEq)]
@@ -225,12 +375,74 @@ pub struct Image {
     pub thumbnail: bool,
     pub full_image: bool
 }
-#[derive(Debug, Clone, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
 pub struct Board {
     pub name: String,
     pub full_images: bool,
     pub archive: bool,
     pub enable_search: bool,
+    pub upstream: String,
+    /// Jobs from boards with higher priority are fetched first
+    pub priority: i32,
+    /// Seconds between polls of the board's thread list
+    pub poll_interval: i32,
+    /// Max thread jobs for this board running at once, across all workers
+    pub max_thread_jobs: i32,
+    /// Percentage of the request rate limit this board can use
+    pub rate_limit_share: i32,
+    /// Postgres text search configuration for this board, like 'english' or 'simple'
+    pub search_language: String,
+}
+impl Default for Board {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            full_images: false,
+            archive: false,
+            enable_search: false,
+            upstream: DEFAULT_UPSTREAM.to_string(),
+            priority: 0,
+            poll_interval: 10,
+            max_thread_jobs: 20,
+            rate_limit_share: 100,
+            search_language: "english".to_string(),
+        }
+    }
+}
+impl Board {
+    // Settings that would stall the board or the whole archiver if zero or negative
+    pub fn validate_settings(&self) -> Result<(), &'static str> {
+        if self.poll_interval < 1 {
+            return Err("poll_interval must be at least 1 second")
+        }
+        if self.max_thread_jobs < 1 {
+            return Err("max_thread_jobs must be at least 1")
+        }
+        if !(1..=100).contains(&self.rate_limit_share) {
+            return Err("rate_limit_share must be between 1 and 100")
+        }
+        Ok(())
+    }
+}
+/**
+ * Decides which threads of a board get archived.
+ * A thread matches a rule if it meets every condition set on it.
+ * When a board has include rules, only threads matching at least one of them are archived.
+ * Threads matching any exclude rule are never archived.
+ */
+#[derive(Debug, Clone, Default, Deserialize, Serialize, Eq, PartialEq)]
+pub struct ThreadFilter {
+    #[serde(default)]
+    pub id: i64,
+    pub board: String,
+    #[serde(default)]
+    pub exclude: bool,
+    /// Regex tested against the subject and comment of the OP
+    pub pattern: Option<String>,
+    #[serde(default)]
+    pub min_replies: i64,
+    #[serde(default)]
+    pub sticky_only: bool,
 }
 // From /boards.json endpoint
 #[derive(Debug, Clone, Deserialize, Serialize, Default, Eq, PartialEq)]
@@ -300,6 +512,22 @@ pub struct IndexSearchResults {
     pub total_results: i64
 }
 
+// Posts that used a file, and when it was first and last posted
+#[derive(Debug, Clone, Deserialize, Serialize, Default, Eq, PartialEq)]
+pub struct FileLookupResults {
+    pub first_seen: Option<i64>,
+    pub last_seen: Option<i64>,
+    pub total_results: i64,
+    pub posts: Vec<Post>
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, Default, Eq, PartialEq)]
+pub struct SimilarImageResults {
+    // Perceptual hash of the searched image, as hex
+    pub phash: String,
+    pub posts: Vec<Post>
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, Default, Eq, PartialEq)]
 pub struct IndexThread {
     pub posts: Vec<IndexPost>
@@ -369,6 +597,186 @@ pub struct PurgeReport {
     pub removed_posts: u64,
 }
 
+#[derive(Debug, Clone, Default, Deserialize, Serialize, Eq, PartialEq)]
+pub struct OrphanReport {
+    // Files past their grace period that were deleted, or would be in a dry run
+    pub deleted: u64,
+    pub bytes_reclaimed: u64,
+    pub failed: u64,
+    // Orphaned files still in their grace period
+    pub waiting: u64,
+    // Keys of the files that would be deleted, only filled in a dry run
+    pub files: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, Eq, PartialEq)]
+pub struct StorageMigrationReport {
+    pub copied: u64,
+    // Already present in the target
+    pub skipped: u64,
+    // Not found in the source, or not matching their hash there
+    pub missing: u64,
+    pub corrupted: u64,
+    // Copies that failed, or did not match their hash once in the target
+    pub failed: u64,
+    pub deleted: u64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ReindexReport {
+    pub boards: u64,
+    pub posts: u64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, Eq, PartialEq)]
+pub struct AsagiImportReport {
+    pub imported: u64,
+    // Posts made on the Asagi archive itself rather than on the board
+    pub skipped_ghosts: u64,
+    // Rows that could not be read
+    pub skipped_invalid: u64,
+    pub files: u64,
+    // Files not found in the media folder
+    pub missing_files: u64,
+    // Posts whose files could not be read or stored, they are logged
+    pub failed_files: u64,
+}
+
+/**
+ * Describes a board export, stored as manifest.json at the start of the bundle.
+ * Threads follow as threads/[no].json, in the same format as the thread API,
+ * each followed by the files it uses under media/, if the export has them.
+ * The bundle ends with moderation.json, a list of `PostModeration`.
+ */
+#[derive(Debug, Clone, Default, Deserialize, Serialize, Eq, PartialEq)]
+pub struct ExportManifest {
+    pub version: i64,
+    pub board: String,
+    // Only threads with posts made, changed, archived or deleted since this time are included, 0 for everything
+    pub since: i64,
+    // When the export started, the `since` of the next incremental export
+    pub exported_at: i64,
+    pub threads: u64,
+    pub media: bool,
+}
+
+/**
+ * Moderation state of a post, for posts of an exported board that are hidden,
+ * or that moderators acted on since the start of the export.
+ * Hidden posts are left out of the threads, this lets the importing instance hide its own copy.
+ */
+#[derive(Debug, Clone, Default, Deserialize, Serialize, Eq, PartialEq)]
+pub struct PostModeration {
+    pub no: i64,
+    pub hidden: bool,
+    pub com_hidden: bool,
+    pub file_hidden: bool,
+    pub file_blacklisted: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, Eq, PartialEq)]
+pub struct ExportReport {
+    pub threads: u64,
+    pub posts: u64,
+    pub files: u64,
+    // Files used by posts that were not found in storage
+    pub missing_files: u64,
+    pub moderated_posts: u64,
+    pub exported_at: i64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, Eq, PartialEq)]
+pub struct BundleImportReport {
+    pub threads: u64,
+    pub posts: u64,
+    pub files: u64,
+    // Files that didn't match their hash
+    pub corrupted_files: u64,
+    // Files used by posts that are neither in the bundle nor in storage
+    pub missing_files: u64,
+    // Posts whose moderation state was applied
+    pub moderated_posts: u64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, Eq, PartialEq)]
+pub struct ThreadExportReport {
+    pub posts: u64,
+    pub files: u64,
+    // Files and thumbnails shown in the thread that were not found in storage
+    pub missing_files: u64,
+}
+
+/**
+ * Position in the replication feed: the transaction that made a change, and the change's order within it.
+ * Written as `xid-seq`. The default is the start of the feed.
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FeedCursor {
+    pub xid: i64,
+    pub seq: i64,
+}
+
+impl std::fmt::Display for FeedCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.xid, self.seq)
+    }
+}
+
+impl FromStr for FeedCursor {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (xid, seq) = s.split_once('-').ok_or(())?;
+        Ok(FeedCursor {
+            xid: xid.parse().map_err(|_| ())?,
+            seq: seq.parse().map_err(|_| ())?
+        })
+    }
+}
+
+// A moderation action taken on a post, as seen by instances replicating the board
+#[derive(Debug, Clone, Default, Deserialize, Serialize, Eq, PartialEq)]
+pub struct FeedAction {
+    pub no: i64,
+    pub action: String,
+}
+
+/**
+ * A page of a board's replication feed. Posts are in the same format as the thread API, hidden ones are left out.
+ * Every post changed since the requested cursor is included, in its current state, then the posts deleted since then and the moderation actions taken.
+ */
+#[derive(Debug, Clone, Default, Deserialize, Serialize, Eq, PartialEq)]
+pub struct ChangeFeed {
+    pub posts: Vec<Post>,
+    // Numbers of the posts deleted from the database
+    #[serde(default)]
+    pub deleted: Vec<i64>,
+    pub actions: Vec<FeedAction>,
+    // Where the next page starts
+    pub cursor: String,
+    // More changes are available right away
+    pub more: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, Eq, PartialEq)]
+pub struct ReplicationReport {
+    pub posts: u64,
+    pub deleted: u64,
+    pub files: u64,
+    pub actions: u64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, Eq, PartialEq)]
+pub struct StorageReport {
+    pub checked: u64,
+    pub missing: u64,
+    pub corrupted: u64,
+    // Files that could not be checked because of storage errors
+    pub failed: u64,
+    pub requeued: u64,
+    // Missing or corrupted files whose downloads could not be scheduled
+    pub requeue_failed: u64,
+}
+
 #[derive(Debug, Clone, Default, Deserialize, Serialize, Eq, PartialEq)]
 pub struct User {
     pub name: String,
@@ -585,4 +993,18 @@ impl std::string::ToString for ModActionType {
             ModActionType::UnhidePostFile => "unhide_post_file".to_string(),
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_board_settings() {
+        assert!(Board::default().validate_settings().is_ok());
+        assert!(Board { poll_interval: 0, ..Default::default() }.validate_settings().is_err());
+        assert!(Board { max_thread_jobs: -1, ..Default::default() }.validate_settings().is_err());
+        assert!(Board { rate_limit_share: 0, ..Default::default() }.validate_settings().is_err());
+        assert!(Board { rate_limit_share: 101, ..Default::default() }.validate_settings().is_err());
+        assert!(Board { poll_interval: 1, max_thread_jobs: 1, rate_limit_share: 1, ..Default::default() }.validate_settings().is_ok());
+    }
+}
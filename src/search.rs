@@ -0,0 +1,193 @@
+use serde::Deserialize;
+
+use crate::models::Board;
+use crate::util::{civil_from_days, days_from_civil};
+
+/**
+ * A parsed search query. Free text is matched against post comments with `websearch_to_tsquery`,
+ * so it supports "quoted phrases", -negation and OR. The rest are filters written as `field:value`:
+ * `subject:`, `name:` and `filename:` are full text searches on those fields,
+ * `trip:`, `id:` and `country:` must match exactly,
+ * `has:image`, `is:op`, `is:reply` and `deleted:true` can be negated with a leading `-`,
+ * `after:`, `before:` and `date:` take a date (YYYY-MM-DD, UTC) or a unix timestamp.
+ * Values with spaces can be quoted: `subject:"cool general"`.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PostSearch {
+    pub text: String,
+    pub subject: Option<String>,
+    pub name: Option<String>,
+    pub trip: Option<String>,
+    pub filename: Option<String>,
+    pub id: Option<String>,
+    pub country: Option<String>,
+    pub has_image: Option<bool>,
+    pub is_op: Option<bool>,
+    pub deleted: Option<bool>,
+    // Posts made at or after this time
+    pub after: Option<i64>,
+    // Posts made before this time
+    pub before: Option<i64>,
+}
+
+impl PostSearch {
+    pub fn parse(query: &str) -> Result<Self, String> {
+        let mut search = PostSearch::default();
+        let mut text = Vec::new();
+        for token in tokenize(query) {
+            let (negated, field) = match token.strip_prefix('-') {
+                Some(rest) => (true, rest),
+                None => (false, token.as_str())
+            };
+            let Some((key, value)) = field.split_once(':') else {
+                text.push(token);
+                continue
+            };
+            let value = unquote(value);
+            if value.is_empty() {
+                text.push(token);
+                continue
+            }
+            let string_filter = match key.to_lowercase().as_str() {
+                "subject" | "sub" => Some(&mut search.subject),
+                "name" => Some(&mut search.name),
+                "trip" => Some(&mut search.trip),
+                "filename" => Some(&mut search.filename),
+                "id" => Some(&mut search.id),
+                "country" => Some(&mut search.country),
+                _ => None
+            };
+            if let Some(filter) = string_filter {
+                if negated {
+                    return Err(format!("{}: can't be negated", key))
+                }
+                *filter = Some(value);
+                continue
+            }
+            match (key.to_lowercase().as_str(), value.to_lowercase().as_str()) {
+                ("has", "image" | "file") => search.has_image = Some(!negated),
+                ("is", "op") => search.is_op = Some(!negated),
+                ("is", "reply") => search.is_op = Some(negated),
+                ("deleted", "true" | "yes") => search.deleted = Some(!negated),
+                ("deleted", "false" | "no") => search.deleted = Some(negated),
+                ("after" | "since", _) => search.after = Some(parse_time(&value)?),
+                ("before" | "until", _) => search.before = Some(parse_time(&value)?),
+                ("date", _) => {
+                    let day = parse_time(&value)?;
+                    search.after = Some(day);
+                    search.before = Some(day + 86400);
+                },
+                ("has" | "is" | "deleted", _) => return Err(format!("Unknown value for {}: {}", key, value)),
+                // Not a filter, eg. a URL
+                _ => text.push(token)
+            }
+        }
+        search.text = text.join(" ");
+        Ok(search)
+    }
+}
+
+/**
+ * Query string of searches over several boards, from the API and the search page.
+ */
+#[derive(Debug, Deserialize)]
+pub struct MultiBoardSearchQuery {
+    pub q: Option<String>,
+    // Comma separated, every board with search enabled if missing
+    pub boards: Option<String>,
+    pub page: Option<i64>,
+}
+
+/**
+ * Boards a search runs on: the comma separated `requested` ones, or every board with search enabled.
+ * Fails if one of the requested boards doesn't exist or has search disabled.
+ */
+pub fn searchable_boards(boards: &[Board], requested: Option<&str>) -> Result<Vec<String>, String> {
+    let requested: Vec<&str> = requested.unwrap_or_default()
+        .split(',').map(|b| b.trim()).filter(|b| !b.is_empty()).collect();
+    if requested.is_empty() {
+        return Ok(boards.iter().filter(|b| b.enable_search).map(|b| b.name.clone()).collect())
+    }
+    requested.into_iter().map(|name| {
+        match boards.iter().find(|b| b.name == name) {
+            Some(board) if board.enable_search => Ok(board.name.clone()),
+            _ => Err(format!("Search is not enabled for /{}/", name))
+        }
+    }).collect()
+}
+
+// Splits on whitespace, except inside double quotes
+fn tokenize(query: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    for c in query.chars() {
+        if c == '"' {
+            quoted = !quoted;
+        }
+        if c.is_whitespace() && !quoted {
+            if !current.is_empty() {
+                tokens.push(std::mem::take(&mut current));
+            }
+            continue
+        }
+        current.push(c);
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    tokens
+}
+
+fn unquote(value: &str) -> String {
+    value.trim_matches('"').to_string()
+}
+
+// Unix timestamp, or the start of a YYYY-MM-DD day in UTC
+pub fn parse_time(value: &str) -> Result<i64, String> {
+    if let Ok(timestamp) = value.parse::<i64>() {
+        return Ok(timestamp)
+    }
+    let invalid = || format!("Invalid date: {}, use YYYY-MM-DD", value);
+    let parts: Vec<i64> = value.split('-').map(|p| p.parse::<i64>()).collect::<Result<_, _>>().map_err(|_| invalid())?;
+    let [year, month, day] = parts[..] else {
+        return Err(invalid())
+    };
+    // Days past the end of the month (2024-02-31) roll over into the next one, which gives them away
+    let days = days_from_civil(year, month, day);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || civil_from_days(days) != (year, month, day) {
+        return Err(invalid())
+    }
+    Ok(days * 86400)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_search() {
+        let search = PostSearch::parse(r#"cat "black dog" -bird subject:"cool general" -has:image is:op date:2023-11-14 https://example.com"#).unwrap();
+        assert_eq!(PostSearch {
+            text: r#"cat "black dog" -bird https://example.com"#.to_string(),
+            subject: Some("cool general".to_string()),
+            has_image: Some(false),
+            is_op: Some(true),
+            after: Some(1699920000),
+            before: Some(1700006400),
+            ..Default::default()
+        }, search);
+        assert!(PostSearch::parse("-name:anon").is_err());
+        assert!(PostSearch::parse("after:yesterday").is_err());
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(Ok(0), parse_time("1970-01-01"));
+        assert_eq!(Ok(1709164800), parse_time("2024-02-29"));
+        assert_eq!(Ok(1700000000), parse_time("1700000000"));
+        for invalid in ["2024-02-30", "2024-02-31", "2023-02-29", "2024-04-31", "2024-13-01", "2024-00-10", "2024-01-00", "2024-01", "2024-01-01-01", "jan"] {
+            assert!(parse_time(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
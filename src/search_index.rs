@@ -0,0 +1,614 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use tantivy::collector::{Count, TopDocs};
+use tantivy::directory::MmapDirectory;
+use tantivy::query::{AllQuery, BooleanQuery, Occur, Query, QueryParser, RangeQuery, TermQuery};
+use tantivy::schema::{Field, IndexRecordOption, Schema, TextFieldIndexing, TextOptions, Value, FAST, INDEXED, STORED, STRING};
+use tantivy::tokenizer::{Language, LowerCaser, PreTokenizedString, RemoveLongFilter, SimpleTokenizer, Stemmer, TextAnalyzer, TokenizerManager};
+use tantivy::{Index, IndexReader, IndexWriter, Order, ReloadPolicy, TantivyDocument, TantivyError, Term};
+#[allow(unused_imports)]
+use log::{info, warn, error, debug};
+
+use crate::models::{Board, Post};
+use crate::search::PostSearch;
+
+/**
+ * Where full text search happens. Searches return post ids, the posts themselves are read from the database.
+ * Picked with SEARCH_BACKEND, see `search_index_from_env`.
+ */
+#[async_trait]
+pub trait SearchIndex: Send + Sync {
+    /// Name of the backend, for logs
+    fn name(&self) -> &str;
+    /// Whether posts have to be given to `index_posts`. Postgres indexes them as they are written.
+    fn needs_posts(&self) -> bool {
+        true
+    }
+    /// Ids of the posts on `boards` matching `search`, newest first, and how many match in total.
+    /// With `remove_hidden`, hidden posts don't match, and hidden comments aren't searched.
+    /// Text is matched with each board's `search_language`.
+    async fn search(&self, boards: &[Board], search: &PostSearch, page: i64, page_size: i64, remove_hidden: bool) -> anyhow::Result<(Vec<i64>, i64)>;
+    /// Adds new posts of a board, and replaces the ones already in the index
+    async fn index_posts(&self, board: &Board, posts: &[Post]) -> anyhow::Result<()>;
+    /// Removes posts deleted from the database, by post id
+    async fn remove_posts(&self, post_ids: &[i64]) -> anyhow::Result<()>;
+    /// Removes every post of a board
+    async fn remove_board(&self, board: &str) -> anyhow::Result<()>;
+    /// Makes sure this process can write to the index, before the archiver starts relying on it
+    fn claim_writer(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/**
+ * The backend configured through environment variables.
+ * SEARCH_BACKEND picks `postgres` (default) or `tantivy`, an index kept in the `search` folder of DATA_ROOT.
+ */
+pub fn search_index_from_env(pool: sqlx::PgPool) -> Arc<dyn SearchIndex> {
+    match search_backend_from_env().as_str() {
+        "tantivy" => {
+            let data_folder_str = std::env::var("DATA_ROOT").unwrap_or("data".to_string());
+            match TantivySearch::open(&Path::new(&data_folder_str).join("search")) {
+                Ok(index) => Arc::new(index),
+                Err(e) => {
+                    error!("Failed to open tantivy search index, using postgres: {}", e);
+                    Arc::new(PostgresSearch::new(pool))
+                }
+            }
+        },
+        "postgres" => Arc::new(PostgresSearch::new(pool)),
+        other => {
+            error!("Unknown search backend '{}', expected postgres or tantivy, using postgres", other);
+            Arc::new(PostgresSearch::new(pool))
+        }
+    }
+}
+
+pub fn search_backend_from_env() -> String {
+    std::env::var("SEARCH_BACKEND").unwrap_or("postgres".to_string()).to_lowercase()
+}
+
+/**
+ * Searches with the GIN indexes the `update_search_index` trigger creates for boards with search enabled.
+ */
+pub struct PostgresSearch {
+    pool: sqlx::PgPool,
+}
+
+impl PostgresSearch {
+    pub fn new(pool: sqlx::PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+impl PostgresSearch {
+    // Matches on a single board as (post_id, time), one board at a time so its partial indexes can be used
+    async fn search_board(&self, board: &Board, search: &PostSearch, limit: i64, offset: i64, remove_hidden: bool) -> anyhow::Result<(Vec<(i64, i64)>, i64)> {
+        // Indexes are only used if the configuration is an oid, a text parameter cast to regconfig doesn't match them
+        let config = sqlx::query_scalar!(
+            "SELECT $1::text::regconfig::oid AS \"oid!\"",
+            board.search_language
+        ).fetch_one(&self.pool).await?;
+
+        let total_count = sqlx::query_scalar!(
+            "
+            SELECT COUNT(*)
+            FROM posts
+            WHERE board = $1
+            AND ($2 = '' OR (($14 = false OR mitsuba_com_hidden = false) AND to_tsvector($15::oid::regconfig, com) @@ websearch_to_tsquery($15::oid::regconfig, $2)))
+            AND ($3::text IS NULL OR to_tsvector($15::oid::regconfig, sub) @@ websearch_to_tsquery($15::oid::regconfig, $3))
+            AND ($4::text IS NULL OR to_tsvector($15::oid::regconfig, name) @@ websearch_to_tsquery($15::oid::regconfig, $4))
+            AND ($5::text IS NULL OR to_tsvector($15::oid::regconfig, filename) @@ websearch_to_tsquery($15::oid::regconfig, $5))
+            AND ($6::text IS NULL OR trip = $6)
+            AND ($7::text IS NULL OR id = $7)
+            AND ($8::text IS NULL OR lower(country) = lower($8))
+            AND ($9::bool IS NULL OR (tim != 0) = $9)
+            AND ($10::bool IS NULL OR (resto = 0) = $10)
+            AND ($11::bool IS NULL OR (deleted_on != 0) = $11)
+            AND ($12::bigint IS NULL OR time >= $12)
+            AND ($13::bigint IS NULL OR time < $13)
+            AND ($14 = false OR mitsuba_post_hidden = false)
+            ",
+            board.name,
+            search.text,
+            search.subject,
+            search.name,
+            search.filename,
+            search.trip,
+            search.id,
+            search.country,
+            search.has_image,
+            search.is_op,
+            search.deleted,
+            search.after,
+            search.before,
+            remove_hidden,
+            config
+        ).fetch_one(&self.pool).await?;
+
+        let rows = sqlx::query!(
+            "
+            SELECT post_id, time
+            FROM posts
+            WHERE board = $1
+            AND ($2 = '' OR (($14 = false OR mitsuba_com_hidden = false) AND to_tsvector($15::oid::regconfig, com) @@ websearch_to_tsquery($15::oid::regconfig, $2)))
+            AND ($3::text IS NULL OR to_tsvector($15::oid::regconfig, sub) @@ websearch_to_tsquery($15::oid::regconfig, $3))
+            AND ($4::text IS NULL OR to_tsvector($15::oid::regconfig, name) @@ websearch_to_tsquery($15::oid::regconfig, $4))
+            AND ($5::text IS NULL OR to_tsvector($15::oid::regconfig, filename) @@ websearch_to_tsquery($15::oid::regconfig, $5))
+            AND ($6::text IS NULL OR trip = $6)
+            AND ($7::text IS NULL OR id = $7)
+            AND ($8::text IS NULL OR lower(country) = lower($8))
+            AND ($9::bool IS NULL OR (tim != 0) = $9)
+            AND ($10::bool IS NULL OR (resto = 0) = $10)
+            AND ($11::bool IS NULL OR (deleted_on != 0) = $11)
+            AND ($12::bigint IS NULL OR time >= $12)
+            AND ($13::bigint IS NULL OR time < $13)
+            AND ($14 = false OR mitsuba_post_hidden = false)
+            ORDER BY time DESC
+            LIMIT $16 OFFSET $17
+            ",
+            board.name,
+            search.text,
+            search.subject,
+            search.name,
+            search.filename,
+            search.trip,
+            search.id,
+            search.country,
+            search.has_image,
+            search.is_op,
+            search.deleted,
+            search.after,
+            search.before,
+            remove_hidden,
+            config,
+            limit,
+            offset
+        ).fetch_all(&self.pool).await?;
+        Ok((rows.into_iter().map(|r| (r.post_id, r.time)).collect(), total_count.unwrap_or(0)))
+    }
+}
+
+#[async_trait]
+impl SearchIndex for PostgresSearch {
+    fn name(&self) -> &str {
+        "postgres"
+    }
+    fn needs_posts(&self) -> bool {
+        false
+    }
+    async fn search(&self, boards: &[Board], search: &PostSearch, page: i64, page_size: i64, remove_hidden: bool) -> anyhow::Result<(Vec<i64>, i64)> {
+        if let [board] = boards {
+            let (rows, total_count) = self.search_board(board, search, page_size, page * page_size, remove_hidden).await?;
+            return Ok((rows.into_iter().map(|(post_id, _)| post_id).collect(), total_count))
+        }
+        // The newest matches of every board, up to the end of the page
+        let mut rows = Vec::new();
+        let mut total_count = 0;
+        for board in boards {
+            let (board_rows, board_count) = self.search_board(board, search, (page + 1) * page_size, 0, remove_hidden).await?;
+            rows.extend(board_rows);
+            total_count += board_count;
+        }
+        rows.sort_by_key(|&(_, time)| std::cmp::Reverse(time));
+        let post_ids = rows.into_iter()
+            .skip((page * page_size) as usize)
+            .take(page_size as usize)
+            .map(|(post_id, _)| post_id)
+            .collect();
+        Ok((post_ids, total_count))
+    }
+    async fn index_posts(&self, _board: &Board, _posts: &[Post]) -> anyhow::Result<()> {
+        Ok(())
+    }
+    async fn remove_posts(&self, _post_ids: &[i64]) -> anyhow::Result<()> {
+        Ok(())
+    }
+    async fn remove_board(&self, _board: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+struct PostFields {
+    post_id: Field,
+    board: Field,
+    com: Field,
+    sub: Field,
+    name: Field,
+    filename: Field,
+    trip: Field,
+    id: Field,
+    country: Field,
+    has_image: Field,
+    is_op: Field,
+    deleted: Field,
+    hidden: Field,
+    com_hidden: Field,
+    time: Field,
+}
+
+/**
+ * An embedded tantivy index. Only one process can write to it at a time,
+ * the first one that indexes posts keeps the lock until it exits. Other processes can still search it.
+ * The archiver claims the lock when it starts (see `claim_writer`), so a second one refuses to start.
+ */
+pub struct TantivySearch {
+    inner: Arc<TantivyInner>,
+}
+
+struct TantivyInner {
+    index: Index,
+    reader: IndexReader,
+    // Opened the first time posts are indexed, read only processes never take the lock
+    writer: Mutex<Option<IndexWriter>>,
+    fields: PostFields,
+}
+
+// Text fields are tokenized with the language of their board, under this tokenizer name
+const TOKENIZER: &str = "mitsuba";
+
+impl TantivySearch {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(path)?;
+        let mut builder = Schema::builder();
+        // Positions are needed for phrases
+        let text = TextOptions::default().set_indexing_options(
+            TextFieldIndexing::default().set_tokenizer(TOKENIZER).set_index_option(IndexRecordOption::WithFreqsAndPositions)
+        );
+        let fields = PostFields {
+            post_id: builder.add_u64_field("post_id", INDEXED | STORED),
+            board: builder.add_text_field("board", STRING),
+            com: builder.add_text_field("com", text.clone()),
+            sub: builder.add_text_field("sub", text.clone()),
+            name: builder.add_text_field("name", text.clone()),
+            filename: builder.add_text_field("filename", text),
+            trip: builder.add_text_field("trip", STRING),
+            id: builder.add_text_field("id", STRING),
+            country: builder.add_text_field("country", STRING),
+            has_image: builder.add_bool_field("has_image", INDEXED),
+            is_op: builder.add_bool_field("is_op", INDEXED),
+            deleted: builder.add_bool_field("deleted", INDEXED),
+            hidden: builder.add_bool_field("hidden", INDEXED),
+            com_hidden: builder.add_bool_field("com_hidden", INDEXED),
+            time: builder.add_i64_field("time", INDEXED | FAST),
+        };
+        let schema = builder.build();
+        let index = match Index::open_or_create(MmapDirectory::open(path)?, schema.clone()) {
+            // Made by an older version, the index can always be built again from the database
+            Err(TantivyError::SchemaError(e)) => {
+                warn!("Search index in {} is outdated ({}), recreating it. Run `reindex` to add existing posts", path.display(), e);
+                std::fs::remove_dir_all(path)?;
+                std::fs::create_dir_all(path)?;
+                Index::create_in_dir(path, schema)?
+            },
+            index => index?
+        };
+        // Text is always added pre-tokenized, but tantivy wants the field's tokenizer to exist
+        index.tokenizers().register(TOKENIZER, analyzer("english"));
+        let reader = index.reader_builder().reload_policy(ReloadPolicy::OnCommitWithDelay).try_into()?;
+        Ok(Self {
+            inner: Arc::new(TantivyInner { index, reader, writer: Mutex::new(None), fields })
+        })
+    }
+}
+
+impl TantivyInner {
+    fn search(&self, boards: &[Board], search: &PostSearch, page: i64, page_size: i64, remove_hidden: bool) -> anyhow::Result<(Vec<i64>, i64)> {
+        let f = self.fields;
+        let term = |field: Field, value: &str| -> Box<dyn Query> {
+            Box::new(TermQuery::new(Term::from_field_text(field, value), IndexRecordOption::Basic))
+        };
+        let flag = |field: Field, value: bool| -> Box<dyn Query> {
+            Box::new(TermQuery::new(Term::from_field_bool(field, value), IndexRecordOption::Basic))
+        };
+
+        // Text has to be tokenized like it was indexed, so boards are grouped by language
+        let mut languages: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for board in boards {
+            languages.entry(board.search_language.as_str()).or_default().push(board.name.as_str());
+        }
+        let mut board_queries: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+        for (language, names) in languages {
+            let tokenizers = TokenizerManager::new();
+            tokenizers.register(TOKENIZER, analyzer(language));
+            // Like websearch_to_tsquery, words are all required unless separated by OR
+            let text = |field: Field, value: &str| -> Box<dyn Query> {
+                let mut parser = QueryParser::new(self.index.schema(), vec![field], tokenizers.clone());
+                parser.set_conjunction_by_default();
+                parser.parse_query_lenient(&uppercase_or(value)).0
+            };
+            let mut clauses: Vec<(Occur, Box<dyn Query>)> = vec![
+                (Occur::Must, Box::new(BooleanQuery::new(names.iter().map(|b| (Occur::Should, term(f.board, b))).collect()))),
+            ];
+            if !search.text.trim().is_empty() {
+                clauses.push((Occur::Must, text(f.com, &search.text)));
+            }
+            for (field, value) in [(f.sub, &search.subject), (f.name, &search.name), (f.filename, &search.filename)] {
+                if let Some(value) = value {
+                    clauses.push((Occur::Must, text(field, value)));
+                }
+            }
+            board_queries.push((Occur::Should, Box::new(BooleanQuery::new(clauses))));
+        }
+
+        let mut clauses: Vec<(Occur, Box<dyn Query>)> = vec![
+            (Occur::Must, Box::new(AllQuery)),
+            (Occur::Must, Box::new(BooleanQuery::new(board_queries))),
+        ];
+        if remove_hidden && !search.text.trim().is_empty() {
+            clauses.push((Occur::MustNot, flag(f.com_hidden, true)));
+        }
+        for (field, value) in [(f.trip, &search.trip), (f.id, &search.id)] {
+            if let Some(value) = value {
+                clauses.push((Occur::Must, term(field, value)));
+            }
+        }
+        if let Some(country) = &search.country {
+            clauses.push((Occur::Must, term(f.country, &country.to_lowercase())));
+        }
+        for (field, value) in [(f.has_image, search.has_image), (f.is_op, search.is_op), (f.deleted, search.deleted)] {
+            if let Some(value) = value {
+                clauses.push((Occur::Must, flag(field, value)));
+            }
+        }
+        if search.after.is_some() || search.before.is_some() {
+            clauses.push((Occur::Must, Box::new(RangeQuery::new_i64_bounds(
+                "time".to_string(),
+                search.after.map_or(Bound::Unbounded, Bound::Included),
+                search.before.map_or(Bound::Unbounded, Bound::Excluded)
+            ))));
+        }
+        if remove_hidden {
+            clauses.push((Occur::MustNot, flag(f.hidden, true)));
+        }
+        let query = BooleanQuery::new(clauses);
+
+        let searcher = self.reader.searcher();
+        // Tantivy makes room for every document up to the end of the page, there are no more than num_docs
+        let top_docs = TopDocs::with_limit(page_size.max(1) as usize)
+            .and_offset((page * page_size).clamp(0, searcher.num_docs() as i64) as usize)
+            .order_by_fast_field::<i64>("time", Order::Desc);
+        let (total, docs) = searcher.search(&query, &(Count, top_docs))?;
+        let mut post_ids = Vec::with_capacity(docs.len());
+        for (_, address) in docs {
+            let doc: TantivyDocument = searcher.doc(address)?;
+            if let Some(post_id) = doc.get_first(f.post_id).and_then(|v| v.as_u64()) {
+                post_ids.push(post_id as i64);
+            }
+        }
+        Ok((post_ids, total as i64))
+    }
+
+    fn lock_writer(&self) -> anyhow::Result<std::sync::MutexGuard<'_, Option<IndexWriter>>> {
+        let mut writer = self.writer.lock().map_err(|_| anyhow::anyhow!("Search index writer lock poisoned"))?;
+        if writer.is_none() {
+            *writer = Some(self.index.writer(50_000_000)
+                .map_err(|e| anyhow::anyhow!("Can't write to the search index, is another archiver using it? {}", e))?);
+        }
+        Ok(writer)
+    }
+
+    fn with_writer<T>(&self, f: impl FnOnce(&mut IndexWriter) -> anyhow::Result<T>) -> anyhow::Result<T> {
+        let mut writer = self.lock_writer()?;
+        let result = f(writer.as_mut().unwrap())?;
+        // Searches made right after a change see it
+        self.reader.reload()?;
+        Ok(result)
+    }
+
+    fn index_posts(&self, board: &Board, posts: &[Post]) -> anyhow::Result<()> {
+        let f = self.fields;
+        let mut analyzer = analyzer(&board.search_language);
+        let mut tokenize = |text: String| -> PreTokenizedString {
+            let mut tokens = Vec::new();
+            analyzer.token_stream(&text).process(&mut |token| tokens.push(token.clone()));
+            PreTokenizedString { text, tokens }
+        };
+        self.with_writer(|writer| {
+            for post in posts {
+                writer.delete_term(Term::from_field_u64(f.post_id, post.post_id as u64));
+                let mut doc = TantivyDocument::default();
+                doc.add_u64(f.post_id, post.post_id as u64);
+                doc.add_text(f.board, &post.board);
+                doc.add_pre_tokenized_text(f.com, tokenize(strip_html(&post.com)));
+                doc.add_pre_tokenized_text(f.sub, tokenize(post.sub.clone()));
+                doc.add_pre_tokenized_text(f.name, tokenize(post.name.clone()));
+                doc.add_pre_tokenized_text(f.filename, tokenize(post.filename.clone()));
+                doc.add_text(f.trip, &post.trip);
+                doc.add_text(f.id, &post.id);
+                doc.add_text(f.country, post.country.to_lowercase());
+                doc.add_bool(f.has_image, post.tim != 0);
+                doc.add_bool(f.is_op, post.resto == 0);
+                doc.add_bool(f.deleted, post.deleted_on != 0);
+                doc.add_bool(f.hidden, post.mitsuba_post_hidden);
+                doc.add_bool(f.com_hidden, post.mitsuba_com_hidden);
+                doc.add_i64(f.time, post.time);
+                writer.add_document(doc)?;
+            }
+            writer.commit()?;
+            Ok(())
+        })
+    }
+
+    fn remove_posts(&self, post_ids: &[i64]) -> anyhow::Result<()> {
+        let post_id_field = self.fields.post_id;
+        self.with_writer(|writer| {
+            for post_id in post_ids {
+                writer.delete_term(Term::from_field_u64(post_id_field, *post_id as u64));
+            }
+            writer.commit()?;
+            Ok(())
+        })
+    }
+
+    fn remove_board(&self, board: &str) -> anyhow::Result<()> {
+        let board_field = self.fields.board;
+        self.with_writer(|writer| {
+            writer.delete_term(Term::from_field_text(board_field, board));
+            writer.commit()?;
+            Ok(())
+        })
+    }
+}
+
+#[async_trait]
+impl SearchIndex for TantivySearch {
+    fn name(&self) -> &str {
+        "tantivy"
+    }
+    async fn search(&self, boards: &[Board], search: &PostSearch, page: i64, page_size: i64, remove_hidden: bool) -> anyhow::Result<(Vec<i64>, i64)> {
+        let inner = self.inner.clone();
+        let (boards, search) = (boards.to_vec(), search.clone());
+        tokio::task::spawn_blocking(move || inner.search(&boards, &search, page, page_size, remove_hidden)).await?
+    }
+    async fn index_posts(&self, board: &Board, posts: &[Post]) -> anyhow::Result<()> {
+        if posts.is_empty() {
+            return Ok(())
+        }
+        let inner = self.inner.clone();
+        let (board, posts) = (board.clone(), posts.to_vec());
+        tokio::task::spawn_blocking(move || inner.index_posts(&board, &posts)).await?
+    }
+    async fn remove_posts(&self, post_ids: &[i64]) -> anyhow::Result<()> {
+        let inner = self.inner.clone();
+        let post_ids = post_ids.to_vec();
+        tokio::task::spawn_blocking(move || inner.remove_posts(&post_ids)).await?
+    }
+    async fn remove_board(&self, board: &str) -> anyhow::Result<()> {
+        let inner = self.inner.clone();
+        let board = board.to_string();
+        tokio::task::spawn_blocking(move || inner.remove_board(&board)).await?
+    }
+    fn claim_writer(&self) -> anyhow::Result<()> {
+        self.inner.lock_writer().map(|_| ())
+    }
+}
+
+/**
+ * Tokenizer for a postgres text search configuration name.
+ * Languages tantivy has no stemmer for are only lowercased, like 'simple'.
+ */
+fn analyzer(language: &str) -> TextAnalyzer {
+    let stemmer = match language {
+        "arabic" => Some(Language::Arabic),
+        "danish" => Some(Language::Danish),
+        "dutch" => Some(Language::Dutch),
+        "english" => Some(Language::English),
+        "finnish" => Some(Language::Finnish),
+        "french" => Some(Language::French),
+        "german" => Some(Language::German),
+        "greek" => Some(Language::Greek),
+        "hungarian" => Some(Language::Hungarian),
+        "italian" => Some(Language::Italian),
+        "norwegian" => Some(Language::Norwegian),
+        "portuguese" => Some(Language::Portuguese),
+        "romanian" => Some(Language::Romanian),
+        "russian" => Some(Language::Russian),
+        "spanish" => Some(Language::Spanish),
+        "swedish" => Some(Language::Swedish),
+        "tamil" => Some(Language::Tamil),
+        "turkish" => Some(Language::Turkish),
+        _ => None
+    };
+    let builder = TextAnalyzer::builder(SimpleTokenizer::default())
+        .filter(RemoveLongFilter::limit(40))
+        .filter(LowerCaser)
+        .dynamic();
+    match stemmer {
+        Some(language) => builder.filter_dynamic(Stemmer::new(language)).build(),
+        None => builder.build()
+    }
+}
+
+// websearch_to_tsquery takes `or` in any case, tantivy's parser only takes `OR`. Quoted phrases are left alone.
+fn uppercase_or(query: &str) -> String {
+    let mut in_quotes = false;
+    query.split(' ')
+        .map(|word| {
+            let word = if !in_quotes && word.eq_ignore_ascii_case("or") { "OR" } else { word };
+            if word.matches('"').count() % 2 == 1 {
+                in_quotes = !in_quotes;
+            }
+            word
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Comments are HTML, postgres' parser skips tags on its own
+fn strip_html(com: &str) -> String {
+    let mut text = String::with_capacity(com.len());
+    let mut in_tag = false;
+    for c in com.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => {
+                in_tag = false;
+                text.push(' ');
+            },
+            _ if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    text.replace("&gt;", ">").replace("&lt;", "<").replace("&quot;", "\"").replace("&#039;", "'").replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tantivy_search() {
+        let path = std::env::temp_dir().join(format!("mitsuba-search-{:08x}", rand::random::<u32>()));
+        let index = TantivySearch::open(&path).unwrap();
+        let post = |board: &str, no: i64, time: i64, com: &str| Post {
+            post_id: no, board: board.to_string(), no, time, com: com.to_string(), ..Default::default()
+        };
+        let test = Board { name: "test".to_string(), ..Default::default() };
+        index.inner.index_posts(&test, &[
+            post("test", 1, 100, "black cats<br>and dogs"),
+            post("test", 2, 200, "<span class=\"quote\">&gt;dogs</span> only"),
+            Post { mitsuba_post_hidden: true, ..post("test", 3, 300, "hidden dogs") },
+            Post { tim: 1, resto: 1, ..post("test", 4, 400, "a cat") },
+        ]).unwrap();
+        let simple = Board { name: "simple".to_string(), search_language: "simple".to_string(), ..Default::default() };
+        index.inner.index_posts(&simple, &[post("simple", 5, 500, "Dogs")]).unwrap();
+        index.inner.reader.reload().unwrap();
+
+        let boards = vec![test, simple];
+        let search = |query: &str, remove_hidden: bool| index.inner.search(&boards, &PostSearch::parse(query).unwrap(), 0, 10, remove_hidden).unwrap();
+        assert_eq!((vec![3, 2, 1], 3), search("dog", false));
+        assert_eq!((vec![5, 2, 1], 3), search("dogs", true));
+        assert_eq!((vec![4, 1], 2), search("cats", true));
+        assert_eq!((vec![1], 1), search("\"black cat\" -only", true));
+        assert_eq!((vec![5, 2], 2), search("-cat", true));
+        assert_eq!((vec![4], 1), search("has:image is:reply", true));
+        assert_eq!((vec![2, 1], 2), search("before:300", true));
+        assert_eq!((vec![5, 2, 1], 3), search("black Or dogs", true));
+        assert_eq!(uppercase_or("\"cats or dogs\" or birds"), "\"cats or dogs\" OR birds");
+
+        index.inner.remove_posts(&[4]).unwrap();
+        assert_eq!((vec![1], 1), search("cats", true));
+        std::fs::remove_dir_all(path).ok();
+    }
+
+    #[test]
+    fn test_tantivy_single_writer() {
+        let path = std::env::temp_dir().join(format!("mitsuba-search-{:08x}", rand::random::<u32>()));
+        let archiver = TantivySearch::open(&path).unwrap();
+        archiver.claim_writer().unwrap();
+        // Claiming again from the process that holds the lock is fine
+        archiver.claim_writer().unwrap();
+        // A second archiver can't write to it, but can still search it
+        let second = TantivySearch::open(&path).unwrap();
+        assert!(second.claim_writer().is_err());
+        let board = Board { name: "test".to_string(), ..Default::default() };
+        assert!(second.inner.search(&[board], &PostSearch::parse("cats").unwrap(), 0, 10, true).is_ok());
+        std::fs::remove_dir_all(path).ok();
+    }
+}
@@ -0,0 +1,148 @@
+use std::io::{Read, Write};
+
+/**
+ * Just enough of the tar format for export bundles: regular files in ustar format, with paths up to 100 bytes.
+ * Entries are read and written whole, the caller handles compression.
+ */
+pub struct TarWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> TarWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner }
+    }
+
+    pub fn append(&mut self, path: &str, data: &[u8], mtime: i64) -> std::io::Result<()> {
+        if path.len() > 100 {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Path too long for tar: {}", path)))
+        }
+        let mut header = [0u8; 512];
+        header[..path.len()].copy_from_slice(path.as_bytes());
+        write_octal(&mut header[100..108], 0o644);
+        write_octal(&mut header[108..116], 0);
+        write_octal(&mut header[116..124], 0);
+        write_octal(&mut header[124..136], data.len() as u64);
+        write_octal(&mut header[136..148], mtime.max(0) as u64);
+        header[156] = b'0';
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        // The checksum is computed with its own field set to spaces
+        header[148..156].fill(b' ');
+        let checksum: u32 = header.iter().map(|b| *b as u32).sum();
+        write_octal(&mut header[148..155], checksum as u64);
+        self.inner.write_all(&header)?;
+        self.inner.write_all(data)?;
+        self.inner.write_all(&[0u8; 512][..padding(data.len() as u64)])
+    }
+
+    // Writes the two empty blocks that end an archive
+    pub fn finish(mut self) -> std::io::Result<W> {
+        self.inner.write_all(&[0u8; 1024])?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+pub struct TarReader<R: Read> {
+    inner: R,
+}
+
+impl<R: Read> TarReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner }
+    }
+
+    // The path and content of the next regular file, None at the end of the archive
+    pub fn next_file(&mut self) -> std::io::Result<Option<(String, Vec<u8>)>> {
+        loop {
+            let mut header = [0u8; 512];
+            self.inner.read_exact(&mut header)?;
+            if header.iter().all(|b| *b == 0) {
+                return Ok(None)
+            }
+            let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid tar header");
+            let checksum = read_octal(&header[148..156]).ok_or_else(invalid)?;
+            let mut unsigned = header;
+            unsigned[148..156].fill(b' ');
+            if unsigned.iter().map(|b| *b as u64).sum::<u64>() != checksum {
+                return Err(invalid())
+            }
+            let size = read_octal(&header[124..136]).ok_or_else(invalid)?;
+            // Directories, links and extended headers are skipped
+            if header[156] != b'0' && header[156] != 0 {
+                let skipped = std::io::copy(&mut (&mut self.inner).take(size), &mut std::io::sink())?;
+                if skipped < size {
+                    return Err(std::io::ErrorKind::UnexpectedEof.into())
+                }
+                self.inner.read_exact(&mut [0u8; 512][..padding(size)])?;
+                continue
+            }
+            // The buffer only grows as data is read, so a header can't make us allocate more than the archive holds
+            let mut data = Vec::new();
+            (&mut self.inner).take(size).read_to_end(&mut data)?;
+            if (data.len() as u64) < size {
+                return Err(std::io::ErrorKind::UnexpectedEof.into())
+            }
+            self.inner.read_exact(&mut [0u8; 512][..padding(size)])?;
+            let name_end = header[..100].iter().position(|b| *b == 0).unwrap_or(100);
+            let path = String::from_utf8_lossy(&header[..name_end]).to_string();
+            return Ok(Some((path, data)))
+        }
+    }
+}
+
+// Fills the field with zero padded octal digits, followed by a NUL
+fn write_octal(field: &mut [u8], value: u64) {
+    let end = field.len() - 1;
+    let digits = format!("{:0width$o}", value, width = end);
+    field[..end].copy_from_slice(digits.as_bytes());
+    field[end] = 0;
+}
+
+fn read_octal(field: &[u8]) -> Option<u64> {
+    let digits = String::from_utf8_lossy(field);
+    let digits = digits.trim_matches(|c: char| c == '\0' || c == ' ');
+    if digits.is_empty() {
+        return Some(0)
+    }
+    u64::from_str_radix(digits, 8).ok()
+}
+
+// Entries are padded to a multiple of 512 bytes
+fn padding(size: u64) -> usize {
+    ((512 - size % 512) % 512) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tar_roundtrip() {
+        let mut writer = TarWriter::new(Vec::new());
+        writer.append("manifest.json", b"{}", 1700000000).unwrap();
+        writer.append("media/thumb/AB/C/ABC.jpg", &[7u8; 600], 1700000000).unwrap();
+        writer.append("empty", b"", 0).unwrap();
+        assert!(writer.append(&"a".repeat(101), b"", 0).is_err());
+        let bytes = writer.finish().unwrap();
+        assert_eq!(bytes.len() % 512, 0);
+
+        let mut reader = TarReader::new(bytes.as_slice());
+        assert_eq!(reader.next_file().unwrap(), Some(("manifest.json".to_string(), b"{}".to_vec())));
+        assert_eq!(reader.next_file().unwrap(), Some(("media/thumb/AB/C/ABC.jpg".to_string(), vec![7u8; 600])));
+        assert_eq!(reader.next_file().unwrap(), Some(("empty".to_string(), vec![])));
+        assert_eq!(reader.next_file().unwrap(), None);
+
+        // A header claiming more data than there is
+        let mut writer = TarWriter::new(Vec::new());
+        writer.append("media/full/AB/C/ABC.webm", b"short", 0).unwrap();
+        let mut bytes = writer.finish().unwrap();
+        write_octal(&mut bytes[124..136], 0o77777777777);
+        bytes[148..156].fill(b' ');
+        let checksum: u64 = bytes[..512].iter().map(|b| *b as u64).sum();
+        write_octal(&mut bytes[148..155], checksum);
+        let error = TarReader::new(bytes.as_slice()).next_file().unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::Arc;
+
+#[allow(unused_imports)]
+use log::{info, warn, error, debug};
+
+pub const DEFAULT_UPSTREAM: &str = "4chan";
+
+/**
+ * A site Mitsuba can archive boards from.
+ * Implementations only know where things live on the remote site, fetching is left to `HttpClient`.
+ * Every source must serve 4chan-API-compatible JSON for threads and catalogs.
+ */
+pub trait UpstreamSource: Send + Sync {
+    /// Name used to refer to this source in board settings (eg. '4chan')
+    fn name(&self) -> &str;
+    /// List of boards on the site. None if the site doesn't publish one.
+    fn boards_url(&self) -> Option<String>;
+    /// Every thread on the board, by page, with its OP and latest replies
+    fn catalog_url(&self, board: &str) -> String;
+    /// List of archived thread ids for a board. None if the site has no archive.
+    fn archive_url(&self, board: &str) -> Option<String>;
+    fn thread_url(&self, board: &str, no: i64) -> String;
+    fn file_url(&self, board: &str, tim: &str, ext: &str) -> String;
+    fn thumbnail_url(&self, board: &str, tim: &str, ext: &str) -> String;
+    /// Replication feed of a board, for sources that are Mitsuba instances. Boards with a feed are replicated from it instead of polled.
+    fn feed_url(&self, _board: &str) -> Option<String> {
+        None
+    }
+    /// Files by their key in storage, on sources that store them like Mitsuba does
+    fn stored_file_url(&self, _key: &str) -> Option<String> {
+        None
+    }
+}
+
+/**
+ * 4chan, or any site that mirrors its API and media layout on separate hosts.
+ */
+pub struct FourChanSource {
+    pub name: String,
+    pub api_url: String,
+    pub media_url: String,
+}
+
+impl Default for FourChanSource {
+    fn default() -> Self {
+        Self {
+            name: DEFAULT_UPSTREAM.to_string(),
+            api_url: "https://a.4cdn.org".to_string(),
+            media_url: "https://i.4cdn.org".to_string(),
+        }
+    }
+}
+
+impl UpstreamSource for FourChanSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn boards_url(&self) -> Option<String> {
+        Some(format!("{}/boards.json", self.api_url))
+    }
+    fn catalog_url(&self, board: &str) -> String {
+        format!("{}/{}/catalog.json", self.api_url, board)
+    }
+    fn archive_url(&self, board: &str) -> Option<String> {
+        Some(format!("{}/{}/archive.json", self.api_url, board))
+    }
+    fn thread_url(&self, board: &str, no: i64) -> String {
+        format!("{}/{}/thread/{}.json", self.api_url, board, no)
+    }
+    fn file_url(&self, board: &str, tim: &str, ext: &str) -> String {
+        format!("{}/{}/{}{}", self.media_url, board, tim, ext)
+    }
+    fn thumbnail_url(&self, board: &str, tim: &str, _ext: &str) -> String {
+        format!("{}/{}/{}s.jpg", self.media_url, board, tim)
+    }
+}
+
+/**
+ * vichan and its forks (lainchan, NPFchan...). API and media are served from the same host.
+ * These have no boards.json or archive.json.
+ */
+pub struct VichanSource {
+    pub name: String,
+    pub base_url: String,
+}
+
+impl UpstreamSource for VichanSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn boards_url(&self) -> Option<String> {
+        None
+    }
+    fn catalog_url(&self, board: &str) -> String {
+        format!("{}/{}/catalog.json", self.base_url, board)
+    }
+    fn archive_url(&self, _board: &str) -> Option<String> {
+        None
+    }
+    fn thread_url(&self, board: &str, no: i64) -> String {
+        format!("{}/{}/res/{}.json", self.base_url, board, no)
+    }
+    fn file_url(&self, board: &str, tim: &str, ext: &str) -> String {
+        format!("{}/{}/src/{}{}", self.base_url, board, tim, ext)
+    }
+    fn thumbnail_url(&self, board: &str, tim: &str, ext: &str) -> String {
+        // vichan keeps the original extension for image thumbnails, videos get a jpg
+        let thumb_ext = match ext {
+            ".webm" | ".mp4" => ".jpg",
+            _ => ext
+        };
+        format!("{}/{}/thumb/{}{}", self.base_url, board, tim, thumb_ext)
+    }
+}
+
+/**
+ * Another Mitsuba instance, which this one replicates boards from through their change feed.
+ * Its thread API and image URLs are compatible with 4chan's, but it has no catalog or archive list.
+ */
+pub struct MitsubaSource {
+    pub name: String,
+    pub base_url: String,
+}
+
+impl UpstreamSource for MitsubaSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn boards_url(&self) -> Option<String> {
+        None
+    }
+    // Not served, boards from Mitsuba are only read through their feed
+    fn catalog_url(&self, board: &str) -> String {
+        format!("{}/{}/catalog.json", self.base_url, board)
+    }
+    fn archive_url(&self, _board: &str) -> Option<String> {
+        None
+    }
+    fn thread_url(&self, board: &str, no: i64) -> String {
+        format!("{}/{}/thread/{}.json", self.base_url, board, no)
+    }
+    fn file_url(&self, board: &str, tim: &str, ext: &str) -> String {
+        format!("{}/{}/{}{}", self.base_url, board, tim, ext)
+    }
+    fn thumbnail_url(&self, board: &str, tim: &str, _ext: &str) -> String {
+        format!("{}/{}/{}s.jpg", self.base_url, board, tim)
+    }
+    fn feed_url(&self, board: &str) -> Option<String> {
+        Some(format!("{}/{}/feed.json", self.base_url, board))
+    }
+    fn stored_file_url(&self, key: &str) -> Option<String> {
+        Some(format!("{}/img/{}", self.base_url, key))
+    }
+}
+
+/**
+ * All configured upstream sources, by name. 4chan is always available.
+ * Additional sources are read from the environment, in the same way as proxies:
+ * UPSTREAM_NAME_0, UPSTREAM_KIND_0 ('4chan', 'vichan' or 'mitsuba'), UPSTREAM_API_URL_0, UPSTREAM_MEDIA_URL_0 and so on.
+ */
+#[derive(Clone)]
+pub struct Upstreams {
+    sources: Arc<HashMap<String, Arc<dyn UpstreamSource>>>
+}
+
+impl Upstreams {
+    pub fn new() -> Self {
+        let mut sources: HashMap<String, Arc<dyn UpstreamSource>> = HashMap::new();
+        sources.insert(DEFAULT_UPSTREAM.to_string(), Arc::new(FourChanSource::default()));
+
+        let mut i = 0;
+        while let Ok(name) = env::var(format!("UPSTREAM_NAME_{}", i)) {
+            let kind = env::var(format!("UPSTREAM_KIND_{}", i)).unwrap_or(DEFAULT_UPSTREAM.to_string());
+            let api_url = env::var(format!("UPSTREAM_API_URL_{}", i)).ok();
+            let media_url = env::var(format!("UPSTREAM_MEDIA_URL_{}", i)).ok();
+            i += 1;
+
+            let api_url = match api_url {
+                Some(url) => url.trim_end_matches('/').to_string(),
+                None => {
+                    error!("UPSTREAM_API_URL_{} must be set for upstream '{}', skipping", i - 1, name);
+                    continue;
+                }
+            };
+            let source: Arc<dyn UpstreamSource> = match kind.as_str() {
+                "4chan" => Arc::new(FourChanSource {
+                    name: name.clone(),
+                    media_url: media_url.map(|u| u.trim_end_matches('/').to_string()).unwrap_or(api_url.clone()),
+                    api_url,
+                }),
+                "vichan" => Arc::new(VichanSource {
+                    name: name.clone(),
+                    base_url: api_url,
+                }),
+                "mitsuba" => Arc::new(MitsubaSource {
+                    name: name.clone(),
+                    base_url: api_url,
+                }),
+                _ => {
+                    error!("Unknown kind '{}' for upstream '{}', skipping", kind, name);
+                    continue;
+                }
+            };
+            info!("Configured upstream '{}' ({})", name, kind);
+            sources.insert(name, source);
+        }
+        Self {
+            sources: Arc::new(sources)
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn UpstreamSource>> {
+        self.sources.get(name).cloned()
+    }
+
+    // For tests that archive from a local server
+    #[cfg(test)]
+    pub fn with_source(mut self, source: Arc<dyn UpstreamSource>) -> Self {
+        Arc::make_mut(&mut self.sources).insert(source.name().to_string(), source);
+        self
+    }
+}
+
+impl Default for Upstreams {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,4 +1,3 @@
-use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::env;
 
@@ -10,6 +9,7 @@ use sha2::{Sha256, Digest};
 use weighted_rs::{SmoothWeight, Weight};
 
 use crate::models::{ImageInfo, Post, Thread};
+use crate::upstream::UpstreamSource;
 
 pub fn hash_file(bytes: &[u8]) -> String {
     let mut hasher = Sha256::new();
@@ -17,6 +17,36 @@ pub fn hash_file(bytes: &[u8]) -> String {
     encode(Alphabet::Rfc4648{padding: false}, hasher.finalize().as_slice())
 }
 
+// Largest image perceptual_hash decodes, in each dimension, so a small upload can't claim a huge image
+const PHASH_MAX_DIMENSION: u32 = 10000;
+
+/**
+ * Perceptual hash (dHash) of an image: each bit is whether a pixel of the image, shrunk to 9x8 and made grayscale,
+ * is brighter than its right neighbour. Similar looking images have hashes with few differing bits.
+ */
+pub fn perceptual_hash(bytes: &[u8]) -> anyhow::Result<i64> {
+    let mut reader = image::ImageReader::new(std::io::Cursor::new(bytes)).with_guessed_format()?;
+    let mut limits = image::Limits::default();
+    limits.max_image_width = Some(PHASH_MAX_DIMENSION);
+    limits.max_image_height = Some(PHASH_MAX_DIMENSION);
+    limits.max_alloc = Some(256 * 1024 * 1024);
+    reader.limits(limits);
+    let image = reader.decode()?
+        .grayscale()
+        .resize_exact(9, 8, image::imageops::FilterType::Triangle)
+        .to_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if image.get_pixel(x, y).0[0] > image.get_pixel(x + 1, y).0[0] {
+                hash |= 1;
+            }
+        }
+    }
+    Ok(hash as i64)
+}
+
 fn bad_hash(s: String) -> i64 {
     let mut msg = 0i64;
     let j = s.len();
@@ -45,34 +75,27 @@ pub fn shorten_string(maxlen: usize, s: String) -> String {
         s
     }
 }
-pub fn get_board_page_api_url(board: &String) -> String {
-    format!("https://a.4cdn.org/{}/threads.json", board)
-}
-pub fn get_board_archive_api_url(board: &String) -> String {
-    format!("https://a.4cdn.org/{}/archive.json", board)
-}
-pub fn get_thread_api_url(board: &String, tid: &String) -> String {
-    format!("https://a.4cdn.org/{}/thread/{}.json", board, tid)
-}
-
-pub fn get_post_image_info(board: &String, page: i32, post: &Post) -> Option<ImageInfo> {
+// Image jobs for every attachment of a post, the post's own file first
+pub fn get_post_image_infos(source: &dyn UpstreamSource, board: &str, page: i32, post: &Post) -> Vec<ImageInfo> {
     if post.tim == 0 || post.filedeleted == 1 {
-        return None // no image
-    }
-    let url = format!("https://i.4cdn.org/{}/{}{}", board, post.tim, post.ext);
-    let thumbnail_url = format!("https://i.4cdn.org/{}/{}s.jpg", board, post.tim);
-    Some(
-        ImageInfo {
-            url,
-            thumbnail_url,
-            ext: post.ext.clone(),
-            file_sha256: post.file_sha256.clone(),
-            thumbnail_sha256: post.thumbnail_sha256.clone(),
-            page,
-            no: post.no,
-            board: board.clone()
-        }
-    )
+        return Vec::new() // no image
+    }
+    let image_info = |idx: usize, tim: &str, ext: &str, file_sha256: &Option<String>, thumbnail_sha256: &Option<String>| ImageInfo {
+        url: source.file_url(board, tim, ext),
+        thumbnail_url: source.thumbnail_url(board, tim, ext),
+        ext: ext.to_string(),
+        file_sha256: file_sha256.clone(),
+        thumbnail_sha256: thumbnail_sha256.clone(),
+        page,
+        no: post.no,
+        idx: idx as i32,
+        board: board.to_string()
+    };
+    let mut infos = vec![image_info(0, &post.tim.to_string(), &post.ext, &post.file_sha256, &post.thumbnail_sha256)];
+    for (i, file) in post.extra_files.0.iter().enumerate() {
+        infos.push(image_info(i + 1, &file.tim, &file.ext, &file.file_sha256, &file.thumbnail_sha256));
+    }
+    infos
 }
 
 pub fn base64_to_32(b64: String) -> anyhow::Result<String> {
@@ -81,26 +104,74 @@ pub fn base64_to_32(b64: String) -> anyhow::Result<String> {
     Ok(s)
 }
 
-pub fn get_file_folder(sha256: &String, is_thumb: bool) -> PathBuf {
-    let data_folder_str = std::env::var("DATA_ROOT").unwrap_or("data".to_string());
-    let image_folder = Path::new(&data_folder_str).join("images");
-    let folder = match is_thumb {
-        true => image_folder.join("thumb"),
-        false => image_folder.join("full")
+/**
+ * Upstream md5 hashes are base64, but a `/` doesn't fit in a URL path.
+ * Accepts them as base64 (standard or URL safe), base32 or hex, and returns the standard base64 used in the database.
+ */
+pub fn parse_md5(s: &str) -> Option<String> {
+    let binary = match s.len() {
+        32 => (0..32).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect::<Option<Vec<u8>>>()?,
+        26 => base32::decode(Alphabet::Rfc4648{padding: false}, &s.to_uppercase())?,
+        _ => STANDARD.decode(s.trim_end_matches('=').replace('-', "+").replace('_', "/") + "==").ok()?
     };
-    folder.join(&sha256[0..2]).join(&sha256[2..3])
+    if binary.len() != 16 {
+        return None
+    }
+    Some(STANDARD.encode(binary))
+}
+
+// Unix timestamp as "YYYY-MM-DD HH:MM UTC"
+pub fn format_timestamp(timestamp: i64) -> String {
+    let seconds = timestamp.rem_euclid(86400);
+    let (year, month, day) = civil_from_days(timestamp.div_euclid(86400));
+    format!("{:04}-{:02}-{:02} {:02}:{:02} UTC", year, month, day, seconds / 3600, seconds % 3600 / 60)
 }
 
-pub fn get_file_url(sha256: &String, ext: &String, is_thumb: bool) -> String {
+// Days since 1970-01-01 to (year, month, day), from Howard Hinnant's date algorithms
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// Inverse of civil_from_days
+pub fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+// Path of a file in the file store, and under /img/
+pub fn get_file_key(sha256: &str, ext: &str, is_thumb: bool) -> String {
     let folder = match is_thumb {
         true => "thumb",
         false => "full"
     };
+    format!("{}/{}/{}/{}{}", folder, &sha256[0..2], &sha256[2..3], sha256, ext)
+}
+
+pub fn get_file_url(sha256: &str, ext: &str, is_thumb: bool) -> String {
     if sha256.len() < 3 {
         return "/static/image/404-Angelguy.png".to_string();
     }
 
-    format!("/img/{}/{}/{}/{}{}", folder, &sha256[0..2], &sha256[2..3], sha256, ext)
+    format!("/img/{}", get_file_key(sha256, ext, is_thumb))
+}
+
+// Pages past this one can't have results, and their offset (or the end of the page) wouldn't fit in an i64
+pub fn clamp_page(page: i64, page_size: i64) -> i64 {
+    page.clamp(0, i64::MAX / page_size.max(1) - 1)
 }
 
 pub fn bool_from_env(env_var: &String) -> bool {
@@ -155,6 +226,12 @@ pub fn process_hidden_post(post: &Post) -> Option<Post> {
         post.thumbnail_sha256 = None;
         post.file_sha256 = None;
     }
+    for file in post.extra_files.0.iter_mut() {
+        if file.mitsuba_file_hidden.unwrap_or(false) {
+            file.thumbnail_sha256 = None;
+            file.file_sha256 = None;
+        }
+    }
     if post.mitsuba_com_hidden {
         post.com = "<b><i>[Hidden]</i></b>".to_string();
     }
@@ -168,4 +245,46 @@ pub fn process_hidden_thread(thread: &Thread) -> Option<Thread> {
     let mut thread = thread.clone();
     thread.posts = thread.posts.into_iter().filter_map(|p| process_hidden_post(&p)).collect();
     Some(thread)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_perceptual_hash_limits() {
+        let encode = |width, height| {
+            let mut png = Vec::new();
+            image::RgbImage::new(width, height).write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png).unwrap();
+            png
+        };
+        assert!(perceptual_hash(&encode(64, 64)).is_ok());
+        // Compresses to a few kilobytes, but would take far more to decode
+        assert!(perceptual_hash(&encode(PHASH_MAX_DIMENSION + 1, 1)).is_err());
+        assert!(perceptual_hash(b"not an image").is_err());
+    }
+
+    #[test]
+    fn test_parse_md5() {
+        let expected = Some("z80ghJXVZe9m59/5+Ydk2g==".to_string());
+        assert_eq!(parse_md5("z80ghJXVZe9m59/5+Ydk2g=="), expected);
+        assert_eq!(parse_md5("z80ghJXVZe9m59_5-Ydk2g"), expected);
+        assert_eq!(parse_md5("cfcd208495d565ef66e7dff9f98764da"), expected);
+        assert_eq!(parse_md5("CFCD208495D565EF66E7DFF9F98764DA"), expected);
+        assert_eq!(parse_md5("2QOYZWMPACZAJ2MABGMOZ6CCPY"), Some("1B2M2Y8AsgTpgAmY7PhCfg==".to_string()));
+        assert_eq!(parse_md5("2qoyzwmpaczaj2mabgmoz6ccpy"), Some("1B2M2Y8AsgTpgAmY7PhCfg==".to_string()));
+        // Wrong length or not a hash at all
+        assert_eq!(parse_md5("cfcd208495d565ef66e7dff9f98764dz"), None);
+        assert_eq!(parse_md5("z80ghJXVZe9m59"), None);
+        assert_eq!(parse_md5(""), None);
+        assert_eq!(parse_md5("not/a/hash"), None);
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00 UTC");
+        assert_eq!(format_timestamp(951782400 + 86399), "2000-02-29 23:59 UTC");
+        assert_eq!(format_timestamp(1700000000), "2023-11-14 22:13 UTC");
+        assert_eq!(format_timestamp(-1), "1969-12-31 23:59 UTC");
+    }
+}
@@ -2,16 +2,19 @@ use actix_session::Session;
 #[allow(unused_imports)]
 use log::{info, warn, error, debug};
 
-use actix_web::{get, put, post, delete, web, HttpResponse};
+use actix_web::{get, put, post, delete, web, HttpRequest, HttpResponse};
 use actix_files::NamedFile;
+use futures::StreamExt;
 use new_mime_guess::from_path;
 use serde::{Deserialize, Serialize};
 
 use crate::archiver::Archiver;
 use crate::db::DBClient;
-use crate::object_storage::ObjectStorage;
-use crate::util::{get_file_folder, get_file_url};
-use crate::models::{Board, BoardsStatus, IndexPage, IndexSearchResults, Post, UserRole};
+use crate::file_store::{FileStore, is_valid_key};
+use crate::util::{get_file_key, parse_md5, perceptual_hash};
+use crate::search::{MultiBoardSearchQuery, PostSearch, searchable_boards};
+use crate::models::{Board, BoardsStatus, FeedCursor, IndexPage, IndexSearchResults, Post, SimilarImageResults, ThreadFilter, UserRole};
+use crate::archiver::thread_filter::validate_filter;
 use crate::web::auth::{should_respect_hidden_files, AuthUser, Authenticated, AdminOnly, JSONError};
 
 use super::auth::RequireJanitor;
@@ -245,7 +248,7 @@ pub(crate) async fn post_mod_action(
             post_edits.mitsuba_file_hidden,
             post_edits.mitsuba_com_hidden,
             post_edits.mitsuba_file_hidden,
-            log_id
+            Some(log_id)
         ).await.map_err(|e| {
             error!("Error hiding post: {}", e);
             JSONError::InternalServerError("Error hiding post")
@@ -258,7 +261,7 @@ pub(crate) async fn post_mod_action(
                 .ban_image(
                     &board,
                     no,
-                    log_id
+                    Some(log_id)
                 ).await.map_err(|e| {
                     error!("Error purging image: {}", e);
                     JSONError::InternalServerError("Error banning image")
@@ -273,7 +276,7 @@ pub(crate) async fn post_mod_action(
                 .unban_image(
                     &board,
                     no,
-                    log_id
+                    Some(log_id)
                 ).await.map_err(|e| {
                     error!("Error unpurging image: {}", e);
                     JSONError::InternalServerError("Error unbanning image")
@@ -388,6 +391,12 @@ struct BoardSettings {
     pub full_images: Option<bool>,
     pub archive: Option<bool>,
     pub enable_search: Option<bool>,
+    pub upstream: Option<String>,
+    pub priority: Option<i32>,
+    pub poll_interval: Option<i32>,
+    pub max_thread_jobs: Option<i32>,
+    pub rate_limit_share: Option<i32>,
+    pub search_language: Option<String>,
 }
 #[put("/{board:[A-z0-9]+}/board.json")]
 pub(crate) async fn put_board(
@@ -404,12 +413,32 @@ pub(crate) async fn put_board(
         error!("Error getting board from DB: {}", e);
         JSONError::InternalServerError("Error getting board from DB")
     })?
-    .unwrap_or_default();
+    .unwrap_or(Board {
+        name: board_name.clone(),
+        ..Default::default()
+    });
 
     board.full_images = settings.full_images.unwrap_or(board.full_images);
     board.archive = settings.archive.unwrap_or(board.archive);
     board.enable_search = settings.enable_search.unwrap_or(board.enable_search);
+    board.upstream = settings.upstream.unwrap_or(board.upstream);
+    board.priority = settings.priority.unwrap_or(board.priority);
+    board.poll_interval = settings.poll_interval.unwrap_or(board.poll_interval);
+    board.max_thread_jobs = settings.max_thread_jobs.unwrap_or(board.max_thread_jobs);
+    board.rate_limit_share = settings.rate_limit_share.unwrap_or(board.rate_limit_share);
+    board.search_language = settings.search_language.unwrap_or(board.search_language);
+    board.validate_settings().map_err(JSONError::BadRequest)?;
+
+    let valid_language = archiver.db_client.is_search_language(&board.search_language).await
+    .map_err(|e| {
+        error!("Error checking search language: {}", e);
+        JSONError::InternalServerError("Error checking search language")
+    })?;
+    if !valid_language {
+        return Err(JSONError::BadRequest("Unknown search language").into())
+    }
 
+    // Search indexes are rebuilt in the background, the response doesn't wait for it
     archiver.set_board(board.clone()).await
         .map_err(|e| {
             error!("Error setting board settings in DB: {}", e);
@@ -446,6 +475,76 @@ pub(crate) async fn delete_board(
     Ok(HttpResponse::Ok().json(ActionSuccess::new("Board deleted")))
 }
 
+#[get("/{board:[A-z0-9]+}/filters.json")]
+pub(crate) async fn get_thread_filters(
+    db: web::Data<DBClient>,
+    info: web::Path<String>,
+    _: AuthUser<AdminOnly>
+) -> actix_web::Result<HttpResponse> {
+    let board_name = info.into_inner();
+    let filters = db.get_thread_filters(Some(&board_name)).await
+        .map_err(|e| {
+            error!("Error getting thread filters from DB: {}", e);
+            JSONError::InternalServerError("Error getting thread filters from DB")
+        })?;
+    Ok(HttpResponse::Ok().json(filters))
+}
+
+#[derive(Serialize, Deserialize)]
+struct NewThreadFilter {
+    pub exclude: Option<bool>,
+    pub pattern: Option<String>,
+    pub min_replies: Option<i64>,
+    pub sticky_only: Option<bool>,
+}
+#[post("/{board:[A-z0-9]+}/filters.json")]
+pub(crate) async fn post_thread_filter(
+    archiver: web::Data<Archiver>,
+    info: web::Path<String>,
+    new_filter: web::Json<NewThreadFilter>,
+    _: AuthUser<AdminOnly>
+) -> actix_web::Result<HttpResponse> {
+    let new_filter = new_filter.into_inner();
+    let filter = ThreadFilter {
+        board: info.into_inner(),
+        exclude: new_filter.exclude.unwrap_or(false),
+        pattern: new_filter.pattern,
+        min_replies: new_filter.min_replies.unwrap_or(0),
+        sticky_only: new_filter.sticky_only.unwrap_or(false),
+        ..Default::default()
+    };
+    validate_filter(&filter).map_err(JSONError::BadRequest)?;
+    let filter = archiver.add_thread_filter(&filter).await
+        .map_err(|e| {
+            error!("Error adding thread filter to DB: {}", e);
+            JSONError::InternalServerError("Error adding thread filter to DB")
+        })?;
+    Ok(HttpResponse::Ok().json(ActionSuccess::new_with_data("Filter added", filter)))
+}
+
+#[delete("/{board:[A-z0-9]+}/filters/{id:\\d+}.json")]
+pub(crate) async fn delete_thread_filter(
+    db: web::Data<DBClient>,
+    info: web::Path<(String, i64)>,
+    _: AuthUser<AdminOnly>
+) -> actix_web::Result<HttpResponse> {
+    let (board_name, filter_id) = info.into_inner();
+    let filters = db.get_thread_filters(Some(&board_name)).await
+        .map_err(|e| {
+            error!("Error getting thread filters from DB: {}", e);
+            JSONError::InternalServerError("Error getting thread filters from DB")
+        })?;
+    if !filters.iter().any(|f| f.id == filter_id) {
+        return Err(JSONError::NotFound("Filter not found").into());
+    }
+    db.delete_thread_filter(filter_id).await
+        .map_err(|e| {
+            error!("Error deleting thread filter from DB: {}", e);
+            JSONError::InternalServerError("Error deleting thread filter from DB")
+        })?;
+    Ok(HttpResponse::Ok().json(ActionSuccess::new("Filter deleted")))
+}
+
 #[get("/{board:[A-z0-9]+}/thread/{no:\\d+}.json")]
 pub(crate) async fn get_thread(
     db: web::Data<DBClient>,
@@ -487,6 +586,34 @@ pub(crate) async fn get_post(
     Ok(HttpResponse::Ok().json(post))
 }
 
+#[derive(Deserialize)]
+struct FeedQuery {
+    cursor: Option<String>,
+    limit: Option<i64>,
+}
+
+// Changes to a board since the cursor, for instances replicating it
+#[get("/{board:[A-z0-9]+}/feed.json")]
+pub(crate) async fn get_feed(
+    db: web::Data<DBClient>,
+    board: web::Path<String>,
+    query: web::Query<FeedQuery>,
+) -> actix_web::Result<HttpResponse> {
+    let board = board.into_inner();
+    let cursor = match query.cursor.as_deref() {
+        Some(cursor) => cursor.parse::<FeedCursor>()
+            .map_err(|_| JSONError::BadRequest("Invalid cursor"))?,
+        None => FeedCursor::default()
+    };
+    let limit = query.limit.unwrap_or(500).clamp(1, 1000);
+    let feed = db.get_change_feed(&board, cursor, limit).await
+        .map_err(|e| {
+            error!("Error getting changes to /{}/ from DB: {}", board, e);
+            JSONError::InternalServerError("")
+        })?;
+    Ok(HttpResponse::Ok().json(feed))
+}
+
 #[derive(Deserialize)]
 struct SearchQuery {
     s: Option<String>,
@@ -505,10 +632,11 @@ pub(crate) async fn get_index(
     }
     let respect_hidden_files = should_respect_hidden_files(user);
     if let Some(search_query) = &query.s {
+        let search = PostSearch::parse(search_query).map_err(JSONError::BadRequest)?;
         let (posts, total_results) = db
-            .posts_full_text_search(
-                &board,
-                &search_query,
+            .posts_search(
+                &[board],
+                &search,
                 index,
                 15,
                 respect_hidden_files
@@ -533,110 +661,267 @@ pub(crate) async fn get_index(
     Ok(HttpResponse::Ok().json(IndexPage {threads: threads.into_iter().map(|t| t.into()).collect()}))
 }
 
-#[get("/{board:[A-z0-9]+}/{tim:\\d+}.{ext}")]
-pub(crate) async fn get_full_image(
+#[get("/_mitsuba/search.json")]
+pub(crate) async fn search_posts(
+    db: web::Data<DBClient>,
+    query: web::Query<MultiBoardSearchQuery>,
+    user: AuthUser,
+) -> actix_web::Result<HttpResponse> {
+    let boards = db.get_all_boards().await
+        .map_err(|e| {
+            error!("Error getting boards from DB: {}", e);
+            JSONError::InternalServerError("")
+        })?;
+    let q = query.q.as_deref().ok_or(JSONError::BadRequest("q is required"))?;
+    let boards = searchable_boards(&boards, query.boards.as_deref()).map_err(JSONError::BadRequest)?;
+    search_results(&db, &boards, q, query.page, user).await
+}
+
+#[derive(Deserialize)]
+struct BoardSearchQuery {
+    q: String,
+    page: Option<i64>,
+}
+
+#[get("/{board:[A-z0-9]+}/search.json")]
+pub(crate) async fn search_board(
+    db: web::Data<DBClient>,
+    board: web::Path<String>,
+    query: web::Query<BoardSearchQuery>,
+    user: AuthUser,
+) -> actix_web::Result<HttpResponse> {
+    let boards = db.get_all_boards().await
+        .map_err(|e| {
+            error!("Error getting boards from DB: {}", e);
+            JSONError::InternalServerError("")
+        })?;
+    let boards = searchable_boards(&boards, Some(&board)).map_err(JSONError::NotFound)?;
+    search_results(&db, &boards, &query.q, query.page, user).await
+}
+
+// Pages start at 1, like index pages
+async fn search_results(db: &DBClient, boards: &[String], q: &str, page: Option<i64>, user: AuthUser) -> actix_web::Result<HttpResponse> {
+    let search = PostSearch::parse(q).map_err(JSONError::BadRequest)?;
+    let respect_hidden_files = should_respect_hidden_files(user);
+    let (posts, total_results) = db
+        .posts_search(
+            boards,
+            &search,
+            page.unwrap_or(1).max(1) - 1,
+            15,
+            respect_hidden_files
+        ).await
+        .map_err(|e| {
+            error!("Error searching posts in DB: {}", e);
+            JSONError::InternalServerError("")
+        })?;
+    Ok(HttpResponse::Ok().json(IndexSearchResults {posts, total_results}))
+}
+
+#[get("/_mitsuba/file/md5/{md5}.json")]
+pub(crate) async fn get_posts_by_md5(
     db: web::Data<DBClient>,
-    info: web::Path<(String, i64, String)>,
+    md5: web::Path<String>,
+    query: web::Query<PageQuery>,
     user: AuthUser,
-) -> actix_web::Result<NamedFile> {
-    let (board, tim, ext) = info.into_inner();
+) -> actix_web::Result<HttpResponse> {
+    let md5 = parse_md5(&md5).ok_or(JSONError::BadRequest("Invalid md5"))?;
     let respect_hidden_files = should_respect_hidden_files(user);
-    get_image_from_tim(db, board, tim, ext, false, respect_hidden_files).await
+    let results = db.get_posts_by_md5(&md5, None, query.page.unwrap_or(0).max(0), query.page_size.unwrap_or(100).clamp(1, 500), respect_hidden_files).await
+        .map_err(|e| {
+            error!("Error getting posts by md5 from DB: {}", e);
+            JSONError::InternalServerError("")
+        })?;
+    Ok(HttpResponse::Ok().json(results))
 }
 
-#[get("/{board:[A-z0-9]+}/{tim:\\d+}s.jpg")]
-pub(crate) async fn get_thumbnail_image(
+#[get("/_mitsuba/file/sha256/{sha256}.json")]
+pub(crate) async fn get_posts_by_sha256(
     db: web::Data<DBClient>,
-    info: web::Path<(String, i64)>,
+    sha256: web::Path<String>,
+    query: web::Query<PageQuery>,
     user: AuthUser,
-) -> actix_web::Result<NamedFile> {
-    let (board, tim) = info.into_inner();
+) -> actix_web::Result<HttpResponse> {
     let respect_hidden_files = should_respect_hidden_files(user);
-    get_image_from_tim(db, board, tim, "".to_string(), true, respect_hidden_files).await
+    let results = db.get_posts_by_sha256(&sha256, query.page.unwrap_or(0).max(0), query.page_size.unwrap_or(100).clamp(1, 500), respect_hidden_files).await
+        .map_err(|e| {
+            error!("Error getting posts by sha256 from DB: {}", e);
+            JSONError::InternalServerError("")
+        })?;
+    Ok(HttpResponse::Ok().json(results))
 }
 
-pub(crate) async fn get_image_from_tim(db: web::Data<DBClient>, board: String, tim: i64, ext: String, is_thumb: bool, remove_hidden: bool)-> actix_web::Result<NamedFile> {
-    let sha256 = db.image_tim_to_sha256(&board, tim, is_thumb, remove_hidden).await
+#[derive(Deserialize)]
+struct SimilarQuery {
+    sha256: Option<String>,
+    // Most bits allowed to differ between perceptual hashes, out of 64
+    distance: Option<i32>,
+}
+
+#[get("/_mitsuba/similar.json")]
+pub(crate) async fn get_similar_posts(
+    db: web::Data<DBClient>,
+    query: web::Query<SimilarQuery>,
+    user: AuthUser,
+) -> actix_web::Result<HttpResponse> {
+    let sha256 = query.sha256.as_deref().ok_or(JSONError::BadRequest("sha256 is required"))?;
+    let phash = db.get_file_phash(sha256).await
         .map_err(|e| {
-            error!("Error getting image from DB: {}", e);
-            actix_web::error::ErrorInternalServerError("")
+            error!("Error getting perceptual hash from DB: {}", e);
+            JSONError::InternalServerError("")
         })?
-        .ok_or(actix_web::error::ErrorNotFound(""))?;
-    
-    let filename = match is_thumb {
-        true => format!("{}.jpg", sha256),
-        false => format!("{}.{}", sha256, ext)
-    };
-    let path = get_file_folder(&sha256, is_thumb).join(filename);
-    NamedFile::open(path).map_err(|e| {
-        error!("Error getting image from filesystem: {}", e);
-        actix_web::error::ErrorNotFound("")
-    })
+        .ok_or(JSONError::NotFound("No image with a perceptual hash found for this sha256"))?;
+    similar_posts_response(&db, phash, query.distance, user).await
 }
 
-#[get("/{board:[A-z0-9]+}/{tim:\\d+}.{ext}")]
-pub(crate) async fn get_full_image_object_storage(
+// Largest image accepted by the similar image search
+const SIMILAR_UPLOAD_MAX_BYTES: usize = 20 * 1024 * 1024;
+
+// The image to search for is the request body
+#[post("/_mitsuba/similar.json")]
+pub(crate) async fn post_similar_search(
     db: web::Data<DBClient>,
-    obc: web::Data<ObjectStorage>,
-    info: web::Path<(String, i64, String)>,
+    query: web::Query<SimilarQuery>,
+    mut payload: web::Payload,
     user: AuthUser,
 ) -> actix_web::Result<HttpResponse> {
-    let (board, tim, ext) = info.into_inner();
+    // Read here rather than with web::Bytes, so the size limit only applies to this route
+    let mut body = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if body.len() + chunk.len() > SIMILAR_UPLOAD_MAX_BYTES {
+            return Err(JSONError::new("Images can be 20 MB at most".to_string(), actix_web::http::StatusCode::PAYLOAD_TOO_LARGE).into())
+        }
+        body.extend_from_slice(&chunk);
+    }
+    if body.is_empty() {
+        return Err(JSONError::BadRequest("Request body must be an image").into())
+    }
+    // Decoding is CPU bound, it runs on the blocking thread pool
+    let phash = web::block(move || perceptual_hash(&body)).await
+        .map_err(|e| {
+            error!("Error computing perceptual hash: {}", e);
+            JSONError::InternalServerError("")
+        })?
+        .map_err(|_| JSONError::BadRequest("Request body is not a supported image"))?;
+    similar_posts_response(&db, phash, query.distance, user).await
+}
+
+async fn similar_posts_response(db: &DBClient, phash: i64, distance: Option<i32>, user: AuthUser) -> actix_web::Result<HttpResponse> {
     let respect_hidden_files = should_respect_hidden_files(user);
-    get_image_from_tim_object_storage(db, obc, board, tim, ext, false, respect_hidden_files).await
+    let posts = db.get_similar_posts(phash, distance.unwrap_or(10).clamp(0, 32), 50, respect_hidden_files).await
+        .map_err(|e| {
+            error!("Error getting similar posts from DB: {}", e);
+            JSONError::InternalServerError("")
+        })?;
+    Ok(HttpResponse::Ok().json(SimilarImageResults {phash: format!("{:016x}", phash), posts}))
 }
-#[get("/{board:[A-z0-9]+}/{tim:\\d+}s.jpg")]
-pub(crate) async fn get_thumbnail_image_object_storage(
+
+// Extra files have their own tim, eg. 1583512345678-1 on vichan
+#[get("/{board:[A-z0-9]+}/{tim:\\d+(?:-\\d+)?}.{ext}")]
+pub(crate) async fn get_full_image(
+    req: HttpRequest,
     db: web::Data<DBClient>,
-    obc: web::Data<ObjectStorage>,
-    info: web::Path<(String, i64)>,
+    store: web::Data<dyn FileStore>,
+    info: web::Path<(String, String, String)>,
+    user: AuthUser,
+) -> actix_web::Result<HttpResponse> {
+    let (board, tim, _ext) = info.into_inner();
+    let respect_hidden_files = should_respect_hidden_files(user);
+    get_image_from_tim(&req, db, store, board, tim, false, respect_hidden_files).await
+}
+
+#[get("/{board:[A-z0-9]+}/{tim:\\d+(?:-\\d+)?}s.jpg")]
+pub(crate) async fn get_thumbnail_image(
+    req: HttpRequest,
+    db: web::Data<DBClient>,
+    store: web::Data<dyn FileStore>,
+    info: web::Path<(String, String)>,
     user: AuthUser,
 ) -> actix_web::Result<HttpResponse> {
     let (board, tim) = info.into_inner();
     let respect_hidden_files = should_respect_hidden_files(user);
-    get_image_from_tim_object_storage(db, obc, board, tim, "jpg".to_string(), true, respect_hidden_files).await
+    get_image_from_tim(&req, db, store, board, tim, true, respect_hidden_files).await
 }
 
-pub(crate) async fn get_image_from_tim_object_storage(
+pub(crate) async fn get_image_from_tim(
+    req: &HttpRequest,
     db: web::Data<DBClient>,
-    obc: web::Data<ObjectStorage>,
+    store: web::Data<dyn FileStore>,
     board: String,
-    tim: i64,
-    ext: String,
+    tim: String,
     is_thumb: bool,
     remove_hidden: bool
 ) -> actix_web::Result<HttpResponse> {
-    let sha256 = db.image_tim_to_sha256(&board, tim, is_thumb, remove_hidden).await
+    let (sha256, ext) = db.image_tim_to_sha256(&board, &tim, is_thumb, remove_hidden).await
         .map_err(|e| {
             error!("Error getting image from DB: {}", e);
             actix_web::error::ErrorInternalServerError("")
         })?
         .ok_or(actix_web::error::ErrorNotFound(""))?;
-    let path = get_file_url(&sha256, &(".".to_string()+&ext), is_thumb);
+    let key = get_file_key(&sha256, &ext, is_thumb);
 
-    get_file_object_storage(obc, &path).await
+    serve_file(req, store.as_ref(), &key).await
 }
 
-pub(crate) async fn get_file_object_storage_handler(obc: web::Data<ObjectStorage>, path: web::Path<String>) -> actix_web::Result<HttpResponse> {
-    let path = &path.into_inner();
-    get_file_object_storage(obc, &("/img/".to_string()+&path)).await
+pub(crate) async fn get_file_handler(req: HttpRequest, store: web::Data<dyn FileStore>, path: web::Path<String>) -> actix_web::Result<HttpResponse> {
+    let key = path.into_inner();
+    if !is_valid_key(&key) {
+        return Err(actix_web::error::ErrorNotFound(""));
+    }
+    serve_file(&req, store.as_ref(), &key).await
 }
 
-pub(crate) async fn get_file_object_storage(obc: web::Data<ObjectStorage>, path: &String) -> actix_web::Result<HttpResponse> {
-    let response_data = obc.bucket.get_object(path).await.map_err(|e| {
-        error!("Error getting file ({}) from bucket: {}", path, e);
-        actix_web::error::ErrorInternalServerError("")
-    })?;
-    let code = response_data.status_code();
-    let data = response_data.bytes().to_owned();
-    if code == 404 {
-        Err(actix_web::error::ErrorNotFound(""))
-    } else if code == 200 {
-        let region = obc.bucket.url();
-        debug!("{}{}", region, path);
-        Ok(HttpResponse::Ok().content_type(from_path(path).first_or_octet_stream().as_ref()).body(data))
-    } else {
-        error!("Error getting file ({}) from bucket: {}", path, code);
-        Err(actix_web::error::ErrorInternalServerError("").into())
+pub(crate) async fn serve_file(req: &HttpRequest, store: &dyn FileStore, key: &str) -> actix_web::Result<HttpResponse> {
+    // Files on disk are served directly, which handles range requests and caching headers for us
+    if let Some(path) = store.local_path(key) {
+        let file = NamedFile::open_async(path).await.map_err(|e| {
+            error!("Error getting file ({}) from filesystem: {}", key, e);
+            actix_web::error::ErrorNotFound("")
+        })?;
+        return Ok(file.into_response(req));
     }
-}
\ No newline at end of file
+    let stream = store.stream(key).await
+        .map_err(|e| {
+            error!("Error getting file ({}) from {} storage: {}", key, store.name(), e);
+            actix_web::error::ErrorInternalServerError("")
+        })?
+        .ok_or(actix_web::error::ErrorNotFound(""))?;
+    Ok(HttpResponse::Ok().content_type(from_path(key).first_or_octet_stream().as_ref()).streaming(stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App};
+    use crate::archiver::tests::memory_archiver;
+    use crate::models::{ExtraFiles, PostFile};
+
+    #[actix_web::test]
+    async fn test_get_extra_file_by_tim() {
+        let archiver = memory_archiver().await;
+        let board = "testextratim".to_string();
+        archiver.get_or_create_board(&board).await.unwrap();
+        let extra_files = ExtraFiles(vec![PostFile { tim: "1700000000000-1".to_string(), ext: ".png".to_string(), ..Default::default() }]);
+        let post = Post { board: board.clone(), no: 1, time: 1700000000, tim: 1700000000000, ext: ".jpg".to_string(), extra_files, ..Default::default() };
+        archiver.db_client.insert_posts(&vec![post]).await.unwrap();
+        for (idx, content, ext) in [(0, &b"first"[..], ".jpg"), (1, &b"second"[..], ".png")] {
+            let sha256 = archiver.store_file(bytes::Bytes::from_static(content), ext, false).await.unwrap();
+            archiver.db_client.add_post_file(&board, 1, idx, &sha256, &ext.to_string(), false).await.unwrap();
+        }
+
+        let app = test::init_service(App::new()
+            .app_data(web::Data::new(archiver.db_client.clone()))
+            .app_data(web::Data::new(archiver.clone()))
+            .app_data(web::Data::from(archiver.file_store.clone()))
+            .service(get_full_image)
+        ).await;
+        let get = |path: String| test::TestRequest::get().uri(&path).to_request();
+        assert_eq!(&b"first"[..], test::call_and_read_body(&app, get(format!("/{}/1700000000000.jpg", board))).await);
+        assert_eq!(&b"second"[..], test::call_and_read_body(&app, get(format!("/{}/1700000000000-1.png", board))).await);
+        let missing = test::call_service(&app, get(format!("/{}/1700000000000-2.png", board))).await;
+        assert_eq!(actix_web::http::StatusCode::NOT_FOUND, missing.status());
+
+        archiver.db_client.purge_board_data(&board).await.unwrap();
+    }
+}
@@ -0,0 +1,514 @@
+#[allow(unused_imports)]
+use log::{info, warn, error, debug};
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use actix_web::{get, web, HttpRequest, HttpResponse, ResponseError};
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::archiver::Archiver;
+use crate::db::DBClient;
+use crate::models::{Board, IndexThread, Post};
+use crate::search::{parse_time, searchable_boards, PostSearch};
+use crate::upstream::UpstreamSource;
+use crate::util::{get_file_url, parse_md5};
+use crate::web::auth::{should_respect_hidden_files, AuthUser};
+
+/**
+ * A post as the FoolFuuka API (`/_/api/chan/...`) returns it, so tools made for Asagi/FoolFuuka archives,
+ * like 4chan X's archive redirection, can use Mitsuba. Like FoolFuuka, most numbers are strings.
+ */
+#[derive(Debug, Serialize)]
+struct FFPost {
+    doc_id: String,
+    num: String,
+    subnum: String,
+    thread_num: String,
+    op: String,
+    timestamp: i64,
+    timestamp_expired: String,
+    fourchan_date: String,
+    capcode: String,
+    email: Option<String>,
+    name: Option<String>,
+    trip: Option<String>,
+    title: Option<String>,
+    // Plain text, with quotes and spoilers written like FoolFuuka stores them
+    comment: Option<String>,
+    comment_processed: Option<String>,
+    poster_hash: Option<String>,
+    poster_country: Option<String>,
+    poster_country_name: Option<String>,
+    sticky: String,
+    locked: String,
+    deleted: String,
+    nreplies: Option<String>,
+    nimages: Option<String>,
+    board: FFBoard,
+    media: Option<FFMedia>,
+}
+
+#[derive(Debug, Serialize)]
+struct FFBoard {
+    name: String,
+    shortname: String,
+}
+
+#[derive(Debug, Serialize)]
+struct FFMedia {
+    media_id: String,
+    spoiler: String,
+    preview_orig: String,
+    preview_w: String,
+    preview_h: String,
+    media: String,
+    media_orig: String,
+    media_filename: String,
+    media_w: String,
+    media_h: String,
+    media_size: String,
+    media_hash: String,
+    banned: String,
+    media_link: Option<String>,
+    remote_media_link: Option<String>,
+    thumb_link: Option<String>,
+}
+
+fn flag(value: bool) -> String {
+    if value { "1" } else { "0" }.to_string()
+}
+
+fn not_empty(value: &str) -> Option<String> {
+    Some(value.to_string()).filter(|v| !v.is_empty())
+}
+
+/**
+ * Where the media of posts can be found. Links to our copies are absolute, since they are used from other sites,
+ * and `remote_media_link` points to the file on the upstream the board is archived from.
+ */
+struct MediaLinks {
+    base_url: String,
+    upstreams: HashMap<String, Arc<dyn UpstreamSource>>,
+}
+
+impl MediaLinks {
+    fn new(base_url: String, boards: &[Board], archiver: &Archiver) -> Self {
+        let upstreams = boards.iter()
+            .filter_map(|board| archiver.get_upstream(board).ok().map(|source| (board.name.clone(), source)))
+            .collect();
+        Self { base_url, upstreams }
+    }
+
+    async fn load(req: &HttpRequest, archiver: &Archiver) -> Result<Self, FFError> {
+        let boards = archiver.db_client.get_all_boards().await
+            .map_err(|e| {
+                error!("Error getting boards from DB: {}", e);
+                FFError::internal()
+            })?;
+        Ok(Self::new(base_url(req), &boards, archiver))
+    }
+
+    fn remote_file(&self, post: &Post) -> Option<String> {
+        self.upstreams.get(&post.board).map(|source| source.file_url(&post.board, &post.tim.to_string(), &post.ext))
+    }
+}
+
+impl FFPost {
+    fn new(post: &Post, links: &MediaLinks) -> Self {
+        let is_op = post.resto == 0;
+        let media = (post.tim != 0).then(|| {
+            let media_link = post.file_sha256.as_ref().map(|sha256| format!("{}{}", links.base_url, get_file_url(sha256, &post.ext, false)));
+            let thumb_link = post.thumbnail_sha256.as_ref().map(|sha256| {
+                let ext = post.thumbnail_ext.as_deref().unwrap_or(".jpg");
+                format!("{}{}", links.base_url, get_file_url(sha256, ext, true))
+            });
+            FFMedia {
+                media_id: post.post_id.to_string(),
+                spoiler: flag(post.spoiler != 0),
+                preview_orig: format!("{}s.jpg", post.tim),
+                preview_w: post.tn_w.to_string(),
+                preview_h: post.tn_h.to_string(),
+                media: format!("{}{}", post.tim, post.ext),
+                media_orig: format!("{}{}", post.tim, post.ext),
+                media_filename: format!("{}{}", post.filename, post.ext),
+                media_w: post.w.to_string(),
+                media_h: post.h.to_string(),
+                media_size: post.fsize.to_string(),
+                media_hash: post.md5.clone(),
+                banned: flag(post.mitsuba_file_blacklisted.unwrap_or(false)),
+                remote_media_link: links.remote_file(post),
+                media_link,
+                thumb_link,
+            }
+        });
+        Self {
+            doc_id: post.post_id.to_string(),
+            num: post.no.to_string(),
+            subnum: "0".to_string(),
+            thread_num: if is_op { post.no } else { post.resto }.to_string(),
+            op: flag(is_op),
+            timestamp: post.time,
+            timestamp_expired: post.deleted_on.to_string(),
+            fourchan_date: post.now.clone(),
+            capcode: capcode_letter(&post.capcode).to_string(),
+            email: None,
+            name: not_empty(&post.name),
+            trip: not_empty(&post.trip),
+            title: not_empty(&post.sub),
+            comment: not_empty(&comment_to_text(&post.com)),
+            comment_processed: not_empty(&post.com),
+            poster_hash: not_empty(&post.id),
+            poster_country: not_empty(&post.country),
+            poster_country_name: not_empty(&post.country_name),
+            sticky: flag(post.sticky != 0),
+            locked: flag(post.closed != 0),
+            deleted: flag(post.deleted_on != 0),
+            nreplies: is_op.then(|| post.replies.to_string()),
+            nimages: is_op.then(|| post.images.to_string()),
+            board: FFBoard {
+                name: post.board.clone(),
+                shortname: post.board.clone(),
+            },
+            media,
+        }
+    }
+}
+
+fn capcode_letter(capcode: &str) -> &'static str {
+    match capcode {
+        "" => "N",
+        "admin" | "admin_highlight" => "A",
+        "developer" => "D",
+        "manager" => "G",
+        "founder" => "F",
+        "verified" => "V",
+        _ => "M",
+    }
+}
+
+/**
+ * 4chan's comment HTML as plain text, the way FoolFuuka stores comments:
+ * line breaks are newlines, spoilers are [spoiler] tags, and other markup is dropped.
+ */
+fn comment_to_text(com: &str) -> String {
+    let com = com
+        .replace("<br>", "\n")
+        .replace("<wbr>", "")
+        .replace("<s>", "[spoiler]")
+        .replace("</s>", "[/spoiler]");
+    let mut text = String::with_capacity(com.len());
+    let mut in_tag = false;
+    for c in com.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            _ if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    text.replace("&gt;", ">").replace("&lt;", "<").replace("&quot;", "\"").replace("&#039;", "'").replace("&amp;", "&")
+}
+
+// Posts as an object keyed by post number, in thread order
+struct PostMap(Vec<FFPost>);
+
+impl Serialize for PostMap {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.0.iter().map(|post| (&post.num, post)))
+    }
+}
+
+#[derive(Serialize)]
+struct FFThread {
+    op: FFPost,
+    posts: PostMap,
+}
+
+#[derive(Serialize)]
+struct FFIndexThread {
+    omitted: i64,
+    images_omitted: i64,
+    op: FFPost,
+    posts: Vec<FFPost>,
+}
+
+#[derive(Serialize)]
+struct FFSearchMeta {
+    total_found: i64,
+    max_results: i64,
+    search_title: String,
+}
+
+#[derive(Serialize)]
+struct FFSearchResults {
+    #[serde(rename = "0")]
+    results: FFSearchPosts,
+    meta: FFSearchMeta,
+}
+
+#[derive(Serialize)]
+struct FFSearchPosts {
+    posts: Vec<FFPost>,
+}
+
+// FoolFuuka reports errors as {"error": "..."}
+#[derive(Debug, Serialize)]
+struct FFError {
+    error: String,
+    #[serde(skip)]
+    code: actix_web::http::StatusCode,
+}
+
+impl FFError {
+    fn not_found(error: &str) -> Self {
+        Self { error: error.to_string(), code: actix_web::http::StatusCode::NOT_FOUND }
+    }
+    fn bad_request<T: ToString>(error: T) -> Self {
+        Self { error: error.to_string(), code: actix_web::http::StatusCode::BAD_REQUEST }
+    }
+    fn internal() -> Self {
+        Self { error: "Internal server error".to_string(), code: actix_web::http::StatusCode::INTERNAL_SERVER_ERROR }
+    }
+}
+
+impl std::fmt::Display for FFError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "FFError: {}", self.error)
+    }
+}
+
+impl ResponseError for FFError {
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(self)
+    }
+
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        self.code
+    }
+}
+
+fn base_url(req: &HttpRequest) -> String {
+    let info = req.connection_info();
+    format!("{}://{}", info.scheme(), info.host())
+}
+
+// FoolFuuka numbers ghost posts "123_1", Mitsuba has none
+fn parse_num(num: &str) -> Result<i64, FFError> {
+    num.parse::<i64>().map_err(|_| FFError::bad_request("Invalid post number."))
+}
+
+#[derive(Deserialize)]
+struct PostQuery {
+    board: String,
+    num: String,
+}
+
+#[get("/_/api/chan/thread")]
+pub(crate) async fn get_thread(
+    req: HttpRequest,
+    db: web::Data<DBClient>,
+    archiver: web::Data<Archiver>,
+    query: web::Query<PostQuery>,
+    user: AuthUser,
+) -> Result<HttpResponse, FFError> {
+    let no = parse_num(&query.num)?;
+    let thread = db.get_thread(&query.board, no, should_respect_hidden_files(user)).await
+        .map_err(|e| {
+            error!("Error getting thread from DB: {}", e);
+            FFError::internal()
+        })?
+        .filter(|thread| !thread.posts.is_empty())
+        .ok_or(FFError::not_found("Thread not found."))?;
+
+    let links = MediaLinks::load(&req, &archiver).await?;
+    let mut posts = thread.posts.iter().map(|post| FFPost::new(post, &links));
+    let op = posts.next().ok_or(FFError::not_found("Thread not found."))?;
+    let thread = FFThread { op, posts: PostMap(posts.collect()) };
+    Ok(HttpResponse::Ok().json(std::collections::HashMap::from([(no.to_string(), thread)])))
+}
+
+#[get("/_/api/chan/post")]
+pub(crate) async fn get_post(
+    req: HttpRequest,
+    db: web::Data<DBClient>,
+    archiver: web::Data<Archiver>,
+    query: web::Query<PostQuery>,
+    user: AuthUser,
+) -> Result<HttpResponse, FFError> {
+    let no = parse_num(&query.num)?;
+    let post = db.get_post(&query.board, no, should_respect_hidden_files(user)).await
+        .map_err(|e| {
+            error!("Error getting post from DB: {}", e);
+            FFError::internal()
+        })?
+        .ok_or(FFError::not_found("Post not found."))?;
+    Ok(HttpResponse::Ok().json(FFPost::new(&post, &MediaLinks::load(&req, &archiver).await?)))
+}
+
+#[derive(Deserialize)]
+struct IndexQuery {
+    board: String,
+    page: Option<i64>,
+}
+
+#[get("/_/api/chan/index")]
+pub(crate) async fn get_index(
+    req: HttpRequest,
+    db: web::Data<DBClient>,
+    archiver: web::Data<Archiver>,
+    query: web::Query<IndexQuery>,
+    user: AuthUser,
+) -> Result<HttpResponse, FFError> {
+    let threads = db.get_thread_index(&query.board, query.page.unwrap_or(1).max(1) - 1, 10, should_respect_hidden_files(user)).await
+        .map_err(|e| {
+            error!("Error getting index from DB: {}", e);
+            FFError::internal()
+        })?;
+    let links = MediaLinks::load(&req, &archiver).await?;
+    let mut index = serde_json::Map::new();
+    for thread in threads {
+        let thread: IndexThread = thread.into();
+        let mut posts = thread.posts.iter();
+        let Some(op) = posts.next() else {
+            continue
+        };
+        let thread = FFIndexThread {
+            omitted: op.omitted_posts,
+            images_omitted: op.omitted_images,
+            op: FFPost::new(&op.inner_post, &links),
+            posts: posts.map(|post| FFPost::new(&post.inner_post, &links)).collect(),
+        };
+        index.insert(op.inner_post.no.to_string(), serde_json::to_value(thread).map_err(|_| FFError::internal())?);
+    }
+    Ok(HttpResponse::Ok().json(index))
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    // One board, or several separated by dots or commas. Every board with search enabled if missing.
+    board: Option<String>,
+    boards: Option<String>,
+    text: Option<String>,
+    subject: Option<String>,
+    username: Option<String>,
+    tripcode: Option<String>,
+    filename: Option<String>,
+    // Base64 md5 of a file
+    image: Option<String>,
+    uid: Option<String>,
+    country: Option<String>,
+    // deleted or not-deleted
+    deleted: Option<String>,
+    // op or posts
+    #[serde(rename = "type")]
+    post_type: Option<String>,
+    // YYYY-MM-DD
+    start: Option<String>,
+    end: Option<String>,
+    page: Option<i64>,
+}
+
+const SEARCH_PAGE_SIZE: i64 = 25;
+
+#[get("/_/api/chan/search")]
+pub(crate) async fn search(
+    req: HttpRequest,
+    db: web::Data<DBClient>,
+    archiver: web::Data<Archiver>,
+    query: web::Query<SearchQuery>,
+    user: AuthUser,
+) -> Result<HttpResponse, FFError> {
+    let query = query.into_inner();
+    let remove_hidden = should_respect_hidden_files(user);
+    let page = query.page.unwrap_or(1).max(1) - 1;
+
+    let all_boards = db.get_all_boards().await
+        .map_err(|e| {
+            error!("Error getting boards from DB: {}", e);
+            FFError::internal()
+        })?;
+    let requested = query.boards.as_ref().or(query.board.as_ref()).map(|b| b.replace('.', ","));
+    let boards = searchable_boards(&all_boards, requested.as_deref()).map_err(FFError::bad_request)?;
+
+    let (posts, total_found) = if let Some(image) = &query.image {
+        // File searches don't combine with the other filters
+        let md5 = parse_md5(image).ok_or(FFError::bad_request("Invalid image hash."))?;
+        let results = db.get_posts_by_md5(&md5, Some(&boards), page, SEARCH_PAGE_SIZE, remove_hidden).await
+            .map_err(|e| {
+                error!("Error looking up md5 in DB: {}", e);
+                FFError::internal()
+            })?;
+        (results.posts, results.total_results)
+    } else {
+        let search = PostSearch {
+            text: query.text.unwrap_or_default(),
+            subject: query.subject,
+            name: query.username,
+            trip: query.tripcode,
+            filename: query.filename,
+            id: query.uid,
+            country: query.country,
+            deleted: match query.deleted.as_deref() {
+                Some("deleted") => Some(true),
+                Some("not-deleted") => Some(false),
+                _ => None
+            },
+            is_op: match query.post_type.as_deref() {
+                Some("op") => Some(true),
+                Some("posts") => Some(false),
+                _ => None
+            },
+            after: query.start.as_deref().map(parse_time).transpose().map_err(FFError::bad_request)?,
+            // The end date is included
+            before: query.end.as_deref().map(parse_time).transpose().map_err(FFError::bad_request)?.map(|t| t + 86400),
+            ..Default::default()
+        };
+        db.posts_search(&boards, &search, page, SEARCH_PAGE_SIZE, remove_hidden).await
+            .map_err(|e| {
+                error!("Error searching posts in DB: {}", e);
+                FFError::internal()
+            })?
+    };
+    if posts.is_empty() {
+        return Err(FFError::not_found("No results found."))
+    }
+    let links = MediaLinks::new(base_url(&req), &all_boards, &archiver);
+    Ok(HttpResponse::Ok().json(FFSearchResults {
+        results: FFSearchPosts { posts: posts.iter().map(|post| FFPost::new(post, &links)).collect() },
+        meta: FFSearchMeta {
+            total_found,
+            max_results: total_found,
+            search_title: format!("Searching for posts, {} results found", total_found),
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::upstream::FourChanSource;
+
+    #[test]
+    fn test_foolfuuka_post() {
+        let post = Post {
+            post_id: 7, board: "po".to_string(), no: 12, resto: 10, time: 1700000000,
+            com: "<a href=\"#p10\" class=\"quotelink\">&gt;&gt;10</a><br><span class=\"quote\">&gt;implying</span><br><s>spoiler</s> &amp; more".to_string(),
+            tim: 1700000000123, ext: ".png".to_string(), filename: "cat".to_string(), md5: "MD5==".to_string(),
+            thumbnail_sha256: Some("ABCDEF".to_string()),
+            ..Default::default()
+        };
+        let links = MediaLinks {
+            base_url: "https://example.com".to_string(),
+            upstreams: HashMap::from([("po".to_string(), Arc::new(FourChanSource::default()) as Arc<dyn UpstreamSource>)]),
+        };
+        let ff = FFPost::new(&post, &links);
+        assert_eq!(Some(">>10\n>implying\n[spoiler]spoiler[/spoiler] & more".to_string()), ff.comment);
+        assert_eq!(("12", "10", "0", "N"), (ff.num.as_str(), ff.thread_num.as_str(), ff.op.as_str(), ff.capcode.as_str()));
+        let media = ff.media.unwrap();
+        assert_eq!("cat.png", media.media_filename);
+        assert_eq!(None, media.media_link);
+        assert_eq!(Some("https://example.com/img/thumb/AB/C/ABCDEF.jpg".to_string()), media.thumb_link);
+        assert_eq!(Some("https://i.4cdn.org/po/1700000000123.png".to_string()), media.remote_media_link);
+    }
+}
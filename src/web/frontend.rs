@@ -14,9 +14,12 @@ use handlebars::{Handlebars, RenderContext, Helper, Context, JsonRender, HelperR
 use handlebars::handlebars_helper;
 use handlebars_misc_helpers::register;
 
-use crate::util::{shorten_string, string_to_idcolor,base64_to_32, get_file_url};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+
+use crate::search::{MultiBoardSearchQuery, PostSearch, searchable_boards};
+use crate::util::{shorten_string, string_to_idcolor,base64_to_32, get_file_url, parse_md5, format_timestamp, clamp_page};
 use crate::db::DBClient;
-use crate::models::{IndexThread, Post, IndexPost, Board, Thread};
+use crate::models::{IndexThread, Post, IndexPost, Board, Thread, FileLookupResults};
 use crate::web::auth::{AuthUser, should_respect_hidden_files};
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -49,6 +52,27 @@ struct TemplateThreadIndexThread {
     pub posts: Vec<IndexPost>
 }
 #[derive(Debug, Clone, Deserialize, Serialize)]
+struct TemplateSearch {
+    pub boards: Vec<Board>,
+    pub search_query: String,
+    pub total_results: i64,
+    pub next: i64,
+    pub prev: i64,
+    pub current: i64,
+    pub pages: Vec<i64>,
+    pub posts: Vec<Post>,
+    pub query_string: String
+}
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct TemplateFileLookup {
+    pub boards: Vec<Board>,
+    pub kind: String,
+    pub hash: String,
+    pub results: FileLookupResults,
+    pub prev: Option<i64>,
+    pub next: Option<i64>,
+}
+#[derive(Debug, Clone, Deserialize, Serialize)]
 struct TemplateHomePage {
     pub boards: Vec<Board>,
     pub posts: Vec<Post>
@@ -107,13 +131,17 @@ pub(crate) async fn thread_page(
         })?
         .ok_or(actix_web::error::ErrorNotFound(""))?;
     
-    let body = hb.render("thread", &TemplateThread{
+    let body = render_thread(&hb, boards, &board, &thread).unwrap();
+    Ok(HttpResponse::Ok().body(body))
+}
+
+pub(crate) fn render_thread(hb: &Handlebars<'_>, boards: Vec<Board>, board: &str, thread: &Thread) -> Result<String, handlebars::RenderError> {
+    hb.render("thread", &TemplateThread{
         boards,
         op: thread.posts[0].clone(),
-        board: board.clone(),
+        board: board.to_string(),
         posts: thread.posts[1..].to_vec()
-    }).unwrap();
-    Ok(HttpResponse::Ok().body(body))
+    })
 }
 
 #[derive(Deserialize)]
@@ -212,7 +240,7 @@ async fn index_search_page(
     hb: web::Data<Handlebars<'_>>,
     board: String,
     index: i64,
-    search_query: &String,
+    search_query: &str,
     remove_hidden_files: bool
 ) 
 -> actix_web::Result<HttpResponse> {
@@ -239,10 +267,11 @@ async fn index_search_page(
 
     let page_size = 15;
 
+    let search = PostSearch::parse(search_query).map_err(actix_web::error::ErrorBadRequest)?;
     let (posts, total_results) = db
-        .posts_full_text_search(
-            &board,
-            search_query,
+        .posts_search(
+            std::slice::from_ref(&board),
+            &search,
             nonzero_index-1,
             page_size,
             remove_hidden_files
@@ -279,7 +308,100 @@ async fn index_search_page(
                 TemplateThreadIndexThread{
                     op: t.posts[0].clone(), posts: t.posts[1..].to_vec()
             }).collect(),
-        query_string: format!("?s={}", search_query.clone())
+        query_string: format!("?s={}", utf8_percent_encode(search_query, NON_ALPHANUMERIC))
+    }).unwrap();
+    Ok(HttpResponse::Ok().body(body))
+}
+
+// Search on several boards at once, or all of them
+#[get("/_mitsuba/search")]
+pub(crate) async fn search_page(
+    db: web::Data<DBClient>,
+    hb: web::Data<Handlebars<'_>>,
+    query: web::Query<MultiBoardSearchQuery>,
+    user: AuthUser
+)
+-> actix_web::Result<HttpResponse> {
+    let remove_hidden_files = should_respect_hidden_files(user);
+    let boards = db.get_all_boards().await
+        .map_err(|e| {
+            error!("Error getting boards from DB: {}", e);
+            actix_web::error::ErrorInternalServerError("")
+        })?;
+    let search_boards = searchable_boards(&boards, query.boards.as_deref()).map_err(actix_web::error::ErrorBadRequest)?;
+    let search_query = query.q.clone().unwrap_or_default();
+    let search = PostSearch::parse(&search_query).map_err(actix_web::error::ErrorBadRequest)?;
+    let current = query.page.unwrap_or(1).max(1);
+    let page_size = 15;
+
+    let (posts, total_results) = match search_query.trim().is_empty() {
+        true => (Vec::new(), 0),
+        false => db.posts_search(&search_boards, &search, current-1, page_size, remove_hidden_files).await
+            .map_err(|e| {
+                error!("Error searching posts in DB: {}", e);
+                actix_web::error::ErrorInternalServerError("")
+            })?
+    };
+    // Calculate the number of pages, capped at 100
+    let num_pages = min((total_results as f64 / page_size as f64).ceil() as i64, 100);
+
+    let body = hb.render("search_page", &TemplateSearch {
+        boards,
+        search_query: search_query.clone(),
+        total_results,
+        next: current+1,
+        current,
+        prev: if current > 1 { current-1 } else { current },
+        pages: (1..=num_pages).collect(),
+        posts,
+        query_string: format!("?q={}&boards={}",
+            utf8_percent_encode(&search_query, NON_ALPHANUMERIC),
+            utf8_percent_encode(query.boards.as_deref().unwrap_or_default(), NON_ALPHANUMERIC))
+    }).unwrap();
+    Ok(HttpResponse::Ok().body(body))
+}
+
+#[derive(Deserialize)]
+struct FileLookupQuery {
+    page: Option<i64>,
+}
+
+// Every post that used a file, `kind` is the hash used to find it
+#[get("/_mitsuba/file/{kind:md5|sha256}/{hash:[A-Za-z0-9+=_-]+}")]
+pub(crate) async fn file_lookup_page(
+    db: web::Data<DBClient>,
+    hb: web::Data<Handlebars<'_>>,
+    info: web::Path<(String, String)>,
+    query: web::Query<FileLookupQuery>,
+    user: AuthUser
+) -> actix_web::Result<HttpResponse> {
+    let (kind, hash) = info.into_inner();
+    let page_size = 100;
+    let page = clamp_page(query.page.unwrap_or(0), page_size);
+    let remove_hidden_files = should_respect_hidden_files(user);
+    let results = match kind.as_str() {
+        "md5" => {
+            let md5 = parse_md5(&hash).ok_or(actix_web::error::ErrorBadRequest("Invalid md5"))?;
+            db.get_posts_by_md5(&md5, None, page, page_size, remove_hidden_files).await
+        },
+        _ => db.get_posts_by_sha256(&hash, page, page_size, remove_hidden_files).await
+    }.map_err(|e| {
+        error!("Error getting posts by {} from DB: {}", kind, e);
+        actix_web::error::ErrorInternalServerError("")
+    })?;
+    let boards = db.get_all_boards().await
+        .map_err(|e| {
+            error!("Error getting boards from DB: {}", e);
+            actix_web::error::ErrorInternalServerError("")
+        })?;
+
+    let body = hb.render("file_lookup", &TemplateFileLookup {
+        boards,
+        kind,
+        hash,
+        prev: (page > 0).then_some(page - 1),
+        next: ((page + 1) * page_size < results.total_results).then_some(page + 1),
+        results,
     }).unwrap();
     Ok(HttpResponse::Ok().body(body))
 }
@@ -340,6 +462,8 @@ pub(crate) fn build_handlebars() -> Handlebars<'static> {
             out.write(base64_to_32(b64_text).unwrap_or_default().as_ref())?;
             Ok(())
         }));
+    handlebars_helper!(format_time: |t: i64| format_timestamp(t));
+    handlebars.register_helper("format_time", Box::new(format_time));
     handlebars.register_helper("get_file_url",
     Box::new(|h: &Helper, _r: &Handlebars, _: &Context, _rc: &mut RenderContext, out: &mut dyn Output| -> HelperResult {
         let sha256 = h.param(0).ok_or(RenderErrorReason::Other("sha256 not found".to_string()))?.value().render();
@@ -350,7 +474,9 @@ pub(crate) fn build_handlebars() -> Handlebars<'static> {
     handlebars.register_helper("get_thumbnail_url",
     Box::new(|h: &Helper, _r: &Handlebars, _: &Context, _rc: &mut RenderContext, out: &mut dyn Output| -> HelperResult {
         let sha256 = h.param(0).ok_or(RenderErrorReason::Other("sha256 not found".to_string()))?.value().render();
-        out.write(get_file_url(&sha256, &".jpg".to_string(), true).as_ref())?;
+        // Thumbnails without a known extension come from upstream, and are always jpg
+        let ext = h.param(1).map(|p| p.value().render()).filter(|ext| !ext.is_empty()).unwrap_or(".jpg".to_string());
+        out.write(get_file_url(&sha256, &ext, true).as_ref())?;
         Ok(())
     }));
     handlebars
@@ -358,7 +484,7 @@ pub(crate) fn build_handlebars() -> Handlebars<'static> {
 
 #[derive(RustEmbed)]
 #[folder = "static"]
-struct Asset;
+pub(crate) struct Asset;
 
 fn handle_embedded_file(path: &str) -> HttpResponse {
     match Asset::get(path) {
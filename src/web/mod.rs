@@ -18,11 +18,15 @@ use s3::creds::time::Duration;
 use tokio::fs::create_dir_all;
 
 use crate::archiver::Archiver;
-use crate::object_storage::ObjectStorage;
 
 mod api;
 mod frontend;
 mod auth;
+mod foolfuuka;
+mod thread_download;
+
+pub(crate) use frontend::build_handlebars;
+pub(crate) use thread_download::ThreadExport;
 
 fn load_or_generate_key(data_folder_str: &String) -> actix_web::cookie::Key {
     let secret_seed_path = format!("{}/cookie_secret_seed", data_folder_str);
@@ -52,8 +56,7 @@ pub async fn web_main(archiver: Archiver) -> std::io::Result<()> {
     info!("Web adress: {}:{}", ip, port);
     create_dir_all(std::path::Path::new(&image_folder)).await.ok();
     HttpServer::new(move || {
-        let obc = ObjectStorage::new();
-        let mut app = App::new()
+        App::new()
         .wrap(
             SessionMiddleware::builder(
                 CookieSessionStore::default(),
@@ -74,13 +77,28 @@ pub async fn web_main(archiver: Archiver) -> std::io::Result<()> {
         .app_data(handlebars_ref.clone())
         .wrap(NormalizePath::new(middleware::TrailingSlash::Trim))
         .wrap(middleware::Compress::default())
+        .service(foolfuuka::get_thread)
+        .service(foolfuuka::get_post)
+        .service(foolfuuka::get_index)
+        .service(foolfuuka::search)
+        .service(api::search_posts)
+        .service(api::search_board)
+        .service(frontend::search_page)
+        .service(api::get_similar_posts)
+        .service(api::get_posts_by_md5)
+        .service(api::get_posts_by_sha256)
+        .service(frontend::file_lookup_page)
+        .service(api::post_similar_search)
         .service(api::get_index)
         .service(api::get_thread)
         .service(api::get_post)
+        .service(api::get_feed)
         .service(api::login_api)
         .service(api::logout_api)
         .service(api::authcheck_api)
         .service(frontend::login_page)
+        // Before thread_page, which would match the download path too
+        .service(thread_download::download_thread)
         .service(frontend::thread_page)
         .service(frontend::index_page_handler)
         .service(frontend::board_page)
@@ -92,21 +110,16 @@ pub async fn web_main(archiver: Archiver) -> std::io::Result<()> {
         .service(api::put_current_user)
         .service(api::put_board)
         .service(api::delete_board)
+        .service(api::get_thread_filters)
+        .service(api::post_thread_filter)
+        .service(api::delete_thread_filter)
         .service(api::post_mod_action)
         .service(frontend::home_page)
-        .service(web::resource("/static/{_:.*}").route(web::get().to(frontend::dist)));
-
-        if obc.enabled {
-            app = app.service(web::resource("/img/{_:.*}").route(web::get().to(api::get_file_object_storage_handler)))
-                    .app_data(web::Data::new(obc))
-                    .service(api::get_full_image_object_storage)
-                    .service(api::get_thumbnail_image_object_storage);
-        } else {
-            app = app.service(actix_files::Files::new("/img", image_folder.clone()))
-                .service(api::get_thumbnail_image)
-                .service(api::get_full_image);
-        }
-        app
+        .service(web::resource("/static/{_:.*}").route(web::get().to(frontend::dist)))
+        .app_data(web::Data::from(archiver.file_store.clone()))
+        .service(web::resource("/img/{path:.*}").route(web::get().to(api::get_file_handler)))
+        .service(api::get_thumbnail_image)
+        .service(api::get_full_image)
     })
     .bind(format!("{}:{}", ip, port))?
     .run()
@@ -0,0 +1,187 @@
+use std::collections::{HashSet, VecDeque};
+use std::sync::OnceLock;
+
+#[allow(unused_imports)]
+use log::{info, warn, error, debug};
+use actix_web::{get, web, HttpResponse};
+use bytes::Bytes;
+use handlebars::Handlebars;
+use regex::{Captures, Regex};
+
+use crate::archiver::Archiver;
+use crate::archiver::board_exporter::attachments;
+use crate::models::ThreadExportReport;
+use crate::web::auth::{AuthUser, should_respect_hidden_files};
+use crate::web::frontend::{Asset, render_thread};
+use crate::zip::ZipWriter;
+
+// Images are already compressed, only text is worth deflating
+fn is_text(path: &str) -> bool {
+    [".html", ".css", ".js", ".json", ".txt"].iter().any(|ext| path.ends_with(ext))
+}
+
+fn regex(cell: &'static OnceLock<Regex>, pattern: &str) -> &'static Regex {
+    cell.get_or_init(|| Regex::new(pattern).unwrap())
+}
+
+/**
+ * Turns a rendered thread page into one that works offline, from a folder:
+ * stylesheets are inlined, alternate styles dropped, links to the thread's own posts become anchors,
+ * and static assets and images are linked relative to the page.
+ */
+fn offline_html(html: &str, board: &str, no: i64) -> String {
+    static ALTERNATE: OnceLock<Regex> = OnceLock::new();
+    static STYLESHEET: OnceLock<Regex> = OnceLock::new();
+    static ABSOLUTE: OnceLock<Regex> = OnceLock::new();
+    let html = regex(&ALTERNATE, r#"<link rel="alternate stylesheet"[^>]*>\n?"#).replace_all(html, "");
+    let html = regex(&STYLESHEET, r#"<link rel="stylesheet"[^>]*href="/static/([^"]+\.css)"[^>]*>"#).replace_all(&html, |caps: &Captures| {
+        match Asset::get(&caps[1]) {
+            Some(css) => format!("<style type=\"text/css\">{}</style>", String::from_utf8_lossy(&css.data)),
+            None => caps[0].to_string()
+        }
+    });
+    let html = html.replace(&format!("href=\"/{}/thread/{}#p", board, no), "href=\"#p");
+    regex(&ABSOLUTE, r#"(["'(])/(static|img)/"#).replace_all(&html, "$1$2/").to_string()
+}
+
+// Static assets the offline page links to, in the order they appear.
+// They are embedded in the binary, so links that post comments make up can't reach anything else.
+fn linked_assets(html: &str) -> Vec<String> {
+    static RELATIVE: OnceLock<Regex> = OnceLock::new();
+    let mut seen = HashSet::new();
+    regex(&RELATIVE, r#"["'(]static/([A-Za-z0-9@._/-]+)"#).captures_iter(html)
+        .map(|caps| caps[1].to_string())
+        .filter(|path| seen.insert(path.clone()))
+        .collect()
+}
+
+/**
+ * A thread being written as a zip file that can be browsed offline, with an index.html page
+ * and the static assets, images and thumbnails it uses, all in a `{board}-{no}` folder.
+ * Files are added one at a time by `next_chunk`, so the whole zip is never held in memory.
+ */
+pub(crate) struct ThreadExport {
+    zip: Option<ZipWriter<Vec<u8>>>,
+    folder: String,
+    mtime: i64,
+    // Storage keys of the thread's files, taken from its posts
+    files: VecDeque<String>,
+    pub report: ThreadExportReport,
+}
+
+impl ThreadExport {
+    /**
+     * Renders the thread page and starts the zip with it and its static assets.
+     * Returns None if the thread is not in the archive.
+     */
+    pub(crate) async fn start(archiver: &Archiver, hb: &Handlebars<'_>, board: &str, no: i64, remove_hidden_files: bool) -> anyhow::Result<Option<Self>> {
+        let Some(thread) = archiver.db_client.get_thread(&board.to_string(), no, remove_hidden_files).await? else {
+            return Ok(None)
+        };
+        let boards = archiver.db_client.get_all_boards().await?;
+        let html = offline_html(&render_thread(hb, boards, board, &thread)?, board, no);
+        let mtime = thread.posts.iter().map(|p| p.time).max().unwrap_or(0);
+        let folder = format!("{}-{}", board, no);
+        let mut zip = ZipWriter::new(Vec::new());
+        zip.add(&format!("{}/index.html", folder), html.as_bytes(), true, mtime)?;
+        for path in linked_assets(&html) {
+            // Links to assets that don't exist are broken online too
+            if let Some(asset) = Asset::get(&path) {
+                zip.add(&format!("{}/static/{}", folder, path), &asset.data, is_text(&path), mtime)?;
+            }
+        }
+        let mut seen = HashSet::new();
+        let files = thread.posts.iter()
+            .flat_map(|post| attachments(post).into_iter().map(|attachment| attachment.key()))
+            .filter(|key| seen.insert(key.clone()))
+            .collect();
+        let report = ThreadExportReport { posts: thread.posts.len() as u64, ..Default::default() };
+        Ok(Some(Self { zip: Some(zip), folder, mtime, files, report }))
+    }
+
+    /**
+     * Adds the next file of the thread that is in storage,
+     * and returns the part of the zip written since the last call. None once the zip is complete.
+     */
+    pub(crate) async fn next_chunk(&mut self, archiver: &Archiver) -> anyhow::Result<Option<Vec<u8>>> {
+        let Some(zip) = self.zip.as_mut() else {
+            return Ok(None)
+        };
+        while let Some(key) = self.files.pop_front() {
+            match archiver.file_store.get(&key).await? {
+                Some(bytes) => {
+                    zip.add(&format!("{}/img/{}", self.folder, key), &bytes, false, self.mtime)?;
+                    self.report.files += 1;
+                    return Ok(Some(std::mem::take(zip.get_mut())))
+                },
+                None => {
+                    debug!("File {} of {} is missing from {} storage", key, self.folder, archiver.file_store.name());
+                    self.report.missing_files += 1;
+                }
+            }
+        }
+        let zip = self.zip.take().unwrap();
+        Ok(Some(zip.finish()?))
+    }
+}
+
+#[get("/{board:[A-z0-9]+}/thread/{no:\\d+}/download")]
+pub(crate) async fn download_thread(
+    archiver: web::Data<Archiver>,
+    hb: web::Data<Handlebars<'_>>,
+    info: web::Path<(String, i64)>,
+    user: AuthUser
+)
+-> actix_web::Result<HttpResponse> {
+    let remove_hidden_files = should_respect_hidden_files(user);
+    let (board, no) = info.into_inner();
+    let export = ThreadExport::start(&archiver, &hb, &board, no, remove_hidden_files).await
+        .map_err(|e| {
+            error!("Error exporting thread /{}/{}: {}", board, no, e);
+            actix_web::error::ErrorInternalServerError("")
+        })?
+        .ok_or(actix_web::error::ErrorNotFound(""))?;
+    let filename = format!("{}-{}.zip", board, no);
+    // Sent as it's written, one file at a time
+    let body = futures::stream::unfold(Some(export), move |export| {
+        let archiver = archiver.clone();
+        let board = board.clone();
+        async move {
+            let mut export = export?;
+            match export.next_chunk(&archiver).await {
+                Ok(Some(chunk)) => Some((Ok(Bytes::from(chunk)), Some(export))),
+                Ok(None) => None,
+                Err(e) => {
+                    error!("Error exporting thread /{}/{}: {}", board, no, e);
+                    Some((Err(actix_web::error::ErrorInternalServerError("")), None))
+                }
+            }
+        }
+    });
+    Ok(HttpResponse::Ok()
+        .content_type("application/zip")
+        .insert_header(("Content-Disposition", format!("attachment; filename=\"{}\"", filename)))
+        .streaming::<_, actix_web::Error>(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_offline_html() {
+        let html = concat!(
+            "<link rel=\"stylesheet\" href=\"/static/css/missing.css\">\n",
+            "<link rel=\"alternate stylesheet\" style=\"text/css\" href=\"/static/css/photon.699.css\" title=\"Photon\">\n",
+            "<a href=\"/b/thread/1#p2\">No.</a><a href=\"/b/thread/3#p3\">&gt;&gt;3</a>\n",
+            "<img src=\"/img/thumb/AB/C/ABC.jpg\"><img src=\"/static/image/spoiler.png\"><a href=\"/img/thumb/AB/C/ABC.jpg\">"
+        );
+        let offline = offline_html(html, "b", 1);
+        assert!(!offline.contains("Photon"));
+        assert!(offline.contains("href=\"#p2\""));
+        assert!(offline.contains("href=\"/b/thread/3#p3\""));
+        assert_eq!(linked_assets(&offline), vec!["css/missing.css", "image/spoiler.png"]);
+        // Comments can't make the export read files outside of storage
+        assert!(linked_assets("(static/../../.env").iter().all(|path| Asset::get(path).is_none()));
+    }
+}
@@ -0,0 +1,147 @@
+use std::io::Write;
+
+use flate2::{Compression, Crc};
+use flate2::write::DeflateEncoder;
+
+use crate::util::civil_from_days;
+
+struct CentralEntry {
+    name: String,
+    method: u16,
+    time: u16,
+    date: u16,
+    crc: u32,
+    compressed_size: u32,
+    size: u32,
+    offset: u32,
+}
+
+/**
+ * Writes zip files for thread downloads, without zip64, so archives must stay under 4 GB.
+ * Entries are either deflated or stored as they are, for files that are already compressed like images.
+ */
+pub struct ZipWriter<W: Write> {
+    inner: W,
+    offset: u32,
+    entries: Vec<CentralEntry>,
+}
+
+impl<W: Write> ZipWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner, offset: 0, entries: Vec::new() }
+    }
+
+    pub fn add(&mut self, name: &str, data: &[u8], deflate: bool, mtime: i64) -> std::io::Result<()> {
+        let too_large = || std::io::Error::new(std::io::ErrorKind::InvalidInput, "Zip file too large");
+        let mut crc = Crc::new();
+        crc.update(data);
+        let compressed = if deflate {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data)?;
+            Some(encoder.finish()?)
+        } else {
+            None
+        };
+        let (time, date) = dos_time(mtime);
+        let entry = CentralEntry {
+            name: name.to_string(),
+            method: if deflate { 8 } else { 0 },
+            time,
+            date,
+            crc: crc.sum(),
+            compressed_size: compressed.as_ref().map_or(data.len(), |c| c.len()).try_into().map_err(|_| too_large())?,
+            size: data.len().try_into().map_err(|_| too_large())?,
+            offset: self.offset,
+        };
+        let mut header = Vec::with_capacity(30 + name.len());
+        header.extend_from_slice(&0x04034b50u32.to_le_bytes());
+        header.extend_from_slice(&20u16.to_le_bytes());
+        // Names are UTF-8
+        header.extend_from_slice(&0x0800u16.to_le_bytes());
+        header.extend_from_slice(&entry.method.to_le_bytes());
+        header.extend_from_slice(&entry.time.to_le_bytes());
+        header.extend_from_slice(&entry.date.to_le_bytes());
+        header.extend_from_slice(&entry.crc.to_le_bytes());
+        header.extend_from_slice(&entry.compressed_size.to_le_bytes());
+        header.extend_from_slice(&entry.size.to_le_bytes());
+        header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(name.as_bytes());
+        self.inner.write_all(&header)?;
+        self.inner.write_all(compressed.as_deref().unwrap_or(data))?;
+        self.offset = self.offset.checked_add(header.len() as u32)
+            .and_then(|o| o.checked_add(entry.compressed_size))
+            .ok_or_else(too_large)?;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    // What has been written so far, so it can be sent before the zip is complete
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    // Writes the central directory, which lists every entry at the end of the file
+    pub fn finish(mut self) -> std::io::Result<W> {
+        let mut directory = Vec::new();
+        for entry in &self.entries {
+            directory.extend_from_slice(&0x02014b50u32.to_le_bytes());
+            directory.extend_from_slice(&20u16.to_le_bytes());
+            directory.extend_from_slice(&20u16.to_le_bytes());
+            directory.extend_from_slice(&0x0800u16.to_le_bytes());
+            directory.extend_from_slice(&entry.method.to_le_bytes());
+            directory.extend_from_slice(&entry.time.to_le_bytes());
+            directory.extend_from_slice(&entry.date.to_le_bytes());
+            directory.extend_from_slice(&entry.crc.to_le_bytes());
+            directory.extend_from_slice(&entry.compressed_size.to_le_bytes());
+            directory.extend_from_slice(&entry.size.to_le_bytes());
+            directory.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+            // Extra field, comment, disk number, internal and external attributes
+            directory.extend_from_slice(&[0u8; 12]);
+            directory.extend_from_slice(&entry.offset.to_le_bytes());
+            directory.extend_from_slice(entry.name.as_bytes());
+        }
+        let count = self.entries.len() as u16;
+        let mut end = Vec::with_capacity(22);
+        end.extend_from_slice(&0x06054b50u32.to_le_bytes());
+        end.extend_from_slice(&[0u8; 4]);
+        end.extend_from_slice(&count.to_le_bytes());
+        end.extend_from_slice(&count.to_le_bytes());
+        end.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        end.extend_from_slice(&self.offset.to_le_bytes());
+        end.extend_from_slice(&0u16.to_le_bytes());
+        self.inner.write_all(&directory)?;
+        self.inner.write_all(&end)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+// MS-DOS time and date, which zip uses, from a unix timestamp. They start in 1980.
+fn dos_time(timestamp: i64) -> (u16, u16) {
+    let timestamp = timestamp.max(315532800);
+    let seconds = timestamp.rem_euclid(86400);
+    let (year, month, day) = civil_from_days(timestamp.div_euclid(86400));
+    let time = ((seconds / 3600) << 11) | ((seconds % 3600 / 60) << 5) | ((seconds % 60) / 2);
+    let date = ((year - 1980) << 9) | (month << 5) | day;
+    (time as u16, date as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_zip_writer() {
+        let mut writer = ZipWriter::new(Vec::new());
+        writer.add("index.html", "<html></html>".repeat(100).as_bytes(), true, 1700000000).unwrap();
+        writer.add("img/thumb/AB/C/ABC.jpg", &[1, 2, 3], false, 1700000000).unwrap();
+        let bytes = writer.finish().unwrap();
+        assert_eq!(&bytes[..4], &[0x50, 0x4b, 0x03, 0x04]);
+        // The end of central directory record lists both entries
+        let end = &bytes[bytes.len() - 22..];
+        assert_eq!(&end[..4], &[0x50, 0x4b, 0x05, 0x06]);
+        assert_eq!(u16::from_le_bytes([end[10], end[11]]), 2);
+        assert_eq!(dos_time(1700000000), ((22 << 11) | (13 << 5) | 10, (43 << 9) | (11 << 5) | 14));
+    }
+}